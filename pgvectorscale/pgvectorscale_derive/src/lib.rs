@@ -95,6 +95,19 @@ fn impl_writeable_macro(ast: &syn::DeriveInput) -> TokenStream {
                 unsafe { tape.write(&bytes) }
             }
 
+            /// Writes the node after `prefix`, in the same item. Readers find the node at the end of the item.
+            pub fn write_with_prefix<S: crate::access_method::stats::StatsNodeWrite>(&self, prefix: &[u8], tape: &mut crate::util::tape::Tape, stats: &mut S) -> ItemPointer {
+                if prefix.is_empty() {
+                    return self.write(tape, stats);
+                }
+                let bytes = self.serialize_to_vec();
+                let mut data = Vec::with_capacity(prefix.len() + bytes.len());
+                data.extend_from_slice(prefix);
+                data.extend_from_slice(&bytes);
+                stats.record_write();
+                unsafe { tape.write(&data) }
+            }
+
             pub fn serialize_to_vec(&self) -> rkyv::util::AlignedVec {
                 //TODO 256 probably too small
                 rkyv::to_bytes::<_, 256>(self).unwrap()
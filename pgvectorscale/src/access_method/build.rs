@@ -1,3 +1,5 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::time::Instant;
 
 use pgrx::pg_sys::{pgstat_progress_update_param, AsPgCStr};
@@ -15,17 +17,26 @@ use crate::util::*;
 
 use self::ports::PROGRESS_CREATE_IDX_SUBPHASE;
 
+use super::entry_points::{EntryPointSample, EntryPoints};
+use super::full_vector::write_full_vector;
 use super::graph_neighbor_store::BuilderNeighborCache;
 use super::sbq::SbqSpeedupStorage;
 
-use super::meta_page::MetaPage;
+use super::meta_page::{DistanceType, MetaPage};
+use super::parallel_build::ParallelBuild;
 
+use super::labels::get_label_attribute;
 use super::plain_storage::PlainStorage;
+use super::pq::PqStorage;
+use super::rabitq::RabitqStorage;
+use super::sq8::Sq8Storage;
 use super::storage::{Storage, StorageType};
+use super::storage_common::form_scalar_columns;
 
-enum StorageBuildState<'a, 'b, 'c, 'd, 'e> {
-    SbqSpeedup(&'a mut SbqSpeedupStorage<'b>, &'c mut BuildState<'d, 'e>),
-    Plain(&'a mut PlainStorage<'b>, &'c mut BuildState<'d, 'e>),
+/// The state of the heap scans of an index build, see build_callback.
+struct StorageBuildState<'a, 'b, 'c, 'd, S> {
+    storage: &'a mut S,
+    build_state: &'b mut BuildState<'c, 'd>,
 }
 
 struct BuildState<'a, 'b> {
@@ -33,9 +44,14 @@ struct BuildState<'a, 'b> {
     meta_page: MetaPage,
     ntuples: usize,
     tape: Tape<'a>, //The tape is a memory abstraction over Postgres pages for writing data.
+    full_vector_tape: Option<Tape<'a>>,
     graph: Graph<'b>,
     started: Instant,
     stats: InsertStats,
+    parallel: Option<ParallelBuild>,
+    /// The sample the centroids of the data are computed from, until they are.
+    entry_point_sample: Option<EntryPointSample>,
+    entry_points: Option<EntryPoints>,
 }
 
 impl<'a, 'b> BuildState<'a, 'b> {
@@ -44,18 +60,83 @@ impl<'a, 'b> BuildState<'a, 'b> {
         meta_page: MetaPage,
         graph: Graph<'b>,
         page_type: PageType,
+        parallel_workers: usize,
     ) -> Self {
         let tape = unsafe { Tape::new(index_relation, page_type) };
+        let full_vector_tape = meta_page
+            .get_full_vectors_in_index()
+            .then(|| unsafe { Tape::new(index_relation, PageType::FullVector) });
+        //the parallel build doesn't know about labels, indexes with labels are built serially
+        let parallel = if get_label_attribute(index_relation).is_some() {
+            if parallel_workers > 0 {
+                notice!("diskann indexes with a label column are built by a single thread");
+            }
+            None
+        } else {
+            ParallelBuild::new(&meta_page, parallel_workers)
+        };
+        let entry_point_sample = Some(EntryPointSample::new(
+            meta_page.get_num_dimensions_to_index() as usize,
+        ));
 
         BuildState {
             memcxt: PgMemoryContexts::new("diskann build context"),
             ntuples: 0,
             meta_page: meta_page,
             tape,
+            full_vector_tape,
             graph: graph,
             started: Instant::now(),
             stats: InsertStats::new(),
+            parallel,
+            entry_point_sample,
+            entry_points: None,
+        }
+    }
+
+    /// Samples the vectors of the training scan for the centroids of the data.
+    fn add_entry_point_sample(&mut self, vector: &[f32]) {
+        if let Some(sample) = &mut self.entry_point_sample {
+            sample.add_sample(vector, None);
+        }
+    }
+
+    /// Computes the centroids of the sample. The nodes closest to them become the init ids.
+    fn start_entry_points(&mut self) {
+        if let Some(sample) = self.entry_point_sample.take() {
+            self.entry_points = Some(sample.into_entry_points(
+                self.meta_page.get_distance_type(),
+                self.meta_page.get_distance_function(),
+            ));
+        }
+    }
+
+    fn add_entry_point_candidate(&mut self, node: IndexPointer, vector: &[f32]) {
+        if let Some(sample) = &mut self.entry_point_sample {
+            //without a training scan, the centroids are computed from the first nodes
+            sample.add_sample(vector, Some(node));
+            if sample.is_full() {
+                self.start_entry_points();
+            }
+        } else if let Some(entry_points) = &mut self.entry_points {
+            entry_points.add_node(node, vector);
+        }
+    }
+
+    /// Moves the init ids to the nodes closest to the centroids, if they changed.
+    fn update_init_ids(&mut self, index: &PgRelation) {
+        let Some(init_ids) = self
+            .entry_points
+            .as_mut()
+            .and_then(|entry_points| entry_points.take_init_ids())
+        else {
+            return;
+        };
+        //the new init ids have to be connected to the graph already
+        if let Some(parallel) = &mut self.parallel {
+            parallel.flush(&mut self.graph, &mut self.stats);
         }
+        self.graph.update_init_ids(index, init_ids, &mut self.stats);
     }
 }
 
@@ -77,11 +158,34 @@ pub extern "C" fn ambuild(
         opt.get_storage_type(),
     );
 
-    let dimensions = index_relation.tuple_desc().get(0).unwrap().atttypmod;
-    assert!(dimensions > 0 && dimensions < 2000);
-    let meta_page = unsafe { MetaPage::create(&index_relation, dimensions as _, opt) };
+    let distance_type = DistanceType::from_index(&index_relation);
+    let dimensions = get_num_dimensions(&index_relation);
+    let meta_page = unsafe { MetaPage::create(&index_relation, dimensions, distance_type, opt) };
 
-    let ntuples = do_heap_scan(index_info, &heap_relation, &index_relation, meta_page);
+    let (heap, index) = (&heap_relation, &index_relation);
+    let ntuples = match meta_page.get_storage_type() {
+        StorageType::Plain => {
+            let storage =
+                PlainStorage::new_for_build(index, heap, meta_page.get_distance_function());
+            do_heap_scan(storage, false, index_info, heap, index, meta_page)
+        }
+        StorageType::SbqSpeedup | StorageType::SbqCompression => {
+            let storage = SbqSpeedupStorage::new_for_build(index, heap, &meta_page);
+            do_heap_scan(storage, true, index_info, heap, index, meta_page)
+        }
+        StorageType::Pq => {
+            let storage = PqStorage::new_for_build(index, heap, &meta_page);
+            do_heap_scan(storage, true, index_info, heap, index, meta_page)
+        }
+        StorageType::Sq8 => {
+            let storage = Sq8Storage::new_for_build(index, heap, &meta_page);
+            do_heap_scan(storage, true, index_info, heap, index, meta_page)
+        }
+        StorageType::Rabitq => {
+            let storage = RabitqStorage::new_for_build(index, heap, &meta_page);
+            do_heap_scan(storage, true, index_info, heap, index, meta_page)
+        }
+    };
 
     let mut result = unsafe { PgBox::<pg_sys::IndexBuildResult>::alloc0() };
     result.heap_tuples = ntuples as f64;
@@ -104,85 +208,148 @@ pub unsafe extern "C" fn aminsert(
     let index_relation = unsafe { PgRelation::from_pg(indexrel) };
     let heap_relation = unsafe { PgRelation::from_pg(heaprel) };
     let mut meta_page = MetaPage::fetch(&index_relation);
-    let vec = PgVector::from_pg_parts(values, isnull, 0, &meta_page, true, false);
+    let vec = PgVector::from_pg_parts(
+        values,
+        isnull,
+        0,
+        &meta_page,
+        true,
+        meta_page.get_full_vectors_in_index(),
+    );
     if let None = vec {
         //todo handle NULLs?
         return false;
     }
-    let vec = vec.unwrap();
-    let heap_pointer = ItemPointer::with_item_pointer_data(*heap_tid);
-
-    let mut storage = meta_page.get_storage_type();
+    let tuple = InsertTuple {
+        index_relation: &index_relation,
+        vector: vec.unwrap(),
+        heap_pointer: ItemPointer::with_item_pointer_data(*heap_tid),
+        scalar_columns: form_scalar_columns(&index_relation, values, isnull),
+    };
+
+    let (heap, index) = (&heap_relation, &index_relation);
     let mut stats = InsertStats::new();
-    match &mut storage {
+    match meta_page.get_storage_type() {
         StorageType::Plain => {
-            let plain = PlainStorage::load_for_insert(
-                &index_relation,
-                &heap_relation,
-                meta_page.get_distance_function(),
-            );
-            insert_storage(
-                &plain,
-                &index_relation,
-                vec,
-                heap_pointer,
-                &mut meta_page,
-                &mut stats,
-            );
+            let plain =
+                PlainStorage::load_for_insert(index, heap, meta_page.get_distance_function());
+            insert_storage(&plain, tuple, &mut meta_page, &mut stats);
         }
         StorageType::SbqSpeedup | StorageType::SbqCompression => {
-            let bq = SbqSpeedupStorage::load_for_insert(
-                &heap_relation,
-                &index_relation,
-                &meta_page,
-                &mut stats.quantizer_stats,
-            );
-            insert_storage(
-                &bq,
-                &index_relation,
-                vec,
-                heap_pointer,
-                &mut meta_page,
-                &mut stats,
-            );
+            let quantizer_stats = &mut stats.quantizer_stats;
+            let bq = SbqSpeedupStorage::load_for_insert(heap, index, &meta_page, quantizer_stats);
+            insert_storage(&bq, tuple, &mut meta_page, &mut stats);
+        }
+        StorageType::Pq => {
+            let quantizer_stats = &mut stats.quantizer_stats;
+            let pq = PqStorage::load_for_insert(heap, index, &meta_page, quantizer_stats);
+            insert_storage(&pq, tuple, &mut meta_page, &mut stats);
+        }
+        StorageType::Sq8 => {
+            let quantizer_stats = &mut stats.quantizer_stats;
+            let sq8 = Sq8Storage::load_for_insert(heap, index, &meta_page, quantizer_stats);
+            insert_storage(&sq8, tuple, &mut meta_page, &mut stats);
+        }
+        StorageType::Rabitq => {
+            let quantizer_stats = &mut stats.quantizer_stats;
+            let rabitq = RabitqStorage::load_for_insert(heap, index, &meta_page, quantizer_stats);
+            insert_storage(&rabitq, tuple, &mut meta_page, &mut stats);
         }
     }
     false
 }
 
-unsafe fn insert_storage<S: Storage>(
-    storage: &S,
-    index_relation: &PgRelation,
+/// A tuple aminsert adds to the index, the same for every storage layout.
+struct InsertTuple<'a> {
+    index_relation: &'a PgRelation,
     vector: PgVector,
     heap_pointer: ItemPointer,
+    scalar_columns: Vec<u8>,
+}
+
+unsafe fn insert_storage<S: Storage>(
+    storage: &S,
+    tuple: InsertTuple,
     meta_page: &mut MetaPage,
     stats: &mut InsertStats,
 ) {
-    let mut tape = Tape::new(&index_relation, S::page_type());
+    let InsertTuple {
+        index_relation,
+        vector,
+        heap_pointer,
+        scalar_columns,
+    } = tuple;
+    let prefix = if meta_page.get_full_vectors_in_index() {
+        let mut tape = Tape::resume(index_relation, PageType::FullVector);
+        let prefix = write_full_vector(&vector, &scalar_columns, &mut tape, stats);
+        tape.close();
+        Cow::Owned(prefix)
+    } else {
+        Cow::Borrowed(scalar_columns.as_slice())
+    };
+
+    let mut tape = Tape::resume(index_relation, S::page_type());
     let index_pointer = storage.create_node(
         vector.to_index_slice(),
         heap_pointer,
+        &prefix,
         &meta_page,
         &mut tape,
         stats,
     );
+    tape.close();
 
     let mut graph = Graph::new(GraphNeighborStore::Disk, meta_page);
     graph.insert(&index_relation, index_pointer, vector, storage, stats)
 }
 
+fn get_num_dimensions(index_relation: &PgRelation) -> u32 {
+    let dimensions = index_relation.tuple_desc().get(0).unwrap().atttypmod;
+    //nodes larger than a page are chained over several pages, see Tape::write
+    assert!(dimensions > 0);
+    dimensions as _
+}
+
+/// Writes an empty index to the init fork of an index on an unlogged table. The index is reset to it
+/// after a crash.
 #[pg_guard]
-pub extern "C" fn ambuildempty(_index_relation: pg_sys::Relation) {
-    panic!("ambuildempty: not yet implemented")
+pub extern "C" fn ambuildempty(index_relation: pg_sys::Relation) {
+    let index_relation = unsafe { PgRelation::from_pg(index_relation) };
+    let opt = TSVIndexOptions::from_relation(&index_relation);
+    let distance_type = DistanceType::from_index(&index_relation);
+    let dimensions = get_num_dimensions(&index_relation);
+    unsafe { MetaPage::create_in_init_fork(&index_relation, dimensions, distance_type, opt) };
 }
 
-fn do_heap_scan<'a>(
+/// The number of workers the planner chose for the build, as it would for a parallel btree build: it honours
+/// max_parallel_maintenance_workers, the parallel_workers storage parameter of the table and the size of the table.
+/// They become build threads of this backend (see ParallelBuild), the heap scan isn't split across workers.
+/// Postgres 15 and 16 only plan workers for btree builds and leave ii_ParallelWorkers at 0 for other access methods,
+/// so the planner is asked directly then.
+fn parallel_workers(
     index_info: *mut pg_sys::IndexInfo,
-    heap_relation: &'a PgRelation,
-    index_relation: &'a PgRelation,
+    heap_relation: &PgRelation,
+    index_relation: &PgRelation,
+) -> usize {
+    let planned = unsafe { (*index_info).ii_ParallelWorkers };
+    let workers = if planned > 0 {
+        planned
+    } else {
+        unsafe { pg_sys::plan_create_index_workers(heap_relation.oid(), index_relation.oid()) }
+    };
+    workers.max(0) as usize
+}
+
+/// Builds the graph of the index. A storage that is trained (all but plain) first learns from a scan of the table.
+fn do_heap_scan<S: Storage>(
+    mut storage: S,
+    train: bool,
+    index_info: *mut pg_sys::IndexInfo,
+    heap_relation: &PgRelation,
+    index_relation: &PgRelation,
     meta_page: MetaPage,
 ) -> usize {
-    let storage = meta_page.get_storage_type();
+    let parallel_workers = parallel_workers(index_info, heap_relation, index_relation);
 
     let mut mp2 = meta_page.clone();
     let graph = Graph::new(
@@ -190,108 +357,109 @@ fn do_heap_scan<'a>(
         &mut mp2,
     );
     let mut write_stats = WriteStats::new();
-    match storage {
-        StorageType::Plain => {
-            let mut plain = PlainStorage::new_for_build(
-                index_relation,
-                heap_relation,
-                meta_page.get_distance_function(),
-            );
-            plain.start_training(&meta_page);
-            let page_type = PlainStorage::page_type();
-            let mut bs = BuildState::new(index_relation, meta_page, graph, page_type);
-            let mut state = StorageBuildState::Plain(&mut plain, &mut bs);
-
-            unsafe {
-                pg_sys::IndexBuildHeapScan(
-                    heap_relation.as_ptr(),
-                    index_relation.as_ptr(),
-                    index_info,
-                    Some(build_callback),
-                    &mut state,
-                );
-            }
 
-            finalize_index_build(&mut plain, &mut bs, write_stats)
+    if train {
+        unsafe {
+            pgstat_progress_update_param(PROGRESS_CREATE_IDX_SUBPHASE, BUILD_PHASE_TRAINING);
         }
-        StorageType::SbqSpeedup | StorageType::SbqCompression => {
-            let mut bq =
-                SbqSpeedupStorage::new_for_build(index_relation, heap_relation, &meta_page);
-
-            let page_type = SbqSpeedupStorage::page_type();
-
-            unsafe {
-                pgstat_progress_update_param(PROGRESS_CREATE_IDX_SUBPHASE, BUILD_PHASE_TRAINING);
-            }
-
-            bq.start_training(&meta_page);
+    }
 
-            let mut bs = BuildState::new(index_relation, meta_page, graph, page_type);
-            let mut state = StorageBuildState::SbqSpeedup(&mut bq, &mut bs);
+    storage.start_training(&meta_page);
 
-            unsafe {
-                pg_sys::IndexBuildHeapScan(
-                    heap_relation.as_ptr(),
-                    index_relation.as_ptr(),
-                    index_info,
-                    Some(build_callback_bq_train),
-                    &mut state,
-                );
-            }
-            bq.finish_training(&mut write_stats);
+    let mut bs = BuildState::new(
+        index_relation,
+        meta_page,
+        graph,
+        S::page_type(),
+        parallel_workers,
+    );
 
-            unsafe {
-                pgstat_progress_update_param(
-                    PROGRESS_CREATE_IDX_SUBPHASE,
-                    BUILD_PHASE_BUILDING_GRAPH,
-                );
-            }
+    if train {
+        let mut state = StorageBuildState {
+            storage: &mut storage,
+            build_state: &mut bs,
+        };
+
+        unsafe {
+            pg_sys::IndexBuildHeapScan(
+                heap_relation.as_ptr(),
+                index_relation.as_ptr(),
+                index_info,
+                Some(build_callback_bq_train::<S>),
+                &mut state,
+            );
+        }
+        storage.finish_training(&mut write_stats);
+        bs.start_entry_points();
 
-            let mut state = StorageBuildState::SbqSpeedup(&mut bq, &mut bs);
+        unsafe {
+            pgstat_progress_update_param(PROGRESS_CREATE_IDX_SUBPHASE, BUILD_PHASE_BUILDING_GRAPH);
+        }
+    }
 
-            unsafe {
-                pg_sys::IndexBuildHeapScan(
-                    heap_relation.as_ptr(),
-                    index_relation.as_ptr(),
-                    index_info,
-                    Some(build_callback),
-                    &mut state,
-                );
-            }
+    let mut state = StorageBuildState {
+        storage: &mut storage,
+        build_state: &mut bs,
+    };
+
+    unsafe {
+        pg_sys::IndexBuildHeapScan(
+            heap_relation.as_ptr(),
+            index_relation.as_ptr(),
+            index_info,
+            Some(build_callback::<S>),
+            &mut state,
+        );
+    }
 
-            unsafe {
-                pgstat_progress_update_param(
-                    PROGRESS_CREATE_IDX_SUBPHASE,
-                    BUILD_PHASE_FINALIZING_GRAPH,
-                );
-            }
-            finalize_index_build(&mut bq, &mut bs, write_stats)
+    if train {
+        unsafe {
+            pgstat_progress_update_param(
+                PROGRESS_CREATE_IDX_SUBPHASE,
+                BUILD_PHASE_FINALIZING_GRAPH,
+            );
         }
     }
+    finalize_index_build(index_relation, &mut storage, &mut bs, write_stats)
 }
 
 fn finalize_index_build<S: Storage>(
+    index: &PgRelation,
     storage: &mut S,
     state: &mut BuildState,
     mut write_stats: WriteStats,
 ) -> usize {
+    state.start_entry_points();
+    state.update_init_ids(index);
+
+    let mut pruned_in_parallel = match &mut state.parallel {
+        Some(parallel) => {
+            parallel.flush(&mut state.graph, &mut state.stats);
+            parallel.prune_for_finalize(&state.graph, &mut write_stats.prune_stats)
+        }
+        None => HashMap::new(),
+    };
+
     match state.graph.get_neighbor_store() {
         GraphNeighborStore::Builder(builder) => {
             for (&index_pointer, neighbors) in builder.iter() {
                 write_stats.num_nodes += 1;
                 let prune_neighbors;
-                let neighbors =
-                    if neighbors.len() > state.graph.get_meta_page().get_num_neighbors() as _ {
-                        //OPT: get rid of this clone
-                        prune_neighbors = state.graph.prune_neighbors(
-                            neighbors.clone(),
-                            storage,
-                            &mut write_stats.prune_stats,
-                        );
-                        &prune_neighbors
-                    } else {
-                        neighbors
-                    };
+                let neighbors = if let Some(pruned) = pruned_in_parallel.remove(&index_pointer) {
+                    prune_neighbors = pruned;
+                    &prune_neighbors
+                } else if neighbors.len() > state.graph.get_meta_page().get_num_neighbors() as _ {
+                    //OPT: get rid of this clone
+                    prune_neighbors = state.graph.prune_neighbors(
+                        index_pointer,
+                        neighbors.clone(),
+                        storage,
+                        &mut write_stats.prune_stats,
+                    );
+                    &prune_neighbors
+                } else {
+                    neighbors
+                };
                 write_stats.num_neighbors += neighbors.len();
 
                 storage.finalize_node_at_end_of_build(
@@ -337,7 +505,7 @@ fn finalize_index_build<S: Storage>(
 }
 
 #[pg_guard]
-unsafe extern "C" fn build_callback_bq_train(
+unsafe extern "C" fn build_callback_bq_train<S: Storage>(
     _index: pg_sys::Relation,
     _ctid: pg_sys::ItemPointer,
     values: *mut pg_sys::Datum,
@@ -345,22 +513,17 @@ unsafe extern "C" fn build_callback_bq_train(
     _tuple_is_alive: bool,
     state: *mut std::os::raw::c_void,
 ) {
-    let state = (state as *mut StorageBuildState).as_mut().unwrap();
-    match state {
-        StorageBuildState::SbqSpeedup(bq, state) => {
-            let vec = PgVector::from_pg_parts(values, isnull, 0, &state.meta_page, true, false);
-            if let Some(vec) = vec {
-                bq.add_sample(vec.to_index_slice());
-            }
-        }
-        StorageBuildState::Plain(_, _) => {
-            panic!("Should not be training with plain storage");
-        }
+    let state = (state as *mut StorageBuildState<S>).as_mut().unwrap();
+    let build_state = &mut *state.build_state;
+    let vec = PgVector::from_pg_parts(values, isnull, 0, &build_state.meta_page, true, false);
+    if let Some(vec) = vec {
+        state.storage.add_sample(vec.to_index_slice());
+        build_state.add_entry_point_sample(vec.to_index_slice());
     }
 }
 
 #[pg_guard]
-unsafe extern "C" fn build_callback(
+unsafe extern "C" fn build_callback<S: Storage>(
     index: pg_sys::Relation,
     ctid: pg_sys::ItemPointer,
     values: *mut pg_sys::Datum,
@@ -369,22 +532,27 @@ unsafe extern "C" fn build_callback(
     state: *mut std::os::raw::c_void,
 ) {
     let index_relation = unsafe { PgRelation::from_pg(index) };
-    let state = (state as *mut StorageBuildState).as_mut().unwrap();
-    match state {
-        StorageBuildState::SbqSpeedup(bq, state) => {
-            let vec = PgVector::from_pg_parts(values, isnull, 0, &state.meta_page, true, false);
-            if let Some(vec) = vec {
-                let heap_pointer = ItemPointer::with_item_pointer_data(*ctid);
-                build_callback_memory_wrapper(index_relation, heap_pointer, vec, state, *bq);
-            }
-        }
-        StorageBuildState::Plain(plain, state) => {
-            let vec = PgVector::from_pg_parts(values, isnull, 0, &state.meta_page, true, false);
-            if let Some(vec) = vec {
-                let heap_pointer = ItemPointer::with_item_pointer_data(*ctid);
-                build_callback_memory_wrapper(index_relation, heap_pointer, vec, state, *plain);
-            }
-        }
+    let state = (state as *mut StorageBuildState<S>).as_mut().unwrap();
+    let build_state = &mut *state.build_state;
+    let vec = PgVector::from_pg_parts(
+        values,
+        isnull,
+        0,
+        &build_state.meta_page,
+        true,
+        build_state.meta_page.get_full_vectors_in_index(),
+    );
+    if let Some(vec) = vec {
+        let heap_pointer = ItemPointer::with_item_pointer_data(*ctid);
+        let scalar_columns = form_scalar_columns(&index_relation, values, isnull);
+        build_callback_memory_wrapper(
+            index_relation,
+            heap_pointer,
+            vec,
+            &scalar_columns,
+            build_state,
+            state.storage,
+        );
     }
 }
 
@@ -393,12 +561,13 @@ unsafe fn build_callback_memory_wrapper<S: Storage>(
     index: PgRelation,
     heap_pointer: ItemPointer,
     vector: PgVector,
+    scalar_columns: &[u8],
     state: &mut BuildState,
     storage: &mut S,
 ) {
     let mut old_context = state.memcxt.set_as_current();
 
-    build_callback_internal(index, heap_pointer, vector, state, storage);
+    build_callback_internal(index, heap_pointer, vector, scalar_columns, state, storage);
 
     old_context.set_as_current();
     state.memcxt.reset();
@@ -409,6 +578,7 @@ fn build_callback_internal<S: Storage>(
     index: PgRelation,
     heap_pointer: ItemPointer,
     vector: PgVector,
+    scalar_columns: &[u8],
     state: &mut BuildState,
     storage: &mut S,
 ) {
@@ -428,19 +598,64 @@ fn build_callback_internal<S: Storage>(
         );
     }
 
+    let prefix = match &mut state.full_vector_tape {
+        Some(tape) => Cow::Owned(unsafe {
+            write_full_vector(&vector, scalar_columns, tape, &mut state.stats)
+        }),
+        None => Cow::Borrowed(scalar_columns),
+    };
+
     let index_pointer = storage.create_node(
         vector.to_index_slice(),
         heap_pointer,
+        &prefix,
         &state.meta_page,
         &mut state.tape,
         &mut state.stats,
     );
 
-    state
-        .graph
-        .insert(&index, index_pointer, vector, storage, &mut state.stats);
+    state.add_entry_point_candidate(index_pointer, vector.to_index_slice());
+
+    match &mut state.parallel {
+        Some(parallel) => {
+            parallel.add_vector(
+                index_pointer,
+                storage.get_build_vector(vector.to_index_slice()),
+            );
+            if state.graph.get_meta_page().get_init_ids().is_none() {
+                //the first node becomes the init id, so it's inserted serially
+                state
+                    .graph
+                    .insert(&index, index_pointer, vector, storage, &mut state.stats);
+            } else {
+                parallel.insert(index_pointer, &mut state.graph, &mut state.stats);
+            }
+
+            if parallel.exceeds_memory_limit() {
+                parallel.flush(&mut state.graph, &mut state.stats);
+                notice!(
+                    "diskann build vectors no longer fit into maintenance_work_mem after {} tuples, building the rest of the graph serially",
+                    parallel.num_vectors()
+                );
+                state.parallel = None;
+            }
+        }
+        None => {
+            state
+                .graph
+                .insert(&index, index_pointer, vector, storage, &mut state.stats);
+        }
+    }
+
+    //the init ids move closer to the centroids as the graph grows, and once more at the end of the build
+    if state.ntuples >= INIT_IDS_FIRST_UPDATE && state.ntuples.is_power_of_two() {
+        state.update_init_ids(&index);
+    }
 }
 
+/// The number of tuples after which a build first moves the init ids near the centroids (see EntryPoints).
+const INIT_IDS_FIRST_UPDATE: usize = 1024;
+
 const BUILD_PHASE_TRAINING: i64 = 0;
 const BUILD_PHASE_BUILDING_GRAPH: i64 = 1;
 const BUILD_PHASE_FINALIZING_GRAPH: i64 = 2;
@@ -676,34 +891,1219 @@ pub mod tests {
         Ok(())
     }
 
+    /// Checks that an index built with the given operator class returns (mostly) the same
+    /// top results as a sequential scan ordered by the given distance operator.
+    /// The vectors are deliberately not normalized.
     #[cfg(any(test, feature = "pg_test"))]
-    pub unsafe fn test_empty_table_insert_scaffold(index_options: &str) -> spi::Result<()> {
+    pub unsafe fn test_distance_operator_scaffold(
+        index_options: &str,
+        opclass: &str,
+        operator: &str,
+    ) -> spi::Result<()> {
         Spi::run(&format!(
-            "CREATE TABLE test(embedding vector(3));
+            "CREATE TABLE test_data (
+                embedding vector (256)
+            );
 
-            CREATE INDEX idxtest
-                  ON test
-               USING diskann(embedding)
-                WITH ({index_options});
+            select setseed(0.5);
+           -- generate 1000 vectors with varying norms
+            INSERT INTO test_data (embedding)
+            SELECT
+                *
+            FROM (
+                SELECT
+                    ('[' || array_to_string(array_agg((random() - 0.5) * (1 + i % 7)), ',', '0') || ']')::vector AS embedding
+                FROM
+                    generate_series(1, 256 * 1000) i
+                GROUP BY
+                    i % 1000) g;
 
-            INSERT INTO test(embedding) VALUES ('[1,2,3]'), ('[4,5,6]'), ('[7,8,10]');
-            ",
+            CREATE INDEX idx_diskann_distance ON test_data USING diskann (embedding {opclass}) WITH ({index_options});
+            "
         ))?;
 
-        let res: Option<i64> = Spi::get_one(&format!(
-            "   set enable_seqscan = 0;
-                WITH cte as (select * from test order by embedding <=> '[0,0,0]') SELECT count(*) from cte;",
+        let test_vec: Option<Vec<f32>> = Spi::get_one(
+            "SELECT('{' || array_to_string(array_agg(random() - 0.5), ',', '0') || '}')::real[] AS embedding
+    FROM generate_series(1, 256)",
+        )?;
+
+        let query =
+            |enable_index: bool| -> spi::Result<Option<Vec<pgrx::pg_sys::ItemPointerData>>> {
+                Spi::get_one_with_args(
+                    &format!(
+                        "
+            SET enable_seqscan = {seqscan};
+            SET enable_indexscan = {indexscan};
+            SET diskann.query_search_list_size = 100;
+            WITH cte AS (
+                SELECT
+                    ctid
+                FROM
+                    test_data
+                ORDER BY
+                    embedding {operator} $1::vector
+                LIMIT 10
+            )
+            SELECT array_agg(ctid) from cte;",
+                        seqscan = if enable_index { 0 } else { 1 },
+                        indexscan = if enable_index { 1 } else { 0 },
+                    ),
+                    vec![(
+                        pgrx::PgOid::Custom(pgrx::pg_sys::FLOAT4ARRAYOID),
+                        test_vec.clone().into_datum(),
+                    )],
+                )
+            };
+
+        let with_index = query(true)?;
+        let without_index = query(false)?;
+
+        let set: HashSet<_> = without_index
+            .unwrap()
+            .iter()
+            .map(|&ctid| ItemPointer::with_item_pointer_data(ctid))
+            .collect();
+
+        let matches = with_index
+            .unwrap()
+            .iter()
+            .filter(|&&ctid| set.contains(&ItemPointer::with_item_pointer_data(ctid)))
+            .count();
+        assert!(matches > 6, "Low number of matches: {}", matches);
+
+        let cnt: Option<i64> = Spi::get_one_with_args(
+            &format!(
+                "
+        SET enable_seqscan = 0;
+        SET enable_indexscan = 1;
+        WITH cte as (select * from test_data order by embedding {operator} $1::vector) SELECT count(*) from cte;
+        ",
+            ),
+            vec![(
+                pgrx::PgOid::Custom(pgrx::pg_sys::FLOAT4ARRAYOID),
+                test_vec.into_datum(),
+            )],
+        )?;
+        assert_eq!(cnt.unwrap(), 1000);
+
+        Ok(())
+    }
+
+    #[cfg(any(test, feature = "pg_test"))]
+    pub unsafe fn test_parallel_build_scaffold(index_options: &str) -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test_data (
+                embedding vector (128)
+            );
+
+            select setseed(0.5);
+           -- generate 2000 vectors
+            INSERT INTO test_data (embedding)
+            SELECT
+                *
+            FROM (
+                SELECT
+                    ('[' || array_to_string(array_agg(random()), ',', '0') || ']')::vector AS embedding
+                FROM
+                    generate_series(1, 128 * 2000) i
+                GROUP BY
+                    i % 2000) g;
+
+            CREATE TABLE test_queries AS
+            SELECT
+                i % 20 AS id,
+                ('[' || array_to_string(array_agg(random()), ',', '0') || ']')::vector AS embedding
+            FROM
+                generate_series(1, 128 * 20) i
+            GROUP BY
+                i % 20;
+
+            -- the exact top 10 of each query, there is no index yet
+            CREATE TABLE ground_truth AS
+            SELECT
+                q.id,
+                t.ctid
+            FROM
+                test_queries q
+                CROSS JOIN LATERAL (
+                    SELECT
+                        ctid
+                    FROM
+                        test_data
+                    ORDER BY
+                        embedding <=> q.embedding
+                    LIMIT 10) t;",
+        )?;
+
+        let build_and_count_matches = |max_parallel_maintenance_workers: i32| -> spi::Result<i64> {
+            /* the table is too small for the planner to choose workers on its own, and the planner gives each
+            worker at least 32MB of maintenance_work_mem */
+            Spi::run(&format!(
+                "SET max_parallel_maintenance_workers = {max_parallel_maintenance_workers};
+                SET maintenance_work_mem = '256MB';
+                ALTER TABLE test_data SET (parallel_workers = 4);
+                CREATE INDEX idx_diskann_parallel ON test_data USING diskann (embedding) WITH ({index_options});"
+            ))?;
+
+            let matches: Option<i64> = Spi::get_one(
+                "SET enable_seqscan = 0;
+                SET enable_indexscan = 1;
+                SET diskann.query_search_list_size = 100;
+                SELECT
+                    count(*)
+                FROM
+                    test_queries q
+                    CROSS JOIN LATERAL (
+                        SELECT
+                            ctid
+                        FROM
+                            test_data
+                        ORDER BY
+                            embedding <=> q.embedding
+                        LIMIT 10) t
+                    JOIN ground_truth g ON g.id = q.id AND g.ctid = t.ctid;",
+            )?;
+
+            let cnt: Option<i64> = Spi::get_one(
+                "SET enable_seqscan = 0;
+                SET enable_indexscan = 1;
+                WITH cte AS (SELECT * FROM test_data ORDER BY embedding <=> (SELECT embedding FROM test_queries WHERE id = 1))
+                SELECT count(*) FROM cte;",
+            )?;
+            assert_eq!(cnt.unwrap(), 2000);
+
+            Spi::run("DROP INDEX idx_diskann_parallel;")?;
+            Ok(matches.unwrap())
+        };
+
+        let serial_matches = build_and_count_matches(0)?;
+        let parallel_matches = build_and_count_matches(4)?;
+        assert!(
+            parallel_matches * 10 >= serial_matches * 9,
+            "Parallel build has lower recall than the serial build: {} vs {} matches",
+            parallel_matches,
+            serial_matches
+        );
+
+        Ok(())
+    }
+
+    #[cfg(any(test, feature = "pg_test"))]
+    pub unsafe fn test_include_columns_scaffold(
+        index_options: &str,
+        opclass: &str,
+        operator: &str,
+        expect_index_only_scan: bool,
+    ) -> spi::Result<()> {
+        Spi::run(&format!(
+            "CREATE TABLE test_data (
+                id int,
+                label text,
+                embedding vector (256)
+            );
+
+            select setseed(0.5);
+           -- generate 1000 vectors
+            INSERT INTO test_data (id, label, embedding)
+            SELECT
+                g.i,
+                'label ' || g.i,
+                g.embedding
+            FROM (
+                SELECT
+                    i % 1000 AS i,
+                    ('[' || array_to_string(array_agg(random()), ',', '0') || ']')::vector AS embedding
+                FROM
+                    generate_series(1, 256 * 1000) i
+                GROUP BY
+                    i % 1000) g;
+
+            CREATE INDEX idx_diskann_include ON test_data USING diskann (embedding {opclass}) INCLUDE (id, label) WITH ({index_options});
+
+            -- and some more through inserts
+            INSERT INTO test_data (id, label, embedding)
+            SELECT
+                1000 + g.i,
+                NULL,
+                g.embedding
+            FROM (
+                SELECT
+                    i % 100 AS i,
+                    ('[' || array_to_string(array_agg(random()), ',', '0') || ']')::vector AS embedding
+                FROM
+                    generate_series(1, 256 * 100) i
+                GROUP BY
+                    i % 100) g;
+
+            SET enable_seqscan = 0;
+            SET diskann.query_search_list_size = 100;
+            "
         ))?;
-        assert_eq!(3, res.unwrap());
 
+        let query = format!(
+            "SELECT
+                id,
+                label
+            FROM
+                test_data
+            ORDER BY
+                embedding {operator} (SELECT embedding FROM test_data WHERE id = 1050)
+            LIMIT 10"
+        );
+
+        let results = |index_only_scan: bool| -> spi::Result<Option<Vec<String>>> {
+            Spi::get_one(&format!(
+                "
+            SET enable_indexscan = {indexscan};
+            SET enable_indexonlyscan = {indexonlyscan};
+            SELECT array_agg(id || ':' || coalesce(label, 'null')) FROM ({query}) r;",
+                indexscan = if index_only_scan { 0 } else { 1 },
+                indexonlyscan = if index_only_scan { 1 } else { 0 },
+            ))
+        };
+
+        let index_scan = results(false)?.unwrap();
+        assert_eq!(index_scan.len(), 10);
+        assert!(index_scan.contains(&"1050:null".to_string()));
+
+        if expect_index_only_scan {
+            let index_only_scan = results(true)?.unwrap();
+            assert_eq!(index_scan, index_only_scan);
+
+            let plan: Option<pgrx::Json> = Spi::get_one(&format!(
+                "
+            SET enable_indexscan = 0;
+            SET enable_indexonlyscan = 1;
+            EXPLAIN (FORMAT JSON) {query};"
+            ))?;
+            let plan = plan.unwrap().0.to_string();
+            assert!(plan.contains("Index Only Scan"), "{}", plan);
+        }
+
+        Ok(())
+    }
+
+    #[cfg(any(test, feature = "pg_test"))]
+    pub unsafe fn test_filtered_search_scaffold(index_options: &str) -> spi::Result<()> {
         Spi::run(&format!(
+            "CREATE TABLE test_data (
+                id int,
+                tenant_id int,
+                embedding vector (128)
+            );
+
+            select setseed(0.5);
+           -- generate 1000 vectors, 50 tenants
+            INSERT INTO test_data (id, tenant_id, embedding)
+            SELECT
+                g.i,
+                g.i % 50,
+                g.embedding
+            FROM (
+                SELECT
+                    i % 1000 AS i,
+                    ('[' || array_to_string(array_agg(random()), ',', '0') || ']')::vector AS embedding
+                FROM
+                    generate_series(1, 128 * 1000) i
+                GROUP BY
+                    i % 1000) g;
+
+            CREATE INDEX idx_diskann_filtered ON test_data USING diskann (embedding, tenant_id) WITH ({index_options});
+
+            -- and some more through inserts
+            INSERT INTO test_data (id, tenant_id, embedding)
+            SELECT
+                1000 + g.i,
+                g.i % 50,
+                g.embedding
+            FROM (
+                SELECT
+                    i % 100 AS i,
+                    ('[' || array_to_string(array_agg(random()), ',', '0') || ']')::vector AS embedding
+                FROM
+                    generate_series(1, 128 * 100) i
+                GROUP BY
+                    i % 100) g;
+
+            -- a search list much smaller than the number of nodes the filter rejects
+            SET diskann.query_search_list_size = 10;
             "
-        set enable_seqscan = 0;
-        explain analyze select * from test order by embedding <=> '[0,0,0]';
-        ",
         ))?;
 
-        Spi::run(&format!("drop index idxtest;",))?;
+        let query = "SELECT
+                id,
+                tenant_id
+            FROM
+                test_data
+            WHERE
+                tenant_id = 7
+            ORDER BY
+                embedding <=> (SELECT embedding FROM test_data WHERE id = 1)
+            LIMIT 10";
+
+        let results = |index_scan: bool| -> spi::Result<Option<Vec<i32>>> {
+            Spi::get_one(&format!(
+                "
+            SET enable_seqscan = {seqscan};
+            SET enable_indexscan = {indexscan};
+            SELECT array_agg(id) FROM ({query}) r WHERE tenant_id = 7;",
+                seqscan = if index_scan { 0 } else { 1 },
+                indexscan = if index_scan { 1 } else { 0 },
+            ))
+        };
+
+        let plan: Option<pgrx::Json> = Spi::get_one(&format!(
+            "
+            SET enable_seqscan = 0;
+            SET enable_indexscan = 1;
+            EXPLAIN (FORMAT JSON) {query};"
+        ))?;
+        let plan = plan.unwrap().0.to_string();
+        assert!(plan.contains("Index Cond"), "{}", plan);
+
+        /* every row returned by the index matches the filter */
+        let index_scan = results(true)?.unwrap();
+        assert_eq!(index_scan.len(), 10);
+
+        let exact = results(false)?.unwrap();
+        assert_eq!(exact.len(), 10);
+        let matches = index_scan.iter().filter(|id| exact.contains(id)).count();
+        assert!(matches >= 8, "index: {:?} exact: {:?}", index_scan, exact);
+
+        /* a filter no row matches */
+        let res: Option<i64> = Spi::get_one(
+            "
+            SET enable_seqscan = 0;
+            SET enable_indexscan = 1;
+            SELECT count(*) FROM (
+                SELECT id FROM test_data WHERE tenant_id = 1000
+                ORDER BY embedding <=> (SELECT embedding FROM test_data WHERE id = 1) LIMIT 10
+            ) r;",
+        )?;
+        assert_eq!(0, res.unwrap());
+
+        /* a filter without an ORDER BY can't use the index, even with sequential scans off */
+        let plan: Option<pgrx::Json> = Spi::get_one(
+            "
+            SET enable_seqscan = 0;
+            EXPLAIN (FORMAT JSON) SELECT count(*) FROM test_data WHERE tenant_id = 7;",
+        )?;
+        let plan = plan.unwrap().0.to_string();
+        assert!(!plan.contains("idx_diskann_filtered"), "{}", plan);
+        let res: Option<i64> = Spi::get_one(
+            "
+            SET enable_seqscan = 0;
+            SELECT count(*) FROM test_data WHERE tenant_id = 7;",
+        )?;
+        assert_eq!(22, res.unwrap());
+
+        /* the vector has to be the first column */
+        let res = Spi::run(
+            "CREATE INDEX idx_diskann_bad ON test_data USING diskann (tenant_id, embedding);",
+        );
+        assert!(res.is_err());
+
+        Ok(())
+    }
+
+    #[cfg(any(test, feature = "pg_test"))]
+    pub unsafe fn test_range_search_scaffold(
+        index_options: &str,
+        opclass: &str,
+        operator: &str,
+        range_operator: &str,
+    ) -> spi::Result<()> {
+        Spi::run(&format!(
+            "CREATE TABLE test_data (
+                id int,
+                tenant_id int,
+                embedding vector (128)
+            );
+
+            select setseed(0.5);
+           -- generate 1000 vectors, 5 tenants
+            INSERT INTO test_data (id, tenant_id, embedding)
+            SELECT
+                g.i,
+                g.i % 5,
+                g.embedding
+            FROM (
+                SELECT
+                    i % 1000 AS i,
+                    ('[' || array_to_string(array_agg(random()), ',', '0') || ']')::vector AS embedding
+                FROM
+                    generate_series(1, 128 * 1000) i
+                GROUP BY
+                    i % 1000) g;
+
+            CREATE INDEX idx_diskann_range ON test_data USING diskann (embedding {opclass}, tenant_id) WITH ({index_options});
+
+            SET diskann.query_search_list_size = 100;
+            "
+        ))?;
+
+        /* the radius of the sphere around row 1 holding its 20 nearest neighbors */
+        let radius: Option<f64> = Spi::get_one(&format!(
+            "
+            SET enable_seqscan = 1;
+            SET enable_indexscan = 0;
+            SET enable_bitmapscan = 0;
+            SELECT (embedding {operator} (SELECT embedding FROM test_data WHERE id = 1))::float8
+            FROM test_data ORDER BY 1 OFFSET 20 LIMIT 1;"
+        ))?;
+        let radius = radius.unwrap();
+
+        let range = format!(
+            "embedding {range_operator} sphere((SELECT embedding FROM test_data WHERE id = 1), {radius})"
+        );
+        let results = |filter: &str,
+                       seqscan: bool,
+                       indexscan: bool,
+                       bitmapscan: bool|
+         -> spi::Result<Option<Vec<i32>>> {
+            Spi::get_one(&format!(
+                "
+            SET enable_seqscan = {seqscan};
+            SET enable_indexscan = {indexscan};
+            SET enable_bitmapscan = {bitmapscan};
+            SELECT array_agg(id ORDER BY id) FROM test_data WHERE {range} {filter};"
+            ))
+        };
+
+        let exact = results("", true, false, false)?.unwrap();
+        assert_eq!(exact.len(), 20);
+        assert!(exact.contains(&1));
+
+        let plan: Option<pgrx::Json> = Spi::get_one(&format!(
+            "
+            SET enable_seqscan = 0;
+            SET enable_indexscan = 0;
+            SET enable_bitmapscan = 1;
+            EXPLAIN (FORMAT JSON) SELECT id FROM test_data WHERE {range};"
+        ))?;
+        let plan = plan.unwrap().0.to_string();
+        assert!(plan.contains("Bitmap Index Scan"), "{}", plan);
+
+        /* every row returned passes the recheck, and few rows in the sphere are missed */
+        for (indexscan, bitmapscan) in [(false, true), (true, false)] {
+            let index = results("", false, indexscan, bitmapscan)?.unwrap();
+            assert!(index.iter().all(|id| exact.contains(id)));
+            assert!(index.len() >= 16, "index: {:?} exact: {:?}", index, exact);
+        }
+
+        /* together with a filter on a scalar key column */
+        let filter = "AND tenant_id = 1";
+        let exact_filtered = results(filter, true, false, false)?.unwrap_or_default();
+        let index_filtered = results(filter, false, false, true)?.unwrap_or_default();
+        assert!(index_filtered.iter().all(|id| exact_filtered.contains(id)));
+        assert!(
+            index_filtered.len() + 2 >= exact_filtered.len(),
+            "index: {:?} exact: {:?}",
+            index_filtered,
+            exact_filtered
+        );
+
+        /* an ordered range search returns the rows in the sphere in order */
+        let ordered: Option<Vec<i32>> = Spi::get_one(&format!(
+            "
+            SET enable_seqscan = 0;
+            SET enable_indexscan = 1;
+            SET enable_bitmapscan = 0;
+            SELECT array_agg(id) FROM (
+                SELECT id FROM test_data WHERE {range}
+                ORDER BY embedding {operator} (SELECT embedding FROM test_data WHERE id = 1)
+            ) r;"
+        ))?;
+        let ordered = ordered.unwrap();
+        assert_eq!(ordered[0], 1);
+        assert!(ordered.len() >= 16 && ordered.len() <= 20);
+
+        /* diskann.max_distance ends an ordered scan at the same radius */
+        let max_distance: Option<Vec<i32>> = Spi::get_one(&format!(
+            "
+            SET enable_seqscan = 0;
+            SET enable_indexscan = 1;
+            SET enable_bitmapscan = 0;
+            SET diskann.max_distance = {radius};
+            SELECT array_agg(id) FROM (
+                SELECT id FROM test_data
+                ORDER BY embedding {operator} (SELECT embedding FROM test_data WHERE id = 1)
+            ) r;"
+        ))?;
+        let max_distance = max_distance.unwrap();
+        Spi::run("RESET diskann.max_distance")?;
+        assert_eq!(max_distance[0], 1);
+        assert!(max_distance.iter().all(|id| exact.contains(id)));
+        assert!(
+            max_distance.len() >= 16,
+            "index: {:?} exact: {:?}",
+            max_distance,
+            exact
+        );
+
+        /* a sphere no row is in */
+        let res: Option<i64> = Spi::get_one(
+            "
+            SET enable_seqscan = 0;
+            SET enable_indexscan = 0;
+            SET enable_bitmapscan = 1;
+            SELECT count(*) FROM test_data
+            WHERE embedding <<->> sphere((SELECT embedding FROM test_data WHERE id = 1), -1);",
+        )?;
+        assert_eq!(0, res.unwrap());
+
+        Ok(())
+    }
+
+    #[cfg(any(test, feature = "pg_test"))]
+    pub unsafe fn test_halfvec_scaffold(index_options: &str) -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test_halfvec (
+                id int,
+                embedding halfvec (128)
+            );
+
+            select setseed(0.5);
+           -- generate 1000 vectors
+            INSERT INTO test_halfvec (id, embedding)
+            SELECT
+                g.i,
+                g.embedding
+            FROM (
+                SELECT
+                    i % 1000 AS i,
+                    ('[' || array_to_string(array_agg(random()), ',', '0') || ']')::halfvec AS embedding
+                FROM
+                    generate_series(1, 128 * 1000) i
+                GROUP BY
+                    i % 1000) g;",
+        )?;
+
+        for (opclass, operator) in [
+            ("halfvec_cosine_ops", "<=>"),
+            ("halfvec_l2_ops", "<->"),
+            ("halfvec_ip_ops", "<#>"),
+        ] {
+            Spi::run(&format!(
+                "CREATE INDEX idx_diskann_halfvec ON test_halfvec USING diskann (embedding {opclass}) WITH ({index_options});
+
+                -- and some more through inserts
+                INSERT INTO test_halfvec (id, embedding)
+                SELECT
+                    1000 + g.i,
+                    g.embedding
+                FROM (
+                    SELECT
+                        i % 100 AS i,
+                        ('[' || array_to_string(array_agg(random()), ',', '0') || ']')::halfvec AS embedding
+                    FROM
+                        generate_series(1, 128 * 100) i
+                    GROUP BY
+                        i % 100) g;"
+            ))?;
+
+            let query = format!(
+                "SELECT array_agg(id) FROM (
+                    SELECT id FROM test_halfvec
+                    ORDER BY embedding {operator} (SELECT embedding FROM test_halfvec WHERE id = 1)
+                    LIMIT 10
+                ) r;"
+            );
+            let index_scan: Option<Vec<i32>> = Spi::get_one(&format!(
+                "SET enable_seqscan = 0;
+                SET enable_indexscan = 1;
+                {query}"
+            ))?;
+            let exact: Option<Vec<i32>> = Spi::get_one(&format!(
+                "SET enable_seqscan = 1;
+                SET enable_indexscan = 0;
+                {query}"
+            ))?;
+            let (index_scan, exact) = (index_scan.unwrap(), exact.unwrap());
+            assert_eq!(index_scan.len(), 10);
+            let matches = index_scan.iter().filter(|id| exact.contains(id)).count();
+            assert!(
+                matches >= 8,
+                "{}: index: {:?} exact: {:?}",
+                opclass,
+                index_scan,
+                exact
+            );
+
+            //the index reaches every row, including the inserted ones
+            let res: Option<i64> = Spi::get_one(&format!(
+                "SET enable_seqscan = 0;
+                SET enable_indexscan = 1;
+                SET diskann.query_search_list_size = 2000;
+                WITH cte as (select * from test_halfvec order by embedding {operator} (SELECT embedding FROM test_halfvec WHERE id = 1)) SELECT count(*) from cte;"
+            ))?;
+            assert_eq!(1100, res.unwrap());
+
+            Spi::run(
+                "RESET diskann.query_search_list_size;
+                DROP INDEX idx_diskann_halfvec;
+                DELETE FROM test_halfvec WHERE id >= 1000;",
+            )?;
+        }
+
+        Ok(())
+    }
+
+    #[cfg(any(test, feature = "pg_test"))]
+    pub unsafe fn test_sparsevec_scaffold(index_options: &str) -> spi::Result<()> {
+        /* 30000 dimensions, more than are indexed, so the vectors are hashed. The non-zero values are in the
+        first 200 dimensions, so that vectors overlap. */
+        let insert = |from: i32, count: i32| {
+            format!(
+                "INSERT INTO test_sparse (id, embedding)
+                SELECT
+                    {from} + i,
+                    ('{{' || string_agg(d || ':' || random(), ',' ORDER BY d) || '}}/30000')::sparsevec
+                FROM (
+                    SELECT DISTINCT i, 1 + (random() * 199)::int AS d
+                    FROM generate_series(1, {count}) i, generate_series(1, 20) j) s
+                GROUP BY i;"
+            )
+        };
+
+        Spi::run(&format!(
+            "CREATE TABLE test_sparse (
+                id int,
+                embedding sparsevec (30000)
+            );
+
+            select setseed(0.5);
+            {}",
+            insert(0, 1000)
+        ))?;
+
+        for (opclass, operator) in [("sparsevec_cosine_ops", "<=>"), ("sparsevec_ip_ops", "<#>")] {
+            Spi::run(&format!(
+                "CREATE INDEX idx_diskann_sparse ON test_sparse USING diskann (embedding {opclass}) WITH ({index_options});
+
+                -- and some more through inserts
+                {}",
+                insert(1000, 100)
+            ))?;
+
+            let query = format!(
+                "SELECT array_agg(id) FROM (
+                    SELECT id FROM test_sparse
+                    ORDER BY embedding {operator} (SELECT embedding FROM test_sparse WHERE id = 1)
+                    LIMIT 10
+                ) r;"
+            );
+            let index_scan: Option<Vec<i32>> = Spi::get_one(&format!(
+                "SET enable_seqscan = 0;
+                SET enable_indexscan = 1;
+                {query}"
+            ))?;
+            let exact: Option<Vec<i32>> = Spi::get_one(&format!(
+                "SET enable_seqscan = 1;
+                SET enable_indexscan = 0;
+                {query}"
+            ))?;
+            let (index_scan, exact) = (index_scan.unwrap(), exact.unwrap());
+            assert_eq!(index_scan.len(), 10);
+            let matches = index_scan.iter().filter(|id| exact.contains(id)).count();
+            assert!(
+                matches >= 8,
+                "{}: index: {:?} exact: {:?}",
+                opclass,
+                index_scan,
+                exact
+            );
+            //by cosine distance, the closest is the query itself
+            if operator == "<=>" {
+                assert_eq!(index_scan[0], 1);
+            }
+
+            Spi::run(
+                "DROP INDEX idx_diskann_sparse;
+                DELETE FROM test_sparse WHERE id > 1000;",
+            )?;
+        }
+
+        Ok(())
+    }
+
+    #[cfg(any(test, feature = "pg_test"))]
+    pub unsafe fn test_bit_scaffold(index_options: &str) -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test_bit (
+                id int,
+                embedding bit (256)
+            );
+
+            select setseed(0.5);
+           -- generate 1000 vectors
+            INSERT INTO test_bit (id, embedding)
+            SELECT
+                g.i,
+                g.embedding
+            FROM (
+                SELECT
+                    i % 1000 AS i,
+                    string_agg(CASE WHEN random() > 0.5 THEN '1' ELSE '0' END, '')::bit(256) AS embedding
+                FROM
+                    generate_series(1, 256 * 1000) i
+                GROUP BY
+                    i % 1000) g;",
+        )?;
+
+        for (opclass, operator) in [("bit_hamming_ops", "<~>"), ("bit_jaccard_ops", "<%>")] {
+            Spi::run(&format!(
+                "CREATE INDEX idx_diskann_bit ON test_bit USING diskann (embedding {opclass}) WITH ({index_options});
+
+                -- and some more through inserts
+                INSERT INTO test_bit (id, embedding)
+                SELECT
+                    1000 + g.i,
+                    g.embedding
+                FROM (
+                    SELECT
+                        i % 100 AS i,
+                        string_agg(CASE WHEN random() > 0.5 THEN '1' ELSE '0' END, '')::bit(256) AS embedding
+                    FROM
+                        generate_series(1, 256 * 100) i
+                    GROUP BY
+                        i % 100) g;"
+            ))?;
+
+            //many rows are at the same distance, so the distances are compared instead of the ids
+            let query = format!(
+                "SELECT array_agg(d) FROM (
+                    SELECT embedding {operator} (SELECT embedding FROM test_bit WHERE id = 1) AS d FROM test_bit
+                    ORDER BY embedding {operator} (SELECT embedding FROM test_bit WHERE id = 1)
+                    LIMIT 10
+                ) r;"
+            );
+            let index_scan: Option<Vec<f64>> = Spi::get_one(&format!(
+                "SET enable_seqscan = 0;
+                SET enable_indexscan = 1;
+                {query}"
+            ))?;
+            let exact: Option<Vec<f64>> = Spi::get_one(&format!(
+                "SET enable_seqscan = 1;
+                SET enable_indexscan = 0;
+                {query}"
+            ))?;
+            let (index_scan, exact) = (index_scan.unwrap(), exact.unwrap());
+            assert_eq!(index_scan.len(), 10);
+            assert_eq!(index_scan[0], 0.0);
+            let matches = index_scan
+                .iter()
+                .filter(|d| **d <= exact[exact.len() - 1])
+                .count();
+            assert!(
+                matches >= 8,
+                "{}: index: {:?} exact: {:?}",
+                opclass,
+                index_scan,
+                exact
+            );
+
+            //the index reaches every row, including the inserted ones
+            let res: Option<i64> = Spi::get_one(&format!(
+                "SET enable_seqscan = 0;
+                SET enable_indexscan = 1;
+                SET diskann.query_search_list_size = 2000;
+                WITH cte as (select * from test_bit order by embedding {operator} (SELECT embedding FROM test_bit WHERE id = 1)) SELECT count(*) from cte;"
+            ))?;
+            assert_eq!(1100, res.unwrap());
+
+            Spi::run(
+                "RESET diskann.query_search_list_size;
+                DROP INDEX idx_diskann_bit;
+                DELETE FROM test_bit WHERE id >= 1000;",
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Vectors whose nodes (or SBQ means) don't fit on a page.
+    #[cfg(any(test, feature = "pg_test"))]
+    pub unsafe fn test_high_dimensions_scaffold(
+        index_options: &str,
+        num_dimensions: usize,
+    ) -> spi::Result<()> {
+        Spi::run(&format!(
+            "CREATE TABLE test_high_dimensions (
+                id int,
+                embedding vector ({num_dimensions})
+            );
+
+            select setseed(0.5);
+           -- generate 300 vectors
+            INSERT INTO test_high_dimensions (id, embedding)
+            SELECT
+                g.i,
+                g.embedding
+            FROM (
+                SELECT
+                    i % 300 AS i,
+                    ('[' || array_to_string(array_agg(random()), ',', '0') || ']')::vector AS embedding
+                FROM
+                    generate_series(1, {num_dimensions} * 300) i
+                GROUP BY
+                    i % 300) g;
+
+            CREATE INDEX idx_diskann_high_dimensions ON test_high_dimensions USING diskann (embedding) WITH ({index_options});
+
+            -- and some more through inserts
+            INSERT INTO test_high_dimensions (id, embedding)
+            SELECT
+                300 + g.i,
+                g.embedding
+            FROM (
+                SELECT
+                    i % 20 AS i,
+                    ('[' || array_to_string(array_agg(random()), ',', '0') || ']')::vector AS embedding
+                FROM
+                    generate_series(1, {num_dimensions} * 20) i
+                GROUP BY
+                    i % 20) g;"
+        ))?;
+
+        for id in [1, 310] {
+            let query = format!(
+                "SELECT array_agg(id) FROM (
+                    SELECT id FROM test_high_dimensions
+                    ORDER BY embedding <=> (SELECT embedding FROM test_high_dimensions WHERE id = {id})
+                    LIMIT 10
+                ) r;"
+            );
+            let index_scan: Option<Vec<i32>> = Spi::get_one(&format!(
+                "SET enable_seqscan = 0;
+                SET enable_indexscan = 1;
+                {query}"
+            ))?;
+            let exact: Option<Vec<i32>> = Spi::get_one(&format!(
+                "SET enable_seqscan = 1;
+                SET enable_indexscan = 0;
+                {query}"
+            ))?;
+            let (index_scan, exact) = (index_scan.unwrap(), exact.unwrap());
+            assert_eq!(index_scan[0], id);
+            let matches = index_scan.iter().filter(|id| exact.contains(id)).count();
+            assert!(matches >= 8, "index: {:?} exact: {:?}", index_scan, exact);
+        }
+
+        //the index reaches every row, including the inserted ones
+        let res: Option<i64> = Spi::get_one(
+            "SET enable_seqscan = 0;
+            SET enable_indexscan = 1;
+            SET diskann.query_search_list_size = 2000;
+            WITH cte as (select * from test_high_dimensions order by embedding <=> (SELECT embedding FROM test_high_dimensions WHERE id = 1)) SELECT count(*) from cte;",
+        )?;
+        assert_eq!(320, res.unwrap());
+
+        Ok(())
+    }
+
+    /// Bit vectors with more bits than a PgVectorInternal has dimensions, which only fit packed.
+    #[cfg(any(test, feature = "pg_test"))]
+    pub unsafe fn test_bit_high_dimensions_scaffold(index_options: &str) -> spi::Result<()> {
+        Spi::run(&format!(
+            "CREATE TABLE test_bit_high_dimensions (
+                id int,
+                embedding bit (40000)
+            );
+
+            select setseed(0.5);
+           -- generate 100 vectors
+            INSERT INTO test_bit_high_dimensions (id, embedding)
+            SELECT
+                g.i,
+                g.embedding
+            FROM (
+                SELECT
+                    i % 100 AS i,
+                    string_agg(CASE WHEN random() > 0.5 THEN '1' ELSE '0' END, '')::bit(40000) AS embedding
+                FROM
+                    generate_series(1, 40000 * 100) i
+                GROUP BY
+                    i % 100) g;
+
+            CREATE INDEX idx_diskann_bit_high_dimensions ON test_bit_high_dimensions
+                USING diskann (embedding bit_hamming_ops) WITH ({index_options});
+
+            -- and some more through inserts
+            INSERT INTO test_bit_high_dimensions (id, embedding)
+            SELECT
+                100 + g.i,
+                g.embedding
+            FROM (
+                SELECT
+                    i % 10 AS i,
+                    string_agg(CASE WHEN random() > 0.5 THEN '1' ELSE '0' END, '')::bit(40000) AS embedding
+                FROM
+                    generate_series(1, 40000 * 10) i
+                GROUP BY
+                    i % 10) g;"
+        ))?;
+
+        for id in [1, 105] {
+            let nearest: Option<i32> = Spi::get_one(&format!(
+                "SET enable_seqscan = 0;
+                SET enable_indexscan = 1;
+                SELECT id FROM test_bit_high_dimensions
+                ORDER BY embedding <~> (SELECT embedding FROM test_bit_high_dimensions WHERE id = {id})
+                LIMIT 1;"
+            ))?;
+            assert_eq!(nearest, Some(id));
+        }
+
+        //the index reaches every row, including the inserted ones
+        let res: Option<i64> = Spi::get_one(
+            "SET enable_seqscan = 0;
+            SET enable_indexscan = 1;
+            SET diskann.query_search_list_size = 200;
+            WITH cte as (select * from test_bit_high_dimensions order by embedding <~> (SELECT embedding FROM test_bit_high_dimensions WHERE id = 1)) SELECT count(*) from cte;",
+        )?;
+        assert_eq!(110, res.unwrap());
+
+        Ok(())
+    }
+
+    #[pg_test(error = "Vectors with 40000 dimensions can't be indexed, the maximum is 32767")]
+    unsafe fn test_index_too_many_dimensions() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test_sparse_high_dimensions (embedding sparsevec (100000));
+            INSERT INTO test_sparse_high_dimensions (embedding) VALUES ('{1:1.0,50000:2.0}/100000');
+            CREATE INDEX idx_diskann_sparse_high_dimensions ON test_sparse_high_dimensions
+                USING diskann (embedding) WITH (num_dimensions = 40000);",
+        )?;
+        Ok(())
+    }
+
+    #[cfg(any(test, feature = "pg_test"))]
+    pub unsafe fn test_labels_scaffold(index_options: &str) -> spi::Result<()> {
+        Spi::run(&format!(
+            "CREATE TABLE test_data (
+                id int,
+                labels int[],
+                embedding vector (128)
+            );
+
+            select setseed(0.5);
+           -- generate 1000 vectors with one of 20 labels, every third one also with one of 7 other labels
+            INSERT INTO test_data (id, labels, embedding)
+            SELECT
+                g.i,
+                CASE WHEN g.i % 3 = 0 THEN ARRAY[g.i % 20, 100 + g.i % 7] ELSE ARRAY[g.i % 20] END,
+                g.embedding
+            FROM (
+                SELECT
+                    i % 1000 AS i,
+                    ('[' || array_to_string(array_agg(random()), ',', '0') || ']')::vector AS embedding
+                FROM
+                    generate_series(1, 128 * 1000) i
+                GROUP BY
+                    i % 1000) g;
+
+            CREATE INDEX idx_diskann_labels ON test_data USING diskann (embedding, labels) WITH ({index_options});
+
+            -- and some more through inserts, with a label that isn't in the index yet
+            INSERT INTO test_data (id, labels, embedding)
+            SELECT
+                1000 + g.i,
+                ARRAY[50],
+                g.embedding
+            FROM (
+                SELECT
+                    i % 100 AS i,
+                    ('[' || array_to_string(array_agg(random()), ',', '0') || ']')::vector AS embedding
+                FROM
+                    generate_series(1, 128 * 100) i
+                GROUP BY
+                    i % 100) g;
+
+            SET diskann.query_search_list_size = 10;
+            "
+        ))?;
+
+        let results = |condition: &str, index_scan: bool| -> spi::Result<Option<Vec<i32>>> {
+            Spi::get_one(&format!(
+                "
+            SET enable_seqscan = {seqscan};
+            SET enable_indexscan = {indexscan};
+            SELECT array_agg(id) FROM (
+                SELECT id, labels FROM test_data WHERE {condition}
+                ORDER BY embedding <=> (SELECT embedding FROM test_data WHERE id = 1) LIMIT 10
+            ) r WHERE {condition};",
+                seqscan = if index_scan { 0 } else { 1 },
+                indexscan = if index_scan { 1 } else { 0 },
+            ))
+        };
+
+        for condition in [
+            "labels && ARRAY[3]",
+            "labels && ARRAY[3, 104]",
+            "labels @> ARRAY[104]",
+            "labels @> ARRAY[50]",
+        ] {
+            /* every row returned by the index has the labels */
+            let index_scan = results(condition, true)?.unwrap();
+            assert_eq!(index_scan.len(), 10, "{}", condition);
+
+            let exact = results(condition, false)?.unwrap();
+            let matches = index_scan.iter().filter(|id| exact.contains(id)).count();
+            assert!(
+                matches >= 8,
+                "{}: index: {:?} exact: {:?}",
+                condition,
+                index_scan,
+                exact
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Checks the recall of an index built with the given operator class: the exact top 10 of 20 queries, computed
+    /// before the index exists, against the top 10 the index returns. Like in test_distance_operator_scaffold the
+    /// vectors are not normalized, so for inner products the norms matter as much as the directions.
+    #[cfg(any(test, feature = "pg_test"))]
+    pub unsafe fn test_distance_operator_recall_scaffold(
+        index_options: &str,
+        opclass: &str,
+        operator: &str,
+    ) -> spi::Result<()> {
+        Spi::run(&format!(
+            "CREATE TABLE test_data (
+                embedding vector (256)
+            );
+
+            select setseed(0.5);
+           -- generate 1000 vectors with varying norms
+            INSERT INTO test_data (embedding)
+            SELECT
+                *
+            FROM (
+                SELECT
+                    ('[' || array_to_string(array_agg((random() - 0.5) * (1 + i % 7)), ',', '0') || ']')::vector AS embedding
+                FROM
+                    generate_series(1, 256 * 1000) i
+                GROUP BY
+                    i % 1000) g;
+
+            CREATE TABLE test_queries AS
+            SELECT
+                i % 20 AS id,
+                ('[' || array_to_string(array_agg(random() - 0.5), ',', '0') || ']')::vector AS embedding
+            FROM
+                generate_series(1, 256 * 20) i
+            GROUP BY
+                i % 20;
+
+            CREATE TABLE ground_truth AS
+            SELECT
+                q.id,
+                t.ctid
+            FROM
+                test_queries q
+                CROSS JOIN LATERAL (
+                    SELECT
+                        ctid
+                    FROM
+                        test_data
+                    ORDER BY
+                        embedding {operator} q.embedding
+                    LIMIT 10) t;
+
+            CREATE INDEX idx_diskann_recall ON test_data USING diskann (embedding {opclass}) WITH ({index_options});"
+        ))?;
+
+        let matches: Option<i64> = Spi::get_one(&format!(
+            "SET enable_seqscan = 0;
+            SET enable_indexscan = 1;
+            SET diskann.query_search_list_size = 100;
+            SET diskann.query_rescore = 200;
+            SELECT
+                count(*)
+            FROM
+                test_queries q
+                CROSS JOIN LATERAL (
+                    SELECT
+                        ctid
+                    FROM
+                        test_data
+                    ORDER BY
+                        embedding {operator} q.embedding
+                    LIMIT 10) t
+                JOIN ground_truth g ON g.id = q.id AND g.ctid = t.ctid;"
+        ))?;
+        let matches = matches.unwrap();
+        assert!(
+            matches >= 150,
+            "Low recall: {} of the 200 nearest rows found",
+            matches
+        );
+
+        Ok(())
+    }
+
+    #[cfg(any(test, feature = "pg_test"))]
+    pub unsafe fn test_empty_table_insert_scaffold(index_options: &str) -> spi::Result<()> {
+        Spi::run(&format!(
+            "CREATE TABLE test(embedding vector(3));
+
+            CREATE INDEX idxtest
+                  ON test
+               USING diskann(embedding)
+                WITH ({index_options});
+
+            INSERT INTO test(embedding) VALUES ('[1,2,3]'), ('[4,5,6]'), ('[7,8,10]');
+            ",
+        ))?;
+
+        let res: Option<i64> = Spi::get_one(&format!(
+            "   set enable_seqscan = 0;
+                WITH cte as (select * from test order by embedding <=> '[0,0,0]') SELECT count(*) from cte;",
+        ))?;
+        assert_eq!(3, res.unwrap());
+
+        Spi::run(&format!(
+            "
+        set enable_seqscan = 0;
+        explain analyze select * from test order by embedding <=> '[0,0,0]';
+        ",
+        ))?;
+
+        Spi::run(&format!("drop index idxtest;",))?;
+
+        Ok(())
+    }
+
+    #[cfg(any(test, feature = "pg_test"))]
+    pub unsafe fn test_unlogged_table_scaffold(index_options: &str) -> spi::Result<()> {
+        Spi::run(&format!(
+            "CREATE UNLOGGED TABLE test_unlogged(embedding vector(3));
+
+            CREATE INDEX idxtest_unlogged
+                  ON test_unlogged
+               USING diskann(embedding)
+                WITH ({index_options});
+
+            INSERT INTO test_unlogged(embedding) VALUES ('[1,2,3]'), ('[4,5,6]'), ('[7,8,10]');
+            ",
+        ))?;
+
+        //the init fork holds only the meta page of the empty index
+        let init_fork_size: Option<i64> =
+            Spi::get_one("SELECT pg_relation_size('idxtest_unlogged', 'init')")?;
+        assert_eq!(pg_sys::BLCKSZ as i64, init_fork_size.unwrap());
+
+        let res: Option<i64> = Spi::get_one(
+            "   set enable_seqscan = 0;
+                WITH cte as (select * from test_unlogged order by embedding <=> '[0,0,0]') SELECT count(*) from cte;",
+        )?;
+        assert_eq!(3, res.unwrap());
+
+        Spi::run("drop index idxtest_unlogged;")?;
+        Spi::run("drop table test_unlogged;")?;
 
         Ok(())
     }
@@ -1,6 +1,135 @@
 use pgrx::*;
 
-/// cost estimate function loosely based on how ivfflat does things
+use super::guc::{TSV_QUERY_SEARCH_LIST_SIZE, TSV_RESORT_SIZE};
+use super::meta_page::MetaPage;
+use super::storage::StorageType;
+
+/* The cost of a distance comparison, in multiples of cpu_operator_cost per dimension. Full-precision distances are
+vectorized over 8 floats, SBQ and RaBitQ compare 64 bits at a time, PQ looks up one table entry per subvector and SQ8
+works on 16 bytes at a time. */
+const FULL_DISTANCE_COST_PER_DIMENSION: f64 = 1.0 / 8.0;
+const SBQ_DISTANCE_COST_PER_BIT: f64 = 1.0 / 64.0;
+const PQ_DISTANCE_COST_PER_DIMENSION: f64 = 1.0 / 8.0;
+const SQ8_DISTANCE_COST_PER_DIMENSION: f64 = 1.0 / 16.0;
+const RABITQ_DISTANCE_COST_PER_DIMENSION: f64 = 1.0 / 64.0;
+/// The rabitq layout rescores about this many candidates per row it returns, as many as its error bounds leave in
+/// doubt.
+const RABITQ_RESCORED_PER_ROW: f64 = 2.0;
+
+/// The work of a scan, based on how the graph search works: a first search visits search_list_size nodes before the
+/// first row is returned, and every later row visits about one more node. Every visited node compares the distances
+/// of its neighbors. Quantized layouts rescore candidates with the full vectors.
+struct ScanCostModel {
+    search_list_size: f64,
+    /// The CPU cost of visiting a node: comparing the query with the vectors of its neighbors.
+    node_cost: f64,
+    /// Index pages read per visited node: the node, and the neighbors unless their vectors are in the node.
+    page_reads_per_node: f64,
+    /// The number of candidates rescored before the first row is returned.
+    rescored_first: f64,
+    /// The number of candidates rescored for every later row.
+    rescored_per_row: f64,
+    /// The cost of the full distance of one rescored candidate.
+    rescore_distance_cost: f64,
+    /// Rescoring reads the full vectors from index pages instead of the heap.
+    rescore_from_index: bool,
+}
+
+impl ScanCostModel {
+    unsafe fn new(meta_page: &MetaPage) -> Self {
+        let num_neighbors = meta_page.get_num_neighbors() as f64;
+        let dimensions = meta_page.get_num_dimensions_to_index() as f64;
+        let full_distance_cost = meta_page.get_num_dimensions() as f64
+            * FULL_DISTANCE_COST_PER_DIMENSION
+            * pg_sys::cpu_operator_cost;
+
+        let storage_type = meta_page.get_storage_type();
+        let cost_per_dimension = match storage_type {
+            StorageType::Plain => FULL_DISTANCE_COST_PER_DIMENSION,
+            StorageType::SbqSpeedup | StorageType::SbqCompression => {
+                meta_page.get_bq_num_bits_per_dimension() as f64 * SBQ_DISTANCE_COST_PER_BIT
+            }
+            StorageType::Pq => PQ_DISTANCE_COST_PER_DIMENSION,
+            StorageType::Sq8 => SQ8_DISTANCE_COST_PER_DIMENSION,
+            StorageType::Rabitq => RABITQ_DISTANCE_COST_PER_DIMENSION,
+        };
+
+        //io_optimized stores the quantized vectors of the neighbors in the node
+        let page_reads_per_node = if storage_type == StorageType::SbqSpeedup {
+            1.0
+        } else {
+            1.0 + num_neighbors
+        };
+
+        let (rescored_first, rescored_per_row) = match storage_type {
+            StorageType::Plain
+                if meta_page.get_num_dimensions() == meta_page.get_num_dimensions_to_index() =>
+            {
+                (0.0, 0.0)
+            }
+            StorageType::Rabitq => (RABITQ_RESCORED_PER_ROW, RABITQ_RESCORED_PER_ROW),
+            _ => (TSV_RESORT_SIZE.get() as f64, 1.0),
+        };
+
+        Self {
+            search_list_size: TSV_QUERY_SEARCH_LIST_SIZE.get() as f64,
+            node_cost: pg_sys::cpu_index_tuple_cost
+                + num_neighbors * dimensions * cost_per_dimension * pg_sys::cpu_operator_cost,
+            page_reads_per_node,
+            rescored_first,
+            rescored_per_row,
+            rescore_distance_cost: full_distance_cost,
+            rescore_from_index: meta_page.get_full_vectors_in_index(),
+        }
+    }
+
+    fn nodes_visited(&self, rows: f64) -> f64 {
+        self.search_list_size + (rows - 1.0).max(0.0)
+    }
+
+    fn rescored(&self, rows: f64) -> f64 {
+        if rows <= 0.0 {
+            return 0.0;
+        }
+        self.rescored_first + (rows - 1.0) * self.rescored_per_row
+    }
+
+    /// The CPU cost of returning `rows` rows.
+    fn cpu_cost(&self, rows: f64) -> f64 {
+        self.nodes_visited(rows) * self.node_cost + self.rescored(rows) * self.rescore_distance_cost
+    }
+
+    /// The index pages read to return `rows` rows, before caching.
+    fn index_page_reads(&self, rows: f64) -> f64 {
+        let mut page_reads = self.nodes_visited(rows) * self.page_reads_per_node;
+        if self.rescore_from_index {
+            page_reads += self.rescored(rows);
+        }
+        page_reads
+    }
+
+    /// The heap pages read by rescoring `rows` rows. The executor fetches the rows returned, and the planner costs
+    /// that, so only the candidates that aren't returned count.
+    fn heap_page_reads(&self, rows: f64) -> f64 {
+        if self.rescore_from_index {
+            0.0
+        } else {
+            (self.rescored(rows) - rows).max(0.0)
+        }
+    }
+}
+
+/// Cost estimate of the graph search (see ScanCostModel). The total cost is the cost of returning all rows the
+/// index quals let through; the planner interpolates between the startup and the total cost for a LIMIT. The LIMIT
+/// decides how many of the index pages read are expected to be cached already.
+///
+/// Filters that aren't index quals are applied to the rows the index returns, so with a filter that keeps a share s
+/// of the rows, the scan has to return 1/s rows for each row of the result. The startup cost covers the rows until
+/// the first one passes the filter. If the search list is expected to hold fewer rows passing the filter than the
+/// LIMIT asks for, the estimate raises a NOTICE: the index scan is costed for all the rows it has to return, so the
+/// planner may choose a plan without the index.
+///
+/// A range search on the vector column (see scan.rs) is costed like an ordered scan returning the rows in the sphere.
 #[pg_guard(immutable, parallel_safe)]
 pub unsafe extern "C" fn amcostestimate(
     root: *mut pg_sys::PlannerInfo,
@@ -12,8 +141,11 @@ pub unsafe extern "C" fn amcostestimate(
     index_correlation: *mut f64,
     index_pages: *mut f64,
 ) {
-    if (*path).indexorderbys.is_null() {
-        //can't use index without order bys
+    let has_range_clause = PgList::<pg_sys::IndexClause>::from_pg((*path).indexclauses)
+        .iter_ptr()
+        .any(|clause| (*clause).indexcol == 0);
+    if (*path).indexorderbys.is_null() && !has_range_clause {
+        //can't use index without order bys or a range search on the vector column
         *index_startup_cost = f64::MAX;
         *index_total_cost = f64::MAX;
         *index_selectivity = 0.;
@@ -22,35 +154,216 @@ pub unsafe extern "C" fn amcostestimate(
         return;
     }
     let path_ref = path.as_ref().expect("path argument is NULL");
-    /*let indexinfo = path_ref
+    let indexinfo = path_ref
         .indexinfo
         .as_ref()
         .expect("indexinfo in path is NULL");
-    let index_relation = unsafe {
-        PgRelation::with_lock(
-            indexinfo.indexoid,
-            pg_sys::AccessShareLock as pg_sys::LOCKMODE,
-        )
-    };
-    let heap_relation = index_relation
-    .heap_relation()
-    .expect("failed to get heap relation for index");*/
+    let index_relation = PgRelation::with_lock(
+        indexinfo.indexoid,
+        pg_sys::AccessShareLock as pg_sys::LOCKMODE,
+    );
+    let meta_page = MetaPage::fetch(&index_relation);
 
-    let total_index_tuples = (*path_ref.indexinfo).tuples;
+    let total_index_tuples = indexinfo.tuples;
+    let total_index_pages = indexinfo.pages as f64;
 
+    //the generic estimate provides the selectivity of the index quals and the page cost of the tablespace
     let mut generic_costs = pg_sys::GenericCosts {
-        numIndexTuples: total_index_tuples / 100., //TODO need better estimate
+        numIndexTuples: total_index_tuples,
         ..Default::default()
     };
-
     pg_sys::genericcostestimate(root, path, loop_count, &mut generic_costs);
+    let random_page_cost = generic_costs.spc_random_page_cost;
+
+    let model = ScanCostModel::new(&meta_page);
+    let num_rows = (generic_costs.indexSelectivity * total_index_tuples).max(1.0);
+    let filter_selectivity = filter_selectivity(indexinfo.rel, generic_costs.indexSelectivity);
+    let limit = (*root).limit_tuples;
+    let expected_rows = if limit > 0.0 {
+        (limit / filter_selectivity).min(num_rows)
+    } else {
+        num_rows
+    };
+
+    if filter_selectivity < 1.0
+        && limit > 0.0
+        && limit / filter_selectivity > model.search_list_size
+    {
+        notice!(
+            "the filter of the query is expected to keep {:.2}% of the rows, so the diskann index {} has to return \
+             about {:.0} rows to find {:.0}. Its scan is costed accordingly, and the planner may prefer a plan \
+             without the index",
+            filter_selectivity * 100.0,
+            index_relation.name(),
+            limit / filter_selectivity,
+            limit
+        );
+    }
+
+    //the share of the index page reads of the expected scan that aren't cached, across all loops
+    let expected_page_reads = model.index_page_reads(expected_rows);
+    let expected_pages_fetched = if expected_page_reads > 0.0 && total_index_pages > 0.0 {
+        pg_sys::index_pages_fetched(
+            expected_page_reads * loop_count,
+            total_index_pages as pg_sys::BlockNumber,
+            total_index_pages,
+            root,
+        ) / loop_count
+    } else {
+        0.0
+    };
+    let uncached_share = if expected_page_reads > 0.0 {
+        (expected_pages_fetched / expected_page_reads).min(1.0)
+    } else {
+        1.0
+    };
 
-    //TODO probably have to adjust costs more here
+    let cost = |rows: f64| {
+        model.cpu_cost(rows)
+            + (model.index_page_reads(rows) * uncached_share + model.heap_page_reads(rows))
+                * random_page_cost
+    };
 
-    *index_startup_cost = generic_costs.indexTotalCost;
-    *index_total_cost = generic_costs.indexTotalCost;
+    *index_startup_cost = cost((1.0 / filter_selectivity).min(num_rows));
+    *index_total_cost = cost(num_rows).max(*index_startup_cost);
     *index_selectivity = generic_costs.indexSelectivity;
-    *index_correlation = generic_costs.indexCorrelation;
-    *index_pages = generic_costs.numIndexPages;
-    //pg_sys::cpu_index_tuple_cost;
+    //the rows are returned by distance, unrelated to their order in the heap
+    *index_correlation = 0.0;
+    *index_pages = expected_pages_fetched.min(total_index_pages);
+}
+
+/// The share of the rows returned by the index that pass the filters that aren't index quals.
+unsafe fn filter_selectivity(rel: *mut pg_sys::RelOptInfo, index_selectivity: f64) -> f64 {
+    let Some(rel) = rel.as_ref() else {
+        return 1.0;
+    };
+    if rel.tuples <= 0.0 || index_selectivity <= 0.0 {
+        return 1.0;
+    }
+    //rel.rows is the estimate after all restrictions, the index quals included
+    let selectivity = rel.rows / rel.tuples / index_selectivity;
+    selectivity.clamp(1.0 / rel.tuples, 1.0)
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    unsafe fn explain_costs(query: &str) -> spi::Result<(f64, f64)> {
+        let plan: Option<pgrx::Json> = Spi::get_one(&format!("EXPLAIN (FORMAT JSON) {query}"))?;
+        let plan = &plan.unwrap().0[0]["Plan"];
+        Ok((
+            plan["Startup Cost"].as_f64().unwrap(),
+            plan["Total Cost"].as_f64().unwrap(),
+        ))
+    }
+
+    /// 1000 rows in 100 categories.
+    unsafe fn create_test_data() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test_data (
+                embedding vector (64),
+                category int
+            );
+
+            select setseed(0.5);
+            INSERT INTO test_data (embedding, category)
+            SELECT
+                ('[' || array_to_string(array_agg(random()), ',', '0') || ']')::vector AS embedding,
+                i % 1000 % 100
+            FROM
+                generate_series(1, 64 * 1000) i
+            GROUP BY
+                i % 1000;
+
+            CREATE INDEX idx_diskann_bq ON test_data USING diskann (embedding);
+            ANALYZE test_data;",
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_cost_estimate() -> spi::Result<()> {
+        create_test_data()?;
+        Spi::run("SET enable_seqscan = 0")?;
+
+        let query = "SELECT * FROM test_data ORDER BY embedding <=> (SELECT embedding FROM test_data LIMIT 1)";
+        let (_, all_rows) = explain_costs(query)?;
+        let (startup, limited) = explain_costs(&format!("{query} LIMIT 10"))?;
+        assert!(startup > 0.0);
+        assert!(limited < all_rows, "{limited} {all_rows}");
+
+        //a larger search list visits more nodes before the first row
+        Spi::run("SET diskann.query_search_list_size = 1000")?;
+        let (larger_startup, _) = explain_costs(&format!("{query} LIMIT 10"))?;
+        assert!(larger_startup > startup, "{larger_startup} {startup}");
+
+        //and rescoring more candidates costs more, too
+        Spi::run("RESET diskann.query_search_list_size; SET diskann.query_rescore = 500")?;
+        let (rescore_startup, _) = explain_costs(&format!("{query} LIMIT 10"))?;
+        assert!(rescore_startup > startup, "{rescore_startup} {startup}");
+
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_cost_estimate_filter_selectivity() -> spi::Result<()> {
+        create_test_data()?;
+        Spi::run("SET enable_seqscan = 0")?;
+
+        let query = "SELECT * FROM test_data WHERE category = 7 ORDER BY embedding <=> (SELECT embedding FROM test_data LIMIT 1) LIMIT 10";
+        let (filtered_startup, filtered) = explain_costs(query)?;
+        let (startup, unfiltered) = explain_costs(&query.replace("WHERE category = 7", ""))?;
+        //the filter keeps 1% of the rows, the index returns about 100 rows for the first one that passes
+        assert!(filtered_startup > startup, "{filtered_startup} {startup}");
+        assert!(filtered > unfiltered, "{filtered} {unfiltered}");
+
+        //with an index on the filter, the 10 rows of the category are sorted instead
+        Spi::run(
+            "CREATE INDEX idx_category ON test_data (category);
+            ANALYZE test_data;
+            RESET enable_seqscan;",
+        )?;
+        let plan: Option<pgrx::Json> = Spi::get_one(&format!("EXPLAIN (FORMAT JSON) {query}"))?;
+        let plan = plan.unwrap().0.to_string();
+        assert!(!plan.contains("idx_diskann_bq"), "{}", plan);
+        assert!(plan.contains("idx_category"), "{}", plan);
+
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_cost_estimate_filter_switch() -> spi::Result<()> {
+        create_test_data()?;
+        //io_optimized reads a page per visited node, so without a filter the search is the cheapest plan
+        Spi::run(
+            "DROP INDEX idx_diskann_io;
+            CREATE INDEX idx_diskann_io ON test_data USING diskann (embedding) WITH (storage_layout = io_optimized);
+            CREATE INDEX idx_category ON test_data (category);
+            ANALYZE test_data;
+            SET enable_seqscan = 0;
+            SET diskann.query_search_list_size = 10;
+            SET diskann.query_rescore = 1;",
+        )?;
+
+        let plan = |query: &str| -> spi::Result<String> {
+            let plan: Option<pgrx::Json> = Spi::get_one(&format!("EXPLAIN (FORMAT JSON) {query}"))?;
+            Ok(plan.unwrap().0.to_string())
+        };
+
+        //without a filter, and with a filter that keeps most rows, the index is used
+        let query = "SELECT * FROM test_data ORDER BY embedding <=> (SELECT embedding FROM test_data LIMIT 1) LIMIT 10";
+        let unfiltered = plan(query)?;
+        assert!(unfiltered.contains("idx_diskann_io"), "{}", unfiltered);
+        let broad = plan(&query.replace("ORDER BY", "WHERE category < 90 ORDER BY"))?;
+        assert!(broad.contains("idx_diskann_io"), "{}", broad);
+
+        //a filter that keeps 1% of the rows needs about 1000 rows from the index, the rows of the category are sorted
+        //instead
+        let selective = plan(&query.replace("ORDER BY", "WHERE category = 7 ORDER BY"))?;
+        assert!(!selective.contains("idx_diskann_io"), "{}", selective);
+        assert!(selective.contains("idx_category"), "{}", selective);
+
+        Ok(())
+    }
 }
@@ -109,6 +109,214 @@ pub fn distance_cosine_unoptimized(a: &[f32], b: &[f32]) -> f32 {
     (1.0 - res).max(0.0)
 }
 
+/// Negative inner product, matching pgvector's `<#>` operator.
+/// Unlike the other distances this can be negative.
+#[inline]
+pub fn distance_inner_product(a: &[f32], b: &[f32]) -> f32 {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    //note safety is guraranteed by compile_error above
+    unsafe {
+        return super::distance_x86::distance_inner_product_x86_avx2(a, b);
+    }
+
+    #[allow(unreachable_code)]
+    {
+        distance_inner_product_unoptimized(a, b)
+    }
+}
+
+#[inline(always)]
+pub fn distance_inner_product_unoptimized(a: &[f32], b: &[f32]) -> f32 {
+    assert_eq!(a.len(), b.len());
+    let res: f32 = a.iter().zip(b).map(|(a, b)| *a * *b).sum();
+    -res
+}
+
+/* Distances between bit vectors, given packed 32 bits to an f32 (see PgBitInternal::to_packed). */
+
+/// The u64 words of a packed bit vector, if it is aligned for them. Vectors read from pages may not be.
+fn bit_words(a: &[f32]) -> Option<&[u64]> {
+    let (head, words, tail) = unsafe { a.align_to::<u64>() };
+    if head.is_empty() && tail.is_empty() {
+        Some(words)
+    } else {
+        None
+    }
+}
+
+fn count_bits_xor(a: &[f32], b: &[f32]) -> u32 {
+    match (bit_words(a), bit_words(b)) {
+        (Some(a), Some(b)) => distance_xor_optimized(a, b) as u32,
+        _ => a
+            .iter()
+            .zip(b)
+            .map(|(a, b)| (a.to_bits() ^ b.to_bits()).count_ones())
+            .sum(),
+    }
+}
+
+fn count_bits(a: &[f32]) -> u32 {
+    a.iter().map(|a| a.to_bits().count_ones()).sum()
+}
+
+/// Number of bits that differ, matching pgvector's `<~>` operator.
+pub fn distance_hamming(a: &[f32], b: &[f32]) -> f32 {
+    assert_eq!(a.len(), b.len());
+    count_bits_xor(a, b) as f32
+}
+
+/// One minus the number of bits set in both over the number of bits set in either, matching pgvector's `<%>`
+/// operator.
+pub fn distance_jaccard(a: &[f32], b: &[f32]) -> f32 {
+    assert_eq!(a.len(), b.len());
+    //the bits set in both and in either are (|a| + |b| - |a xor b|) / 2 and (|a| + |b| + |a xor b|) / 2
+    let xor = count_bits_xor(a, b);
+    let sum = count_bits(a) + count_bits(b);
+    if sum == 0 {
+        return 0.0;
+    }
+    2.0 * xor as f32 / (sum + xor) as f32
+}
+
+/* Distances to vectors of 8-bit codes (see sq8.rs). Every dimension has its own range, so the codes are widened to
+f32 and weighted per dimension instead of being compared as integers. */
+
+/// Weighted squared L2 distance to 8-bit codes: the sum of `w[i] * (t[i] - c[i])^2`.
+#[inline]
+pub fn distance_l2_sq8(t: &[f32], w: &[f32], c: &[u8]) -> f32 {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    //note safety is guraranteed by compile_error above
+    unsafe {
+        return super::distance_x86::distance_l2_sq8_x86_avx2(t, w, c);
+    }
+
+    #[allow(unreachable_code)]
+    {
+        distance_l2_sq8_unoptimized(t, w, c)
+    }
+}
+
+#[inline(always)]
+pub fn distance_l2_sq8_unoptimized(t: &[f32], w: &[f32], c: &[u8]) -> f32 {
+    assert_eq!(t.len(), c.len());
+    assert_eq!(w.len(), c.len());
+    t.iter()
+        .zip(w)
+        .zip(c)
+        .map(|((t, w), &c)| w * (t - c as f32) * (t - c as f32))
+        .sum()
+}
+
+/// Inner product with 8-bit codes: the sum of `w[i] * c[i]`.
+#[inline]
+pub fn inner_product_sq8(w: &[f32], c: &[u8]) -> f32 {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    //note safety is guraranteed by compile_error above
+    unsafe {
+        return super::distance_x86::inner_product_sq8_x86_avx2(w, c);
+    }
+
+    #[allow(unreachable_code)]
+    {
+        inner_product_sq8_unoptimized(w, c)
+    }
+}
+
+#[inline(always)]
+pub fn inner_product_sq8_unoptimized(w: &[f32], c: &[u8]) -> f32 {
+    assert_eq!(w.len(), c.len());
+    w.iter().zip(c).map(|(w, &c)| w * c as f32).sum()
+}
+
+/* Distances between sparse vectors, given as their sorted indices and the values at those indices. They match the
+dense distances above: cosine expects normalized vectors and l2 isn't square-rooted. */
+
+/// Calls `f` with the values of every index in either vector, 0.0 for the vector that doesn't have the index.
+#[inline(always)]
+fn sparse_merge(
+    a_indices: &[i32],
+    a_values: &[f32],
+    b_indices: &[i32],
+    b_values: &[f32],
+    mut f: impl FnMut(f32, f32),
+) {
+    let (mut i, mut j) = (0, 0);
+    while i < a_indices.len() && j < b_indices.len() {
+        match a_indices[i].cmp(&b_indices[j]) {
+            std::cmp::Ordering::Less => {
+                f(a_values[i], 0.0);
+                i += 1;
+            }
+            std::cmp::Ordering::Greater => {
+                f(0.0, b_values[j]);
+                j += 1;
+            }
+            std::cmp::Ordering::Equal => {
+                f(a_values[i], b_values[j]);
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    a_values[i..].iter().for_each(|&a| f(a, 0.0));
+    b_values[j..].iter().for_each(|&b| f(0.0, b));
+}
+
+fn sparse_dot_product(
+    a_indices: &[i32],
+    a_values: &[f32],
+    b_indices: &[i32],
+    b_values: &[f32],
+) -> f32 {
+    let (mut i, mut j) = (0, 0);
+    let mut res = 0.0;
+    while i < a_indices.len() && j < b_indices.len() {
+        match a_indices[i].cmp(&b_indices[j]) {
+            std::cmp::Ordering::Less => i += 1,
+            std::cmp::Ordering::Greater => j += 1,
+            std::cmp::Ordering::Equal => {
+                res += a_values[i] * b_values[j];
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    res
+}
+
+pub fn distance_sparse_l2(
+    a_indices: &[i32],
+    a_values: &[f32],
+    b_indices: &[i32],
+    b_values: &[f32],
+) -> f32 {
+    let mut norm = 0.0;
+    sparse_merge(a_indices, a_values, b_indices, b_values, |a, b| {
+        norm += (a - b) * (a - b)
+    });
+    norm
+}
+
+pub fn distance_sparse_cosine(
+    a_indices: &[i32],
+    a_values: &[f32],
+    b_indices: &[i32],
+    b_values: &[f32],
+) -> f32 {
+    debug_assert!(preprocess_cosine_get_norm(a_values).is_none());
+    debug_assert!(preprocess_cosine_get_norm(b_values).is_none());
+    (1.0 - sparse_dot_product(a_indices, a_values, b_indices, b_values)).max(0.0)
+}
+
+pub fn distance_sparse_inner_product(
+    a_indices: &[i32],
+    a_values: &[f32],
+    b_indices: &[i32],
+    b_values: &[f32],
+) -> f32 {
+    -sparse_dot_product(a_indices, a_values, b_indices, b_values)
+}
+
 pub fn preprocess_cosine_get_norm(a: &[f32]) -> Option<f32> {
     let norm = a.iter().map(|v| v * v).sum::<f32>();
     //adjust the epsilon to the length of the vector
@@ -128,6 +128,141 @@ simdeez::simd_runtime_generate!(
     }
 );
 
+simdeez::simd_runtime_generate!(
+    pub fn distance_inner_product_x86(x: &[f32], y: &[f32]) -> f32 {
+        let mut accum0 = S::setzero_ps();
+        let mut accum1 = S::setzero_ps();
+        let mut accum2 = S::setzero_ps();
+        let mut accum3 = S::setzero_ps();
+
+        let mut x = x;
+        let mut y = y;
+
+        //assert!(x.len() == y.len());
+
+        while x.len() >= S::VF32_WIDTH * 4 {
+            accum0 = S::fmadd_ps(S::loadu_ps(&x[0]), S::loadu_ps(&y[0]), accum0);
+            accum1 = S::fmadd_ps(
+                S::loadu_ps(&x[S::VF32_WIDTH]),
+                S::loadu_ps(&y[S::VF32_WIDTH]),
+                accum1,
+            );
+            accum2 = S::fmadd_ps(
+                S::loadu_ps(&x[S::VF32_WIDTH * 2]),
+                S::loadu_ps(&y[S::VF32_WIDTH * 2]),
+                accum2,
+            );
+            accum3 = S::fmadd_ps(
+                S::loadu_ps(&x[S::VF32_WIDTH * 3]),
+                S::loadu_ps(&y[S::VF32_WIDTH * 3]),
+                accum3,
+            );
+
+            // Move each slice to the next position
+            x = &x[S::VF32_WIDTH * 4..];
+            y = &y[S::VF32_WIDTH * 4..];
+        }
+
+        let mut dist = S::horizontal_add_ps(accum0)
+            + S::horizontal_add_ps(accum1)
+            + S::horizontal_add_ps(accum2)
+            + S::horizontal_add_ps(accum3);
+
+        // compute for the remaining elements
+        for i in 0..x.len() {
+            dist += x[i] * y[i];
+        }
+
+        //negated so that a larger inner product means a smaller distance (same as pgvector's <#>)
+        -dist
+    }
+);
+
+/* simdeez has no 8-bit loads, so the kernels for 8-bit codes use the intrinsics directly. Every iteration widens 16
+codes to two registers of 8 f32 each. */
+
+#[cfg(target_arch = "x86")]
+use std::arch::x86::*;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+
+#[inline(always)]
+unsafe fn horizontal_add_avx2(v: __m256) -> f32 {
+    let sum = _mm_add_ps(_mm256_castps256_ps128(v), _mm256_extractf128_ps(v, 1));
+    let sum = _mm_add_ps(sum, _mm_movehl_ps(sum, sum));
+    let sum = _mm_add_ss(sum, _mm_shuffle_ps(sum, sum, 1));
+    _mm_cvtss_f32(sum)
+}
+
+/// Loads 16 codes as two vectors of 8 f32.
+#[inline(always)]
+unsafe fn load_codes_avx2(c: &[u8]) -> (__m256, __m256) {
+    debug_assert!(c.len() >= 16);
+    let codes = _mm_loadu_si128(c.as_ptr() as *const __m128i);
+    (
+        _mm256_cvtepi32_ps(_mm256_cvtepu8_epi32(codes)),
+        _mm256_cvtepi32_ps(_mm256_cvtepu8_epi32(_mm_srli_si128(codes, 8))),
+    )
+}
+
+#[target_feature(enable = "avx2,fma")]
+pub unsafe fn distance_l2_sq8_x86_avx2(t: &[f32], w: &[f32], c: &[u8]) -> f32 {
+    assert_eq!(t.len(), c.len());
+    assert_eq!(w.len(), c.len());
+    let mut accum0 = _mm256_setzero_ps();
+    let mut accum1 = _mm256_setzero_ps();
+
+    let mut i = 0;
+    while i + 16 <= c.len() {
+        let (c0, c1) = load_codes_avx2(&c[i..]);
+        let d0 = _mm256_sub_ps(_mm256_loadu_ps(t.as_ptr().add(i)), c0);
+        let d1 = _mm256_sub_ps(_mm256_loadu_ps(t.as_ptr().add(i + 8)), c1);
+        accum0 = _mm256_fmadd_ps(
+            _mm256_mul_ps(d0, d0),
+            _mm256_loadu_ps(w.as_ptr().add(i)),
+            accum0,
+        );
+        accum1 = _mm256_fmadd_ps(
+            _mm256_mul_ps(d1, d1),
+            _mm256_loadu_ps(w.as_ptr().add(i + 8)),
+            accum1,
+        );
+        i += 16;
+    }
+
+    let mut dist = horizontal_add_avx2(_mm256_add_ps(accum0, accum1));
+
+    // compute for the remaining elements
+    for j in i..c.len() {
+        let diff = t[j] - c[j] as f32;
+        dist += w[j] * diff * diff;
+    }
+    dist
+}
+
+#[target_feature(enable = "avx2,fma")]
+pub unsafe fn inner_product_sq8_x86_avx2(w: &[f32], c: &[u8]) -> f32 {
+    assert_eq!(w.len(), c.len());
+    let mut accum0 = _mm256_setzero_ps();
+    let mut accum1 = _mm256_setzero_ps();
+
+    let mut i = 0;
+    while i + 16 <= c.len() {
+        let (c0, c1) = load_codes_avx2(&c[i..]);
+        accum0 = _mm256_fmadd_ps(_mm256_loadu_ps(w.as_ptr().add(i)), c0, accum0);
+        accum1 = _mm256_fmadd_ps(_mm256_loadu_ps(w.as_ptr().add(i + 8)), c1, accum1);
+        i += 16;
+    }
+
+    let mut res = horizontal_add_avx2(_mm256_add_ps(accum0, accum1));
+
+    // compute for the remaining elements
+    for j in i..c.len() {
+        res += w[j] * c[j] as f32;
+    }
+    res
+}
+
 #[cfg(test)]
 mod tests {
     #[test]
@@ -145,6 +280,11 @@ mod tests {
             super::super::distance::distance_l2_unoptimized(&r, &l)
         );
 
+        assert_eq!(
+            unsafe { super::distance_inner_product_x86_avx2(&r, &l) },
+            super::super::distance::distance_inner_product_unoptimized(&r, &l)
+        );
+
         //don't use too many dimensions to avoid overflow
         let r: Vec<f32> = (0..20).map(|v| v as f32).collect();
         let l: Vec<f32> = (0..20).map(|v| v as f32).collect();
@@ -157,6 +297,10 @@ mod tests {
             unsafe { super::distance_l2_x86_avx2(&r, &l) },
             super::super::distance::distance_l2_unoptimized(&r, &l)
         );
+        assert_eq!(
+            unsafe { super::distance_inner_product_x86_avx2(&r, &l) },
+            super::super::distance::distance_inner_product_unoptimized(&r, &l)
+        );
 
         //many dimensions but normalized
         let r: Vec<f32> = (0..2000).map(|v| v as f32 + 1.0).collect();
@@ -181,4 +325,25 @@ mod tests {
                 < 0.000001
         );
     }
+    #[test]
+    fn sq8_distances_equal() {
+        //a length that isn't a multiple of 16, so that the remainder is used as well
+        let t: Vec<f32> = (0..1001).map(|v| (v % 300) as f32 * 0.7).collect();
+        let w: Vec<f32> = (0..1001).map(|v| (v % 7) as f32 * 0.01).collect();
+        let c: Vec<u8> = (0..1001).map(|v| (v * 31 % 256) as u8).collect();
+
+        let expected = super::super::distance::distance_l2_sq8_unoptimized(&t, &w, &c);
+        let actual = unsafe { super::distance_l2_sq8_x86_avx2(&t, &w, &c) };
+        assert!((actual - expected).abs() <= expected.abs() * 0.00001);
+
+        let expected = super::super::distance::inner_product_sq8_unoptimized(&w, &c);
+        let actual = unsafe { super::inner_product_sq8_x86_avx2(&w, &c) };
+        assert!((actual - expected).abs() <= expected.abs() * 0.00001);
+
+        //shorter than one iteration
+        assert_eq!(
+            unsafe { super::distance_l2_sq8_x86_avx2(&t[..5], &w[..5], &c[..5]) },
+            super::super::distance::distance_l2_sq8_unoptimized(&t[..5], &w[..5], &c[..5])
+        );
+    }
 }
@@ -0,0 +1,349 @@
+use ndarray::{Array2, ArrayView1, ArrayView2, Axis};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use reductive::kmeans::{KMeansWithCentroids, NIterationsCondition};
+
+use crate::util::IndexPointer;
+
+use super::distance::{distance_hamming, distance_l2};
+use super::meta_page::DistanceType;
+
+/// The number of centroids, and so the maximum number of init ids.
+pub const NUM_INIT_IDS: usize = 4;
+/// The centroids are computed from a random sample of at most this many vectors.
+const ENTRY_POINT_MAX_SAMPLES: usize = 1_000;
+const ENTRY_POINT_KMEANS_ITERATIONS: usize = 10;
+/// Clusters holding less than 1/ENTRY_POINT_MIN_CLUSTER_SHARE of their even share of the sample don't get an init id.
+const ENTRY_POINT_MIN_CLUSTER_SHARE: usize = 4;
+/* a fixed seed, so that building the same table twice gives the same index */
+const ENTRY_POINT_SEED: u64 = 0x656e_7472_7970;
+
+type DistanceFn = fn(&[f32], &[f32]) -> f32;
+
+/// A sample of the vectors of an index build, to find the centroids of the data. The samples are taken during the
+/// training scan of the quantizer, or from the first nodes of the graph if the storage doesn't train.
+pub struct EntryPointSample {
+    samples: Vec<f32>,
+    /// The nodes of the samples taken from the graph.
+    nodes: Vec<Option<IndexPointer>>,
+    num_dimensions: usize,
+    num_samples_seen: u64,
+    rng: ChaCha8Rng,
+}
+
+impl EntryPointSample {
+    pub fn new(num_dimensions: usize) -> Self {
+        Self {
+            samples: vec![],
+            nodes: vec![],
+            num_dimensions,
+            num_samples_seen: 0,
+            rng: ChaCha8Rng::seed_from_u64(ENTRY_POINT_SEED),
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.nodes.len() >= ENTRY_POINT_MAX_SAMPLES
+    }
+
+    pub fn add_sample(&mut self, sample: &[f32], node: Option<IndexPointer>) {
+        assert_eq!(sample.len(), self.num_dimensions);
+        self.num_samples_seen += 1;
+        if !self.is_full() {
+            self.samples.extend_from_slice(sample);
+            self.nodes.push(node);
+        } else {
+            //reservoir sampling: every vector seen so far is in the sample with the same probability
+            let replace = self.rng.gen_range(0..self.num_samples_seen) as usize;
+            if replace < ENTRY_POINT_MAX_SAMPLES {
+                let len = self.num_dimensions;
+                self.samples[replace * len..(replace + 1) * len].copy_from_slice(sample);
+                self.nodes[replace] = node;
+            }
+        }
+    }
+
+    /// The k-means centroids of the sample. The nodes of the sample are the first candidates for the init ids.
+    pub fn into_entry_points(
+        mut self,
+        distance_type: DistanceType,
+        distance_fn: DistanceFn,
+    ) -> EntryPoints {
+        let num_samples = self.nodes.len();
+        let k = NUM_INIT_IDS.min(num_samples);
+        let centroids: Vec<Vec<f32>> = if k == 0 || self.num_dimensions == 0 {
+            vec![]
+        } else {
+            let instances =
+                ArrayView2::from_shape((num_samples, self.num_dimensions), &self.samples).unwrap();
+            let (centroids, distance): (_, DistanceFn) = match distance_type {
+                DistanceType::Hamming | DistanceType::Jaccard => {
+                    (bit_kmeans(instances, k, &mut self.rng), distance_hamming)
+                }
+                _ => {
+                    //distance_l2 is the squared distance
+                    let mut centroids = initial_centroids(instances, k, &mut self.rng, distance_l2);
+                    instances.kmeans_with_centroids(
+                        Axis(0),
+                        centroids.view_mut(),
+                        NIterationsCondition(ENTRY_POINT_KMEANS_ITERATIONS),
+                    );
+                    (centroids, distance_l2)
+                }
+            };
+
+            //the seeding favors outliers, which end up with a centroid of their own
+            let mut cluster_sizes = vec![0; k];
+            for instance in instances.outer_iter() {
+                cluster_sizes[closest_centroid(centroids.view(), &instance, distance)] += 1;
+            }
+            centroids
+                .outer_iter()
+                .zip(cluster_sizes)
+                .filter(|&(_, size)| size * k * ENTRY_POINT_MIN_CLUSTER_SHARE >= num_samples)
+                .map(|(centroid, _)| centroid.to_vec())
+                .collect()
+        };
+
+        let mut entry_points = EntryPoints {
+            closest: vec![None; centroids.len()],
+            centroids,
+            distance_fn,
+            changed: false,
+        };
+        for (sample, node) in self
+            .samples
+            .chunks(self.num_dimensions.max(1))
+            .zip(self.nodes)
+        {
+            if let Some(node) = node {
+                entry_points.add_node(node, sample);
+            }
+        }
+        entry_points
+    }
+}
+
+/// k-means++ seeding: the first centroid is a random instance, every further one an instance picked with a
+/// probability proportional to its squared distance to the closest centroid so far.
+fn initial_centroids(
+    instances: ArrayView2<f32>,
+    k: usize,
+    rng: &mut ChaCha8Rng,
+    squared_distance: DistanceFn,
+) -> Array2<f32> {
+    let num_instances = instances.len_of(Axis(0));
+    let mut centroids = Array2::zeros((k, instances.len_of(Axis(1))));
+    let mut closest_distances = vec![f32::MAX; num_instances];
+    let mut next = rng.gen_range(0..num_instances);
+    for c in 0..k {
+        centroids.row_mut(c).assign(&instances.row(next));
+        let centroid = centroids.row(c);
+        let centroid = centroid.as_slice().unwrap();
+        for (closest, instance) in closest_distances.iter_mut().zip(instances.outer_iter()) {
+            *closest = closest.min(squared_distance(centroid, instance.as_slice().unwrap()));
+        }
+
+        let total: f32 = closest_distances.iter().sum();
+        if total <= 0.0 {
+            //fewer distinct instances than centroids, the remaining centroids repeat this one
+            continue;
+        }
+        let mut target = rng.gen_range(0.0..total);
+        next = closest_distances
+            .iter()
+            .position(|&distance| {
+                target -= distance;
+                target < 0.0
+            })
+            .unwrap_or(num_instances - 1);
+    }
+    centroids
+}
+
+/// k-means of bit vectors packed into f32s (see PgBitInternal::to_packed): the instances are assigned to the
+/// centroid at the smallest Hamming distance, and every bit of a centroid is the majority of that bit in its
+/// cluster, which makes it the closest bit vector to the cluster.
+fn bit_kmeans(instances: ArrayView2<f32>, k: usize, rng: &mut ChaCha8Rng) -> Array2<f32> {
+    let mut centroids = initial_centroids(instances, k, rng, |a, b| distance_hamming(a, b).powi(2));
+    let num_bits = instances.len_of(Axis(1)) * 32;
+    for _ in 0..ENTRY_POINT_KMEANS_ITERATIONS {
+        let mut cluster_sizes = vec![0; k];
+        let mut bit_counts = vec![vec![0; num_bits]; k];
+        for instance in instances.outer_iter() {
+            let closest = closest_centroid(centroids.view(), &instance, distance_hamming);
+            cluster_sizes[closest] += 1;
+            for (i, word) in instance.iter().enumerate() {
+                let word = word.to_bits();
+                for bit in 0..32 {
+                    bit_counts[closest][i * 32 + bit] += (word >> bit) & 1;
+                }
+            }
+        }
+
+        for (c, (size, counts)) in cluster_sizes.iter().zip(bit_counts).enumerate() {
+            if *size == 0 {
+                continue;
+            }
+            for (i, word_counts) in counts.chunks(32).enumerate() {
+                let word = word_counts
+                    .iter()
+                    .enumerate()
+                    .filter(|&(_, &count)| 2 * count >= *size)
+                    .fold(0, |word, (bit, _)| word | 1 << bit);
+                centroids[[c, i]] = f32::from_bits(word);
+            }
+        }
+    }
+    centroids
+}
+
+fn closest_centroid(
+    centroids: ArrayView2<f32>,
+    instance: &ArrayView1<f32>,
+    distance: DistanceFn,
+) -> usize {
+    let instance = instance.as_slice().unwrap();
+    let (closest, _) = centroids
+        .outer_iter()
+        .map(|centroid| distance(centroid.as_slice().unwrap(), instance))
+        .enumerate()
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        .unwrap();
+    closest
+}
+
+/// The nodes closest to the centroids of the data, which become the init ids of the graph. Searches starting from
+/// them need fewer hops than searches starting from the first node inserted, which may well be an outlier.
+pub struct EntryPoints {
+    centroids: Vec<Vec<f32>>,
+    /// The closest node to each centroid so far, with its distance.
+    closest: Vec<Option<(f32, IndexPointer)>>,
+    distance_fn: DistanceFn,
+    changed: bool,
+}
+
+impl EntryPoints {
+    pub fn add_node(&mut self, node: IndexPointer, vector: &[f32]) {
+        for (centroid, closest) in self.centroids.iter().zip(self.closest.iter_mut()) {
+            let distance = (self.distance_fn)(centroid, vector);
+            let is_closer = match closest {
+                Some((closest_distance, _)) => distance < *closest_distance,
+                None => true,
+            };
+            if is_closer {
+                *closest = Some((distance, node));
+                self.changed = true;
+            }
+        }
+    }
+
+    /// The init ids, if they changed since the last call.
+    pub fn take_init_ids(&mut self) -> Option<Vec<IndexPointer>> {
+        if !self.changed {
+            return None;
+        }
+        self.changed = false;
+
+        let mut init_ids: Vec<IndexPointer> = Vec::with_capacity(self.closest.len());
+        for &(_, node) in self.closest.iter().flatten() {
+            //a node can be the closest to several centroids
+            if !init_ids.contains(&node) {
+                init_ids.push(node);
+            }
+        }
+        Some(init_ids)
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    use crate::access_method::distance::distance_l2;
+    use crate::access_method::meta_page::{DistanceType, MetaPage};
+    use crate::access_method::plain_node::Node;
+    use crate::access_method::stats::WriteStats;
+    use crate::access_method::storage::ArchivedData;
+    use crate::util::ItemPointer;
+
+    use super::{EntryPointSample, NUM_INIT_IDS};
+
+    #[pg_test]
+    unsafe fn test_entry_points_near_centroids() {
+        //four clusters around the corners of a square, each with a node right at its center
+        let corners = [[0.0, 0.0], [0.0, 10.0], [10.0, 0.0], [10.0, 10.0]];
+        let mut sample = EntryPointSample::new(2);
+        for (c, corner) in corners.iter().enumerate() {
+            for (i, offset) in [[0.0, 0.0], [1.0, 0.0], [-1.0, 0.0], [0.0, 1.0], [0.0, -1.0]]
+                .iter()
+                .enumerate()
+            {
+                let node = ItemPointer::new(c as u32 + 1, i as u16 + 1);
+                let vector = [corner[0] + offset[0], corner[1] + offset[1]];
+                sample.add_sample(&vector, Some(node));
+            }
+        }
+
+        let mut entry_points = sample.into_entry_points(DistanceType::L2, distance_l2);
+        let mut init_ids = entry_points.take_init_ids().unwrap();
+        init_ids.sort_by_key(|node| node.block_number);
+        let expected: Vec<_> = (1..=corners.len() as u32)
+            .map(|block| ItemPointer::new(block, 1))
+            .collect();
+        assert_eq!(init_ids, expected);
+        assert!(entry_points.take_init_ids().is_none());
+
+        //a node that is farther from every centroid doesn't change the init ids
+        entry_points.add_node(ItemPointer::new(100, 1), &[100.0, 100.0]);
+        assert!(entry_points.take_init_ids().is_none());
+    }
+
+    #[pg_test]
+    unsafe fn test_entry_points_outlier_first() -> spi::Result<()> {
+        //the first row is far from all the others
+        Spi::run(
+            "CREATE TABLE test_data (
+                id int,
+                embedding vector (128)
+            );
+
+            INSERT INTO test_data (id, embedding)
+            SELECT 0, array_fill(100, ARRAY[128])::vector;
+
+            select setseed(0.5);
+            INSERT INTO test_data (id, embedding)
+            SELECT
+                g.i + 1,
+                g.embedding
+            FROM (
+                SELECT
+                    i % 1000 AS i,
+                    ('[' || array_to_string(array_agg(random()), ',', '0') || ']')::vector AS embedding
+                FROM
+                    generate_series(1, 128 * 1000) i
+                GROUP BY
+                    i % 1000) g;
+
+            CREATE INDEX idx_diskann_entry_points ON test_data USING diskann (embedding vector_l2_ops)
+                WITH (storage_layout = plain);",
+        )?;
+
+        let index = PgRelation::open_with_name("idx_diskann_entry_points").unwrap();
+        let init_ids = MetaPage::fetch(&index).get_init_ids().unwrap();
+        assert!(!init_ids.is_empty() && init_ids.len() <= NUM_INIT_IDS);
+        //the outlier was the first node inserted
+        let outlier: Option<Vec<pg_sys::ItemPointerData>> =
+            Spi::get_one("SELECT array_agg(ctid) FROM test_data WHERE id = 0")?;
+        let outlier = ItemPointer::with_item_pointer_data(outlier.unwrap()[0]);
+        let mut stats = WriteStats::new();
+        for init_id in init_ids {
+            let node = Node::read(&index, init_id, &mut stats);
+            let heap_pointer = node.get_archived_node().get_heap_item_pointer();
+            assert!(heap_pointer != outlier);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,147 @@
+use pgrx::PgRelation;
+use pgvectorscale_derive::{Readable, Writeable};
+use rkyv::{Archive, Deserialize, Serialize};
+
+use crate::util::{tape::Tape, IndexPointer, ItemPointer, ReadableBuffer, WritableBuffer};
+
+use super::{
+    meta_page::MetaPage,
+    pg_vector::PgVector,
+    stats::{StatsNodeRead, StatsNodeWrite},
+    storage_common::{get_scalar_columns_size, FULL_VECTOR_POINTER_SIZE},
+};
+
+/* With the full_vectors_in_index option, the full vector of every node is stored on pages of its own, the way DiskANN
+keeps the full vectors next to the graph. Rescoring then reads index pages, which tend to be cached, instead of random
+heap pages. The pointer to the full vector is stored in front of the node, after the scalar columns. */
+
+#[derive(Archive, Deserialize, Serialize, Readable, Writeable)]
+#[archive(check_bytes)]
+pub struct FullVector {
+    vector: Vec<f32>,
+}
+
+/// Writes the full vector of a new node. Returns the bytes to store in front of the node: the scalar columns,
+/// followed by the pointer to the full vector.
+pub unsafe fn write_full_vector<S: StatsNodeWrite>(
+    vector: &PgVector,
+    scalar_columns: &[u8],
+    tape: &mut Tape,
+    stats: &mut S,
+) -> Vec<u8> {
+    let full_vector = FullVector {
+        vector: vector.to_full_slice().to_vec(),
+    };
+    let pointer = full_vector.write(tape, stats);
+
+    let mut prefix = scalar_columns.to_vec();
+    prefix.extend_from_slice(&pointer.block_number.to_le_bytes());
+    prefix.extend_from_slice(&pointer.offset.to_le_bytes());
+    prefix.resize(scalar_columns.len() + FULL_VECTOR_POINTER_SIZE, 0);
+    prefix
+}
+
+/// Returns the pointer to the full vector of the node at `index_pointer`.
+pub unsafe fn read_full_vector_pointer(
+    index: &PgRelation,
+    index_pointer: IndexPointer,
+) -> ItemPointer {
+    let rb = index_pointer.read_bytes(index);
+    let data = rb.get_data_slice();
+    let start = get_scalar_columns_size(index, data);
+    let bytes = &data[start..start + FULL_VECTOR_POINTER_SIZE];
+    ItemPointer::new(
+        u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+        u16::from_le_bytes(bytes[4..6].try_into().unwrap()),
+    )
+}
+
+/// The distance between the query and the full vector of the node at `index_pointer`, used to resort.
+pub unsafe fn get_full_vector_distance<S: StatsNodeRead>(
+    index: &PgRelation,
+    index_pointer: IndexPointer,
+    query: &PgVector,
+    meta_page: &MetaPage,
+    stats: &mut S,
+) -> f32 {
+    let pointer = read_full_vector_pointer(index, index_pointer);
+    let rn = FullVector::read(index, pointer, stats);
+    meta_page.get_distance_function()(
+        query.to_full_slice(),
+        rn.get_archived_node().vector.as_slice(),
+    )
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    #[pg_test]
+    unsafe fn test_full_vectors_in_index_sbq_index_creation() -> spi::Result<()> {
+        crate::access_method::build::tests::test_index_creation_and_accuracy_scaffold(
+            "num_neighbors=38, storage_layout = io_optimized, full_vectors_in_index = true",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_full_vectors_in_index_plain_index_creation() -> spi::Result<()> {
+        crate::access_method::build::tests::test_index_creation_and_accuracy_scaffold(
+            "num_neighbors=38, storage_layout = plain, full_vectors_in_index = true",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_full_vectors_in_index_pq_index_creation() -> spi::Result<()> {
+        crate::access_method::build::tests::test_index_creation_and_accuracy_scaffold(
+            "num_neighbors=38, storage_layout = pq, full_vectors_in_index = true",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_full_vectors_in_index_rabitq_index_creation() -> spi::Result<()> {
+        crate::access_method::build::tests::test_index_creation_and_accuracy_scaffold(
+            "num_neighbors=38, storage_layout = rabitq, full_vectors_in_index = true",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_full_vectors_in_index_filtered_search() -> spi::Result<()> {
+        crate::access_method::build::tests::test_filtered_search_scaffold(
+            "storage_layout = io_optimized, full_vectors_in_index = true",
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_full_vectors_in_index_halfvec() -> spi::Result<()> {
+        crate::access_method::build::tests::test_halfvec_scaffold(
+            "storage_layout = sq8, full_vectors_in_index = true",
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_full_vectors_in_index_insert_empty_insert() -> spi::Result<()> {
+        crate::access_method::build::tests::test_insert_empty_insert_scaffold(
+            "storage_layout = io_optimized, full_vectors_in_index = true",
+        )
+    }
+
+    #[test]
+    fn test_full_vectors_in_index_delete_vacuum_full() {
+        crate::access_method::vacuum::tests::test_delete_vacuum_full_scaffold(
+            "num_neighbors = 38, storage_layout = io_optimized, full_vectors_in_index = true",
+        );
+    }
+
+    #[test]
+    fn test_full_vectors_in_index_delete_vacuum_reuse() {
+        crate::access_method::vacuum::tests::test_delete_vacuum_reuse_scaffold(
+            "num_neighbors = 10, storage_layout = io_optimized, full_vectors_in_index = true",
+            64,
+        );
+    }
+}
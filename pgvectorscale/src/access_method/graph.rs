@@ -1,6 +1,9 @@
 use std::cmp::Reverse;
 use std::collections::BinaryHeap;
-use std::{cmp::Ordering, collections::HashSet};
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+};
 
 use pgrx::PgRelation;
 
@@ -9,9 +12,12 @@ use crate::access_method::storage::NodeDistanceMeasure;
 use crate::util::{HeapPointer, IndexPointer, ItemPointer};
 
 use super::graph_neighbor_store::GraphNeighborStore;
+use super::labels::LabelSet;
 
 use super::pg_vector::PgVector;
-use super::stats::{GreedySearchStats, InsertStats, PruneNeighborStats, StatsNodeVisit};
+use super::stats::{
+    GreedySearchStats, InsertStats, PruneNeighborStats, StatsNodeModify, StatsNodeVisit,
+};
 use super::storage::Storage;
 use super::{meta_page::MetaPage, neighbor_with_distance::NeighborWithDistance};
 
@@ -44,7 +50,6 @@ impl<PD> Ord for ListSearchNeighbor<PD> {
 impl<PD> ListSearchNeighbor<PD> {
     pub fn new(index_pointer: IndexPointer, distance: f32, private_data: PD) -> Self {
         assert!(!distance.is_nan());
-        debug_assert!(distance >= 0.0);
         Self {
             index_pointer,
             private_data,
@@ -55,6 +60,10 @@ impl<PD> ListSearchNeighbor<PD> {
     pub fn get_private_data(&self) -> &PD {
         &self.private_data
     }
+
+    pub fn get_distance(&self) -> f32 {
+        self.distance
+    }
 }
 
 pub struct ListSearchResult<QDM, PD> {
@@ -102,6 +111,21 @@ impl<QDM, PD> ListSearchResult<QDM, PD> {
         res
     }
 
+    /// Creates a ListSearchResult that isn't tied to a Storage. The caller computes the distances
+    /// and inserts the neighbors of visited nodes itself (see parallel_build).
+    pub fn new_without_storage(search_list_size: usize, meta_page: &MetaPage) -> Self {
+        let neigbors = meta_page.get_num_neighbors() as usize;
+        let mut res = Self {
+            candidates: BinaryHeap::with_capacity(search_list_size * neigbors),
+            visited: Vec::with_capacity(search_list_size * 2),
+            inserted: HashSet::with_capacity(search_list_size * neigbors),
+            stats: GreedySearchStats::new(),
+            sdm: None,
+        };
+        res.stats.record_call();
+        res
+    }
+
     pub fn prepare_insert(&mut self, ip: ItemPointer) -> bool {
         return self.inserted.insert(ip);
     }
@@ -116,7 +140,7 @@ impl<QDM, PD> ListSearchResult<QDM, PD> {
         &self.visited[idx]
     }
 
-    fn visit_closest(&mut self, pos_limit: usize) -> Option<usize> {
+    pub fn visit_closest(&mut self, pos_limit: usize) -> Option<usize> {
         if self.candidates.len() == 0 {
             return None;
         }
@@ -139,16 +163,17 @@ impl<QDM, PD> ListSearchResult<QDM, PD> {
 
     //removes and returns the first element. Given that the element remains in self.inserted, that means the element will never again be insereted
     //into the best_candidate list, so it will never again be returned.
+    /// Returns the next closest node with the distance it was found with.
     pub fn consume<S: Storage<QueryDistanceMeasure = QDM, LSNPrivateData = PD>>(
         &mut self,
         storage: &S,
-    ) -> Option<(HeapPointer, IndexPointer)> {
+    ) -> Option<(HeapPointer, ListSearchNeighbor<PD>)> {
         if self.visited.len() == 0 {
             return None;
         }
         let lsn = self.visited.remove(0);
         let heap_pointer = storage.return_lsn(&lsn, &mut self.stats);
-        return Some((heap_pointer, lsn.index_pointer));
+        Some((heap_pointer, lsn))
     }
 }
 
@@ -169,10 +194,25 @@ impl<'a> Graph<'a> {
         &self.neighbor_store
     }
 
+    pub fn get_neighbor_store_mut(&mut self) -> &mut GraphNeighborStore {
+        &mut self.neighbor_store
+    }
+
     fn get_init_ids(&self) -> Option<Vec<ItemPointer>> {
         self.meta_page.get_init_ids()
     }
 
+    /// Make `init_ids` the nodes searches start from.
+    pub fn update_init_ids<S: StatsNodeModify>(
+        &mut self,
+        index: &PgRelation,
+        init_ids: Vec<ItemPointer>,
+        stats: &mut S,
+    ) {
+        MetaPage::update_init_ids(index, init_ids, stats);
+        *self.meta_page = MetaPage::fetch(index);
+    }
+
     fn add_neighbors<S: Storage>(
         &mut self,
         storage: &S,
@@ -211,9 +251,14 @@ impl<'a> Graph<'a> {
             candidates.remove(index);
         }
 
+        //VACUUM relies on inserts never adding edges to deleted nodes (see vacuum.rs)
+        if let GraphNeighborStore::Disk = self.neighbor_store {
+            candidates.retain(|c| !storage.is_deleted(c.get_index_pointer_to_neighbor(), stats));
+        }
+
         let (pruned, new_neighbors) =
             if candidates.len() > self.neighbor_store.max_neighbors(self.get_meta_page()) {
-                let new_list = self.prune_neighbors(candidates, storage, stats);
+                let new_list = self.prune_neighbors(neighbors_of, candidates, storage, stats);
                 (true, new_list)
             } else {
                 (false, candidates)
@@ -246,23 +291,22 @@ impl<'a> Graph<'a> {
     ///
     /// Note this is the one-shot implementation that keeps only the closest `search_list_size` results in
     /// the returned ListSearchResult elements. It shouldn't be used with self.greedy_search_iterate
+    ///
+    /// With a `filter`, only the nodes passing it are returned (see greedy_search_iterate).
     fn greedy_search_for_build<S: Storage>(
         &self,
+        init_ids: Vec<ItemPointer>,
         query: PgVector,
         meta_page: &MetaPage,
+        filter: Option<&dyn Fn(IndexPointer) -> bool>,
         storage: &S,
         stats: &mut GreedySearchStats,
     ) -> HashSet<NeighborWithDistance> {
-        let init_ids = self.get_init_ids();
-        if let None = init_ids {
-            //no nodes in the graph
-            return HashSet::with_capacity(0);
-        }
         let dm = storage.get_query_distance_measure(query);
         let search_list_size = meta_page.get_search_list_size_for_build() as usize;
 
         let mut l = ListSearchResult::new(
-            init_ids.unwrap(),
+            init_ids,
             dm,
             search_list_size,
             meta_page,
@@ -270,17 +314,26 @@ impl<'a> Graph<'a> {
             storage,
         );
         let mut visited_nodes = HashSet::with_capacity(search_list_size);
-        self.greedy_search_iterate(&mut l, search_list_size, Some(&mut visited_nodes), storage);
+        self.greedy_search_iterate(
+            &mut l,
+            search_list_size,
+            Some(&mut visited_nodes),
+            filter,
+            storage,
+        );
         stats.combine(&l.stats);
         return visited_nodes;
     }
 
     /// Returns a ListSearchResult initialized for streaming. The output should be used with greedy_search_iterate to obtain
     /// the next elements.
+    ///
+    /// A search for rows with one of the given `labels` also starts from the labels' entry points.
     pub fn greedy_search_streaming_init<S: Storage>(
         &self,
         query: PgVector,
         search_list_size: usize,
+        labels: Option<&LabelSet>,
         storage: &S,
     ) -> ListSearchResult<S::QueryDistanceMeasure, S::LSNPrivateData> {
         let init_ids = self.get_init_ids();
@@ -288,10 +341,18 @@ impl<'a> Graph<'a> {
             //no nodes in the graph
             return ListSearchResult::empty();
         }
+        let mut init_ids = init_ids.unwrap();
+        if let Some(labels) = labels {
+            for entry_point in self.meta_page.get_label_entry_points(labels) {
+                if !init_ids.contains(&entry_point) {
+                    init_ids.push(entry_point);
+                }
+            }
+        }
         let dm = storage.get_query_distance_measure(query);
 
         ListSearchResult::new(
-            init_ids.unwrap(),
+            init_ids,
             dm,
             search_list_size,
             &self.meta_page,
@@ -301,26 +362,39 @@ impl<'a> Graph<'a> {
     }
 
     /// Advance the state of the lsr until the closest `visit_n_closest` elements have been visited.
+    ///
+    /// If a `filter` is given, nodes that don't pass it are still traversed (their neighbors are added to the
+    /// candidates) but they are dropped from the results, so they don't take up room in the `visit_n_closest` window.
     pub fn greedy_search_iterate<S: Storage>(
         &self,
         lsr: &mut ListSearchResult<S::QueryDistanceMeasure, S::LSNPrivateData>,
         visit_n_closest: usize,
         mut visited_nodes: Option<&mut HashSet<NeighborWithDistance>>,
+        filter: Option<&dyn Fn(IndexPointer) -> bool>,
         storage: &S,
     ) {
         while let Some(list_search_entry_idx) = lsr.visit_closest(visit_n_closest) {
+            let passes_filter = match filter {
+                None => true,
+                Some(filter) => filter(lsr.visited[list_search_entry_idx].index_pointer),
+            };
             match visited_nodes {
-                None => {}
-                Some(ref mut visited_nodes) => {
+                Some(ref mut visited_nodes) if passes_filter => {
                     let list_search_entry = &lsr.visited[list_search_entry_idx];
                     visited_nodes.insert(NeighborWithDistance::new(
                         list_search_entry.index_pointer,
                         list_search_entry.distance,
                     ));
                 }
+                _ => {}
             }
             lsr.stats.record_visit();
             storage.visit_lsn(lsr, list_search_entry_idx, &self.neighbor_store);
+
+            if !passes_filter {
+                //stays in lsr.inserted, so it won't be visited again
+                lsr.visited.remove(list_search_entry_idx);
+            }
         }
     }
 
@@ -329,36 +403,90 @@ impl<'a> Graph<'a> {
     ///
     /// TODO: this is the ann-disk implementation. There may be better implementations
     /// if we save the factors or the distances and add incrementally. Not sure.
+    ///
+    /// If `neighbors_of` has labels, the prune follows Filtered-DiskANN: a neighbor only prunes candidates whose
+    /// labels shared with `neighbors_of` it also has (see LabelSet::can_occlude).
     pub fn prune_neighbors<S: Storage>(
         &self,
-        mut candidates: Vec<NeighborWithDistance>,
+        neighbors_of: ItemPointer,
+        candidates: Vec<NeighborWithDistance>,
         storage: &S,
         stats: &mut PruneNeighborStats,
     ) -> Vec<NeighborWithDistance> {
+        let node_distance_measure = |index_pointer, stats: &mut PruneNeighborStats| unsafe {
+            storage.get_node_distance_measure(index_pointer, stats)
+        };
+
+        let labels = storage.get_labels(neighbors_of);
+        if labels.is_empty() {
+            return Self::prune_neighbors_with_measure(
+                self.get_meta_page(),
+                candidates,
+                stats,
+                node_distance_measure,
+                None,
+            );
+        }
+
+        let candidate_labels: HashMap<IndexPointer, LabelSet> = candidates
+            .iter()
+            .map(|c| {
+                let index_pointer = c.get_index_pointer_to_neighbor();
+                (index_pointer, storage.get_labels(index_pointer))
+            })
+            .collect();
+        let can_occlude = |existing_neighbor: IndexPointer, candidate: IndexPointer| {
+            LabelSet::can_occlude(
+                &labels,
+                &candidate_labels[&existing_neighbor],
+                &candidate_labels[&candidate],
+            )
+        };
+        Self::prune_neighbors_with_measure(
+            self.get_meta_page(),
+            candidates,
+            stats,
+            node_distance_measure,
+            Some(&can_occlude),
+        )
+    }
+
+    /// The pruning logic of `prune_neighbors`, with node-to-node distances coming from `node_distance_measure`
+    /// instead of a Storage. This allows pruning from threads that can't touch Postgres (see parallel_build).
+    /// `can_occlude(existing_neighbor, candidate)` restricts which candidates an existing neighbor may prune.
+    pub fn prune_neighbors_with_measure<D, F>(
+        meta_page: &MetaPage,
+        mut candidates: Vec<NeighborWithDistance>,
+        stats: &mut PruneNeighborStats,
+        node_distance_measure: F,
+        can_occlude: Option<&dyn Fn(IndexPointer, IndexPointer) -> bool>,
+    ) -> Vec<NeighborWithDistance>
+    where
+        D: NodeDistanceMeasure,
+        F: Fn(IndexPointer, &mut PruneNeighborStats) -> D,
+    {
         stats.calls += 1;
         //TODO make configurable?
-        let max_alpha = self.get_meta_page().get_max_alpha();
+        let max_alpha = meta_page.get_max_alpha();
 
         stats.num_neighbors_before_prune += candidates.len();
-        //TODO remove deleted nodes
 
         //TODO diskann has something called max_occlusion_size/max_candidate_size(default:750). Do we need to implement?
 
         //sort by distance
         candidates.sort();
-        let mut results = Vec::<NeighborWithDistance>::with_capacity(
-            self.get_meta_page().get_num_neighbors() as _,
-        );
+        let mut results =
+            Vec::<NeighborWithDistance>::with_capacity(meta_page.get_num_neighbors() as _);
 
         let mut max_factors: Vec<f64> = vec![0.0; candidates.len()];
 
         let mut alpha = 1.0;
-        let dimension_epsilon = self.get_meta_page().get_num_dimensions() as f32 * f32::EPSILON;
+        let dimension_epsilon = meta_page.get_num_dimensions() as f32 * f32::EPSILON;
         //first we add nodes that "pass" a small alpha. Then, if there
         //is still room we loop again with a larger alpha.
-        while alpha <= max_alpha && results.len() < self.get_meta_page().get_num_neighbors() as _ {
+        while alpha <= max_alpha && results.len() < meta_page.get_num_neighbors() as _ {
             for (i, neighbor) in candidates.iter().enumerate() {
-                if results.len() >= self.get_meta_page().get_num_neighbors() as _ {
+                if results.len() >= meta_page.get_num_neighbors() as _ {
                     return results;
                 }
                 if max_factors[i] > alpha {
@@ -373,12 +501,8 @@ impl<'a> Graph<'a> {
                 //rename for clarity.
                 let existing_neighbor = neighbor;
 
-                let dist_state = unsafe {
-                    storage.get_node_distance_measure(
-                        existing_neighbor.get_index_pointer_to_neighbor(),
-                        stats,
-                    )
-                };
+                let dist_state =
+                    node_distance_measure(existing_neighbor.get_index_pointer_to_neighbor(), stats);
 
                 //go thru the other candidates (tail of the list)
                 for (j, candidate_neighbor) in candidates.iter().enumerate().skip(i + 1) {
@@ -387,6 +511,15 @@ impl<'a> Graph<'a> {
                         continue;
                     }
 
+                    if let Some(can_occlude) = can_occlude {
+                        if !can_occlude(
+                            existing_neighbor.get_index_pointer_to_neighbor(),
+                            candidate_neighbor.get_index_pointer_to_neighbor(),
+                        ) {
+                            continue;
+                        }
+                    }
+
                     let mut distance_between_candidate_and_existing_neighbor = unsafe {
                         dist_state
                             .get_distance(candidate_neighbor.get_index_pointer_to_neighbor(), stats)
@@ -394,9 +527,7 @@ impl<'a> Graph<'a> {
                     let mut distance_between_candidate_and_point =
                         candidate_neighbor.get_distance();
 
-                    //We need both values to be positive.
-                    //Otherwise, the case where distance_between_candidate_and_point > 0 and distance_between_candidate_and_existing_neighbor < 0 is totally wrong.
-                    //If we implement inner product distance we'll have to figure something else out.
+                    //treat tiny negative values caused by rounding errors as zero
                     if distance_between_candidate_and_point < 0.0
                         && distance_between_candidate_and_point >= 0.0 - dimension_epsilon
                     {
@@ -410,26 +541,10 @@ impl<'a> Graph<'a> {
                         distance_between_candidate_and_existing_neighbor = 0.0;
                     }
 
-                    debug_assert!(
-                        distance_between_candidate_and_point >= 0.0,
-                        "distance_between_candidate_and_point is negative: {}, {}",
+                    let factor = Self::occlusion_factor(
                         distance_between_candidate_and_point,
-                        f32::EPSILON
+                        distance_between_candidate_and_existing_neighbor,
                     );
-                    debug_assert!(distance_between_candidate_and_existing_neighbor >= 0.0);
-
-                    //factor is high if the candidate is closer to an existing neighbor than the point it's being considered for
-                    let factor =
-                        if distance_between_candidate_and_existing_neighbor < 0.0 + f32::EPSILON {
-                            if distance_between_candidate_and_point < 0.0 + f32::EPSILON {
-                                1.0
-                            } else {
-                                f64::MAX
-                            }
-                        } else {
-                            distance_between_candidate_and_point as f64
-                                / distance_between_candidate_and_existing_neighbor as f64
-                        };
                     max_factors[j] = max_factors[j].max(factor)
                 }
             }
@@ -439,6 +554,47 @@ impl<'a> Graph<'a> {
         results
     }
 
+    /// Returns how strongly an existing neighbor occludes a candidate neighbor.
+    /// The candidate is pruned once the factor is larger than alpha.
+    ///
+    /// For non-negative distances, the factor is high if the candidate is closer to an existing
+    /// neighbor than the point it's being considered for.
+    ///
+    /// Negative distances only happen with the inner product distance (the negated dot product).
+    /// Ratios of distances with mixed signs are meaningless, so we follow DiskANN's approach for
+    /// MIPS and compare the similarities (dot products) instead: the candidate is occluded if it's
+    /// more similar to the existing neighbor than to the point.
+    fn occlusion_factor(
+        distance_between_candidate_and_point: f32,
+        distance_between_candidate_and_existing_neighbor: f32,
+    ) -> f64 {
+        if distance_between_candidate_and_point >= 0.0
+            && distance_between_candidate_and_existing_neighbor >= 0.0
+        {
+            if distance_between_candidate_and_existing_neighbor < 0.0 + f32::EPSILON {
+                if distance_between_candidate_and_point < 0.0 + f32::EPSILON {
+                    1.0
+                } else {
+                    f64::MAX
+                }
+            } else {
+                distance_between_candidate_and_point as f64
+                    / distance_between_candidate_and_existing_neighbor as f64
+            }
+        } else {
+            let similarity_to_point = -distance_between_candidate_and_point as f64;
+            let similarity_to_existing_neighbor =
+                -distance_between_candidate_and_existing_neighbor as f64;
+            if similarity_to_point > 0.0 {
+                similarity_to_existing_neighbor / similarity_to_point
+            } else if similarity_to_existing_neighbor > similarity_to_point {
+                f64::MAX
+            } else {
+                0.0
+            }
+        }
+    }
+
     pub fn insert<S: Storage>(
         &mut self,
         index: &PgRelation,
@@ -448,9 +604,8 @@ impl<'a> Graph<'a> {
         stats: &mut InsertStats,
     ) {
         if self.meta_page.get_init_ids().is_none() {
-            //TODO probably better set off of centeroids
-            MetaPage::update_init_ids(index, vec![index_pointer], stats);
-            *self.meta_page = MetaPage::fetch(index);
+            //the first node starts the graph, a build later moves the init ids near the centroids (see EntryPoints)
+            self.update_init_ids(index, vec![index_pointer], stats);
 
             self.neighbor_store.set_neighbors(
                 storage,
@@ -463,11 +618,65 @@ impl<'a> Graph<'a> {
             );
         }
 
+        let labels = storage.get_labels(index_pointer);
+        let labels_without_entry_point = self.meta_page.get_labels_without_entry_point(&labels);
+        if !labels_without_entry_point.is_empty() {
+            //the first node with a label becomes the label's entry point
+            MetaPage::update_label_entry_points(
+                index,
+                &labels_without_entry_point,
+                index_pointer,
+                stats,
+            );
+            *self.meta_page = MetaPage::fetch(index);
+        }
+
         let meta_page = self.get_meta_page();
+        let init_ids = self.get_init_ids().unwrap();
+
+        let v = if labels.is_empty() {
+            //TODO: make configurable?
+            self.greedy_search_for_build(
+                init_ids,
+                vec,
+                meta_page,
+                None,
+                storage,
+                &mut stats.greedy_search_stats,
+            )
+        } else {
+            /* Filtered-DiskANN: besides the nearest nodes overall, look for the nearest nodes sharing a label,
+            starting from the labels' entry points. They may be too rare among the nearest nodes overall for
+            the node to get connected to them otherwise. */
+            let label_query = vec.copy();
+            let mut v = self.greedy_search_for_build(
+                init_ids,
+                vec,
+                meta_page,
+                None,
+                storage,
+                &mut stats.greedy_search_stats,
+            );
 
-        //TODO: make configurable?
-        let v =
-            self.greedy_search_for_build(vec, meta_page, storage, &mut stats.greedy_search_stats);
+            let label_init_ids: Vec<ItemPointer> = meta_page
+                .get_label_entry_points(&labels)
+                .into_iter()
+                .filter(|&entry_point| entry_point != index_pointer)
+                .collect();
+            if !label_init_ids.is_empty() {
+                let shares_label =
+                    |other: IndexPointer| storage.get_labels(other).overlaps(&labels);
+                v.extend(self.greedy_search_for_build(
+                    label_init_ids,
+                    label_query,
+                    meta_page,
+                    Some(&shares_label),
+                    storage,
+                    &mut stats.greedy_search_stats,
+                ));
+            }
+            v
+        };
 
         let (_, neighbor_list) = self.add_neighbors(
             storage,
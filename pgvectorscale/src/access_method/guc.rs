@@ -2,6 +2,7 @@ use pgrx::*;
 
 pub static TSV_QUERY_SEARCH_LIST_SIZE: GucSetting<i32> = GucSetting::<i32>::new(100);
 pub static TSV_RESORT_SIZE: GucSetting<i32> = GucSetting::<i32>::new(50);
+pub static TSV_MAX_DISTANCE: GucSetting<f64> = GucSetting::<f64>::new(f64::INFINITY);
 
 pub fn init() {
     GucRegistry::define_int_guc(
@@ -18,11 +19,22 @@ pub fn init() {
     GucRegistry::define_int_guc(
         "diskann.query_rescore",
         "The number of elements rescored (0 to disable rescoring)",
-        "Rescoring takes the query_rescore number of elements that have the smallest approximate distance, rescores them with the exact distance, returning the closest ones with the exact distance.",
+        "Rescoring takes the query_rescore number of elements that have the smallest approximate distance, rescores them with the exact distance, returning the closest ones with the exact distance. The rabitq storage layout ignores it: its error bounds decide which elements to rescore.",
         &TSV_RESORT_SIZE,
         1,
         1000,
         GucContext::Userset,
         GucFlags::default(),
     );
+
+    GucRegistry::define_float_guc(
+        "diskann.max_distance",
+        "The largest distance of the rows returned by index scans (infinity to disable)",
+        "Index scans end at the first row farther from the query vector than max_distance, instead of continuing to search the graph until the query stops fetching rows. The distance is the one of the ORDER BY operator, rescored where the storage rescores.",
+        &TSV_MAX_DISTANCE,
+        f64::NEG_INFINITY,
+        f64::INFINITY,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
 }
@@ -0,0 +1,111 @@
+use pgrx::{pg_sys, FromDatum, PgRelation};
+
+use crate::util::IndexPointer;
+
+/* Labels for Filtered-DiskANN. An index can have an int4[] key column after the vector column, for example
+`CREATE INDEX ON t USING diskann (embedding, labels)`. The labels of a node are stored with its other scalar
+columns. The graph is built so that the nodes sharing a label stay connected to each other, and every label gets an
+entry point in the meta page, so searches restricted to a label start next to the matching nodes. */
+
+pub type Label = i32;
+
+/// Strategy numbers of the int4[] operator class (see the operator classes in mod.rs).
+pub const LABELS_OVERLAP_STRATEGY: pg_sys::StrategyNumber = 1;
+pub const LABELS_CONTAINS_STRATEGY: pg_sys::StrategyNumber = 2;
+
+/// A sorted set of labels without duplicates.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LabelSet(Vec<Label>);
+
+impl LabelSet {
+    pub fn new(mut labels: Vec<Label>) -> Self {
+        labels.sort_unstable();
+        labels.dedup();
+        Self(labels)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Label> {
+        self.0.iter()
+    }
+
+    pub fn contains(&self, label: Label) -> bool {
+        self.0.binary_search(&label).is_ok()
+    }
+
+    pub fn overlaps(&self, other: &LabelSet) -> bool {
+        self.iter().any(|l| other.contains(*l))
+    }
+
+    /// The occlusion rule of Filtered-DiskANN's prune: a neighbor of `point` may only prune a candidate if the
+    /// neighbor has every label the candidate shares with `point`. Otherwise the candidate is kept, because it
+    /// may be the only path from `point` to the other nodes of those labels.
+    pub fn can_occlude(
+        point: &LabelSet,
+        existing_neighbor: &LabelSet,
+        candidate: &LabelSet,
+    ) -> bool {
+        point
+            .iter()
+            .filter(|l| candidate.contains(**l))
+            .all(|l| existing_neighbor.contains(*l))
+    }
+}
+
+/// Returns the position of the labels column: the first int4[] key column after the vector column.
+pub fn get_label_attribute(index: &PgRelation) -> Option<usize> {
+    unsafe {
+        let nkeyatts = (*index.rd_index).indnkeyatts as usize;
+        let tuple_desc = index.tuple_desc();
+        (1..nkeyatts).find(|&att| tuple_desc.get(att).unwrap().atttypid == pg_sys::INT4ARRAYOID)
+    }
+}
+
+unsafe fn labels_from_datum(datum: pg_sys::Datum, isnull: bool) -> LabelSet {
+    match Vec::<Option<Label>>::from_datum(datum, isnull) {
+        Some(labels) => LabelSet::new(labels.into_iter().flatten().collect()),
+        None => LabelSet::default(),
+    }
+}
+
+/// Returns the labels stored with the node at `index_pointer`, empty if the index has no labels column.
+pub unsafe fn read_labels(index: &PgRelation, index_pointer: IndexPointer) -> LabelSet {
+    let att = match get_label_attribute(index) {
+        Some(att) => att,
+        None => return LabelSet::default(),
+    };
+
+    let rb = index_pointer.read_bytes(index);
+    let tuple = rb.get_data_slice().as_ptr() as pg_sys::IndexTuple;
+    let natts = (*index.rd_index).indnatts as usize;
+    let mut values = vec![pg_sys::Datum::from(0); natts];
+    let mut isnull = vec![true; natts];
+    pg_sys::index_deform_tuple(
+        tuple,
+        index.rd_att,
+        values.as_mut_ptr(),
+        isnull.as_mut_ptr(),
+    );
+    //the labels are copied out of the page before the buffer is released
+    labels_from_datum(values[att], isnull[att])
+}
+
+/// Returns the labels a search should start from: the ones in the scan keys on the labels column. A row
+/// matching `labels && '{1,2}'` or `labels @> '{1,2}'` has one of those labels.
+pub unsafe fn labels_from_scan_keys(
+    index: &PgRelation,
+    keys: &[pg_sys::ScanKeyData],
+) -> Option<LabelSet> {
+    let att = get_label_attribute(index)?;
+    keys.iter()
+        .find(|key| {
+            key.sk_attno as usize == att + 1
+                && key.sk_flags & pg_sys::SK_ISNULL as i32 == 0
+                && (key.sk_strategy == LABELS_OVERLAP_STRATEGY
+                    || key.sk_strategy == LABELS_CONTAINS_STRATEGY)
+        })
+        .map(|key| labels_from_datum(key.sk_argument, false))
+}
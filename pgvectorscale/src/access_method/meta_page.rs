@@ -9,21 +9,34 @@ use crate::util::page;
 use crate::util::*;
 
 use super::distance;
+use super::labels::{Label, LabelSet};
 use super::options::{
     NUM_DIMENSIONS_DEFAULT_SENTINEL, NUM_NEIGHBORS_DEFAULT_SENTINEL,
     SBQ_NUM_BITS_PER_DIMENSION_DEFAULT_SENTINEL,
 };
+use super::pg_vector::{PgBitInternal, VectorType, MAX_DIMENSIONS};
 use super::sbq::SbqNode;
 use super::stats::StatsNodeModify;
 use super::storage::StorageType;
+use super::storage_common::{
+    has_scalar_columns, FULL_VECTOR_POINTER_SIZE, MAX_SCALAR_COLUMNS_SIZE,
+};
 
 const TSV_MAGIC_NUMBER: u32 = 768756476; //Magic number, random
-const TSV_VERSION: u32 = 2;
+const TSV_VERSION: u32 = 3;
 const GRAPH_SLACK_FACTOR: f64 = 1.3_f64;
+/// The number of dimensions a sparsevec is hashed into by default (see SparseVector::to_dense).
+const SPARSE_NUM_DIMENSIONS_TO_INDEX_DEFAULT: u32 = 1024;
+/// The bits of a bit vector stored in each dimension of the index, see PgBitInternal::to_packed.
+pub const BIT_VECTOR_BITS_PER_DIMENSION: u8 = 32;
+/// Labels beyond this many don't get their own entry point, which keeps the meta page within a page.
+const MAX_LABEL_ENTRY_POINTS: usize = 256;
 
 const META_BLOCK_NUMBER: pg_sys::BlockNumber = 0;
 const META_HEADER_OFFSET: pgrx::pg_sys::OffsetNumber = 1;
 const META_OFFSET: pgrx::pg_sys::OffsetNumber = 2;
+/// Strategy number of the ORDER BY distance operator in our operator classes
+const DISTANCE_STRATEGY_NUMBER: i16 = 1;
 /// This is old metadata version for extension versions <=0.0.2.
 /// Note it is NOT repr(C)
 #[derive(Clone)]
@@ -74,12 +87,81 @@ impl MetaPageV1 {
             storage_type: StorageType::Plain as u8,
             search_list_size: self.search_list_size,
             max_alpha: self.max_alpha,
-            init_ids: ItemPointer::new(self.init_ids_block_number, self.init_ids_offset),
+            init_ids: init_ids_from_pointer(ItemPointer::new(
+                self.init_ids_block_number,
+                self.init_ids_offset,
+            )),
             quantizer_metadata: ItemPointer::new(InvalidBlockNumber, InvalidOffsetNumber),
+            label_entry_points: vec![],
+            vector_type: VectorType::Vector as u8,
+            full_vectors_in_index: false,
+            sbq_learned_boundaries: false,
         }
     }
 }
 
+/// This is the old metadata version 2, from before labels had their own entry points, other vector types than
+/// `vector` were supported, full vectors could be stored in the index, SBQ learned its bucket boundaries and
+/// indexes had several init ids.
+#[derive(Clone, PartialEq, Archive, Deserialize, Serialize, Readable)]
+#[archive(check_bytes)]
+pub struct MetaPageV2 {
+    magic_number: u32,
+    version: u32,
+    extension_version_when_built: String,
+    distance_type: u16,
+    num_dimensions: u32,
+    num_dimensions_to_index: u32,
+    bq_num_bits_per_dimension: u8,
+    storage_type: u8,
+    num_neighbors: u32,
+    search_list_size: u32,
+    max_alpha: f64,
+    init_ids: ItemPointer,
+    quantizer_metadata: ItemPointer,
+}
+
+impl MetaPageV2 {
+    pub fn get_new_meta(self) -> MetaPage {
+        MetaPage {
+            magic_number: self.magic_number,
+            version: TSV_VERSION,
+            extension_version_when_built: self.extension_version_when_built,
+            distance_type: self.distance_type,
+            num_dimensions: self.num_dimensions,
+            num_dimensions_to_index: self.num_dimensions_to_index,
+            bq_num_bits_per_dimension: self.bq_num_bits_per_dimension,
+            storage_type: self.storage_type,
+            num_neighbors: self.num_neighbors,
+            search_list_size: self.search_list_size,
+            max_alpha: self.max_alpha,
+            init_ids: init_ids_from_pointer(self.init_ids),
+            quantizer_metadata: self.quantizer_metadata,
+            label_entry_points: vec![],
+            vector_type: VectorType::Vector as u8,
+            full_vectors_in_index: false,
+            sbq_learned_boundaries: false,
+        }
+    }
+}
+
+/// The init ids of the old versions with a single init id, which is invalid for an empty graph.
+fn init_ids_from_pointer(init_id: ItemPointer) -> Vec<ItemPointer> {
+    if init_id.is_valid() {
+        vec![init_id]
+    } else {
+        vec![]
+    }
+}
+
+/// The node a search for a label starts from.
+#[derive(Clone, PartialEq, Archive, Deserialize, Serialize)]
+#[archive(check_bytes)]
+pub struct LabelEntryPoint {
+    label: Label,
+    entry_point: ItemPointer,
+}
+
 /// This is metadata header. It contains just the magic number and version number.
 /// Stored as the first page (offset 1) in the index relation.
 /// The header is separate from the actual metadata to allow for future-proofing.
@@ -93,9 +175,13 @@ pub struct MetaPageHeader {
     version: u32,
 }
 
-enum DistanceType {
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum DistanceType {
     Cosine = 0,
     L2 = 1,
+    InnerProduct = 2,
+    Hamming = 3,
+    Jaccard = 4,
 }
 
 impl DistanceType {
@@ -103,9 +189,46 @@ impl DistanceType {
         match value {
             0 => DistanceType::Cosine,
             1 => DistanceType::L2,
+            2 => DistanceType::InnerProduct,
+            3 => DistanceType::Hamming,
+            4 => DistanceType::Jaccard,
             _ => panic!("Unknown DistanceType number {}", value),
         }
     }
+
+    /// Determine the distance type from the ordering operator of the
+    /// operator class used for the indexed column.
+    pub fn from_index(index: &PgRelation) -> Self {
+        let operator_name = unsafe {
+            let opfamily = *index.rd_opfamily;
+            let opcintype = *index.rd_opcintype;
+            let operator = pg_sys::get_opfamily_member(
+                opfamily,
+                opcintype,
+                opcintype,
+                DISTANCE_STRATEGY_NUMBER,
+            );
+            if operator == pg_sys::InvalidOid {
+                pgrx::error!("No distance operator found in the operator class of the index");
+            }
+            //scalar columns are only used to filter the search, their operators aren't ordering operators
+            if pg_sys::get_op_opfamily_sortfamily(operator, opfamily) == pg_sys::InvalidOid {
+                pgrx::error!("The first column of a diskann index must be the vector column");
+            }
+            std::ffi::CStr::from_ptr(pg_sys::get_opname(operator))
+                .to_string_lossy()
+                .into_owned()
+        };
+
+        match operator_name.as_str() {
+            "<=>" => DistanceType::Cosine,
+            "<->" => DistanceType::L2,
+            "<#>" => DistanceType::InnerProduct,
+            "<~>" => DistanceType::Hamming,
+            "<%>" => DistanceType::Jaccard,
+            _ => pgrx::error!("Unsupported distance operator {}", operator_name),
+        }
+    }
 }
 
 /// This is metadata about the entire index.
@@ -130,8 +253,17 @@ pub struct MetaPage {
     num_neighbors: u32,
     search_list_size: u32,
     max_alpha: f64,
-    init_ids: ItemPointer,
+    /// the nodes searches start from, near the centroids of the data (see EntryPoints). Empty for an empty graph.
+    init_ids: Vec<ItemPointer>,
     quantizer_metadata: ItemPointer,
+    /// sorted by label
+    label_entry_points: Vec<LabelEntryPoint>,
+    /// the value of the VectorType enum
+    vector_type: u8,
+    /// the full vectors are stored on index pages, for rescoring without reading the heap
+    full_vectors_in_index: bool,
+    /// the SbqMeans hold bucket boundaries learned from the data, instead of fixed z-score ranges
+    sbq_learned_boundaries: bool,
 }
 
 impl MetaPage {
@@ -156,6 +288,7 @@ impl MetaPage {
             }
             StorageType::SbqSpeedup => self.num_dimensions_to_index,
             StorageType::SbqCompression => 0,
+            StorageType::Pq | StorageType::Sq8 | StorageType::Rabitq => 0,
         }
     }
 
@@ -173,10 +306,17 @@ impl MetaPage {
         self.max_alpha
     }
 
+    pub fn get_distance_type(&self) -> DistanceType {
+        DistanceType::from_u16(self.distance_type)
+    }
+
     pub fn get_distance_function(&self) -> fn(&[f32], &[f32]) -> f32 {
-        match DistanceType::from_u16(self.distance_type) {
+        match self.get_distance_type() {
             DistanceType::Cosine => distance::distance_cosine,
             DistanceType::L2 => distance::distance_l2,
+            DistanceType::InnerProduct => distance::distance_inner_product,
+            DistanceType::Hamming => distance::distance_hamming,
+            DistanceType::Jaccard => distance::distance_jaccard,
         }
     }
 
@@ -184,16 +324,54 @@ impl MetaPage {
         StorageType::from_u8(self.storage_type)
     }
 
+    pub fn get_vector_type(&self) -> VectorType {
+        VectorType::from_u8(self.vector_type)
+    }
+
+    /// True if the full vectors are stored on index pages, see full_vector.rs.
+    pub fn get_full_vectors_in_index(&self) -> bool {
+        self.full_vectors_in_index
+    }
+
+    /// True if the SbqMeans of the index hold learned bucket boundaries, see SbqQuantizer.
+    pub fn get_sbq_learned_boundaries(&self) -> bool {
+        self.sbq_learned_boundaries
+    }
+
     pub fn get_max_neighbors_during_build(&self) -> usize {
         return ((self.get_num_neighbors() as f64) * GRAPH_SLACK_FACTOR).ceil() as usize;
     }
 
     pub fn get_init_ids(&self) -> Option<Vec<IndexPointer>> {
-        if !self.init_ids.is_valid() {
+        if self.init_ids.is_empty() {
             return None;
         }
 
-        Some(vec![self.init_ids])
+        Some(self.init_ids.clone())
+    }
+
+    /// Returns the entry points of the given labels, for the labels that have one.
+    pub fn get_label_entry_points(&self, labels: &LabelSet) -> Vec<IndexPointer> {
+        self.label_entry_points
+            .iter()
+            .filter(|e| labels.contains(e.label))
+            .map(|e| e.entry_point)
+            .collect()
+    }
+
+    /// Returns the labels that don't have an entry point yet and can still get one.
+    pub fn get_labels_without_entry_point(&self, labels: &LabelSet) -> Vec<Label> {
+        let room = MAX_LABEL_ENTRY_POINTS.saturating_sub(self.label_entry_points.len());
+        labels
+            .iter()
+            .filter(|&&l| {
+                self.label_entry_points
+                    .binary_search_by_key(&l, |e| e.label)
+                    .is_err()
+            })
+            .take(room)
+            .cloned()
+            .collect()
     }
 
     pub fn get_quantizer_metadata_pointer(&self) -> Option<IndexPointer> {
@@ -203,7 +381,11 @@ impl MetaPage {
 
         match self.get_storage_type() {
             StorageType::Plain => None,
-            StorageType::SbqSpeedup | StorageType::SbqCompression => Some(self.quantizer_metadata),
+            StorageType::SbqSpeedup
+            | StorageType::SbqCompression
+            | StorageType::Pq
+            | StorageType::Sq8
+            | StorageType::Rabitq => Some(self.quantizer_metadata),
         }
     }
 
@@ -211,6 +393,7 @@ impl MetaPage {
         num_dimensions: u32,
         num_bits_per_dimension: u8,
         opt: &PgBox<TSVIndexOptions>,
+        reserved_size: usize,
     ) -> u32 {
         let num_neighbors = (*opt).get_num_neighbors();
         if num_neighbors == NUM_NEIGHBORS_DEFAULT_SENTINEL {
@@ -220,8 +403,10 @@ impl MetaPage {
                     num_dimensions as usize,
                     num_dimensions as usize,
                     num_bits_per_dimension,
+                    reserved_size,
                 ) as u32,
                 StorageType::SbqCompression => 50,
+                StorageType::Pq | StorageType::Sq8 | StorageType::Rabitq => 50,
             }
         } else {
             num_neighbors as u32
@@ -233,19 +418,105 @@ impl MetaPage {
     pub unsafe fn create(
         index: &PgRelation,
         num_dimensions: u32,
+        distance_type: DistanceType,
+        opt: PgBox<TSVIndexOptions>,
+    ) -> MetaPage {
+        let meta = Self::new(index, num_dimensions, distance_type, opt);
+        let page = page::WritablePage::new(index, crate::util::page::PageType::Meta);
+        meta.write_to_page(page);
+        meta
+    }
+
+    /// Writes the meta page of an empty index to the init fork of an unlogged index.
+    pub unsafe fn create_in_init_fork(
+        index: &PgRelation,
+        num_dimensions: u32,
+        distance_type: DistanceType,
+        opt: PgBox<TSVIndexOptions>,
+    ) {
+        let meta = Self::new(index, num_dimensions, distance_type, opt);
+        let page = page::WritablePage::new_in_init_fork(index, crate::util::page::PageType::Meta);
+        meta.write_to_page(page);
+    }
+
+    unsafe fn new(
+        index: &PgRelation,
+        num_dimensions: u32,
+        distance_type: DistanceType,
         opt: PgBox<TSVIndexOptions>,
     ) -> MetaPage {
         let version = Version::parse(env!("CARGO_PKG_VERSION")).unwrap();
 
-        let num_dimensions_to_index = if (*opt).num_dimensions == NUM_DIMENSIONS_DEFAULT_SENTINEL {
+        let vector_type = VectorType::from_index(index);
+        //the index stores the bits of a bit vector packed into f32s
+        let num_dimensions = if vector_type == VectorType::Bit {
+            if opt.num_dimensions != NUM_DIMENSIONS_DEFAULT_SENTINEL {
+                pgrx::error!("num_dimensions is not supported for bit vectors");
+            }
+            PgBitInternal::packed_len(num_dimensions as usize) as u32
+        } else {
             num_dimensions
+        };
+        let num_dimensions_to_index = if (*opt).num_dimensions == NUM_DIMENSIONS_DEFAULT_SENTINEL {
+            if vector_type == VectorType::SparseVec {
+                num_dimensions.min(SPARSE_NUM_DIMENSIONS_TO_INDEX_DEFAULT)
+            } else {
+                num_dimensions
+            }
         } else {
             (*opt).num_dimensions
         };
+        if vector_type == VectorType::SparseVec && num_dimensions_to_index > num_dimensions {
+            pgrx::error!("num_dimensions has to be at most the dimensions of the sparsevec");
+        }
+        //a sparsevec is only materialized with the dimensions that are indexed, other vectors also in full
+        let num_dimensions_materialized = if vector_type == VectorType::SparseVec {
+            num_dimensions_to_index
+        } else {
+            num_dimensions.max(num_dimensions_to_index)
+        };
+        if num_dimensions_materialized as usize > MAX_DIMENSIONS {
+            pgrx::error!(
+                "Vectors with {} dimensions can't be indexed, the maximum is {}",
+                num_dimensions_materialized,
+                MAX_DIMENSIONS
+            );
+        }
+
+        //the bits of a bit vector are stored as they are, see SbqQuantizer
+        if vector_type == VectorType::Bit
+            && opt.bq_num_bits_per_dimension != SBQ_NUM_BITS_PER_DIMENSION_DEFAULT_SENTINEL
+            && opt.bq_num_bits_per_dimension != 1
+        {
+            pgrx::error!("num_bits_per_dimension has to be 1 for bit vectors");
+        }
+
+        //a bit vector takes as little space as its PQ codes would (one byte per 8 dimensions)
+        if vector_type == VectorType::Bit && (*opt).get_storage_type() == StorageType::Pq {
+            pgrx::error!("The pq storage layout doesn't support bit vectors");
+        }
+
+        //a byte per dimension would take 8 times the space of the bit vector itself
+        if vector_type == VectorType::Bit && (*opt).get_storage_type() == StorageType::Sq8 {
+            pgrx::error!("The sq8 storage layout doesn't support bit vectors");
+        }
+
+        //a bit vector takes as little space as its RaBitQ code would (one bit per dimension)
+        if vector_type == VectorType::Bit && (*opt).get_storage_type() == StorageType::Rabitq {
+            pgrx::error!("The rabitq storage layout doesn't support bit vectors");
+        }
+
+        //bit vectors are their own exact distance, and sparse vectors are rescored with their nonzero elements
+        if opt.full_vectors_in_index
+            && (vector_type == VectorType::Bit || vector_type == VectorType::SparseVec)
+        {
+            pgrx::error!("full_vectors_in_index is only supported for vector and halfvec columns");
+        }
 
         let bq_num_bits_per_dimension =
             if (*opt).bq_num_bits_per_dimension == SBQ_NUM_BITS_PER_DIMENSION_DEFAULT_SENTINEL {
                 if (*opt).get_storage_type() == StorageType::SbqCompression
+                    && vector_type != VectorType::Bit
                     && num_dimensions_to_index < 900
                 {
                     2
@@ -255,39 +526,45 @@ impl MetaPage {
             } else {
                 (*opt).bq_num_bits_per_dimension as u8
             };
-
-        if bq_num_bits_per_dimension > 1 && num_dimensions_to_index > 930 {
-            //limited by SbqMeans fitting on a page
-            pgrx::error!("SBQ with more than 1 bit per dimension is not supported for more than 900 dimensions");
-        }
-        if bq_num_bits_per_dimension > 1 && (*opt).get_storage_type() != StorageType::SbqCompression
-        {
-            pgrx::error!(
-                "SBQ with more than 1 bit per dimension is only supported with the memory_optimized storage layout"
-            );
-        }
+        //the SBQ vector of a bit vector is the bits themselves, 32 for each f32 they are packed into
+        let bq_num_bits_per_dimension = if vector_type == VectorType::Bit {
+            BIT_VECTOR_BITS_PER_DIMENSION
+        } else {
+            bq_num_bits_per_dimension
+        };
 
         let meta = MetaPage {
             magic_number: TSV_MAGIC_NUMBER,
             version: TSV_VERSION,
             extension_version_when_built: version.to_string(),
-            distance_type: DistanceType::Cosine as u16,
+            distance_type: distance_type as u16,
             num_dimensions,
             num_dimensions_to_index,
             storage_type: (*opt).get_storage_type() as u8,
             num_neighbors: Self::calculate_num_neighbors(
-                num_dimensions,
+                num_dimensions_to_index,
                 bq_num_bits_per_dimension,
                 &opt,
+                if has_scalar_columns(index) {
+                    MAX_SCALAR_COLUMNS_SIZE
+                } else {
+                    0
+                } + if opt.full_vectors_in_index {
+                    FULL_VECTOR_POINTER_SIZE
+                } else {
+                    0
+                },
             ),
             bq_num_bits_per_dimension,
             search_list_size: (*opt).search_list_size,
             max_alpha: (*opt).max_alpha,
-            init_ids: ItemPointer::new(InvalidBlockNumber, InvalidOffsetNumber),
+            init_ids: vec![],
             quantizer_metadata: ItemPointer::new(InvalidBlockNumber, InvalidOffsetNumber),
+            label_entry_points: vec![],
+            vector_type: vector_type as u8,
+            full_vectors_in_index: opt.full_vectors_in_index,
+            sbq_learned_boundaries: true,
         };
-        let page = page::WritablePage::new(index, crate::util::page::PageType::Meta);
-        meta.write_to_page(page);
         meta
     }
 
@@ -326,7 +603,7 @@ impl MetaPage {
                 page_type
             );
         }
-        let meta = Self::get_meta_from_page(page);
+        let meta = Self::get_meta_from_page(*page);
         if meta != *new_meta {
             pgrx::error!("Problem upgrading meta page: meta mismatch");
         }
@@ -347,41 +624,72 @@ impl MetaPage {
                 Self::overwrite(index, &new_meta);
                 return new_meta;
             }
-            Self::get_meta_from_page(page)
+            Self::get_meta_from_page(*page)
         }
     }
 
-    unsafe fn get_meta_from_page(page: page::ReadablePage) -> MetaPage {
+    /// Reads the meta page from `page`, which the caller keeps locked.
+    unsafe fn get_meta_from_page(page: pg_sys::Page) -> MetaPage {
+        let item = |offset| {
+            let item_id = ports::PageGetItemId(page, offset);
+            let item = ports::PageGetItem(page, item_id) as *const u8;
+            std::slice::from_raw_parts(item, (*item_id).lp_len() as usize)
+        };
+
         //check the header. In the future, we can use this to check the version
-        let rb = page.get_item_unchecked(META_HEADER_OFFSET);
-        let meta = ReadableMetaPageHeader::with_readable_buffer(rb);
-        let archived = meta.get_archived_node();
+        let archived = rkyv::archived_root::<MetaPageHeader>(item(META_HEADER_OFFSET));
         assert!(archived.magic_number == TSV_MAGIC_NUMBER);
-        assert!(archived.version == TSV_VERSION);
-
-        let page = meta.get_owned_page();
+        let version = archived.version;
+
+        if version == 2 {
+            //upgraded in memory, the new version is written out with the next change to the meta page
+            let archived = rkyv::archived_root::<MetaPageV2>(item(META_OFFSET));
+            assert!(archived.magic_number == TSV_MAGIC_NUMBER);
+            let old_meta: MetaPageV2 = archived.deserialize(&mut rkyv::Infallible).unwrap();
+            return old_meta.get_new_meta();
+        }
+        assert!(version == TSV_VERSION);
 
         //retrieve the MetaPage itself and deserialize it
-        let rb = page.get_item_unchecked(META_OFFSET);
-        let meta = ReadableMetaPage::with_readable_buffer(rb);
-        let archived = meta.get_archived_node();
+        let archived = rkyv::archived_root::<MetaPage>(item(META_OFFSET));
         assert!(archived.magic_number == TSV_MAGIC_NUMBER);
         assert!(archived.version == TSV_VERSION);
 
         archived.deserialize(&mut rkyv::Infallible).unwrap()
     }
 
-    /// Change the init ids for an index.
+    /// Changes the meta page with `change`, which returns whether it changed anything. The meta page is read and
+    /// written under one exclusive lock, so concurrent changes, e.g. by inserts adding labels, aren't lost.
+    unsafe fn modify_locked<S: StatsNodeModify>(
+        index: &PgRelation,
+        stats: &mut S,
+        change: impl FnOnce(&mut MetaPage) -> bool,
+    ) {
+        if page::ReadablePage::read(index, META_BLOCK_NUMBER).get_type()
+            == crate::util::page::PageType::MetaV1
+        {
+            //upgrades the page
+            Self::fetch(index);
+        }
+
+        let mut page = page::WritablePage::modify(index, META_BLOCK_NUMBER);
+        let mut meta = Self::get_meta_from_page(*page);
+        if !change(&mut meta) {
+            return;
+        }
+        page.reinit(crate::util::page::PageType::Meta);
+        meta.write_to_page(page);
+        stats.record_modify();
+    }
+
+    /// Change the init ids for an index. No init ids means the graph is empty.
     pub fn update_init_ids<S: StatsNodeModify>(
         index: &PgRelation,
         init_ids: Vec<IndexPointer>,
         stats: &mut S,
     ) {
-        assert_eq!(init_ids.len(), 1); //change this if we support multiple
-        let id = init_ids[0];
-
         let mut meta = Self::fetch(index);
-        meta.init_ids = id;
+        meta.init_ids = init_ids;
 
         unsafe {
             Self::overwrite(index, &meta);
@@ -389,6 +697,57 @@ impl MetaPage {
         };
     }
 
+    /// Remove the entry points that satisfy `predicate`, and return their labels.
+    pub fn remove_label_entry_points<S: StatsNodeModify>(
+        index: &PgRelation,
+        predicate: impl Fn(IndexPointer) -> bool,
+        stats: &mut S,
+    ) -> Vec<Label> {
+        let mut removed = vec![];
+        unsafe {
+            Self::modify_locked(index, stats, |meta| {
+                meta.label_entry_points.retain(|e| {
+                    let remove = predicate(e.entry_point);
+                    if remove {
+                        removed.push(e.label);
+                    }
+                    !remove
+                });
+                !removed.is_empty()
+            })
+        };
+        removed
+    }
+
+    /// Make `entry_point` the entry point of those of `labels` that don't have one yet, a concurrent insert may
+    /// have added one since the caller looked.
+    pub fn update_label_entry_points<S: StatsNodeModify>(
+        index: &PgRelation,
+        labels: &[Label],
+        entry_point: IndexPointer,
+        stats: &mut S,
+    ) {
+        unsafe {
+            Self::modify_locked(index, stats, |meta| {
+                let mut changed = false;
+                for &label in labels {
+                    if meta.label_entry_points.len() >= MAX_LABEL_ENTRY_POINTS {
+                        break;
+                    }
+                    if let Err(pos) = meta
+                        .label_entry_points
+                        .binary_search_by_key(&label, |e| e.label)
+                    {
+                        meta.label_entry_points
+                            .insert(pos, LabelEntryPoint { label, entry_point });
+                        changed = true;
+                    }
+                }
+                changed
+            })
+        };
+    }
+
     pub fn update_quantizer_metadata_pointer<S: StatsNodeModify>(
         index: &PgRelation,
         quantizer_pointer: IndexPointer,
@@ -2,16 +2,23 @@ use pgrx::*;
 mod build;
 mod cost_estimate;
 mod debugging;
+mod entry_points;
+mod full_vector;
 mod graph;
 mod graph_neighbor_store;
 pub mod guc;
+mod labels;
 mod meta_page;
 mod neighbor_with_distance;
 pub mod options;
+mod parallel_build;
 pub mod pg_vector;
 mod plain_node;
 mod plain_storage;
+mod pq;
+mod rabitq;
 mod scan;
+mod sq8;
 pub mod stats;
 mod storage;
 mod storage_common;
@@ -55,15 +62,18 @@ fn amhandler(_fcinfo: pg_sys::FunctionCallInfo) -> PgBox<pg_sys::IndexAmRoutine>
     amroutine.amcanorderbyop = true;
     amroutine.amcanbackward = false; /* can change direction mid-scan */
     amroutine.amcanunique = false;
-    amroutine.amcanmulticol = false;
+    amroutine.amcanmulticol = true;
     amroutine.amoptionalkey = true;
     amroutine.amsearcharray = false;
     amroutine.amsearchnulls = false;
     amroutine.amstorage = false;
     amroutine.amclusterable = false;
     amroutine.ampredlocks = false;
-    amroutine.amcanparallel = false; //TODO
-    amroutine.amcaninclude = false; //TODO
+    /* Not supported: the greedy search can't be split between processes, its state lives in the memory of the
+    backend running it. Every participant would have to run the whole search and could only skip results, which
+    is only correct if all of them see the same graph. */
+    amroutine.amcanparallel = false;
+    amroutine.amcaninclude = true;
     amroutine.amusemaintenanceworkmem = false; /* not used during VACUUM */
     //amroutine.amparallelvacuumoptions = pg_sys  VACUUM_OPTION_PARALLEL_BULKDEL; //TODO
     amroutine.amkeytype = pg_sys::InvalidOid;
@@ -79,8 +89,9 @@ fn amhandler(_fcinfo: pg_sys::FunctionCallInfo) -> PgBox<pg_sys::IndexAmRoutine>
     amroutine.ambeginscan = Some(scan::ambeginscan);
     amroutine.amrescan = Some(scan::amrescan);
     amroutine.amgettuple = Some(scan::amgettuple);
-    amroutine.amgetbitmap = None;
+    amroutine.amgetbitmap = Some(scan::amgetbitmap);
     amroutine.amendscan = Some(scan::amendscan);
+    amroutine.amcanreturn = Some(scan::amcanreturn);
 
     amroutine.ambuildphasename = Some(build::ambuildphasename);
 
@@ -93,6 +104,8 @@ extension_sql!(
 DO $$
 DECLARE
   c int;
+  t text;
+  ops text[];
 BEGIN
     SELECT count(*)
     INTO c
@@ -105,6 +118,168 @@ BEGIN
         FOR TYPE vector USING diskann AS
 	        OPERATOR 1 <=> (vector, vector) FOR ORDER BY float_ops;
     END IF;
+
+    SELECT count(*)
+    INTO c
+    FROM pg_catalog.pg_opclass c
+    WHERE c.opcname = 'vector_l2_ops'
+    AND c.opcmethod = (SELECT oid FROM pg_catalog.pg_am am  WHERE am.amname = 'diskann');
+
+    IF c = 0 THEN
+        CREATE OPERATOR CLASS vector_l2_ops
+        FOR TYPE vector USING diskann AS
+	        OPERATOR 1 <-> (vector, vector) FOR ORDER BY float_ops;
+    END IF;
+
+    SELECT count(*)
+    INTO c
+    FROM pg_catalog.pg_opclass c
+    WHERE c.opcname = 'vector_ip_ops'
+    AND c.opcmethod = (SELECT oid FROM pg_catalog.pg_am am  WHERE am.amname = 'diskann');
+
+    IF c = 0 THEN
+        CREATE OPERATOR CLASS vector_ip_ops
+        FOR TYPE vector USING diskann AS
+	        OPERATOR 1 <#> (vector, vector) FOR ORDER BY float_ops;
+    END IF;
+
+    -- range searches, e.g. `WHERE embedding <<=>> sphere('[1,2,3]', 0.2)` for a cosine distance below 0.2, see scan.rs
+    IF NOT EXISTS (SELECT 1 FROM pg_catalog.pg_type WHERE typname = 'vector_sphere') THEN
+        CREATE TYPE vector_sphere AS (center vector, radius float8);
+    END IF;
+
+    CREATE OR REPLACE FUNCTION sphere(vector, float8) RETURNS vector_sphere
+        IMMUTABLE STRICT PARALLEL SAFE LANGUAGE sql AS 'SELECT ROW($1, $2)::vector_sphere';
+
+    FOREACH ops SLICE 1 IN ARRAY ARRAY[['cosine', '<=>', '<<=>>'], ['l2', '<->', '<<->>'], ['ip', '<#>', '<<#>>']]
+    LOOP
+        -- plpgsql, since a sql function would be inlined and the index couldn't be used for the operator
+        EXECUTE format('CREATE OR REPLACE FUNCTION %I(vector, vector_sphere) RETURNS bool
+            IMMUTABLE STRICT PARALLEL SAFE LANGUAGE plpgsql AS
+            %L', 'vector_sphere_' || ops[1] || '_within', format('BEGIN RETURN ($1 %s $2.center) < $2.radius; END', ops[2]));
+
+        IF NOT EXISTS (SELECT 1 FROM pg_catalog.pg_operator WHERE oprname = ops[3] AND oprright = 'vector_sphere'::regtype) THEN
+            EXECUTE format('CREATE OPERATOR %s (LEFTARG = vector, RIGHTARG = vector_sphere, FUNCTION = %I, RESTRICT = contsel)',
+                ops[3], 'vector_sphere_' || ops[1] || '_within');
+        END IF;
+
+        SELECT count(*)
+        INTO c
+        FROM pg_catalog.pg_amop o
+        JOIN pg_catalog.pg_opfamily f ON f.oid = o.amopfamily
+        WHERE f.opfname = 'vector_' || ops[1] || '_ops'
+        AND f.opfmethod = (SELECT oid FROM pg_catalog.pg_am am  WHERE am.amname = 'diskann')
+        AND o.amopstrategy = 2;
+
+        IF c = 0 THEN
+            EXECUTE format('ALTER OPERATOR FAMILY %I USING diskann ADD
+                OPERATOR 2 %s (vector, vector_sphere)', 'vector_' || ops[1] || '_ops', ops[3]);
+        END IF;
+    END LOOP;
+
+    -- halfvec and sparsevec need pgvector 0.7.0 or later
+    FOREACH t IN ARRAY ARRAY['halfvec', 'sparsevec']
+    LOOP
+        IF EXISTS (SELECT 1 FROM pg_catalog.pg_type WHERE typname = t) THEN
+            SELECT count(*)
+            INTO c
+            FROM pg_catalog.pg_opclass c
+            WHERE c.opcname = t || '_cosine_ops'
+            AND c.opcmethod = (SELECT oid FROM pg_catalog.pg_am am  WHERE am.amname = 'diskann');
+
+            IF c = 0 THEN
+                EXECUTE format('CREATE OPERATOR CLASS %I DEFAULT
+                    FOR TYPE %s USING diskann AS
+                        OPERATOR 1 <=> (%2$s, %2$s) FOR ORDER BY float_ops', t || '_cosine_ops', t);
+            END IF;
+
+            SELECT count(*)
+            INTO c
+            FROM pg_catalog.pg_opclass c
+            WHERE c.opcname = t || '_l2_ops'
+            AND c.opcmethod = (SELECT oid FROM pg_catalog.pg_am am  WHERE am.amname = 'diskann');
+
+            IF c = 0 THEN
+                EXECUTE format('CREATE OPERATOR CLASS %I
+                    FOR TYPE %s USING diskann AS
+                        OPERATOR 1 <-> (%2$s, %2$s) FOR ORDER BY float_ops', t || '_l2_ops', t);
+            END IF;
+
+            SELECT count(*)
+            INTO c
+            FROM pg_catalog.pg_opclass c
+            WHERE c.opcname = t || '_ip_ops'
+            AND c.opcmethod = (SELECT oid FROM pg_catalog.pg_am am  WHERE am.amname = 'diskann');
+
+            IF c = 0 THEN
+                EXECUTE format('CREATE OPERATOR CLASS %I
+                    FOR TYPE %s USING diskann AS
+                        OPERATOR 1 <#> (%2$s, %2$s) FOR ORDER BY float_ops', t || '_ip_ops', t);
+            END IF;
+        END IF;
+    END LOOP;
+
+    -- the hamming and jaccard distances between bit vectors need pgvector 0.7.0 or later
+    IF EXISTS (SELECT 1 FROM pg_catalog.pg_operator WHERE oprname = '<~>' AND oprleft = 'bit'::regtype) THEN
+        SELECT count(*)
+        INTO c
+        FROM pg_catalog.pg_opclass c
+        WHERE c.opcname = 'bit_hamming_ops'
+        AND c.opcmethod = (SELECT oid FROM pg_catalog.pg_am am  WHERE am.amname = 'diskann');
+
+        IF c = 0 THEN
+            CREATE OPERATOR CLASS bit_hamming_ops DEFAULT
+            FOR TYPE bit USING diskann AS
+                OPERATOR 1 <~> (bit, bit) FOR ORDER BY float_ops;
+        END IF;
+
+        SELECT count(*)
+        INTO c
+        FROM pg_catalog.pg_opclass c
+        WHERE c.opcname = 'bit_jaccard_ops'
+        AND c.opcmethod = (SELECT oid FROM pg_catalog.pg_am am  WHERE am.amname = 'diskann');
+
+        IF c = 0 THEN
+            CREATE OPERATOR CLASS bit_jaccard_ops
+            FOR TYPE bit USING diskann AS
+                OPERATOR 1 <%> (bit, bit) FOR ORDER BY float_ops;
+        END IF;
+    END IF;
+
+    -- operator classes for the scalar columns that follow the vector column, used to filter the search
+    FOREACH t IN ARRAY ARRAY['int2', 'int4', 'int8', 'text', 'uuid', 'date', 'timestamp', 'timestamptz', 'bool']
+    LOOP
+        SELECT count(*)
+        INTO c
+        FROM pg_catalog.pg_opclass c
+        WHERE c.opcname = t || '_ops'
+        AND c.opcmethod = (SELECT oid FROM pg_catalog.pg_am am  WHERE am.amname = 'diskann');
+
+        IF c = 0 THEN
+            EXECUTE format('CREATE OPERATOR CLASS %I DEFAULT
+                FOR TYPE %s USING diskann AS
+                    OPERATOR 1 < (%2$s, %2$s),
+                    OPERATOR 2 <= (%2$s, %2$s),
+                    OPERATOR 3 = (%2$s, %2$s),
+                    OPERATOR 4 >= (%2$s, %2$s),
+                    OPERATOR 5 > (%2$s, %2$s)', t || '_ops', t);
+        END IF;
+    END LOOP;
+
+    -- labels for Filtered-DiskANN, see labels.rs
+    SELECT count(*)
+    INTO c
+    FROM pg_catalog.pg_opclass c
+    WHERE c.opcname = 'int4_array_ops'
+    AND c.opcmethod = (SELECT oid FROM pg_catalog.pg_am am  WHERE am.amname = 'diskann');
+
+    IF c = 0 THEN
+        CREATE OPERATOR CLASS int4_array_ops DEFAULT
+        FOR TYPE int4[] USING diskann AS
+            OPERATOR 1 && (anyarray, anyarray),
+            OPERATOR 2 @> (anyarray, anyarray),
+            OPERATOR 3 <@ (anyarray, anyarray);
+    END IF;
 END;
 $$;
 
@@ -13,7 +13,7 @@ pub struct NeighborWithDistance {
 impl NeighborWithDistance {
     pub fn new(neighbor_index_pointer: ItemPointer, distance: Distance) -> Self {
         assert!(!distance.is_nan());
-        assert!(distance >= 0.0);
+        //distances can be negative for the inner product distance
         Self {
             index_pointer: neighbor_index_pointer,
             distance,
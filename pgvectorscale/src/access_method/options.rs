@@ -18,6 +18,7 @@ pub struct TSVIndexOptions {
     pub num_dimensions: u32,
     pub max_alpha: f64,
     pub bq_num_bits_per_dimension: u32,
+    pub full_vectors_in_index: bool,
 }
 
 pub const NUM_NEIGHBORS_DEFAULT_SENTINEL: i32 = -1;
@@ -40,6 +41,7 @@ impl TSVIndexOptions {
             ops.max_alpha = DEFAULT_MAX_ALPHA;
             ops.num_dimensions = NUM_DIMENSIONS_DEFAULT_SENTINEL;
             ops.bq_num_bits_per_dimension = SBQ_NUM_BITS_PER_DIMENSION_DEFAULT_SENTINEL;
+            ops.full_vectors_in_index = false;
             unsafe {
                 set_varsize(
                     ops.as_ptr().cast(),
@@ -86,7 +88,7 @@ impl TSVIndexOptions {
     }
 }
 
-const NUM_REL_OPTS: usize = 6;
+const NUM_REL_OPTS: usize = 7;
 static mut RELOPT_KIND_TSV: pg_sys::relopt_kind = 0;
 
 // amoptions is a function that gets a datum of text[] data from pg_class.reloptions (which contains text in the format "key=value") and returns a bytea for the struct for the parsed options.
@@ -136,6 +138,11 @@ pub unsafe extern "C" fn amoptions(
             opttype: pg_sys::relopt_type_RELOPT_TYPE_REAL,
             offset: offset_of!(TSVIndexOptions, max_alpha) as i32,
         },
+        pg_sys::relopt_parse_elt {
+            optname: "full_vectors_in_index".as_pg_cstr(),
+            opttype: pg_sys::relopt_type_RELOPT_TYPE_BOOL,
+            offset: offset_of!(TSVIndexOptions, full_vectors_in_index) as i32,
+        },
     ];
 
     build_relopts(reloptions, validate, tab)
@@ -181,7 +188,8 @@ pub unsafe fn init() {
     pg_sys::add_string_reloption(
         RELOPT_KIND_TSV,
         "storage_layout".as_pg_cstr(),
-        "Storage layout: either memory_optimized, io_optimized, or plain".as_pg_cstr(),
+        "Storage layout: either memory_optimized, io_optimized, pq, sq8, rabitq, or plain"
+            .as_pg_cstr(),
         super::storage::DEFAULT_STORAGE_TYPE_STR.as_pg_cstr(),
         Some(validate_storage_layout),
         pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE,
@@ -236,6 +244,14 @@ pub unsafe fn init() {
         32,
         pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE,
     );
+
+    pg_sys::add_bool_reloption(
+        RELOPT_KIND_TSV,
+        "full_vectors_in_index".as_pg_cstr(),
+        "Store the full vectors in the index to rescore without reading the table".as_pg_cstr(),
+        false,
+        pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE,
+    );
 }
 
 #[cfg(any(test, feature = "pg_test"))]
@@ -270,6 +286,7 @@ mod tests {
             options.bq_num_bits_per_dimension,
             SBQ_NUM_BITS_PER_DIMENSION_DEFAULT_SENTINEL,
         );
+        assert!(!options.full_vectors_in_index);
         Ok(())
     }
 
@@ -320,6 +337,34 @@ mod tests {
         Ok(())
     }
 
+    #[pg_test]
+    unsafe fn test_index_options_storage_layouts() -> spi::Result<()> {
+        Spi::run("CREATE TABLE test(encoding vector(3));")?;
+
+        for (storage_layout, storage_type) in [
+            ("pq", StorageType::Pq),
+            ("sq8", StorageType::Sq8),
+            ("rabitq", StorageType::Rabitq),
+        ] {
+            Spi::run(&format!(
+                "CREATE INDEX idxtest_{storage_layout}
+                      ON test
+                   USING diskann(encoding)
+                   WITH (storage_layout = {storage_layout});",
+            ))?;
+
+            let index_oid = Spi::get_one::<pg_sys::Oid>(&format!(
+                "SELECT 'idxtest_{storage_layout}'::regclass::oid"
+            ))?
+            .expect("oid was null");
+            let indexrel = PgRelation::from_pg(pg_sys::RelationIdGetRelation(index_oid));
+            let options = TSVIndexOptions::from_relation(&indexrel);
+            assert_eq!(options.get_num_neighbors(), NUM_NEIGHBORS_DEFAULT_SENTINEL);
+            assert_eq!(options.get_storage_type(), storage_type);
+        }
+        Ok(())
+    }
+
     #[pg_test]
     unsafe fn test_index_options_plain() -> spi::Result<()> {
         Spi::run(&format!(
@@ -389,4 +434,23 @@ mod tests {
         assert_eq!(options.bq_num_bits_per_dimension, 5);
         Ok(())
     }
+
+    #[pg_test]
+    unsafe fn test_index_options_full_vectors_in_index() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(encoding vector(3));
+        CREATE INDEX idxtest
+                  ON test
+               USING diskann(encoding)
+               WITH (storage_layout = io_optimized, full_vectors_in_index = true);",
+        )?;
+
+        let index_oid =
+            Spi::get_one::<pg_sys::Oid>("SELECT 'idxtest'::regclass::oid")?.expect("oid was null");
+        let indexrel = PgRelation::from_pg(pg_sys::RelationIdGetRelation(index_oid));
+        let options = TSVIndexOptions::from_relation(&indexrel);
+        assert_eq!(options.get_storage_type(), StorageType::SbqSpeedup);
+        assert!(options.full_vectors_in_index);
+        Ok(())
+    }
 }
@@ -0,0 +1,426 @@
+use std::collections::{HashMap, HashSet};
+
+use pgrx::*;
+use rayon::prelude::*;
+use rayon::ThreadPool;
+
+use crate::util::{IndexPointer, ItemPointer};
+
+use super::distance::distance_xor_optimized;
+use super::graph::{Graph, ListSearchNeighbor, ListSearchResult};
+use super::graph_neighbor_store::{BuilderNeighborCache, GraphNeighborStore};
+use super::meta_page::MetaPage;
+use super::neighbor_with_distance::NeighborWithDistance;
+use super::stats::{
+    GreedySearchStats, InsertStats, PruneNeighborStats, StatsDistanceComparison, StatsNodeRead,
+    StatsNodeVisit,
+};
+use super::storage::NodeDistanceMeasure;
+
+/// Upper bound on the number of nodes inserted into the graph as one batch.
+const MAX_BATCH_SIZE: usize = 1000;
+
+/// The vector of a node as used for distance comparisons while building the graph.
+/// Storages return the vector their node distance measure compares: the full vector for plain, the reconstructed
+/// vector for PQ, SQ8 and RaBitQ, and the SBQ bits for SBQ. The SBQ bits are compared by their xor count, not with
+/// the distance function of the index, like a serial SBQ build compares them. The graph still differs from the one
+/// of a serial build, as the nodes of a batch don't see each other (see ParallelBuild).
+pub enum BuildVector {
+    Full(Vec<f32>),
+    Quantized(Vec<u64>),
+}
+
+impl BuildVector {
+    fn size_bytes(&self) -> usize {
+        match self {
+            BuildVector::Full(v) => v.len() * std::mem::size_of::<f32>(),
+            BuildVector::Quantized(v) => v.len() * std::mem::size_of::<u64>(),
+        }
+    }
+}
+
+/// In-memory copy of the vectors of all nodes created so far. The rayon threads can't read Postgres
+/// pages, so every distance comparison of a parallel build is done on these vectors.
+struct BuildVectors {
+    vectors: HashMap<IndexPointer, BuildVector>,
+    distance_fn: fn(&[f32], &[f32]) -> f32,
+    size_bytes: usize,
+}
+
+impl BuildVectors {
+    fn get(&self, index_pointer: IndexPointer) -> &BuildVector {
+        self.vectors
+            .get(&index_pointer)
+            .expect("every node in the graph should have a build vector")
+    }
+
+    fn distance<S: StatsDistanceComparison>(
+        &self,
+        vector: &BuildVector,
+        index_pointer: IndexPointer,
+        stats: &mut S,
+    ) -> f32 {
+        match (vector, self.get(index_pointer)) {
+            (BuildVector::Full(a), BuildVector::Full(b)) => {
+                stats.record_full_distance_comparison();
+                (self.distance_fn)(a, b)
+            }
+            (BuildVector::Quantized(a), BuildVector::Quantized(b)) => {
+                stats.record_quantized_distance_comparison();
+                distance_xor_optimized(a, b) as f32
+            }
+            _ => panic!("build vectors of different kinds can't be compared"),
+        }
+    }
+}
+
+struct BuildVectorDistanceMeasure<'a> {
+    vectors: &'a BuildVectors,
+    vector: &'a BuildVector,
+}
+
+impl<'a> NodeDistanceMeasure for BuildVectorDistanceMeasure<'a> {
+    unsafe fn get_distance<S: StatsNodeRead + StatsDistanceComparison>(
+        &self,
+        index_pointer: IndexPointer,
+        stats: &mut S,
+    ) -> f32 {
+        self.vectors.distance(self.vector, index_pointer, stats)
+    }
+}
+
+/// Builds the in-memory graph of an index build on several threads.
+///
+/// Only building the graph is parallel, on threads of the backend running the build: no worker processes are
+/// started, the heap is scanned by the backend alone, and the nodes are created on the index pages as it goes.
+/// Instead of being inserted into the graph one at a time they are collected into batches. Each node of a batch
+/// searches the graph and prunes its candidate list on the thread pool, then the back pointers to the
+/// new nodes are merged and pruned on the thread pool as well. Postgres isn't thread safe, so the
+/// threads only touch the BuilderNeighborCache and the in-memory vectors, never pages.
+///
+/// Nodes of the same batch don't see each other while searching. Batches are kept small relative
+/// to the size of the graph so this doesn't affect the quality of the graph.
+pub struct ParallelBuild {
+    pool: ThreadPool,
+    num_threads: usize,
+    vectors: BuildVectors,
+    pending: Vec<IndexPointer>,
+}
+
+impl ParallelBuild {
+    /// Returns None if the build should run serially, that is if the planner chose no workers for the build
+    /// (see build::parallel_workers) or the threads can't be started.
+    pub fn new(meta_page: &MetaPage, num_workers: usize) -> Option<Self> {
+        if num_workers == 0 {
+            return None;
+        }
+        //the workers plus the leader, like a parallel btree build
+        let num_threads = num_workers + 1;
+        let pool = match rayon::ThreadPoolBuilder::new()
+            .num_threads(num_threads)
+            .build()
+        {
+            Ok(pool) => pool,
+            Err(e) => {
+                warning!(
+                    "Could not start the parallel index build, building serially: {}",
+                    e
+                );
+                return None;
+            }
+        };
+
+        debug1!("Building the graph with {} threads", num_threads);
+        Some(Self {
+            pool,
+            num_threads,
+            vectors: BuildVectors {
+                vectors: HashMap::new(),
+                distance_fn: meta_page.get_distance_function(),
+                size_bytes: 0,
+            },
+            pending: vec![],
+        })
+    }
+
+    /// Records the vector of a node that was just created on the index pages.
+    pub fn add_vector(&mut self, index_pointer: IndexPointer, vector: BuildVector) {
+        self.vectors.size_bytes += vector.size_bytes();
+        self.vectors.vectors.insert(index_pointer, vector);
+    }
+
+    /// Whether the in-memory vectors have outgrown maintenance_work_mem.
+    pub fn exceeds_memory_limit(&self) -> bool {
+        let limit = unsafe { pg_sys::maintenance_work_mem } as usize * 1024;
+        self.vectors.size_bytes > limit
+    }
+
+    pub fn num_vectors(&self) -> usize {
+        self.vectors.vectors.len()
+    }
+
+    /// Queues a node for insertion into the graph, inserting the queued nodes once there are enough of them.
+    /// The node's vector must have been added with `add_vector`.
+    pub fn insert(
+        &mut self,
+        index_pointer: IndexPointer,
+        graph: &mut Graph,
+        stats: &mut InsertStats,
+    ) {
+        self.pending.push(index_pointer);
+
+        let num_in_graph = self.vectors.vectors.len() - self.pending.len();
+        let batch_size = (num_in_graph / 20)
+            .min(MAX_BATCH_SIZE)
+            .max(self.num_threads);
+        if self.pending.len() >= batch_size {
+            self.flush(graph, stats);
+        }
+    }
+
+    /// Inserts all the queued nodes into the graph.
+    pub fn flush(&mut self, graph: &mut Graph, stats: &mut InsertStats) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let batch = std::mem::take(&mut self.pending);
+        let vectors = &self.vectors;
+
+        //search for the neighbors of the new nodes
+        let new_nodes: Vec<_> = {
+            let meta_page = graph.get_meta_page();
+            let init_ids = meta_page
+                .get_init_ids()
+                .expect("the first node should have been inserted serially");
+            let builder = Self::builder(graph);
+            self.pool.install(|| {
+                batch
+                    .par_iter()
+                    .map(|&index_pointer| {
+                        let mut greedy_search_stats = GreedySearchStats::new();
+                        let mut prune_stats = PruneNeighborStats::new();
+                        let candidates = vectors.greedy_search(
+                            builder,
+                            meta_page,
+                            &init_ids,
+                            vectors.get(index_pointer),
+                            &mut greedy_search_stats,
+                        );
+                        let neighbors = vectors.merge_neighbors(
+                            builder,
+                            meta_page,
+                            index_pointer,
+                            candidates,
+                            &mut prune_stats,
+                        );
+                        (index_pointer, neighbors, greedy_search_stats, prune_stats)
+                    })
+                    .collect()
+            })
+        };
+
+        let mut back_pointers: HashMap<IndexPointer, Vec<NeighborWithDistance>> = HashMap::new();
+        {
+            let builder = Self::builder_mut(graph);
+            for (index_pointer, neighbors, greedy_search_stats, prune_stats) in new_nodes {
+                stats.greedy_search_stats.combine(&greedy_search_stats);
+                stats.prune_neighbor_stats.combine(&prune_stats);
+                for neighbor in &neighbors {
+                    back_pointers
+                        .entry(neighbor.get_index_pointer_to_neighbor())
+                        .or_default()
+                        .push(NeighborWithDistance::new(
+                            index_pointer,
+                            neighbor.get_distance(),
+                        ));
+                }
+                builder.set_neighbors(index_pointer, neighbors);
+            }
+        }
+
+        //update back pointers
+        let updated: Vec<_> = {
+            let meta_page = graph.get_meta_page();
+            let builder = Self::builder(graph);
+            self.pool.install(|| {
+                back_pointers
+                    .into_par_iter()
+                    .map(|(neighbors_of, additional_neighbors)| {
+                        let mut prune_stats = PruneNeighborStats::new();
+                        let neighbors = vectors.merge_neighbors(
+                            builder,
+                            meta_page,
+                            neighbors_of,
+                            additional_neighbors,
+                            &mut prune_stats,
+                        );
+                        (neighbors_of, neighbors, prune_stats)
+                    })
+                    .collect()
+            })
+        };
+
+        let builder = Self::builder_mut(graph);
+        for (neighbors_of, neighbors, prune_stats) in updated {
+            stats.prune_neighbor_stats.combine(&prune_stats);
+            builder.set_neighbors(neighbors_of, neighbors);
+        }
+    }
+
+    /// Prunes the neighbor lists that are longer than num_neighbors at the end of the build.
+    pub fn prune_for_finalize(
+        &self,
+        graph: &Graph,
+        stats: &mut PruneNeighborStats,
+    ) -> HashMap<IndexPointer, Vec<NeighborWithDistance>> {
+        let meta_page = graph.get_meta_page();
+        let builder = Self::builder(graph);
+        let vectors = &self.vectors;
+        let to_prune: Vec<_> = builder
+            .iter()
+            .filter(|(_, neighbors)| neighbors.len() > meta_page.get_num_neighbors() as _)
+            .collect();
+
+        let pruned: Vec<_> = self.pool.install(|| {
+            to_prune
+                .into_par_iter()
+                .map(|(&index_pointer, neighbors)| {
+                    let mut prune_stats = PruneNeighborStats::new();
+                    //OPT: get rid of this clone
+                    let neighbors = vectors.prune(meta_page, neighbors.clone(), &mut prune_stats);
+                    (index_pointer, neighbors, prune_stats)
+                })
+                .collect()
+        });
+
+        pruned
+            .into_iter()
+            .map(|(index_pointer, neighbors, prune_stats)| {
+                stats.combine(&prune_stats);
+                (index_pointer, neighbors)
+            })
+            .collect()
+    }
+
+    fn builder<'b>(graph: &'b Graph) -> &'b BuilderNeighborCache {
+        match graph.get_neighbor_store() {
+            GraphNeighborStore::Builder(b) => b,
+            GraphNeighborStore::Disk => {
+                panic!("Should not be using the disk neighbor store during build")
+            }
+        }
+    }
+
+    fn builder_mut<'b>(graph: &'b mut Graph) -> &'b mut BuilderNeighborCache {
+        match graph.get_neighbor_store_mut() {
+            GraphNeighborStore::Builder(b) => b,
+            GraphNeighborStore::Disk => {
+                panic!("Should not be using the disk neighbor store during build")
+            }
+        }
+    }
+}
+
+impl BuildVectors {
+    /// Same search as Graph::greedy_search_for_build, on the in-memory graph and vectors.
+    fn greedy_search(
+        &self,
+        builder: &BuilderNeighborCache,
+        meta_page: &MetaPage,
+        init_ids: &[IndexPointer],
+        query: &BuildVector,
+        stats: &mut GreedySearchStats,
+    ) -> Vec<NeighborWithDistance> {
+        let search_list_size = meta_page.get_search_list_size_for_build() as usize;
+        let mut lsr = ListSearchResult::<(), ()>::new_without_storage(search_list_size, meta_page);
+        for &index_pointer in init_ids {
+            if !lsr.prepare_insert(index_pointer) {
+                panic!("should not have had an init id already inserted");
+            }
+            let distance = self.distance(query, index_pointer, &mut lsr.stats);
+            lsr.insert_neighbor(ListSearchNeighbor::new(index_pointer, distance, ()));
+        }
+
+        let mut visited_nodes = HashSet::with_capacity(search_list_size);
+        while let Some(list_search_entry_idx) = lsr.visit_closest(search_list_size) {
+            let list_search_entry = lsr.get_lsn_by_idx(list_search_entry_idx);
+            let visiting = list_search_entry.index_pointer;
+            visited_nodes.insert(NeighborWithDistance::new(
+                visiting,
+                list_search_entry.get_distance(),
+            ));
+            lsr.stats.record_visit();
+
+            for neighbor_index_pointer in builder.get_neighbors(visiting) {
+                if !lsr.prepare_insert(neighbor_index_pointer) {
+                    continue;
+                }
+                let distance = self.distance(query, neighbor_index_pointer, &mut lsr.stats);
+                lsr.insert_neighbor(ListSearchNeighbor::new(
+                    neighbor_index_pointer,
+                    distance,
+                    (),
+                ));
+            }
+        }
+        stats.combine(&lsr.stats);
+        visited_nodes.into_iter().collect()
+    }
+
+    /// Same as Graph::add_neighbors, except that the new neighbor list is returned instead of being set.
+    fn merge_neighbors(
+        &self,
+        builder: &BuilderNeighborCache,
+        meta_page: &MetaPage,
+        neighbors_of: ItemPointer,
+        additional_neighbors: Vec<NeighborWithDistance>,
+        stats: &mut PruneNeighborStats,
+    ) -> Vec<NeighborWithDistance> {
+        let max_neighbors = builder.max_neighbors(meta_page);
+        let mut candidates =
+            Vec::<NeighborWithDistance>::with_capacity(max_neighbors + additional_neighbors.len());
+        builder.get_neighbors_with_full_vector_distances(neighbors_of, &mut candidates);
+
+        let mut hash: HashSet<ItemPointer> = candidates
+            .iter()
+            .map(|c| c.get_index_pointer_to_neighbor())
+            .collect();
+        for n in additional_neighbors {
+            if hash.insert(n.get_index_pointer_to_neighbor()) {
+                candidates.push(n);
+            }
+        }
+        //prevent self-loops
+        if !hash.insert(neighbors_of) {
+            let index = candidates
+                .iter()
+                .position(|x| x.get_index_pointer_to_neighbor() == neighbors_of)
+                .unwrap();
+            candidates.remove(index);
+        }
+
+        if candidates.len() > max_neighbors {
+            self.prune(meta_page, candidates, stats)
+        } else {
+            candidates
+        }
+    }
+
+    fn prune(
+        &self,
+        meta_page: &MetaPage,
+        candidates: Vec<NeighborWithDistance>,
+        stats: &mut PruneNeighborStats,
+    ) -> Vec<NeighborWithDistance> {
+        Graph::prune_neighbors_with_measure(
+            meta_page,
+            candidates,
+            stats,
+            |index_pointer, _| BuildVectorDistanceMeasure {
+                vectors: self,
+                vector: self.get(index_pointer),
+            },
+            None,
+        )
+    }
+}
@@ -1,6 +1,12 @@
 use pgrx::*;
 
-use super::{distance::preprocess_cosine, meta_page};
+use super::{
+    distance::{self, preprocess_cosine},
+    meta_page::{self, DistanceType},
+};
+
+/// The most elements a PgVectorInternal can hold, its dim is an i16 like in pgvector.
+pub const MAX_DIMENSIONS: usize = i16::MAX as usize;
 
 //Ported from pg_vector code
 #[repr(C)]
@@ -18,6 +24,182 @@ impl PgVectorInternal {
         let raw_slice = unsafe { (*self).x.as_slice(dim as _) };
         raw_slice
     }
+
+    /// Returns a palloc'd vector with the given elements.
+    ///
+    /// # Safety
+    ///
+    /// The vector is allocated in the current memory context, it has to be used while that context lives.
+    pub unsafe fn from_slice(slice: &[f32]) -> *mut PgVectorInternal {
+        if slice.len() > MAX_DIMENSIONS {
+            pgrx::error!(
+                "Vectors with {} dimensions can't be indexed, the maximum is {}",
+                slice.len(),
+                MAX_DIMENSIONS
+            );
+        }
+        let size = std::mem::size_of::<PgVectorInternal>() + std::mem::size_of_val(slice);
+        let vector = pg_sys::palloc0(size) as *mut PgVectorInternal;
+        set_varsize(vector.cast(), size as i32);
+        (*vector).dim = slice.len() as _;
+        (*vector).x.as_mut_slice(slice.len()).copy_from_slice(slice);
+        vector
+    }
+}
+
+//Ported from pg_vector code
+#[repr(C)]
+#[derive(Debug)]
+pub struct PgHalfVectorInternal {
+    vl_len_: i32, /* varlena header (do not touch directly!) */
+    pub dim: i16, /* number of dimensions */
+    unused: i16,
+    pub x: pg_sys::__IncompleteArrayField<half::f16>,
+}
+
+impl PgHalfVectorInternal {
+    pub fn to_slice(&self) -> &[half::f16] {
+        let dim = self.dim;
+        unsafe { self.x.as_slice(dim as _) }
+    }
+}
+
+//Ported from pg_vector code. The values follow the indices.
+#[repr(C)]
+#[derive(Debug)]
+pub struct PgSparseVectorInternal {
+    vl_len_: i32, /* varlena header (do not touch directly!) */
+    pub dim: i32, /* number of dimensions */
+    pub nnz: i32, /* number of non-zero elements */
+    unused: i32,
+    pub indices: pg_sys::__IncompleteArrayField<i32>,
+}
+
+impl PgSparseVectorInternal {
+    pub fn indices(&self) -> &[i32] {
+        unsafe { self.indices.as_slice(self.nnz as _) }
+    }
+
+    pub fn values(&self) -> &[f32] {
+        unsafe {
+            let values = self.indices.as_ptr().add(self.nnz as _) as *const f32;
+            std::slice::from_raw_parts(values, self.nnz as _)
+        }
+    }
+}
+
+//Ported from the Postgres VarBit code. The bits are stored most significant bit first.
+#[repr(C)]
+#[derive(Debug)]
+pub struct PgBitInternal {
+    vl_len_: i32,     /* varlena header (do not touch directly!) */
+    pub bit_len: i32, /* number of bits */
+    pub bit_dat: pg_sys::__IncompleteArrayField<u8>,
+}
+
+impl PgBitInternal {
+    /// The number of f32s holding a bit vector of bit_len bits, see to_packed.
+    pub fn packed_len(bit_len: usize) -> usize {
+        bit_len.div_ceil(64) * 2
+    }
+
+    /// The bits packed 32 to an f32, as its bit pattern, and padded with zeros to whole u64 words. The index
+    /// works with f32 vectors, this way it stores the bits in as much space as the bit vector itself and the
+    /// distances are computed on the words (see distance_hamming).
+    pub fn to_packed(&self) -> Vec<f32> {
+        let bytes = unsafe { self.bit_dat.as_slice((self.bit_len as usize).div_ceil(8)) };
+        let mut packed = vec![0.0; Self::packed_len(self.bit_len as usize)];
+        for (word, chunk) in packed.iter_mut().zip(bytes.chunks(4)) {
+            let mut word_bytes = [0; 4];
+            word_bytes[..chunk.len()].copy_from_slice(chunk);
+            *word = f32::from_bits(u32::from_le_bytes(word_bytes));
+        }
+        packed
+    }
+}
+
+/// A sparse vector: the values at the (sorted) indices, every other value is zero.
+#[derive(Clone)]
+pub struct SparseVector {
+    indices: Vec<i32>,
+    values: Vec<f32>,
+}
+
+impl SparseVector {
+    /// The dense vector the graph is built on. If the sparse vector has more dimensions than that, the
+    /// dimensions are hashed into it ("feature hashing"): every dimension adds its value, with a sign also
+    /// chosen by the hash, to one of the dense dimensions. This keeps inner products unbiased.
+    fn to_dense(&self, num_dimensions: usize, hashed: bool) -> Vec<f32> {
+        let mut dense = vec![0.0; num_dimensions];
+        for (&index, &value) in self.indices.iter().zip(self.values.iter()) {
+            if hashed {
+                let hash = hash_dimension(index);
+                let sign = if hash & 1 == 0 { 1.0 } else { -1.0 };
+                dense[(hash >> 1) as usize % num_dimensions] += sign * value;
+            } else {
+                dense[index as usize] = value;
+            }
+        }
+        dense
+    }
+
+    /// The distance between the full sparse vectors, used to resort.
+    pub fn distance(&self, other: &SparseVector, distance_type: DistanceType) -> f32 {
+        let distance_fn = match distance_type {
+            DistanceType::Cosine => distance::distance_sparse_cosine,
+            DistanceType::L2 => distance::distance_sparse_l2,
+            DistanceType::InnerProduct => distance::distance_sparse_inner_product,
+            DistanceType::Hamming | DistanceType::Jaccard => {
+                unreachable!("bit distances are not defined for sparse vectors")
+            }
+        };
+        distance_fn(&self.indices, &self.values, &other.indices, &other.values)
+    }
+}
+
+fn hash_dimension(index: i32) -> u32 {
+    let mut x = index as u32;
+    x = ((x >> 16) ^ x).wrapping_mul(0x45d9f3b);
+    x = ((x >> 16) ^ x).wrapping_mul(0x45d9f3b);
+    (x >> 16) ^ x
+}
+
+/// The type of the indexed column. The index works with f32 vectors, other types are converted.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum VectorType {
+    Vector = 0,
+    HalfVec = 1,
+    SparseVec = 2,
+    Bit = 3,
+}
+
+impl VectorType {
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            0 => VectorType::Vector,
+            1 => VectorType::HalfVec,
+            2 => VectorType::SparseVec,
+            3 => VectorType::Bit,
+            _ => panic!("Unknown VectorType number {}", value),
+        }
+    }
+
+    pub fn from_index(index: &PgRelation) -> Self {
+        let type_oid = index.tuple_desc().get(0).unwrap().atttypid;
+        let type_name = unsafe {
+            std::ffi::CStr::from_ptr(pg_sys::format_type_be(type_oid))
+                .to_string_lossy()
+                .into_owned()
+        };
+        //the name is schema-qualified if pgvector's schema isn't in the search path
+        match type_name.rsplit('.').next().unwrap() {
+            "vector" => VectorType::Vector,
+            "halfvec" => VectorType::HalfVec,
+            "sparsevec" => VectorType::SparseVec,
+            "bit" => VectorType::Bit,
+            _ => pgrx::error!("Unsupported vector type {}", type_name),
+        }
+    }
 }
 
 pub struct PgVector {
@@ -25,6 +207,8 @@ pub struct PgVector {
     index_distance_needs_pfree: bool,
     full_distance: Option<*mut PgVectorInternal>,
     full_distance_needs_pfree: bool,
+    /* the full vector of a sparsevec that is hashed into fewer dimensions, instead of full_distance */
+    full_sparse: Option<SparseVector>,
 }
 
 impl Drop for PgVector {
@@ -73,33 +257,75 @@ impl PgVector {
         meta_page: &meta_page::MetaPage,
         is_index_distance: bool,
     ) -> *mut PgVectorInternal {
-        //TODO: we are using a copy here to avoid lifetime issues and because in some cases we have to
-        //modify the datum in preprocess_cosine. We should find a way to avoid the copy if the vector is
-        //normalized and preprocess_cosine is a noop;
-        let detoasted = pg_sys::pg_detoast_datum_copy(datum.cast_mut_ptr());
-        let is_copy = !std::ptr::eq(
-            detoasted.cast::<PgVectorInternal>(),
-            datum.cast_mut_ptr::<PgVectorInternal>(),
-        );
-
-        /* if is_copy every changes, need to change needs_pfree */
-        assert!(is_copy, "Datum should be a copy");
-        let casted = detoasted.cast::<PgVectorInternal>();
+        let casted = match meta_page.get_vector_type() {
+            VectorType::Vector => {
+                //TODO: we are using a copy here to avoid lifetime issues and because in some cases we have to
+                //modify the datum in preprocess_cosine. We should find a way to avoid the copy if the vector is
+                //normalized and preprocess_cosine is a noop;
+                let detoasted = pg_sys::pg_detoast_datum_copy(datum.cast_mut_ptr());
+                let is_copy = !std::ptr::eq(
+                    detoasted.cast::<PgVectorInternal>(),
+                    datum.cast_mut_ptr::<PgVectorInternal>(),
+                );
+
+                /* if is_copy every changes, need to change needs_pfree */
+                assert!(is_copy, "Datum should be a copy");
+                detoasted.cast::<PgVectorInternal>()
+            }
+            VectorType::SparseVec => {
+                let sparse = Self::read_sparse(datum);
+                let num_dimensions_to_index = meta_page.get_num_dimensions_to_index() as usize;
+                let hashed = meta_page.get_num_dimensions() as usize != num_dimensions_to_index;
+                PgVectorInternal::from_slice(&sparse.to_dense(num_dimensions_to_index, hashed))
+            }
+            VectorType::HalfVec => {
+                let detoasted = pg_sys::pg_detoast_datum(datum.cast_mut_ptr());
+                let half = detoasted.cast::<PgHalfVectorInternal>();
+                let full: Vec<f32> = (*half).to_slice().iter().map(|x| x.to_f32()).collect();
+                if !std::ptr::eq(detoasted, datum.cast_mut_ptr()) {
+                    pg_sys::pfree(detoasted.cast());
+                }
+                PgVectorInternal::from_slice(&full)
+            }
+            VectorType::Bit => {
+                let detoasted = pg_sys::pg_detoast_datum(datum.cast_mut_ptr());
+                let bits = (*detoasted.cast::<PgBitInternal>()).to_packed();
+                if !std::ptr::eq(detoasted, datum.cast_mut_ptr()) {
+                    pg_sys::pfree(detoasted.cast());
+                }
+                PgVectorInternal::from_slice(&bits)
+            }
+        };
 
         if is_index_distance
             && meta_page.get_num_dimensions() != meta_page.get_num_dimensions_to_index()
+            && meta_page.get_vector_type() != VectorType::SparseVec
         {
             assert!((*casted).dim > meta_page.get_num_dimensions_to_index() as _);
             (*casted).dim = meta_page.get_num_dimensions_to_index() as _;
         }
 
-        let dim = (*casted).dim;
-        let raw_slice = unsafe { (*casted).x.as_mut_slice(dim as _) };
-
-        preprocess_cosine(raw_slice);
+        if meta_page.get_distance_type() == DistanceType::Cosine {
+            let dim = (*casted).dim;
+            let raw_slice = unsafe { (*casted).x.as_mut_slice(dim as _) };
+            preprocess_cosine(raw_slice);
+        }
         casted
     }
 
+    unsafe fn read_sparse(datum: pg_sys::Datum) -> SparseVector {
+        let detoasted = pg_sys::pg_detoast_datum(datum.cast_mut_ptr());
+        let sparse = detoasted.cast::<PgSparseVectorInternal>();
+        let res = SparseVector {
+            indices: (*sparse).indices().to_vec(),
+            values: (*sparse).values().to_vec(),
+        };
+        if !std::ptr::eq(detoasted, datum.cast_mut_ptr()) {
+            pg_sys::pfree(detoasted.cast());
+        }
+        res
+    }
+
     pub unsafe fn from_datum(
         datum: pg_sys::Datum,
         meta_page: &meta_page::MetaPage,
@@ -114,6 +340,31 @@ impl PgVector {
                 index_distance_needs_pfree: true,
                 full_distance: Some(inner),
                 full_distance_needs_pfree: false,
+                full_sparse: None,
+            };
+        }
+
+        if meta_page.get_vector_type() == VectorType::SparseVec {
+            let idx = if index_distance {
+                Some(Self::create_inner(datum, meta_page, true))
+            } else {
+                None
+            };
+            let full_sparse = if full_distance {
+                let mut sparse = Self::read_sparse(datum);
+                if meta_page.get_distance_type() == DistanceType::Cosine {
+                    preprocess_cosine(&mut sparse.values);
+                }
+                Some(sparse)
+            } else {
+                None
+            };
+            return PgVector {
+                index_distance: idx,
+                index_distance_needs_pfree: true,
+                full_distance: None,
+                full_distance_needs_pfree: false,
+                full_sparse,
             };
         }
 
@@ -134,6 +385,27 @@ impl PgVector {
             index_distance_needs_pfree: true,
             full_distance: full,
             full_distance_needs_pfree: true,
+            full_sparse: None,
+        }
+    }
+
+    /// Returns a copy with its own (palloc'd) vectors, for searching the graph with the same query twice.
+    pub fn copy(&self) -> PgVector {
+        let copy =
+            |v: *mut PgVectorInternal| unsafe { PgVectorInternal::from_slice((*v).to_slice()) };
+        let index_distance = self.index_distance.map(copy);
+        let shared = self.index_distance == self.full_distance;
+        let full_distance = if shared {
+            index_distance
+        } else {
+            self.full_distance.map(copy)
+        };
+        PgVector {
+            index_distance,
+            index_distance_needs_pfree: true,
+            full_distance,
+            full_distance_needs_pfree: !shared,
+            full_sparse: self.full_sparse.clone(),
         }
     }
 
@@ -144,4 +416,12 @@ impl PgVector {
     pub fn to_full_slice(&self) -> &[f32] {
         unsafe { (*self.full_distance.unwrap()).to_slice() }
     }
+
+    /// The distance between the full vectors, used to resort.
+    pub fn get_full_distance(&self, other: &PgVector, meta_page: &meta_page::MetaPage) -> f32 {
+        match (&self.full_sparse, &other.full_sparse) {
+            (Some(a), Some(b)) => a.distance(b, meta_page.get_distance_type()),
+            _ => meta_page.get_distance_function()(self.to_full_slice(), other.to_full_slice()),
+        }
+    }
 }
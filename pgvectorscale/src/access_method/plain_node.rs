@@ -111,6 +111,10 @@ impl ArchivedData for ArchivedNode {
         ArchivedNode::with_data(data)
     }
 
+    fn with_readable_data(data: &[u8]) -> &ArchivedNode {
+        unsafe { rkyv::archived_root::<Node>(data) }
+    }
+
     fn get_index_pointer_to_neighbors(&self) -> Vec<ItemPointer> {
         self.iter_neighbors().collect()
     }
@@ -1,6 +1,9 @@
 use super::{
+    full_vector::get_full_vector_distance,
     graph::{ListSearchNeighbor, ListSearchResult},
     graph_neighbor_store::GraphNeighborStore,
+    labels::{read_labels, LabelSet},
+    parallel_build::BuildVector,
     pg_vector::PgVector,
     plain_node::{ArchivedNode, Node, ReadableNode},
     stats::{
@@ -171,13 +174,14 @@ impl<'a> Storage for PlainStorage<'a> {
         &self,
         full_vector: &[f32],
         heap_pointer: HeapPointer,
+        scalar_columns: &[u8],
         meta_page: &MetaPage,
         tape: &mut Tape,
         stats: &mut S,
     ) -> ItemPointer {
         //OPT: avoid the clone?
         let node = Node::new_for_full_vector(full_vector.to_vec(), heap_pointer, meta_page);
-        let index_pointer: IndexPointer = node.write(tape, stats);
+        let index_pointer: IndexPointer = node.write_with_prefix(scalar_columns, tape, stats);
         index_pointer
     }
 
@@ -211,10 +215,12 @@ impl<'a> Storage for PlainStorage<'a> {
     fn get_query_distance_measure(&self, query: PgVector) -> PlainDistanceMeasure {
         return PlainDistanceMeasure::Full(query);
     }
-    fn get_full_distance_for_resort<S: StatsHeapNodeRead + StatsDistanceComparison>(
+    fn get_full_distance_for_resort<
+        S: StatsNodeRead + StatsHeapNodeRead + StatsDistanceComparison,
+    >(
         &self,
         qdm: &Self::QueryDistanceMeasure,
-        _index_pointer: IndexPointer,
+        index_pointer: IndexPointer,
         heap_pointer: HeapPointer,
         meta_page: &MetaPage,
         stats: &mut S,
@@ -222,12 +228,19 @@ impl<'a> Storage for PlainStorage<'a> {
         /* Plain storage only needs to resort when the index is using less dimensions than the underlying data. */
         assert!(meta_page.get_num_dimensions() > meta_page.get_num_dimensions_to_index());
 
+        if meta_page.get_full_vectors_in_index() {
+            let PlainDistanceMeasure::Full(query) = qdm;
+            return unsafe {
+                get_full_vector_distance(self.index, index_pointer, query, meta_page, stats)
+            };
+        }
+
         let slot = unsafe { TableSlot::new(self.heap_rel, heap_pointer, stats) };
         match qdm {
             PlainDistanceMeasure::Full(query) => {
                 let datum = unsafe { slot.get_attribute(self.heap_attr).unwrap() };
                 let vec = unsafe { PgVector::from_datum(datum, meta_page, false, true) };
-                self.get_distance_function()(vec.to_full_slice(), query.to_full_slice())
+                vec.get_full_distance(query, meta_page)
             }
         }
     }
@@ -336,9 +349,40 @@ impl<'a> Storage for PlainStorage<'a> {
         node.commit();
     }
 
+    fn replace_neighbors_on_disk<S: StatsNodeModify + StatsNodeRead>(
+        &self,
+        meta: &MetaPage,
+        index_pointer: IndexPointer,
+        expected: &[IndexPointer],
+        neighbors: &[NeighborWithDistance],
+        stats: &mut S,
+    ) -> bool {
+        let node = unsafe { Node::modify(self.index, index_pointer, stats) };
+        let mut archived = node.get_archived_node();
+        if archived.get_index_pointer_to_neighbors() != expected {
+            return false;
+        }
+        archived.as_mut().set_neighbors(neighbors, meta);
+        node.commit();
+        true
+    }
+
     fn get_distance_function(&self) -> fn(&[f32], &[f32]) -> f32 {
         self.distance_fn
     }
+
+    fn get_build_vector(&self, full_vector: &[f32]) -> BuildVector {
+        BuildVector::Full(full_vector.to_vec())
+    }
+
+    fn get_labels(&self, index_pointer: IndexPointer) -> LabelSet {
+        unsafe { read_labels(self.index, index_pointer) }
+    }
+
+    fn is_deleted<S: StatsNodeRead>(&self, index_pointer: IndexPointer, stats: &mut S) -> bool {
+        let rn = unsafe { Node::read(self.index, index_pointer, stats) };
+        rn.get_archived_node().is_deleted()
+    }
 }
 
 #[cfg(any(test, feature = "pg_test"))]
@@ -378,6 +422,36 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_plain_storage_delete_vacuum_repair() {
+        crate::access_method::vacuum::tests::test_delete_vacuum_repair_scaffold(
+            "num_neighbors = 38, storage_layout = plain",
+        );
+    }
+
+    #[test]
+    fn test_plain_storage_delete_vacuum_reuse() {
+        crate::access_method::vacuum::tests::test_delete_vacuum_reuse_scaffold(
+            "num_neighbors = 38, storage_layout = plain",
+            64,
+        );
+    }
+
+    #[test]
+    fn test_plain_storage_delete_vacuum_concurrent_scan() {
+        crate::access_method::vacuum::tests::test_delete_vacuum_concurrent_scan_scaffold(
+            "num_neighbors = 38, storage_layout = plain",
+        );
+    }
+
+    #[test]
+    fn test_plain_storage_delete_vacuum_reuse_chained() {
+        crate::access_method::vacuum::tests::test_delete_vacuum_reuse_scaffold(
+            "num_neighbors = 38, storage_layout = plain",
+            3000,
+        );
+    }
+
     #[pg_test]
     unsafe fn test_plain_storage_empty_table_insert() -> spi::Result<()> {
         crate::access_method::build::tests::test_empty_table_insert_scaffold(
@@ -385,6 +459,13 @@ mod tests {
         )
     }
 
+    #[pg_test]
+    unsafe fn test_plain_storage_unlogged_table() -> spi::Result<()> {
+        crate::access_method::build::tests::test_unlogged_table_scaffold(
+            "num_neighbors=38, storage_layout = plain",
+        )
+    }
+
     #[pg_test]
     unsafe fn test_plain_storage_insert_empty_insert() -> spi::Result<()> {
         crate::access_method::build::tests::test_insert_empty_insert_scaffold(
@@ -408,4 +489,117 @@ mod tests {
         )?;
         Ok(())
     }
+
+    #[pg_test]
+    unsafe fn test_plain_storage_inner_product() -> spi::Result<()> {
+        crate::access_method::build::tests::test_distance_operator_scaffold(
+            "num_neighbors=38, storage_layout = plain",
+            "vector_ip_ops",
+            "<#>",
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_plain_storage_l2() -> spi::Result<()> {
+        crate::access_method::build::tests::test_distance_operator_scaffold(
+            "num_neighbors=38, storage_layout = plain",
+            "vector_l2_ops",
+            "<->",
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_plain_storage_parallel_build() -> spi::Result<()> {
+        crate::access_method::build::tests::test_parallel_build_scaffold(
+            "num_neighbors=38, storage_layout = plain",
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_plain_storage_include_columns() -> spi::Result<()> {
+        crate::access_method::build::tests::test_include_columns_scaffold(
+            "num_neighbors=38, storage_layout = plain",
+            "vector_l2_ops",
+            "<->",
+            true,
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_plain_storage_include_columns_cosine() -> spi::Result<()> {
+        crate::access_method::build::tests::test_include_columns_scaffold(
+            "num_neighbors=38, storage_layout = plain",
+            "vector_cosine_ops",
+            "<=>",
+            false,
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_plain_storage_filtered_search() -> spi::Result<()> {
+        crate::access_method::build::tests::test_filtered_search_scaffold(
+            "num_neighbors=38, storage_layout = plain",
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_plain_storage_range_search() -> spi::Result<()> {
+        crate::access_method::build::tests::test_range_search_scaffold(
+            "num_neighbors=38, storage_layout = plain",
+            "vector_l2_ops",
+            "<->",
+            "<<->>",
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_plain_storage_halfvec() -> spi::Result<()> {
+        crate::access_method::build::tests::test_halfvec_scaffold(
+            "num_neighbors=38, storage_layout = plain",
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_plain_storage_sparsevec() -> spi::Result<()> {
+        crate::access_method::build::tests::test_sparsevec_scaffold(
+            "num_neighbors=38, storage_layout = plain",
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_plain_storage_bit() -> spi::Result<()> {
+        crate::access_method::build::tests::test_bit_scaffold(
+            "num_neighbors=38, storage_layout = plain",
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_plain_storage_bit_high_dimensions() -> spi::Result<()> {
+        crate::access_method::build::tests::test_bit_high_dimensions_scaffold(
+            "num_neighbors=38, storage_layout = plain",
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_plain_storage_high_dimensions() -> spi::Result<()> {
+        crate::access_method::build::tests::test_high_dimensions_scaffold(
+            "num_neighbors=38, storage_layout = plain",
+            3072,
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_plain_storage_many_pages_per_node() -> spi::Result<()> {
+        crate::access_method::build::tests::test_high_dimensions_scaffold(
+            "num_neighbors=38, storage_layout = plain",
+            10000,
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_plain_storage_labels() -> spi::Result<()> {
+        crate::access_method::build::tests::test_labels_scaffold(
+            "num_neighbors=38, storage_layout = plain",
+        )
+    }
 }
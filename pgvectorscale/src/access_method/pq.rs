@@ -0,0 +1,957 @@
+use super::{
+    distance::{
+        distance_inner_product, distance_l2_optimized_for_few_dimensions, preprocess_cosine,
+    },
+    full_vector::get_full_vector_distance,
+    graph::{ListSearchNeighbor, ListSearchResult},
+    graph_neighbor_store::GraphNeighborStore,
+    labels::{read_labels, LabelSet},
+    meta_page::DistanceType,
+    parallel_build::BuildVector,
+    pg_vector::PgVector,
+    stats::{
+        GreedySearchStats, StatsDistanceComparison, StatsHeapNodeRead, StatsNodeModify,
+        StatsNodeRead, StatsNodeWrite, WriteStats,
+    },
+    storage::{ArchivedData, NodeDistanceMeasure, Storage},
+    storage_common::get_attribute_number_from_index,
+};
+use std::{borrow::Cow, cell::RefCell, collections::HashMap, marker::PhantomData, pin::Pin};
+
+use ndarray::{ArrayView2, Axis};
+use pgrx::{
+    pg_sys::{InvalidBlockNumber, InvalidOffsetNumber},
+    PgRelation,
+};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use reductive::pq::{Pq, TrainPq};
+use rkyv::{vec::ArchivedVec, Archive, Deserialize, Serialize};
+
+use crate::util::{
+    page::PageType, table_slot::TableSlot, tape::Tape, ArchivedItemPointer, HeapPointer,
+    IndexPointer, ItemPointer, ReadableBuffer,
+};
+
+use super::{meta_page::MetaPage, neighbor_with_distance::NeighborWithDistance};
+use crate::util::WritableBuffer;
+
+/// Every subvector of this many dimensions is quantized to one byte, the index of its nearest centroid.
+/// Vectors are padded with zeros to a multiple of it, which doesn't change any of the distances.
+const PQ_DIMENSIONS_PER_SUBVECTOR: usize = 8;
+const PQ_NUM_SUBQUANTIZER_BITS: u32 = 8;
+/// The codebooks are trained on a random sample of at most this many vectors.
+const PQ_MAX_TRAINING_SAMPLES: usize = 10_000;
+const PQ_TRAINING_ITERATIONS: usize = 10;
+const PQ_TRAINING_ATTEMPTS: usize = 1;
+/* a fixed seed, so that building the same table twice gives the same index */
+const PQ_TRAINING_SEED: u64 = 0x7476_5f70_7100;
+
+#[derive(Archive, Deserialize, Serialize, Readable, Writeable)]
+#[archive(check_bytes)]
+#[repr(C)]
+pub struct PqCodebooks {
+    dimensions_per_subvector: u32,
+    num_subvectors: u32,
+    num_centroids: u32,
+    centroids: Vec<f32>,
+}
+
+impl PqCodebooks {
+    pub unsafe fn load<S: StatsNodeRead>(
+        index: &PgRelation,
+        meta_page: &super::meta_page::MetaPage,
+        stats: &mut S,
+    ) -> PqQuantizer {
+        let mut quantizer = PqQuantizer::new(meta_page);
+        if meta_page.get_quantizer_metadata_pointer().is_none() {
+            //only the empty index of an unlogged table (see ambuildempty) has no codebooks. These are the
+            //codebooks an empty build stores.
+            quantizer.start_training(meta_page);
+            quantizer.finish_training();
+            return quantizer;
+        }
+        let quantizer_item_pointer = meta_page.get_quantizer_metadata_pointer().unwrap();
+        let codebooks = PqCodebooks::read(index, quantizer_item_pointer, stats);
+        let archived = codebooks.get_archived_node();
+
+        assert_eq!(
+            archived.dimensions_per_subvector as usize,
+            PQ_DIMENSIONS_PER_SUBVECTOR
+        );
+        assert_eq!(archived.num_subvectors as usize, quantizer.num_subvectors);
+        quantizer.load(archived.num_centroids as usize, archived.centroids.to_vec());
+        quantizer
+    }
+
+    pub unsafe fn store<S: StatsNodeWrite>(
+        index: &PgRelation,
+        quantizer: &PqQuantizer,
+        stats: &mut S,
+    ) -> ItemPointer {
+        //the codebooks of large vectors don't fit on a page, they are chained over several (see Tape::write)
+        let mut tape = Tape::new(index, PageType::PqQuantizerDef);
+        let node = PqCodebooks {
+            dimensions_per_subvector: PQ_DIMENSIONS_PER_SUBVECTOR as u32,
+            num_subvectors: quantizer.num_subvectors as u32,
+            num_centroids: quantizer.num_centroids as u32,
+            centroids: quantizer.centroids.clone(),
+        };
+        let ptr = node.write(&mut tape, stats);
+        tape.close();
+        ptr
+    }
+}
+
+/// The state of the training of the codebooks during a build.
+#[derive(Clone)]
+struct PqTraining {
+    /// A reservoir sample of the padded vectors seen so far.
+    samples: Vec<f32>,
+    num_samples_seen: u64,
+    rng: ChaCha8Rng,
+}
+
+/// Product quantization: a vector is split into subvectors and each subvector is replaced by the index of the
+/// nearest of the centroids trained for its position (its codebook).
+#[derive(Clone)]
+pub struct PqQuantizer {
+    distance_type: DistanceType,
+    training: Option<Box<PqTraining>>,
+    num_dimensions: usize,
+    num_subvectors: usize,
+    num_centroids: usize,
+    /// The centroids of all codebooks. Centroid c of subvector s starts at
+    /// (s * num_centroids + c) * PQ_DIMENSIONS_PER_SUBVECTOR.
+    centroids: Vec<f32>,
+}
+
+impl PqQuantizer {
+    fn new(meta_page: &super::meta_page::MetaPage) -> PqQuantizer {
+        let num_dimensions = meta_page.get_num_dimensions_to_index() as usize;
+        Self {
+            distance_type: meta_page.get_distance_type(),
+            training: None,
+            num_dimensions,
+            num_subvectors: num_dimensions.div_ceil(PQ_DIMENSIONS_PER_SUBVECTOR),
+            num_centroids: 0,
+            centroids: vec![],
+        }
+    }
+
+    fn load(&mut self, num_centroids: usize, centroids: Vec<f32>) {
+        assert_eq!(
+            centroids.len(),
+            self.num_subvectors * num_centroids * PQ_DIMENSIONS_PER_SUBVECTOR
+        );
+        self.num_centroids = num_centroids;
+        self.centroids = centroids;
+    }
+
+    fn padded_len(&self) -> usize {
+        self.num_subvectors * PQ_DIMENSIONS_PER_SUBVECTOR
+    }
+
+    fn pad(&self, full_vector: &[f32]) -> Vec<f32> {
+        assert_eq!(full_vector.len(), self.num_dimensions);
+        let mut padded = full_vector.to_vec();
+        padded.resize(self.padded_len(), 0.0);
+        padded
+    }
+
+    fn centroid(&self, subvector: usize, centroid: usize) -> &[f32] {
+        let start = (subvector * self.num_centroids + centroid) * PQ_DIMENSIONS_PER_SUBVECTOR;
+        &self.centroids[start..start + PQ_DIMENSIONS_PER_SUBVECTOR]
+    }
+
+    fn quantize(&self, full_vector: &[f32]) -> Vec<u8> {
+        assert!(self.training.is_none());
+        self.pad(full_vector)
+            .chunks(PQ_DIMENSIONS_PER_SUBVECTOR)
+            .enumerate()
+            .map(|(s, subvector)| {
+                (0..self.num_centroids)
+                    .map(|c| {
+                        let d = distance_l2_optimized_for_few_dimensions(
+                            subvector,
+                            self.centroid(s, c),
+                        );
+                        (c, d)
+                    })
+                    .min_by(|a, b| a.1.total_cmp(&b.1))
+                    .unwrap()
+                    .0 as u8
+            })
+            .collect()
+    }
+
+    /// The vector a quantized vector stands for.
+    fn reconstruct(&self, pq_vector: &[u8]) -> Vec<f32> {
+        let mut res: Vec<f32> = pq_vector
+            .iter()
+            .enumerate()
+            .flat_map(|(s, &c)| self.centroid(s, c as usize).iter().copied())
+            .collect();
+        res.truncate(self.num_dimensions);
+        //the cosine distance is only defined on normalized vectors
+        if self.distance_type == DistanceType::Cosine {
+            preprocess_cosine(&mut res);
+        }
+        res
+    }
+
+    fn start_training(&mut self, meta_page: &super::meta_page::MetaPage) {
+        self.num_dimensions = meta_page.get_num_dimensions_to_index() as usize;
+        self.training = Some(Box::new(PqTraining {
+            samples: vec![],
+            num_samples_seen: 0,
+            rng: ChaCha8Rng::seed_from_u64(PQ_TRAINING_SEED),
+        }));
+    }
+
+    fn add_sample(&mut self, sample: &[f32]) {
+        let padded = self.pad(sample);
+        let training = self
+            .training
+            .as_mut()
+            .expect("the quantizer isn't training");
+        training.num_samples_seen += 1;
+        let len = padded.len();
+        let num_samples = training.samples.len() / len;
+        if num_samples < PQ_MAX_TRAINING_SAMPLES {
+            training.samples.extend_from_slice(&padded);
+        } else {
+            //reservoir sampling: every vector seen so far is in the sample with the same probability
+            let replace = training.rng.gen_range(0..training.num_samples_seen) as usize;
+            if replace < PQ_MAX_TRAINING_SAMPLES {
+                training.samples[replace * len..(replace + 1) * len].copy_from_slice(&padded);
+            }
+        }
+    }
+
+    fn finish_training(&mut self) {
+        let mut training = self.training.take().expect("the quantizer isn't training");
+        let len = self.padded_len();
+        let num_samples = training.samples.len() / len;
+        //k-means can't find more centroids than there are samples
+        let num_bits = PQ_NUM_SUBQUANTIZER_BITS.min((num_samples as f64).log2().max(0.0) as u32);
+
+        if num_bits == 0 {
+            //a single centroid per subvector: the only sample, or zeros for an empty table
+            self.num_centroids = 1;
+            self.centroids = if num_samples == 1 {
+                training.samples
+            } else {
+                vec![0.0; len]
+            };
+        } else {
+            let instances = ArrayView2::from_shape((num_samples, len), &training.samples).unwrap();
+            let pq: Pq<f32> = Pq::train_pq_using(
+                self.num_subvectors,
+                num_bits,
+                PQ_TRAINING_ITERATIONS,
+                PQ_TRAINING_ATTEMPTS,
+                instances,
+                &mut training.rng,
+            )
+            .unwrap_or_else(|e| pgrx::error!("failed to train the PQ codebooks: {}", e));
+            let codebooks = pq.subquantizers();
+            self.num_centroids = codebooks.len_of(Axis(1));
+            //the codebooks are in standard layout, so this iterates in the order of self.centroids
+            self.centroids = codebooks.iter().copied().collect();
+        }
+    }
+}
+
+/// Asymmetric distance computation (ADC): the distances between the subvectors of the query and all the centroids
+/// are calculated once, the distance to a quantized vector is then the sum of a lookup per subvector.
+pub struct PqSearchDistanceMeasure {
+    query: PgVector,
+    /// The partial distance to centroid c of subvector s is at s * num_centroids + c.
+    lookup_table: Vec<f32>,
+    num_centroids: usize,
+    offset: f32,
+}
+
+impl PqSearchDistanceMeasure {
+    pub fn new(quantizer: &PqQuantizer, query: PgVector) -> PqSearchDistanceMeasure {
+        let padded = quantizer.pad(query.to_index_slice());
+        let mut lookup_table =
+            Vec::with_capacity(quantizer.num_subvectors * quantizer.num_centroids);
+        for (s, subvector) in padded.chunks(PQ_DIMENSIONS_PER_SUBVECTOR).enumerate() {
+            for c in 0..quantizer.num_centroids {
+                let centroid = quantizer.centroid(s, c);
+                lookup_table.push(match quantizer.distance_type {
+                    DistanceType::L2 => {
+                        distance_l2_optimized_for_few_dimensions(subvector, centroid)
+                    }
+                    //both are sums over the products of the dimensions
+                    DistanceType::Cosine | DistanceType::InnerProduct => {
+                        distance_inner_product(subvector, centroid)
+                    }
+                    DistanceType::Hamming | DistanceType::Jaccard => {
+                        unreachable!("bit vectors can't be indexed with the pq storage layout")
+                    }
+                });
+            }
+        }
+
+        PqSearchDistanceMeasure {
+            query,
+            lookup_table,
+            num_centroids: quantizer.num_centroids,
+            //the cosine distance is 1 - the inner product
+            offset: if quantizer.distance_type == DistanceType::Cosine {
+                1.0
+            } else {
+                0.0
+            },
+        }
+    }
+
+    pub fn calculate_pq_distance<S: StatsDistanceComparison>(
+        &self,
+        pq_vector: &[u8],
+        stats: &mut S,
+    ) -> f32 {
+        debug_assert_eq!(
+            pq_vector.len() * self.num_centroids,
+            self.lookup_table.len()
+        );
+        stats.record_quantized_distance_comparison();
+        let sum: f32 = pq_vector
+            .iter()
+            .enumerate()
+            .map(|(s, &c)| self.lookup_table[s * self.num_centroids + c as usize])
+            .sum();
+        self.offset + sum
+    }
+}
+
+pub struct PqNodeDistanceMeasure<'a> {
+    vec: Vec<f32>,
+    storage: &'a PqStorage<'a>,
+}
+
+impl<'a> PqNodeDistanceMeasure<'a> {
+    pub unsafe fn with_index_pointer<T: StatsNodeRead>(
+        storage: &'a PqStorage<'a>,
+        index_pointer: IndexPointer,
+        stats: &mut T,
+    ) -> Self {
+        let cache = &mut storage.qv_cache.borrow_mut();
+        Self {
+            vec: storage
+                .quantizer
+                .reconstruct(cache.get(index_pointer, storage, stats)),
+            storage,
+        }
+    }
+}
+
+impl<'a> NodeDistanceMeasure for PqNodeDistanceMeasure<'a> {
+    unsafe fn get_distance<T: StatsNodeRead + StatsDistanceComparison>(
+        &self,
+        index_pointer: IndexPointer,
+        stats: &mut T,
+    ) -> f32 {
+        let cache = &mut self.storage.qv_cache.borrow_mut();
+        let vec1 =
+            self.storage
+                .quantizer
+                .reconstruct(cache.get(index_pointer, self.storage, stats));
+        stats.record_quantized_distance_comparison();
+        (self.storage.distance_fn)(vec1.as_slice(), self.vec.as_slice())
+    }
+}
+
+struct PqVectorCache {
+    pq_vector_map: HashMap<ItemPointer, Vec<u8>>,
+}
+
+/* like the QuantizedVectorCache of SBQ, this never evicts */
+impl PqVectorCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            pq_vector_map: HashMap::with_capacity(capacity),
+        }
+    }
+
+    fn get<S: StatsNodeRead>(
+        &mut self,
+        index_pointer: IndexPointer,
+        storage: &PqStorage,
+        stats: &mut S,
+    ) -> &[u8] {
+        self.pq_vector_map
+            .entry(index_pointer)
+            .or_insert_with(|| storage.get_pq_vector_from_index_pointer(index_pointer, stats))
+    }
+}
+
+pub struct PqStorage<'a> {
+    pub index: &'a PgRelation,
+    pub distance_fn: fn(&[f32], &[f32]) -> f32,
+    /* the codebooks can be large, a search borrows them from the scan state instead of copying them */
+    quantizer: Cow<'a, PqQuantizer>,
+    heap_rel: &'a PgRelation,
+    heap_attr: pgrx::pg_sys::AttrNumber,
+    qv_cache: RefCell<PqVectorCache>,
+}
+
+impl<'a> PqStorage<'a> {
+    pub fn new_for_build(
+        index: &'a PgRelation,
+        heap_rel: &'a PgRelation,
+        meta_page: &super::meta_page::MetaPage,
+    ) -> PqStorage<'a> {
+        Self {
+            index,
+            distance_fn: meta_page.get_distance_function(),
+            quantizer: Cow::Owned(PqQuantizer::new(meta_page)),
+            heap_rel,
+            heap_attr: get_attribute_number_from_index(index),
+            qv_cache: RefCell::new(PqVectorCache::new(1000)),
+        }
+    }
+
+    pub fn load_for_insert<S: StatsNodeRead>(
+        heap_rel: &'a PgRelation,
+        index_relation: &'a PgRelation,
+        meta_page: &super::meta_page::MetaPage,
+        stats: &mut S,
+    ) -> PqStorage<'a> {
+        Self {
+            index: index_relation,
+            distance_fn: meta_page.get_distance_function(),
+            quantizer: Cow::Owned(unsafe { PqCodebooks::load(index_relation, meta_page, stats) }),
+            heap_rel,
+            heap_attr: get_attribute_number_from_index(index_relation),
+            qv_cache: RefCell::new(PqVectorCache::new(1000)),
+        }
+    }
+
+    pub fn load_for_search(
+        index_relation: &'a PgRelation,
+        heap_relation: &'a PgRelation,
+        quantizer: &'a PqQuantizer,
+        meta_page: &super::meta_page::MetaPage,
+    ) -> PqStorage<'a> {
+        Self {
+            index: index_relation,
+            distance_fn: meta_page.get_distance_function(),
+            quantizer: Cow::Borrowed(quantizer),
+            heap_rel: heap_relation,
+            heap_attr: get_attribute_number_from_index(index_relation),
+            qv_cache: RefCell::new(PqVectorCache::new(1000)),
+        }
+    }
+
+    fn get_pq_vector_from_index_pointer<S: StatsNodeRead>(
+        &self,
+        index_pointer: IndexPointer,
+        stats: &mut S,
+    ) -> Vec<u8> {
+        let rn = unsafe { PqNode::read(self.index, index_pointer, stats) };
+        let node = rn.get_archived_node();
+        node.pq_vector.as_slice().to_vec()
+    }
+
+    fn write_quantizer_metadata<S: StatsNodeWrite + StatsNodeModify>(&self, stats: &mut S) {
+        let index_pointer = unsafe { PqCodebooks::store(self.index, &self.quantizer, stats) };
+        super::meta_page::MetaPage::update_quantizer_metadata_pointer(
+            self.index,
+            index_pointer,
+            stats,
+        );
+    }
+
+    fn visit_lsn_internal(
+        &self,
+        lsr: &mut ListSearchResult<
+            <PqStorage<'a> as Storage>::QueryDistanceMeasure,
+            <PqStorage<'a> as Storage>::LSNPrivateData,
+        >,
+        lsn_index_pointer: IndexPointer,
+        gns: &GraphNeighborStore,
+    ) {
+        match gns {
+            GraphNeighborStore::Disk => {
+                let rn_visiting =
+                    unsafe { PqNode::read(self.index, lsn_index_pointer, &mut lsr.stats) };
+                let node_visiting = rn_visiting.get_archived_node();
+                let neighbors = node_visiting.get_index_pointer_to_neighbors();
+
+                for &neighbor_index_pointer in neighbors.iter() {
+                    if !lsr.prepare_insert(neighbor_index_pointer) {
+                        continue;
+                    }
+
+                    let rn_neighbor =
+                        unsafe { PqNode::read(self.index, neighbor_index_pointer, &mut lsr.stats) };
+                    let node_neighbor = rn_neighbor.get_archived_node();
+                    let distance = lsr
+                        .sdm
+                        .as_ref()
+                        .unwrap()
+                        .calculate_pq_distance(node_neighbor.pq_vector.as_slice(), &mut lsr.stats);
+
+                    let lsn = ListSearchNeighbor::new(
+                        neighbor_index_pointer,
+                        distance,
+                        PhantomData::<bool>,
+                    );
+
+                    lsr.insert_neighbor(lsn);
+                }
+            }
+            GraphNeighborStore::Builder(b) => {
+                let neighbors = b.get_neighbors(lsn_index_pointer);
+                for &neighbor_index_pointer in neighbors.iter() {
+                    if !lsr.prepare_insert(neighbor_index_pointer) {
+                        continue;
+                    }
+                    let mut cache = self.qv_cache.borrow_mut();
+                    let pq_vector = cache.get(neighbor_index_pointer, self, &mut lsr.stats);
+                    let distance = lsr
+                        .sdm
+                        .as_ref()
+                        .unwrap()
+                        .calculate_pq_distance(pq_vector, &mut lsr.stats);
+
+                    let lsn = ListSearchNeighbor::new(
+                        neighbor_index_pointer,
+                        distance,
+                        PhantomData::<bool>,
+                    );
+
+                    lsr.insert_neighbor(lsn);
+                }
+            }
+        }
+    }
+
+    unsafe fn get_heap_table_slot_from_heap_pointer<T: StatsHeapNodeRead>(
+        &self,
+        heap_pointer: HeapPointer,
+        stats: &mut T,
+    ) -> TableSlot {
+        TableSlot::new(self.heap_rel, heap_pointer, stats)
+    }
+}
+
+pub type PqStorageLsnPrivateData = PhantomData<bool>; //no data stored
+
+impl<'a> Storage for PqStorage<'a> {
+    type QueryDistanceMeasure = PqSearchDistanceMeasure;
+    type NodeDistanceMeasure<'b> = PqNodeDistanceMeasure<'b> where Self: 'b;
+    type ArchivedType = ArchivedPqNode;
+    type LSNPrivateData = PqStorageLsnPrivateData; //no data stored
+
+    fn page_type() -> PageType {
+        PageType::PqNode
+    }
+
+    fn create_node<S: StatsNodeWrite>(
+        &self,
+        full_vector: &[f32],
+        heap_pointer: HeapPointer,
+        scalar_columns: &[u8],
+        meta_page: &MetaPage,
+        tape: &mut Tape,
+        stats: &mut S,
+    ) -> ItemPointer {
+        let pq_vector = self.quantizer.quantize(full_vector);
+
+        let node = PqNode::new(
+            heap_pointer,
+            meta_page.get_num_neighbors() as usize,
+            pq_vector,
+        );
+
+        let index_pointer: IndexPointer = node.write_with_prefix(scalar_columns, tape, stats);
+        index_pointer
+    }
+
+    fn start_training(&mut self, meta_page: &super::meta_page::MetaPage) {
+        self.quantizer.to_mut().start_training(meta_page);
+    }
+
+    fn add_sample(&mut self, sample: &[f32]) {
+        self.quantizer.to_mut().add_sample(sample);
+    }
+
+    fn finish_training(&mut self, stats: &mut WriteStats) {
+        self.quantizer.to_mut().finish_training();
+        self.write_quantizer_metadata(stats);
+    }
+
+    fn finalize_node_at_end_of_build<S: StatsNodeRead + StatsNodeModify>(
+        &mut self,
+        meta: &MetaPage,
+        index_pointer: IndexPointer,
+        neighbors: &Vec<NeighborWithDistance>,
+        stats: &mut S,
+    ) {
+        self.set_neighbors_on_disk(meta, index_pointer, neighbors, stats);
+    }
+
+    unsafe fn get_node_distance_measure<'b, S: StatsNodeRead>(
+        &'b self,
+        index_pointer: IndexPointer,
+        stats: &mut S,
+    ) -> PqNodeDistanceMeasure<'b> {
+        PqNodeDistanceMeasure::with_index_pointer(self, index_pointer, stats)
+    }
+
+    fn get_query_distance_measure(&self, query: PgVector) -> PqSearchDistanceMeasure {
+        PqSearchDistanceMeasure::new(&self.quantizer, query)
+    }
+
+    fn get_full_distance_for_resort<
+        S: StatsNodeRead + StatsHeapNodeRead + StatsDistanceComparison,
+    >(
+        &self,
+        qdm: &Self::QueryDistanceMeasure,
+        index_pointer: IndexPointer,
+        heap_pointer: HeapPointer,
+        meta_page: &MetaPage,
+        stats: &mut S,
+    ) -> f32 {
+        if meta_page.get_full_vectors_in_index() {
+            return unsafe {
+                get_full_vector_distance(self.index, index_pointer, &qdm.query, meta_page, stats)
+            };
+        }
+
+        let slot = unsafe { self.get_heap_table_slot_from_heap_pointer(heap_pointer, stats) };
+
+        let datum = unsafe { slot.get_attribute(self.heap_attr).unwrap() };
+        let vec = unsafe { PgVector::from_datum(datum, meta_page, false, true) };
+        vec.get_full_distance(&qdm.query, meta_page)
+    }
+
+    fn get_neighbors_with_distances_from_disk<S: StatsNodeRead + StatsDistanceComparison>(
+        &self,
+        neighbors_of: ItemPointer,
+        result: &mut Vec<NeighborWithDistance>,
+        stats: &mut S,
+    ) {
+        let rn = unsafe { PqNode::read(self.index, neighbors_of, stats) };
+        let archived = rn.get_archived_node();
+        let q = self.quantizer.reconstruct(archived.pq_vector.as_slice());
+
+        for n in archived.iter_neighbors() {
+            let rn1 = unsafe { PqNode::read(self.index, n, stats) };
+            let v = self
+                .quantizer
+                .reconstruct(rn1.get_archived_node().pq_vector.as_slice());
+            stats.record_quantized_distance_comparison();
+            let dist = (self.distance_fn)(q.as_slice(), v.as_slice());
+            result.push(NeighborWithDistance::new(n, dist))
+        }
+    }
+
+    /* get_lsn and visit_lsn are different because the distance
+    comparisons for PQ get the vector from different places */
+    fn create_lsn_for_init_id(
+        &self,
+        lsr: &mut ListSearchResult<Self::QueryDistanceMeasure, Self::LSNPrivateData>,
+        index_pointer: ItemPointer,
+        _gns: &GraphNeighborStore,
+    ) -> ListSearchNeighbor<Self::LSNPrivateData> {
+        if !lsr.prepare_insert(index_pointer) {
+            panic!("should not have had an init id already inserted");
+        }
+
+        let rn = unsafe { PqNode::read(self.index, index_pointer, &mut lsr.stats) };
+        let node = rn.get_archived_node();
+
+        let distance = lsr
+            .sdm
+            .as_ref()
+            .unwrap()
+            .calculate_pq_distance(node.pq_vector.as_slice(), &mut lsr.stats);
+
+        ListSearchNeighbor::new(index_pointer, distance, PhantomData::<bool>)
+    }
+
+    fn visit_lsn(
+        &self,
+        lsr: &mut ListSearchResult<Self::QueryDistanceMeasure, Self::LSNPrivateData>,
+        lsn_idx: usize,
+        gns: &GraphNeighborStore,
+    ) {
+        let lsn_index_pointer = lsr.get_lsn_by_idx(lsn_idx).index_pointer;
+        self.visit_lsn_internal(lsr, lsn_index_pointer, gns);
+    }
+
+    fn return_lsn(
+        &self,
+        lsn: &ListSearchNeighbor<Self::LSNPrivateData>,
+        stats: &mut GreedySearchStats,
+    ) -> HeapPointer {
+        let lsn_index_pointer = lsn.index_pointer;
+        let rn = unsafe { PqNode::read(self.index, lsn_index_pointer, stats) };
+        let node = rn.get_archived_node();
+        node.heap_item_pointer.deserialize_item_pointer()
+    }
+
+    fn set_neighbors_on_disk<S: StatsNodeModify + StatsNodeRead>(
+        &self,
+        meta: &MetaPage,
+        index_pointer: IndexPointer,
+        neighbors: &[NeighborWithDistance],
+        stats: &mut S,
+    ) {
+        let node = unsafe { PqNode::modify(self.index, index_pointer, stats) };
+        let mut archived = node.get_archived_node();
+        archived.as_mut().set_neighbors(neighbors, meta);
+        node.commit();
+    }
+
+    fn replace_neighbors_on_disk<S: StatsNodeModify + StatsNodeRead>(
+        &self,
+        meta: &MetaPage,
+        index_pointer: IndexPointer,
+        expected: &[IndexPointer],
+        neighbors: &[NeighborWithDistance],
+        stats: &mut S,
+    ) -> bool {
+        let node = unsafe { PqNode::modify(self.index, index_pointer, stats) };
+        let mut archived = node.get_archived_node();
+        if archived.get_index_pointer_to_neighbors() != expected {
+            return false;
+        }
+        archived.as_mut().set_neighbors(neighbors, meta);
+        node.commit();
+        true
+    }
+
+    fn get_distance_function(&self) -> fn(&[f32], &[f32]) -> f32 {
+        self.distance_fn
+    }
+
+    fn get_build_vector(&self, full_vector: &[f32]) -> BuildVector {
+        //the same vector the node distance measure compares
+        BuildVector::Full(
+            self.quantizer
+                .reconstruct(&self.quantizer.quantize(full_vector)),
+        )
+    }
+
+    fn get_labels(&self, index_pointer: IndexPointer) -> LabelSet {
+        unsafe { read_labels(self.index, index_pointer) }
+    }
+
+    fn is_deleted<S: StatsNodeRead>(&self, index_pointer: IndexPointer, stats: &mut S) -> bool {
+        let rn = unsafe { PqNode::read(self.index, index_pointer, stats) };
+        rn.get_archived_node().is_deleted()
+    }
+}
+
+use pgvectorscale_derive::{Readable, Writeable};
+
+#[derive(Archive, Deserialize, Serialize, Readable, Writeable)]
+#[archive(check_bytes)]
+pub struct PqNode {
+    pub heap_item_pointer: HeapPointer,
+    pub pq_vector: Vec<u8>,
+    neighbor_index_pointers: Vec<ItemPointer>,
+}
+
+impl PqNode {
+    fn new(heap_pointer: HeapPointer, num_neighbors: usize, pq_vector: Vec<u8>) -> Self {
+        // always use vectors of num_neighbors in length because we never want the serialized size of a Node to change
+        let neighbor_index_pointers: Vec<_> = (0..num_neighbors)
+            .map(|_| ItemPointer::new(InvalidBlockNumber, InvalidOffsetNumber))
+            .collect();
+
+        Self {
+            heap_item_pointer: heap_pointer,
+            pq_vector,
+            neighbor_index_pointers,
+        }
+    }
+}
+
+impl ArchivedPqNode {
+    fn neighbor_index_pointer(self: Pin<&mut Self>) -> Pin<&mut ArchivedVec<ArchivedItemPointer>> {
+        unsafe { self.map_unchecked_mut(|s| &mut s.neighbor_index_pointers) }
+    }
+
+    fn set_neighbors(
+        mut self: Pin<&mut Self>,
+        neighbors: &[NeighborWithDistance],
+        meta_page: &MetaPage,
+    ) {
+        for (i, new_neighbor) in neighbors.iter().enumerate() {
+            let mut a_index_pointer = self.as_mut().neighbor_index_pointer().index_pin(i);
+            let ip = new_neighbor.get_index_pointer_to_neighbor();
+            a_index_pointer.block_number = ip.block_number;
+            a_index_pointer.offset = ip.offset;
+        }
+        //set the marker that the list ended
+        if neighbors.len() < meta_page.get_num_neighbors() as _ {
+            let mut past_last_index_pointers =
+                self.neighbor_index_pointer().index_pin(neighbors.len());
+            past_last_index_pointers.block_number = InvalidBlockNumber;
+            past_last_index_pointers.offset = InvalidOffsetNumber;
+        }
+    }
+
+    pub fn num_neighbors(&self) -> usize {
+        self.neighbor_index_pointers
+            .iter()
+            .position(|f| f.block_number == InvalidBlockNumber)
+            .unwrap_or(self.neighbor_index_pointers.len())
+    }
+
+    pub fn iter_neighbors(&self) -> impl Iterator<Item = ItemPointer> + '_ {
+        self.neighbor_index_pointers
+            .iter()
+            .take(self.num_neighbors())
+            .map(|ip| ip.deserialize_item_pointer())
+    }
+}
+
+impl ArchivedData for ArchivedPqNode {
+    fn with_data(data: &mut [u8]) -> Pin<&mut ArchivedPqNode> {
+        ArchivedPqNode::with_data(data)
+    }
+
+    fn with_readable_data(data: &[u8]) -> &ArchivedPqNode {
+        unsafe { rkyv::archived_root::<PqNode>(data) }
+    }
+
+    fn get_index_pointer_to_neighbors(&self) -> Vec<ItemPointer> {
+        self.iter_neighbors().collect()
+    }
+
+    fn is_deleted(&self) -> bool {
+        self.heap_item_pointer.offset == InvalidOffsetNumber
+    }
+
+    fn delete(self: Pin<&mut Self>) {
+        let mut heap_pointer = unsafe { self.map_unchecked_mut(|s| &mut s.heap_item_pointer) };
+        heap_pointer.offset = InvalidOffsetNumber;
+        heap_pointer.block_number = InvalidBlockNumber;
+    }
+
+    fn get_heap_item_pointer(&self) -> HeapPointer {
+        self.heap_item_pointer.deserialize_item_pointer()
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    #[pg_test]
+    unsafe fn test_pq_storage_index_creation_default_neighbors() -> spi::Result<()> {
+        crate::access_method::build::tests::test_index_creation_and_accuracy_scaffold(
+            "storage_layout = pq",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_pq_storage_index_creation_few_neighbors() -> spi::Result<()> {
+        //a test with few neighbors tests the case that nodes share a page, which has caused deadlocks in the past.
+        crate::access_method::build::tests::test_index_creation_and_accuracy_scaffold(
+            "num_neighbors=10, storage_layout = pq",
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_pq_storage_delete_vacuum_plain() {
+        crate::access_method::vacuum::tests::test_delete_vacuum_plain_scaffold(
+            "num_neighbors = 10, storage_layout = pq",
+        );
+    }
+
+    #[test]
+    fn test_pq_storage_delete_vacuum_full() {
+        crate::access_method::vacuum::tests::test_delete_vacuum_full_scaffold(
+            "num_neighbors = 38, storage_layout = pq",
+        );
+    }
+
+    #[test]
+    fn test_pq_storage_delete_vacuum_repair() {
+        crate::access_method::vacuum::tests::test_delete_vacuum_repair_scaffold(
+            "num_neighbors = 10, storage_layout = pq",
+        );
+    }
+
+    #[test]
+    fn test_pq_storage_delete_vacuum_reuse() {
+        crate::access_method::vacuum::tests::test_delete_vacuum_reuse_scaffold(
+            "num_neighbors = 10, storage_layout = pq",
+            64,
+        );
+    }
+
+    #[pg_test]
+    unsafe fn test_pq_storage_empty_table_insert() -> spi::Result<()> {
+        crate::access_method::build::tests::test_empty_table_insert_scaffold(
+            "num_neighbors=38, storage_layout = pq",
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_pq_storage_unlogged_table() -> spi::Result<()> {
+        crate::access_method::build::tests::test_unlogged_table_scaffold(
+            "num_neighbors=38, storage_layout = pq",
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_pq_storage_insert_empty_insert() -> spi::Result<()> {
+        crate::access_method::build::tests::test_insert_empty_insert_scaffold(
+            "num_neighbors=38, storage_layout = pq",
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_pq_storage_index_updates() -> spi::Result<()> {
+        crate::access_method::build::tests::test_index_updates(
+            "storage_layout = pq, num_neighbors=10",
+            300,
+        )?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_pq_storage_inner_product() -> spi::Result<()> {
+        crate::access_method::build::tests::test_distance_operator_scaffold(
+            "storage_layout = pq",
+            "vector_ip_ops",
+            "<#>",
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_pq_storage_l2() -> spi::Result<()> {
+        crate::access_method::build::tests::test_distance_operator_scaffold(
+            "storage_layout = pq",
+            "vector_l2_ops",
+            "<->",
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_pq_storage_parallel_build() -> spi::Result<()> {
+        crate::access_method::build::tests::test_parallel_build_scaffold("storage_layout = pq")
+    }
+
+    #[pg_test]
+    unsafe fn test_pq_storage_high_dimensions() -> spi::Result<()> {
+        crate::access_method::build::tests::test_high_dimensions_scaffold(
+            "storage_layout = pq",
+            3072,
+        )
+    }
+}
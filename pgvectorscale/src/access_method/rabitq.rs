@@ -0,0 +1,1090 @@
+use super::{
+    distance::preprocess_cosine,
+    full_vector::get_full_vector_distance,
+    graph::{ListSearchNeighbor, ListSearchResult},
+    graph_neighbor_store::GraphNeighborStore,
+    labels::{read_labels, LabelSet},
+    meta_page::DistanceType,
+    parallel_build::BuildVector,
+    pg_vector::PgVector,
+    stats::{
+        GreedySearchStats, StatsDistanceComparison, StatsHeapNodeRead, StatsNodeModify,
+        StatsNodeRead, StatsNodeWrite, WriteStats,
+    },
+    storage::{ArchivedData, NodeDistanceMeasure, Storage},
+    storage_common::get_attribute_number_from_index,
+};
+use std::{borrow::Cow, cell::RefCell, collections::HashMap, pin::Pin};
+
+use pgrx::{
+    pg_sys::{InvalidBlockNumber, InvalidOffsetNumber},
+    PgRelation,
+};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use rkyv::{vec::ArchivedVec, Archive, Deserialize, Serialize};
+
+use crate::util::{
+    page::PageType, table_slot::TableSlot, tape::Tape, ArchivedItemPointer, HeapPointer,
+    IndexPointer, ItemPointer, ReadableBuffer,
+};
+
+use super::{meta_page::MetaPage, neighbor_with_distance::NeighborWithDistance};
+use crate::util::WritableBuffer;
+
+/* a fixed seed, so that building the same table twice gives the same index */
+const RABITQ_ROTATION_SEED: u64 = 0x7476_5f72_6271;
+
+/// The number of times the vectors are flipped by random signs and mixed by a Walsh-Hadamard transform. Together
+/// these rounds stand in for the random orthogonal rotation of RaBitQ without storing a matrix.
+const RABITQ_ROTATION_ROUNDS: usize = 3;
+
+/// The confidence of the error bounds (epsilon_0 in the RaBitQ paper). With 1.9, the value the paper recommends,
+/// only a few percent of the estimates are off by more than their bound, and rarely by much.
+const RABITQ_ERROR_CONFIDENCE: f32 = 1.9;
+
+#[derive(Archive, Deserialize, Serialize, Readable, Writeable)]
+#[archive(check_bytes)]
+#[repr(C)]
+pub struct RabitqCentroid {
+    centroid: Vec<f32>,
+    seed: u64,
+}
+
+impl RabitqCentroid {
+    pub unsafe fn load<S: StatsNodeRead>(
+        index: &PgRelation,
+        meta_page: &super::meta_page::MetaPage,
+        stats: &mut S,
+    ) -> RabitqQuantizer {
+        let mut quantizer = RabitqQuantizer::new(meta_page);
+        if meta_page.get_quantizer_metadata_pointer().is_none() {
+            //only the empty index of an unlogged table (see ambuildempty) has no centroid. This is the centroid
+            //an empty build stores.
+            quantizer.start_training(meta_page);
+            quantizer.finish_training();
+            return quantizer;
+        }
+        let quantizer_item_pointer = meta_page.get_quantizer_metadata_pointer().unwrap();
+        let centroid = RabitqCentroid::read(index, quantizer_item_pointer, stats);
+        let archived = centroid.get_archived_node();
+
+        quantizer.load(archived.centroid.to_vec(), archived.seed);
+        quantizer
+    }
+
+    pub unsafe fn store<S: StatsNodeWrite>(
+        index: &PgRelation,
+        quantizer: &RabitqQuantizer,
+        stats: &mut S,
+    ) -> ItemPointer {
+        let mut tape = Tape::new(index, PageType::RabitqCentroid);
+        let node = RabitqCentroid {
+            centroid: quantizer.centroid.clone(),
+            seed: quantizer.rotation.seed,
+        };
+        let ptr = node.write(&mut tape, stats);
+        tape.close();
+        ptr
+    }
+}
+
+/// A random orthogonal transform of vectors padded to a power of two: rounds of random sign flips followed by a
+/// normalized Walsh-Hadamard transform.
+#[derive(Clone)]
+struct RabitqRotation {
+    seed: u64,
+    num_dimensions: usize,
+    padded_dimensions: usize,
+    signs: Vec<Vec<bool>>,
+}
+
+impl RabitqRotation {
+    fn new(num_dimensions: usize, seed: u64) -> Self {
+        let padded_dimensions = num_dimensions.next_power_of_two();
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+        let signs = (0..RABITQ_ROTATION_ROUNDS)
+            .map(|_| (0..padded_dimensions).map(|_| rng.gen::<bool>()).collect())
+            .collect();
+        Self {
+            seed,
+            num_dimensions,
+            padded_dimensions,
+            signs,
+        }
+    }
+
+    fn walsh_hadamard(v: &mut [f32]) {
+        let mut h = 1;
+        while h < v.len() {
+            for i in (0..v.len()).step_by(2 * h) {
+                for j in i..i + h {
+                    let (a, b) = (v[j], v[j + h]);
+                    v[j] = a + b;
+                    v[j + h] = a - b;
+                }
+            }
+            h *= 2;
+        }
+        let norm = 1.0 / (v.len() as f32).sqrt();
+        v.iter_mut().for_each(|x| *x *= norm);
+    }
+
+    fn flip(v: &mut [f32], signs: &[bool]) {
+        for (x, &negative) in v.iter_mut().zip(signs) {
+            if negative {
+                *x = -*x;
+            }
+        }
+    }
+
+    fn rotate(&self, v: &[f32]) -> Vec<f32> {
+        assert_eq!(v.len(), self.num_dimensions);
+        let mut res = v.to_vec();
+        res.resize(self.padded_dimensions, 0.0);
+        for signs in self.signs.iter() {
+            Self::flip(&mut res, signs);
+            Self::walsh_hadamard(&mut res);
+        }
+        res
+    }
+
+    fn rotate_back(&self, v: &[f32]) -> Vec<f32> {
+        assert_eq!(v.len(), self.padded_dimensions);
+        let mut res = v.to_vec();
+        //the normalized Walsh-Hadamard transform and the sign flips are their own inverses
+        for signs in self.signs.iter().rev() {
+            Self::walsh_hadamard(&mut res);
+            Self::flip(&mut res, signs);
+        }
+        res.truncate(self.num_dimensions);
+        res
+    }
+}
+
+/// The RaBitQ code of a vector: the signs of its rotated direction from the centroid, with the two factors the
+/// distance estimates need.
+struct RabitqCode {
+    bits: Vec<u64>,
+    /// The distance of the vector from the centroid.
+    norm: f32,
+    /// The inner product of the direction and the unit vector the bits stand for, about 0.8.
+    quality: f32,
+}
+
+/// RaBitQ quantization (Gao and Long, 2024): one bit per dimension with distance estimates that come with an error
+/// bound, which decides the candidates that have to be rescored.
+#[derive(Clone)]
+pub struct RabitqQuantizer {
+    distance_type: DistanceType,
+    training: bool,
+    training_sum: Vec<f64>,
+    training_count: u64,
+    pub centroid: Vec<f32>,
+    rotation: RabitqRotation,
+}
+
+impl RabitqQuantizer {
+    fn new(meta_page: &super::meta_page::MetaPage) -> RabitqQuantizer {
+        let num_dimensions = meta_page.get_num_dimensions_to_index() as usize;
+        Self {
+            distance_type: meta_page.get_distance_type(),
+            training: false,
+            training_sum: vec![],
+            training_count: 0,
+            centroid: vec![0.0; num_dimensions],
+            rotation: RabitqRotation::new(num_dimensions, RABITQ_ROTATION_SEED),
+        }
+    }
+
+    fn load(&mut self, centroid: Vec<f32>, seed: u64) {
+        self.rotation = RabitqRotation::new(centroid.len(), seed);
+        self.centroid = centroid;
+    }
+
+    fn padded_dimensions(&self) -> usize {
+        self.rotation.padded_dimensions
+    }
+
+    fn quantize(&self, full_vector: &[f32]) -> RabitqCode {
+        assert!(!self.training);
+        assert_eq!(full_vector.len(), self.centroid.len());
+        let mut direction: Vec<f32> = full_vector
+            .iter()
+            .zip(self.centroid.iter())
+            .map(|(v, c)| v - c)
+            .collect();
+        let norm = direction.iter().map(|x| x * x).sum::<f32>().sqrt();
+        let mut bits = vec![0u64; self.padded_dimensions().div_ceil(64)];
+        if norm == 0.0 {
+            //the vector is the centroid, every estimate is exact
+            return RabitqCode {
+                bits,
+                norm,
+                quality: 1.0,
+            };
+        }
+        direction.iter_mut().for_each(|x| *x /= norm);
+
+        let rotated = self.rotation.rotate(&direction);
+        let mut abs_sum = 0.0;
+        for (i, x) in rotated.iter().enumerate() {
+            if *x > 0.0 {
+                bits[i / 64] |= 1 << (i % 64);
+            }
+            abs_sum += x.abs();
+        }
+        RabitqCode {
+            bits,
+            norm,
+            quality: (abs_sum / (self.padded_dimensions() as f32).sqrt()).min(1.0),
+        }
+    }
+
+    /// The vector a code stands for.
+    fn dequantize(&self, bits: &[u64], norm: f32) -> Vec<f32> {
+        let unit = 1.0 / (self.padded_dimensions() as f32).sqrt();
+        let rotated: Vec<f32> = (0..self.padded_dimensions())
+            .map(|i| {
+                if bits[i / 64] & (1 << (i % 64)) != 0 {
+                    unit
+                } else {
+                    -unit
+                }
+            })
+            .collect();
+        let mut res: Vec<f32> = self
+            .rotation
+            .rotate_back(&rotated)
+            .iter()
+            .zip(self.centroid.iter())
+            .map(|(x, c)| c + norm * x)
+            .collect();
+        //the cosine distance is only defined on normalized vectors
+        if self.distance_type == DistanceType::Cosine {
+            preprocess_cosine(&mut res);
+        }
+        res
+    }
+
+    fn start_training(&mut self, meta_page: &super::meta_page::MetaPage) {
+        self.training = true;
+        self.training_sum = vec![0.0; meta_page.get_num_dimensions_to_index() as usize];
+        self.training_count = 0;
+    }
+
+    fn add_sample(&mut self, sample: &[f32]) {
+        assert_eq!(self.training_sum.len(), sample.len());
+        for (sum, &v) in self.training_sum.iter_mut().zip(sample) {
+            *sum += v as f64;
+        }
+        self.training_count += 1;
+    }
+
+    fn finish_training(&mut self) {
+        self.training = false;
+        //no samples: the centroid is the origin
+        let count = self.training_count.max(1) as f64;
+        self.centroid = self
+            .training_sum
+            .iter()
+            .map(|sum| (sum / count) as f32)
+            .collect();
+    }
+}
+
+/// A vector prepared for distance estimates against RaBitQ codes.
+pub struct RabitqQueryVector {
+    distance_type: DistanceType,
+    /// The rotated direction of the vector from the centroid (L2) or from the origin (inner product and cosine).
+    rotated: Vec<f32>,
+    rotated_sum: f32,
+    norm: f32,
+    /// The inner product with the centroid (inner product and cosine).
+    offset: f32,
+    error_factor: f32,
+}
+
+impl RabitqQueryVector {
+    fn new(quantizer: &RabitqQuantizer, vector: &[f32]) -> Self {
+        assert_eq!(vector.len(), quantizer.centroid.len());
+        let (mut v, offset): (Vec<f32>, f32) = match quantizer.distance_type {
+            DistanceType::L2 => (
+                vector
+                    .iter()
+                    .zip(quantizer.centroid.iter())
+                    .map(|(v, c)| v - c)
+                    .collect(),
+                0.0,
+            ),
+            DistanceType::Cosine | DistanceType::InnerProduct => (
+                vector.to_vec(),
+                vector
+                    .iter()
+                    .zip(quantizer.centroid.iter())
+                    .map(|(v, c)| v * c)
+                    .sum(),
+            ),
+            DistanceType::Hamming | DistanceType::Jaccard => {
+                unreachable!("bit vectors can't be indexed with the rabitq storage layout")
+            }
+        };
+        let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            v.iter_mut().for_each(|x| *x /= norm);
+        }
+        let rotated = quantizer.rotation.rotate(&v);
+        let padded_dimensions = quantizer.padded_dimensions() as f32;
+        Self {
+            distance_type: quantizer.distance_type,
+            rotated_sum: rotated.iter().sum(),
+            rotated,
+            norm,
+            offset,
+            error_factor: RABITQ_ERROR_CONFIDENCE / (padded_dimensions - 1.0).max(1.0).sqrt(),
+        }
+    }
+
+    /// The estimated distance to a code and the bound of the estimate's error.
+    fn distance_with_error(&self, bits: &[u64], norm: f32, quality: f32) -> (f32, f32) {
+        //the sum of the rotated query over the dimensions whose bit is set
+        let mut selected = 0.0;
+        for (word_index, &word) in bits.iter().enumerate() {
+            let mut word = word;
+            while word != 0 {
+                selected += self.rotated[word_index * 64 + word.trailing_zeros() as usize];
+                word &= word - 1;
+            }
+        }
+        let padded_dimensions = self.rotated.len() as f32;
+        let quantized_product = (2.0 * selected - self.rotated_sum) / padded_dimensions.sqrt();
+        let product = quantized_product / quality;
+        let product_error = if quality < 1.0 {
+            (1.0 - quality * quality).sqrt() / quality * self.error_factor
+        } else {
+            0.0
+        };
+
+        let scale = norm * self.norm;
+        match self.distance_type {
+            DistanceType::L2 => (
+                norm * norm + self.norm * self.norm - 2.0 * scale * product,
+                2.0 * scale * product_error,
+            ),
+            DistanceType::InnerProduct => (-(scale * product + self.offset), scale * product_error),
+            DistanceType::Cosine => (1.0 - (scale * product + self.offset), scale * product_error),
+            DistanceType::Hamming | DistanceType::Jaccard => {
+                unreachable!("bit vectors can't be indexed with the rabitq storage layout")
+            }
+        }
+    }
+}
+
+pub struct RabitqSearchDistanceMeasure {
+    query: PgVector,
+    query_vector: RabitqQueryVector,
+}
+
+impl RabitqSearchDistanceMeasure {
+    pub fn new(quantizer: &RabitqQuantizer, query: PgVector) -> RabitqSearchDistanceMeasure {
+        RabitqSearchDistanceMeasure {
+            query_vector: RabitqQueryVector::new(quantizer, query.to_index_slice()),
+            query,
+        }
+    }
+
+    /// The estimated distance to a node and the bound of the estimate's error.
+    pub fn calculate_rabitq_distance<S: StatsDistanceComparison>(
+        &self,
+        node: &ArchivedRabitqNode,
+        stats: &mut S,
+    ) -> (f32, f32) {
+        stats.record_quantized_distance_comparison();
+        self.query_vector
+            .distance_with_error(node.bits.as_slice(), node.norm, node.quality)
+    }
+}
+
+pub struct RabitqNodeDistanceMeasure<'a> {
+    vec: RabitqQueryVector,
+    storage: &'a RabitqStorage<'a>,
+}
+
+impl<'a> RabitqNodeDistanceMeasure<'a> {
+    pub unsafe fn with_index_pointer<T: StatsNodeRead>(
+        storage: &'a RabitqStorage<'a>,
+        index_pointer: IndexPointer,
+        stats: &mut T,
+    ) -> Self {
+        let cache = &mut storage.qv_cache.borrow_mut();
+        let code = cache.get(index_pointer, storage, stats);
+        let vec = storage.quantizer.dequantize(&code.bits, code.norm);
+        Self {
+            vec: RabitqQueryVector::new(&storage.quantizer, &vec),
+            storage,
+        }
+    }
+}
+
+impl<'a> NodeDistanceMeasure for RabitqNodeDistanceMeasure<'a> {
+    unsafe fn get_distance<T: StatsNodeRead + StatsDistanceComparison>(
+        &self,
+        index_pointer: IndexPointer,
+        stats: &mut T,
+    ) -> f32 {
+        let cache = &mut self.storage.qv_cache.borrow_mut();
+        let code = cache.get(index_pointer, self.storage, stats);
+        stats.record_quantized_distance_comparison();
+        self.vec
+            .distance_with_error(&code.bits, code.norm, code.quality)
+            .0
+    }
+}
+
+struct RabitqVectorCache {
+    code_map: HashMap<ItemPointer, RabitqCode>,
+}
+
+/* like the QuantizedVectorCache of SBQ, this never evicts */
+impl RabitqVectorCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            code_map: HashMap::with_capacity(capacity),
+        }
+    }
+
+    fn get<S: StatsNodeRead>(
+        &mut self,
+        index_pointer: IndexPointer,
+        storage: &RabitqStorage,
+        stats: &mut S,
+    ) -> &RabitqCode {
+        self.code_map
+            .entry(index_pointer)
+            .or_insert_with(|| storage.get_code_from_index_pointer(index_pointer, stats))
+    }
+}
+
+pub struct RabitqStorage<'a> {
+    pub index: &'a PgRelation,
+    pub distance_fn: fn(&[f32], &[f32]) -> f32,
+    quantizer: Cow<'a, RabitqQuantizer>,
+    heap_rel: &'a PgRelation,
+    heap_attr: pgrx::pg_sys::AttrNumber,
+    qv_cache: RefCell<RabitqVectorCache>,
+}
+
+impl<'a> RabitqStorage<'a> {
+    pub fn new_for_build(
+        index: &'a PgRelation,
+        heap_rel: &'a PgRelation,
+        meta_page: &super::meta_page::MetaPage,
+    ) -> RabitqStorage<'a> {
+        Self {
+            index,
+            distance_fn: meta_page.get_distance_function(),
+            quantizer: Cow::Owned(RabitqQuantizer::new(meta_page)),
+            heap_rel,
+            heap_attr: get_attribute_number_from_index(index),
+            qv_cache: RefCell::new(RabitqVectorCache::new(1000)),
+        }
+    }
+
+    pub fn load_for_insert<S: StatsNodeRead>(
+        heap_rel: &'a PgRelation,
+        index_relation: &'a PgRelation,
+        meta_page: &super::meta_page::MetaPage,
+        stats: &mut S,
+    ) -> RabitqStorage<'a> {
+        Self {
+            index: index_relation,
+            distance_fn: meta_page.get_distance_function(),
+            quantizer: Cow::Owned(unsafe {
+                RabitqCentroid::load(index_relation, meta_page, stats)
+            }),
+            heap_rel,
+            heap_attr: get_attribute_number_from_index(index_relation),
+            qv_cache: RefCell::new(RabitqVectorCache::new(1000)),
+        }
+    }
+
+    pub fn load_for_search(
+        index_relation: &'a PgRelation,
+        heap_relation: &'a PgRelation,
+        quantizer: &'a RabitqQuantizer,
+        meta_page: &super::meta_page::MetaPage,
+    ) -> RabitqStorage<'a> {
+        Self {
+            index: index_relation,
+            distance_fn: meta_page.get_distance_function(),
+            quantizer: Cow::Borrowed(quantizer),
+            heap_rel: heap_relation,
+            heap_attr: get_attribute_number_from_index(index_relation),
+            qv_cache: RefCell::new(RabitqVectorCache::new(1000)),
+        }
+    }
+
+    fn get_code_from_index_pointer<S: StatsNodeRead>(
+        &self,
+        index_pointer: IndexPointer,
+        stats: &mut S,
+    ) -> RabitqCode {
+        let rn = unsafe { RabitqNode::read(self.index, index_pointer, stats) };
+        let node = rn.get_archived_node();
+        RabitqCode {
+            bits: node.bits.as_slice().to_vec(),
+            norm: node.norm,
+            quality: node.quality,
+        }
+    }
+
+    fn write_quantizer_metadata<S: StatsNodeWrite + StatsNodeModify>(&self, stats: &mut S) {
+        let index_pointer = unsafe { RabitqCentroid::store(self.index, &self.quantizer, stats) };
+        super::meta_page::MetaPage::update_quantizer_metadata_pointer(
+            self.index,
+            index_pointer,
+            stats,
+        );
+    }
+
+    fn visit_lsn_internal(
+        &self,
+        lsr: &mut ListSearchResult<
+            <RabitqStorage<'a> as Storage>::QueryDistanceMeasure,
+            <RabitqStorage<'a> as Storage>::LSNPrivateData,
+        >,
+        lsn_index_pointer: IndexPointer,
+        gns: &GraphNeighborStore,
+    ) {
+        match gns {
+            GraphNeighborStore::Disk => {
+                let rn_visiting =
+                    unsafe { RabitqNode::read(self.index, lsn_index_pointer, &mut lsr.stats) };
+                let node_visiting = rn_visiting.get_archived_node();
+                let neighbors = node_visiting.get_index_pointer_to_neighbors();
+
+                for &neighbor_index_pointer in neighbors.iter() {
+                    if !lsr.prepare_insert(neighbor_index_pointer) {
+                        continue;
+                    }
+
+                    let rn_neighbor = unsafe {
+                        RabitqNode::read(self.index, neighbor_index_pointer, &mut lsr.stats)
+                    };
+                    let node_neighbor = rn_neighbor.get_archived_node();
+                    let (distance, error) = lsr
+                        .sdm
+                        .as_ref()
+                        .unwrap()
+                        .calculate_rabitq_distance(node_neighbor, &mut lsr.stats);
+
+                    let lsn = ListSearchNeighbor::new(neighbor_index_pointer, distance, error);
+
+                    lsr.insert_neighbor(lsn);
+                }
+            }
+            GraphNeighborStore::Builder(b) => {
+                let neighbors = b.get_neighbors(lsn_index_pointer);
+                for &neighbor_index_pointer in neighbors.iter() {
+                    if !lsr.prepare_insert(neighbor_index_pointer) {
+                        continue;
+                    }
+                    let mut cache = self.qv_cache.borrow_mut();
+                    let code = cache.get(neighbor_index_pointer, self, &mut lsr.stats);
+                    lsr.stats.record_quantized_distance_comparison();
+                    let (distance, error) = lsr
+                        .sdm
+                        .as_ref()
+                        .unwrap()
+                        .query_vector
+                        .distance_with_error(&code.bits, code.norm, code.quality);
+
+                    let lsn = ListSearchNeighbor::new(neighbor_index_pointer, distance, error);
+
+                    lsr.insert_neighbor(lsn);
+                }
+            }
+        }
+    }
+
+    unsafe fn get_heap_table_slot_from_heap_pointer<T: StatsHeapNodeRead>(
+        &self,
+        heap_pointer: HeapPointer,
+        stats: &mut T,
+    ) -> TableSlot {
+        TableSlot::new(self.heap_rel, heap_pointer, stats)
+    }
+}
+
+/// The bound of the error of the estimated distance a node was found with.
+pub type RabitqStorageLsnPrivateData = f32;
+
+impl<'a> Storage for RabitqStorage<'a> {
+    type QueryDistanceMeasure = RabitqSearchDistanceMeasure;
+    type NodeDistanceMeasure<'b> = RabitqNodeDistanceMeasure<'b> where Self: 'b;
+    type ArchivedType = ArchivedRabitqNode;
+    type LSNPrivateData = RabitqStorageLsnPrivateData;
+
+    fn page_type() -> PageType {
+        PageType::RabitqNode
+    }
+
+    fn create_node<S: StatsNodeWrite>(
+        &self,
+        full_vector: &[f32],
+        heap_pointer: HeapPointer,
+        scalar_columns: &[u8],
+        meta_page: &MetaPage,
+        tape: &mut Tape,
+        stats: &mut S,
+    ) -> ItemPointer {
+        let code = self.quantizer.quantize(full_vector);
+
+        let node = RabitqNode::new(heap_pointer, meta_page.get_num_neighbors() as usize, code);
+
+        node.write_with_prefix(scalar_columns, tape, stats)
+    }
+
+    fn start_training(&mut self, meta_page: &super::meta_page::MetaPage) {
+        self.quantizer.to_mut().start_training(meta_page);
+    }
+
+    fn add_sample(&mut self, sample: &[f32]) {
+        self.quantizer.to_mut().add_sample(sample);
+    }
+
+    fn finish_training(&mut self, stats: &mut WriteStats) {
+        self.quantizer.to_mut().finish_training();
+        self.write_quantizer_metadata(stats);
+    }
+
+    fn finalize_node_at_end_of_build<S: StatsNodeRead + StatsNodeModify>(
+        &mut self,
+        meta: &MetaPage,
+        index_pointer: IndexPointer,
+        neighbors: &Vec<NeighborWithDistance>,
+        stats: &mut S,
+    ) {
+        self.set_neighbors_on_disk(meta, index_pointer, neighbors, stats);
+    }
+
+    unsafe fn get_node_distance_measure<'b, S: StatsNodeRead>(
+        &'b self,
+        index_pointer: IndexPointer,
+        stats: &mut S,
+    ) -> RabitqNodeDistanceMeasure<'b> {
+        RabitqNodeDistanceMeasure::with_index_pointer(self, index_pointer, stats)
+    }
+
+    fn get_query_distance_measure(&self, query: PgVector) -> RabitqSearchDistanceMeasure {
+        RabitqSearchDistanceMeasure::new(&self.quantizer, query)
+    }
+
+    fn get_full_distance_for_resort<
+        S: StatsNodeRead + StatsHeapNodeRead + StatsDistanceComparison,
+    >(
+        &self,
+        qdm: &Self::QueryDistanceMeasure,
+        index_pointer: IndexPointer,
+        heap_pointer: HeapPointer,
+        meta_page: &MetaPage,
+        stats: &mut S,
+    ) -> f32 {
+        if meta_page.get_full_vectors_in_index() {
+            return unsafe {
+                get_full_vector_distance(self.index, index_pointer, &qdm.query, meta_page, stats)
+            };
+        }
+
+        let slot = unsafe { self.get_heap_table_slot_from_heap_pointer(heap_pointer, stats) };
+
+        let datum = unsafe { slot.get_attribute(self.heap_attr).unwrap() };
+        let vec = unsafe { PgVector::from_datum(datum, meta_page, false, true) };
+        vec.get_full_distance(&qdm.query, meta_page)
+    }
+
+    fn get_neighbors_with_distances_from_disk<S: StatsNodeRead + StatsDistanceComparison>(
+        &self,
+        neighbors_of: ItemPointer,
+        result: &mut Vec<NeighborWithDistance>,
+        stats: &mut S,
+    ) {
+        let rn = unsafe { RabitqNode::read(self.index, neighbors_of, stats) };
+        let archived = rn.get_archived_node();
+        let q = RabitqQueryVector::new(
+            &self.quantizer,
+            &self
+                .quantizer
+                .dequantize(archived.bits.as_slice(), archived.norm),
+        );
+
+        for n in archived.iter_neighbors() {
+            let rn1 = unsafe { RabitqNode::read(self.index, n, stats) };
+            let node = rn1.get_archived_node();
+            stats.record_quantized_distance_comparison();
+            let (dist, _) = q.distance_with_error(node.bits.as_slice(), node.norm, node.quality);
+            result.push(NeighborWithDistance::new(n, dist))
+        }
+    }
+
+    /* get_lsn and visit_lsn are different because the distance
+    comparisons for RaBitQ get the vector from different places */
+    fn create_lsn_for_init_id(
+        &self,
+        lsr: &mut ListSearchResult<Self::QueryDistanceMeasure, Self::LSNPrivateData>,
+        index_pointer: ItemPointer,
+        _gns: &GraphNeighborStore,
+    ) -> ListSearchNeighbor<Self::LSNPrivateData> {
+        if !lsr.prepare_insert(index_pointer) {
+            panic!("should not have had an init id already inserted");
+        }
+
+        let rn = unsafe { RabitqNode::read(self.index, index_pointer, &mut lsr.stats) };
+        let node = rn.get_archived_node();
+
+        let (distance, error) = lsr
+            .sdm
+            .as_ref()
+            .unwrap()
+            .calculate_rabitq_distance(node, &mut lsr.stats);
+
+        ListSearchNeighbor::new(index_pointer, distance, error)
+    }
+
+    fn visit_lsn(
+        &self,
+        lsr: &mut ListSearchResult<Self::QueryDistanceMeasure, Self::LSNPrivateData>,
+        lsn_idx: usize,
+        gns: &GraphNeighborStore,
+    ) {
+        let lsn_index_pointer = lsr.get_lsn_by_idx(lsn_idx).index_pointer;
+        self.visit_lsn_internal(lsr, lsn_index_pointer, gns);
+    }
+
+    fn return_lsn(
+        &self,
+        lsn: &ListSearchNeighbor<Self::LSNPrivateData>,
+        stats: &mut GreedySearchStats,
+    ) -> HeapPointer {
+        let lsn_index_pointer = lsn.index_pointer;
+        let rn = unsafe { RabitqNode::read(self.index, lsn_index_pointer, stats) };
+        let node = rn.get_archived_node();
+        node.heap_item_pointer.deserialize_item_pointer()
+    }
+
+    fn set_neighbors_on_disk<S: StatsNodeModify + StatsNodeRead>(
+        &self,
+        meta: &MetaPage,
+        index_pointer: IndexPointer,
+        neighbors: &[NeighborWithDistance],
+        stats: &mut S,
+    ) {
+        let node = unsafe { RabitqNode::modify(self.index, index_pointer, stats) };
+        let mut archived = node.get_archived_node();
+        archived.as_mut().set_neighbors(neighbors, meta);
+        node.commit();
+    }
+
+    fn replace_neighbors_on_disk<S: StatsNodeModify + StatsNodeRead>(
+        &self,
+        meta: &MetaPage,
+        index_pointer: IndexPointer,
+        expected: &[IndexPointer],
+        neighbors: &[NeighborWithDistance],
+        stats: &mut S,
+    ) -> bool {
+        let node = unsafe { RabitqNode::modify(self.index, index_pointer, stats) };
+        let mut archived = node.get_archived_node();
+        if archived.get_index_pointer_to_neighbors() != expected {
+            return false;
+        }
+        archived.as_mut().set_neighbors(neighbors, meta);
+        node.commit();
+        true
+    }
+
+    fn get_distance_function(&self) -> fn(&[f32], &[f32]) -> f32 {
+        self.distance_fn
+    }
+
+    fn get_build_vector(&self, full_vector: &[f32]) -> BuildVector {
+        //the same vector the node distance measure compares
+        let code = self.quantizer.quantize(full_vector);
+        BuildVector::Full(self.quantizer.dequantize(&code.bits, code.norm))
+    }
+
+    fn get_labels(&self, index_pointer: IndexPointer) -> LabelSet {
+        unsafe { read_labels(self.index, index_pointer) }
+    }
+
+    fn is_deleted<S: StatsNodeRead>(&self, index_pointer: IndexPointer, stats: &mut S) -> bool {
+        let rn = unsafe { RabitqNode::read(self.index, index_pointer, stats) };
+        rn.get_archived_node().is_deleted()
+    }
+}
+
+use pgvectorscale_derive::{Readable, Writeable};
+
+#[derive(Archive, Deserialize, Serialize, Readable, Writeable)]
+#[archive(check_bytes)]
+pub struct RabitqNode {
+    pub heap_item_pointer: HeapPointer,
+    bits: Vec<u64>,
+    norm: f32,
+    quality: f32,
+    neighbor_index_pointers: Vec<ItemPointer>,
+}
+
+impl RabitqNode {
+    fn new(heap_pointer: HeapPointer, num_neighbors: usize, code: RabitqCode) -> Self {
+        // always use vectors of num_neighbors in length because we never want the serialized size of a Node to change
+        let neighbor_index_pointers: Vec<_> = (0..num_neighbors)
+            .map(|_| ItemPointer::new(InvalidBlockNumber, InvalidOffsetNumber))
+            .collect();
+
+        Self {
+            heap_item_pointer: heap_pointer,
+            bits: code.bits,
+            norm: code.norm,
+            quality: code.quality,
+            neighbor_index_pointers,
+        }
+    }
+}
+
+impl ArchivedRabitqNode {
+    fn neighbor_index_pointer(self: Pin<&mut Self>) -> Pin<&mut ArchivedVec<ArchivedItemPointer>> {
+        unsafe { self.map_unchecked_mut(|s| &mut s.neighbor_index_pointers) }
+    }
+
+    fn set_neighbors(
+        mut self: Pin<&mut Self>,
+        neighbors: &[NeighborWithDistance],
+        meta_page: &MetaPage,
+    ) {
+        for (i, new_neighbor) in neighbors.iter().enumerate() {
+            let mut a_index_pointer = self.as_mut().neighbor_index_pointer().index_pin(i);
+            let ip = new_neighbor.get_index_pointer_to_neighbor();
+            a_index_pointer.block_number = ip.block_number;
+            a_index_pointer.offset = ip.offset;
+        }
+        //set the marker that the list ended
+        if neighbors.len() < meta_page.get_num_neighbors() as _ {
+            let mut past_last_index_pointers =
+                self.neighbor_index_pointer().index_pin(neighbors.len());
+            past_last_index_pointers.block_number = InvalidBlockNumber;
+            past_last_index_pointers.offset = InvalidOffsetNumber;
+        }
+    }
+
+    pub fn num_neighbors(&self) -> usize {
+        self.neighbor_index_pointers
+            .iter()
+            .position(|f| f.block_number == InvalidBlockNumber)
+            .unwrap_or(self.neighbor_index_pointers.len())
+    }
+
+    pub fn iter_neighbors(&self) -> impl Iterator<Item = ItemPointer> + '_ {
+        self.neighbor_index_pointers
+            .iter()
+            .take(self.num_neighbors())
+            .map(|ip| ip.deserialize_item_pointer())
+    }
+}
+
+impl ArchivedData for ArchivedRabitqNode {
+    fn with_data(data: &mut [u8]) -> Pin<&mut ArchivedRabitqNode> {
+        ArchivedRabitqNode::with_data(data)
+    }
+
+    fn with_readable_data(data: &[u8]) -> &ArchivedRabitqNode {
+        unsafe { rkyv::archived_root::<RabitqNode>(data) }
+    }
+
+    fn get_index_pointer_to_neighbors(&self) -> Vec<ItemPointer> {
+        self.iter_neighbors().collect()
+    }
+
+    fn is_deleted(&self) -> bool {
+        self.heap_item_pointer.offset == InvalidOffsetNumber
+    }
+
+    fn delete(self: Pin<&mut Self>) {
+        let mut heap_pointer = unsafe { self.map_unchecked_mut(|s| &mut s.heap_item_pointer) };
+        heap_pointer.offset = InvalidOffsetNumber;
+        heap_pointer.block_number = InvalidBlockNumber;
+    }
+
+    fn get_heap_item_pointer(&self) -> HeapPointer {
+        self.heap_item_pointer.deserialize_item_pointer()
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+
+    use super::{RabitqQuantizer, RabitqQueryVector, RabitqRotation};
+    use crate::access_method::{distance::distance_l2, meta_page::DistanceType};
+
+    #[pg_test]
+    unsafe fn test_rabitq_error_bound() {
+        let num_dimensions = 100;
+        let mut rng = ChaCha8Rng::seed_from_u64(42);
+        let mut random_vector = || -> Vec<f32> {
+            (0..num_dimensions)
+                .map(|_| rng.gen_range(-1.0..1.0))
+                .collect()
+        };
+
+        let mut quantizer = RabitqQuantizer {
+            distance_type: DistanceType::L2,
+            training: true,
+            training_sum: vec![0.0; num_dimensions],
+            training_count: 0,
+            centroid: vec![0.0; num_dimensions],
+            rotation: RabitqRotation::new(num_dimensions, super::RABITQ_ROTATION_SEED),
+        };
+        let vectors: Vec<Vec<f32>> = (0..1000).map(|_| random_vector()).collect();
+        for v in vectors.iter() {
+            quantizer.add_sample(v);
+        }
+        quantizer.finish_training();
+
+        let query = random_vector();
+        let query_vector = RabitqQueryVector::new(&quantizer, &query);
+        let mut outside = 0;
+        for v in vectors.iter() {
+            let code = quantizer.quantize(v);
+            let (distance, error) =
+                query_vector.distance_with_error(&code.bits, code.norm, code.quality);
+            let exact = distance_l2(&query, v);
+            if (distance - exact).abs() > error {
+                outside += 1;
+            }
+        }
+        assert!(
+            outside <= 100,
+            "{} of 1000 estimates outside of the bound",
+            outside
+        );
+
+        //the rotation pads to a power of two and can be undone
+        let rotated = quantizer.rotation.rotate(&query);
+        assert_eq!(rotated.len(), 128);
+        let back = quantizer.rotation.rotate_back(&rotated);
+        for (a, b) in query.iter().zip(back.iter()) {
+            assert!((a - b).abs() < 1e-5);
+        }
+    }
+
+    #[pg_test]
+    unsafe fn test_rabitq_storage_index_creation_default_neighbors() -> spi::Result<()> {
+        crate::access_method::build::tests::test_index_creation_and_accuracy_scaffold(
+            "storage_layout = rabitq",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_rabitq_storage_index_creation_few_neighbors() -> spi::Result<()> {
+        //a test with few neighbors tests the case that nodes share a page, which has caused deadlocks in the past.
+        crate::access_method::build::tests::test_index_creation_and_accuracy_scaffold(
+            "num_neighbors=10, storage_layout = rabitq",
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_rabitq_storage_delete_vacuum_plain() {
+        crate::access_method::vacuum::tests::test_delete_vacuum_plain_scaffold(
+            "num_neighbors = 10, storage_layout = rabitq",
+        );
+    }
+
+    #[test]
+    fn test_rabitq_storage_delete_vacuum_full() {
+        crate::access_method::vacuum::tests::test_delete_vacuum_full_scaffold(
+            "num_neighbors = 38, storage_layout = rabitq",
+        );
+    }
+
+    #[test]
+    fn test_rabitq_storage_delete_vacuum_repair() {
+        crate::access_method::vacuum::tests::test_delete_vacuum_repair_scaffold(
+            "num_neighbors = 10, storage_layout = rabitq",
+        );
+    }
+
+    #[test]
+    fn test_rabitq_storage_delete_vacuum_reuse() {
+        crate::access_method::vacuum::tests::test_delete_vacuum_reuse_scaffold(
+            "num_neighbors = 10, storage_layout = rabitq",
+            64,
+        );
+    }
+
+    #[pg_test]
+    unsafe fn test_rabitq_storage_empty_table_insert() -> spi::Result<()> {
+        crate::access_method::build::tests::test_empty_table_insert_scaffold(
+            "num_neighbors=38, storage_layout = rabitq",
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_rabitq_storage_unlogged_table() -> spi::Result<()> {
+        crate::access_method::build::tests::test_unlogged_table_scaffold(
+            "num_neighbors=38, storage_layout = rabitq",
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_rabitq_storage_insert_empty_insert() -> spi::Result<()> {
+        crate::access_method::build::tests::test_insert_empty_insert_scaffold(
+            "num_neighbors=38, storage_layout = rabitq",
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_rabitq_storage_index_updates() -> spi::Result<()> {
+        crate::access_method::build::tests::test_index_updates(
+            "storage_layout = rabitq, num_neighbors=10",
+            300,
+        )?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_rabitq_storage_inner_product() -> spi::Result<()> {
+        crate::access_method::build::tests::test_distance_operator_scaffold(
+            "storage_layout = rabitq",
+            "vector_ip_ops",
+            "<#>",
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_rabitq_storage_l2() -> spi::Result<()> {
+        crate::access_method::build::tests::test_distance_operator_scaffold(
+            "storage_layout = rabitq",
+            "vector_l2_ops",
+            "<->",
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_rabitq_storage_parallel_build() -> spi::Result<()> {
+        crate::access_method::build::tests::test_parallel_build_scaffold("storage_layout = rabitq")
+    }
+
+    #[pg_test]
+    unsafe fn test_rabitq_storage_high_dimensions() -> spi::Result<()> {
+        crate::access_method::build::tests::test_high_dimensions_scaffold(
+            "storage_layout = rabitq",
+            3072,
+        )
+    }
+}
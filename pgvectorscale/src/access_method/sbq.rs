@@ -1,8 +1,11 @@
 use super::{
     distance::distance_xor_optimized,
+    full_vector::get_full_vector_distance,
     graph::{ListSearchNeighbor, ListSearchResult},
     graph_neighbor_store::GraphNeighborStore,
-    pg_vector::PgVector,
+    labels::{read_labels, LabelSet},
+    parallel_build::BuildVector,
+    pg_vector::{PgVector, VectorType},
     stats::{
         GreedySearchStats, StatsDistanceComparison, StatsHeapNodeRead, StatsNodeModify,
         StatsNodeRead, StatsNodeWrite, WriteStats,
@@ -16,6 +19,8 @@ use pgrx::{
     pg_sys::{InvalidBlockNumber, InvalidOffsetNumber, BLCKSZ},
     PgRelation,
 };
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
 use rkyv::{vec::ArchivedVec, Archive, Deserialize, Serialize};
 
 use crate::util::{
@@ -28,6 +33,13 @@ use crate::util::WritableBuffer;
 
 type SbqVectorElement = u64;
 const BITS_STORE_TYPE_SIZE: usize = 64;
+/* the fewest neighbors the io_optimized layout chooses by default, even if the nodes don't fit on a page then */
+const SBQ_MIN_DEFAULT_NUM_NEIGHBORS: usize = 10;
+/// The bucket boundaries of more than one bit per dimension are percentiles of a random sample of at most this many
+/// vectors.
+const SBQ_MAX_TRAINING_SAMPLES: usize = 2_000;
+/// The seed of the sampling, so that builds of the same data learn the same boundaries.
+const SBQ_TRAINING_SEED: u64 = 0x5b9_b0da;
 
 #[derive(Archive, Deserialize, Serialize, Readable, Writeable)]
 #[archive(check_bytes)]
@@ -36,6 +48,17 @@ pub struct SbqMeans {
     count: u64,
     means: Vec<f32>,
     m2: Vec<f32>,
+    boundaries: Vec<f32>,
+}
+
+/// The SbqMeans of indexes built before the bucket boundaries were learned.
+#[derive(Archive, Deserialize, Serialize, Readable)]
+#[archive(check_bytes)]
+#[repr(C)]
+pub struct SbqMeansV1 {
+    count: u64,
+    means: Vec<f32>,
+    m2: Vec<f32>,
 }
 
 impl SbqMeans {
@@ -47,17 +70,34 @@ impl SbqMeans {
         let mut quantizer = SbqQuantizer::new(meta_page);
         if quantizer.use_mean {
             if meta_page.get_quantizer_metadata_pointer().is_none() {
-                pgrx::error!("No SBQ pointer found in meta page");
+                //only the empty index of an unlogged table (see ambuildempty) has no means. These are the
+                //means an empty build stores.
+                quantizer.start_training(meta_page);
+                quantizer.finish_training();
+                return quantizer;
             }
             let quantizer_item_pointer = meta_page.get_quantizer_metadata_pointer().unwrap();
-            let bq = SbqMeans::read(index, quantizer_item_pointer, stats);
-            let archived = bq.get_archived_node();
-
-            quantizer.load(
-                archived.count,
-                archived.means.to_vec(),
-                archived.m2.to_vec(),
-            );
+            if meta_page.get_sbq_learned_boundaries() {
+                let bq = SbqMeans::read(index, quantizer_item_pointer, stats);
+                let archived = bq.get_archived_node();
+
+                quantizer.load(
+                    archived.count,
+                    archived.means.to_vec(),
+                    archived.m2.to_vec(),
+                    archived.boundaries.to_vec(),
+                );
+            } else {
+                let bq = SbqMeansV1::read(index, quantizer_item_pointer, stats);
+                let archived = bq.get_archived_node();
+
+                quantizer.load(
+                    archived.count,
+                    archived.means.to_vec(),
+                    archived.m2.to_vec(),
+                    vec![],
+                );
+            }
         }
         quantizer
     }
@@ -72,6 +112,7 @@ impl SbqMeans {
             count: quantizer.count,
             means: quantizer.mean.to_vec(),
             m2: quantizer.m2.to_vec(),
+            boundaries: quantizer.boundaries.to_vec(),
         };
         let ptr = node.write(&mut tape, stats);
         tape.close();
@@ -79,6 +120,59 @@ impl SbqMeans {
     }
 }
 
+/// A reservoir sample of the vectors seen during a build, to learn the bucket boundaries from.
+#[derive(Clone)]
+struct SbqTraining {
+    samples: Vec<f32>,
+    num_samples_seen: u64,
+    rng: ChaCha8Rng,
+}
+
+impl SbqTraining {
+    fn new() -> Self {
+        Self {
+            samples: vec![],
+            num_samples_seen: 0,
+            rng: ChaCha8Rng::seed_from_u64(SBQ_TRAINING_SEED),
+        }
+    }
+
+    fn add_sample(&mut self, sample: &[f32]) {
+        self.num_samples_seen += 1;
+        let len = sample.len();
+        if self.samples.len() / len < SBQ_MAX_TRAINING_SAMPLES {
+            self.samples.extend_from_slice(sample);
+        } else {
+            //reservoir sampling: every vector seen so far is in the sample with the same probability
+            let replace = self.rng.gen_range(0..self.num_samples_seen) as usize;
+            if replace < SBQ_MAX_TRAINING_SAMPLES {
+                self.samples[replace * len..(replace + 1) * len].copy_from_slice(sample);
+            }
+        }
+    }
+
+    /// The boundaries that split the sampled values of each dimension into num_bits_per_dimension + 1 buckets
+    /// holding the same number of values.
+    fn boundaries(&self, num_dimensions: usize, num_bits_per_dimension: u8) -> Vec<f32> {
+        if num_dimensions == 0 || self.samples.is_empty() {
+            return vec![];
+        }
+        let num_samples = self.samples.len() / num_dimensions;
+        let num_buckets = num_bits_per_dimension as usize + 1;
+        let mut boundaries = Vec::with_capacity(num_dimensions * num_bits_per_dimension as usize);
+        let mut values: Vec<f32> = Vec::with_capacity(num_samples);
+        for d in 0..num_dimensions {
+            values.clear();
+            values.extend(self.samples.iter().skip(d).step_by(num_dimensions));
+            values.sort_unstable_by(|a, b| a.total_cmp(b));
+            for k in 1..num_buckets {
+                boundaries.push(values[(k * num_samples / num_buckets).min(num_samples - 1)]);
+            }
+        }
+        boundaries
+    }
+}
+
 #[derive(Clone)]
 pub struct SbqQuantizer {
     pub use_mean: bool,
@@ -86,25 +180,33 @@ pub struct SbqQuantizer {
     pub count: u64,
     pub mean: Vec<f32>,
     pub m2: Vec<f32>,
+    /// With more than one bit per dimension, the num_bits_per_dimension ascending boundaries between the buckets of
+    /// each dimension. Empty for indexes that bucket the z-scores between -2 and 2 instead.
+    pub boundaries: Vec<f32>,
+    training_sample: Option<Box<SbqTraining>>,
     pub num_bits_per_dimension: u8,
 }
 
 impl SbqQuantizer {
     fn new(meta_page: &super::meta_page::MetaPage) -> SbqQuantizer {
         Self {
-            use_mean: true,
+            //the bits of a bit vector are used as they are, their xor distance is the hamming distance
+            use_mean: meta_page.get_vector_type() != VectorType::Bit,
             training: false,
             count: 0,
             mean: vec![],
             m2: vec![],
+            boundaries: vec![],
+            training_sample: None,
             num_bits_per_dimension: meta_page.get_bq_num_bits_per_dimension(),
         }
     }
 
-    fn load(&mut self, count: u64, mean: Vec<f32>, m2: Vec<f32>) {
+    fn load(&mut self, count: u64, mean: Vec<f32>, m2: Vec<f32>, boundaries: Vec<f32>) {
         self.count = count;
         self.mean = mean;
-        self.m2 = m2
+        self.m2 = m2;
+        self.boundaries = boundaries;
     }
 
     fn quantized_size(&self, full_vector_size: usize) -> usize {
@@ -138,43 +240,64 @@ impl SbqQuantizer {
                     }
                 }
             } else {
+                let num_bits_per_dimension = self.num_bits_per_dimension as usize;
                 for (i, &v) in full_vector.iter().enumerate() {
-                    let mean = self.mean[i];
-                    let variance = self.m2[i] / self.count as f32;
-                    let std_dev = variance.sqrt();
-                    let ranges = self.num_bits_per_dimension + 1;
-
-                    let v_z_score = (v - mean) / std_dev;
-                    let index = (v_z_score + 2.0) / (4.0 / ranges as f32); //we consider z scores between -2 and 2 and divide them into {ranges} ranges
-
-                    let bit_position = i * self.num_bits_per_dimension as usize;
-                    if index < 1.0 {
-                        //all zeros
+                    let count_ones = if self.boundaries.is_empty() {
+                        self.z_score_bucket(i, v)
                     } else {
-                        let count_ones =
-                            (index.floor() as usize).min(self.num_bits_per_dimension as usize);
-                        //fill in count_ones bits from the left
-                        // ex count_ones=1: 100
-                        // ex count_ones=2: 110
-                        // ex count_ones=3: 111
-                        for j in 0..count_ones {
-                            res_vector[(bit_position + j) / BITS_STORE_TYPE_SIZE] |=
-                                1 << ((bit_position + j) % BITS_STORE_TYPE_SIZE);
-                        }
+                        //the bucket is the number of boundaries below the value
+                        self.boundaries
+                            [i * num_bits_per_dimension..(i + 1) * num_bits_per_dimension]
+                            .iter()
+                            .take_while(|&&b| v > b)
+                            .count()
+                    };
+
+                    let bit_position = i * num_bits_per_dimension;
+                    //fill in count_ones bits from the left
+                    // ex count_ones=1: 100
+                    // ex count_ones=2: 110
+                    // ex count_ones=3: 111
+                    for j in 0..count_ones {
+                        res_vector[(bit_position + j) / BITS_STORE_TYPE_SIZE] |=
+                            1 << ((bit_position + j) % BITS_STORE_TYPE_SIZE);
                     }
                 }
             }
             res_vector
         } else {
-            let mut res_vector = vec![0; self.quantized_size(full_vector.len())];
+            //the f32s of a bit vector hold 32 of its bits each (see PgBitInternal::to_packed)
+            full_vector
+                .chunks(2)
+                .map(|word| {
+                    word[0].to_bits() as SbqVectorElement
+                        | (word[1].to_bits() as SbqVectorElement) << 32
+                })
+                .collect()
+        }
+    }
 
-            for (i, &v) in full_vector.iter().enumerate() {
-                if v > 0.0 {
-                    res_vector[i / BITS_STORE_TYPE_SIZE] |= 1 << (i % BITS_STORE_TYPE_SIZE);
-                }
-            }
+    /// The bucket of dimension i of indexes built before the boundaries were learned.
+    fn z_score_bucket(&self, i: usize, v: f32) -> usize {
+        let mean = self.mean[i];
+        let variance = self.m2[i] / self.count as f32;
+        let std_dev = variance.sqrt();
+        let ranges = self.num_bits_per_dimension + 1;
 
-            res_vector
+        //Vectors aren't normalized for L2 or inner product, so dimensions can have any scale
+        //(including none at all). Constant dimensions (or an untrained quantizer) carry no
+        //information, map them to the middle range instead of dividing by zero.
+        let v_z_score = if std_dev > f32::EPSILON * mean.abs().max(1.0) {
+            (v - mean) / std_dev
+        } else {
+            0.0
+        };
+        let index = (v_z_score + 2.0) / (4.0 / ranges as f32); //we consider z scores between -2 and 2 and divide them into {ranges} ranges
+
+        if index < 1.0 {
+            0
+        } else {
+            (index.floor() as usize).min(self.num_bits_per_dimension as usize)
         }
     }
 
@@ -185,6 +308,8 @@ impl SbqQuantizer {
             self.mean = vec![0.0; meta_page.get_num_dimensions_to_index() as _];
             if self.num_bits_per_dimension > 1 {
                 self.m2 = vec![0.0; meta_page.get_num_dimensions_to_index() as _];
+                self.boundaries = vec![];
+                self.training_sample = Some(Box::new(SbqTraining::new()));
             }
         }
     }
@@ -215,6 +340,10 @@ impl SbqQuantizer {
                     .zip(delta.iter())
                     .zip(delta2)
                     .for_each(|((m2, d), d2)| *m2 += d * d2);
+
+                if let Some(training_sample) = self.training_sample.as_mut() {
+                    training_sample.add_sample(sample);
+                }
             } else {
                 self.mean
                     .iter_mut()
@@ -226,6 +355,11 @@ impl SbqQuantizer {
 
     fn finish_training(&mut self) {
         self.training = false;
+        if let Some(training_sample) = self.training_sample.take() {
+            //without samples (an empty table) the quantizer falls back to the z-score ranges
+            self.boundaries =
+                training_sample.boundaries(self.mean.len(), self.num_bits_per_dimension);
+        }
     }
 
     fn vector_for_new_node(
@@ -289,6 +423,9 @@ impl SbqSearchDistanceMeasure {
         let count_ones = distance_xor_optimized(a, b);
         //dot product is LOWER the more xors that lead to 1 becaues that means a negative times a positive = negative component
         //but the distance is 1 - dot product, so the more count_ones the higher the distance.
+        //With more than one bit per dimension, the bits encode which range of z-scores the component falls in,
+        //so the xor count is the number of ranges between the two components. That approximates the L2 distance
+        //of standardized vectors, which is why we don't require normalized vectors.
         // one other check for distance(a,a), xor=0, count_ones=0, distance=0
         count_ones as f32
     }
@@ -564,6 +701,7 @@ impl<'a> Storage for SbqSpeedupStorage<'a> {
         &self,
         full_vector: &[f32],
         heap_pointer: HeapPointer,
+        scalar_columns: &[u8],
         meta_page: &MetaPage,
         tape: &mut Tape,
         stats: &mut S,
@@ -577,7 +715,7 @@ impl<'a> Storage for SbqSpeedupStorage<'a> {
             bq_vector.as_slice(),
         );
 
-        let index_pointer: IndexPointer = node.write(tape, stats);
+        let index_pointer: IndexPointer = node.write_with_prefix(scalar_columns, tape, stats);
         index_pointer
     }
 
@@ -633,19 +771,27 @@ impl<'a> Storage for SbqSpeedupStorage<'a> {
         );
     }
 
-    fn get_full_distance_for_resort<S: StatsHeapNodeRead + StatsDistanceComparison>(
+    fn get_full_distance_for_resort<
+        S: StatsNodeRead + StatsHeapNodeRead + StatsDistanceComparison,
+    >(
         &self,
         qdm: &Self::QueryDistanceMeasure,
-        _index_pointer: IndexPointer,
+        index_pointer: IndexPointer,
         heap_pointer: HeapPointer,
         meta_page: &MetaPage,
         stats: &mut S,
     ) -> f32 {
+        if meta_page.get_full_vectors_in_index() {
+            return unsafe {
+                get_full_vector_distance(self.index, index_pointer, &qdm.query, meta_page, stats)
+            };
+        }
+
         let slot = unsafe { self.get_heap_table_slot_from_heap_pointer(heap_pointer, stats) };
 
         let datum = unsafe { slot.get_attribute(self.heap_attr).unwrap() };
         let vec = unsafe { PgVector::from_datum(datum, meta_page, false, true) };
-        self.get_distance_function()(vec.to_full_slice(), qdm.query.to_full_slice())
+        vec.get_full_distance(&qdm.query, meta_page)
     }
 
     fn get_neighbors_with_distances_from_disk<S: StatsNodeRead + StatsDistanceComparison>(
@@ -736,9 +882,49 @@ impl<'a> Storage for SbqSpeedupStorage<'a> {
         node.commit();
     }
 
+    fn replace_neighbors_on_disk<S: StatsNodeModify + StatsNodeRead>(
+        &self,
+        meta: &MetaPage,
+        index_pointer: IndexPointer,
+        expected: &[IndexPointer],
+        neighbors: &[NeighborWithDistance],
+        stats: &mut S,
+    ) -> bool {
+        let mut cache = QuantizedVectorCache::new(neighbors.len() + 1);
+
+        //preloaded for the same reason as in set_neighbors_on_disk
+        let iter = neighbors
+            .iter()
+            .map(|n| n.get_index_pointer_to_neighbor())
+            .chain(once(index_pointer));
+        cache.preload(iter, self, stats);
+
+        let node = unsafe { SbqNode::modify(self.index, index_pointer, stats) };
+        let mut archived = node.get_archived_node();
+        if archived.get_index_pointer_to_neighbors() != expected {
+            return false;
+        }
+        archived.as_mut().set_neighbors(neighbors, meta, &cache);
+        node.commit();
+        true
+    }
+
     fn get_distance_function(&self) -> fn(&[f32], &[f32]) -> f32 {
         self.distance_fn
     }
+
+    fn get_build_vector(&self, full_vector: &[f32]) -> BuildVector {
+        BuildVector::Quantized(self.quantizer.quantize(full_vector))
+    }
+
+    fn get_labels(&self, index_pointer: IndexPointer) -> LabelSet {
+        unsafe { read_labels(self.index, index_pointer) }
+    }
+
+    fn is_deleted<S: StatsNodeRead>(&self, index_pointer: IndexPointer, stats: &mut S) -> bool {
+        let rn = unsafe { SbqNode::read(self.index, index_pointer, stats) };
+        rn.get_archived_node().is_deleted()
+    }
 }
 
 use pgvectorscale_derive::{Readable, Writeable};
@@ -830,18 +1016,21 @@ impl SbqNode {
         num_dimensions: usize,
         num_dimensions_for_neighbors: usize,
         num_bits_per_dimension: u8,
+        reserved_size: usize,
     ) -> usize {
         //how many neighbors can fit on one page? That's what we choose.
+        //reserved_size is space on the page used by something else than the node (scalar columns).
 
         //we first overapproximate the number of neighbors and then double check by actually calculating the size of the SbqNode.
 
         //blocksize - 100 bytes for the padding/header/etc.
-        let page_size = BLCKSZ as usize - 50;
+        let page_size = BLCKSZ as usize - 50 - reserved_size;
         //one quantized_vector takes this many bytes
         let vec_size =
             SbqQuantizer::quantized_size_bytes(num_dimensions as usize, num_bits_per_dimension) + 1;
         //start from the page size then subtract the heap_item_pointer and bq_vector elements of SbqNode.
-        let starting = BLCKSZ as usize - std::mem::size_of::<HeapPointer>() - vec_size;
+        let starting =
+            (BLCKSZ as usize - std::mem::size_of::<HeapPointer>()).saturating_sub(vec_size);
         //one neigbors contribution to neighbor_index_pointers + neighbor_vectors in SbqNode.
         let one_neighbor = vec_size + std::mem::size_of::<ItemPointer>();
 
@@ -854,13 +1043,13 @@ impl SbqNode {
                 num_bits_per_dimension,
             );
             if serialized_size <= page_size {
-                return num_neighbors_overapproximate;
+                return num_neighbors_overapproximate.max(SBQ_MIN_DEFAULT_NUM_NEIGHBORS);
             }
             num_neighbors_overapproximate -= 1;
         }
-        pgrx::error!(
-            "Could not find a valid number of neighbors for the default value. Please specify one."
-        );
+        //the vectors are too large for a useful number of neighbors on a page, so the nodes are chained over
+        //several pages (see Tape::write)
+        SBQ_MIN_DEFAULT_NUM_NEIGHBORS
     }
 }
 
@@ -927,6 +1116,10 @@ impl ArchivedData for ArchivedSbqNode {
         ArchivedSbqNode::with_data(data)
     }
 
+    fn with_readable_data(data: &[u8]) -> &ArchivedSbqNode {
+        unsafe { rkyv::archived_root::<SbqNode>(data) }
+    }
+
     fn get_index_pointer_to_neighbors(&self) -> Vec<ItemPointer> {
         self.iter_neighbors().collect()
     }
@@ -983,6 +1176,21 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_bq_speedup_storage_delete_vacuum_repair() {
+        crate::access_method::vacuum::tests::test_delete_vacuum_repair_scaffold(
+            "num_neighbors = 10, storage_layout = io_optimized",
+        );
+    }
+
+    #[test]
+    fn test_bq_speedup_storage_delete_vacuum_reuse() {
+        crate::access_method::vacuum::tests::test_delete_vacuum_reuse_scaffold(
+            "num_neighbors = 10, storage_layout = io_optimized",
+            64,
+        );
+    }
+
     #[pg_test]
     unsafe fn test_bq_speedup_storage_empty_table_insert() -> spi::Result<()> {
         crate::access_method::build::tests::test_empty_table_insert_scaffold(
@@ -990,6 +1198,13 @@ mod tests {
         )
     }
 
+    #[pg_test]
+    unsafe fn test_bq_speedup_storage_unlogged_table() -> spi::Result<()> {
+        crate::access_method::build::tests::test_unlogged_table_scaffold(
+            "num_neighbors=38, storage_layout = io_optimized",
+        )
+    }
+
     #[pg_test]
     unsafe fn test_bq_speedup_storage_insert_empty_insert() -> spi::Result<()> {
         crate::access_method::build::tests::test_insert_empty_insert_scaffold(
@@ -1045,6 +1260,21 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_bq_compressed_storage_delete_vacuum_repair() {
+        crate::access_method::vacuum::tests::test_delete_vacuum_repair_scaffold(
+            "num_neighbors = 10, storage_layout = memory_optimized",
+        );
+    }
+
+    #[test]
+    fn test_bq_compressed_storage_delete_vacuum_reuse() {
+        crate::access_method::vacuum::tests::test_delete_vacuum_reuse_scaffold(
+            "num_neighbors = 10, storage_layout = memory_optimized",
+            64,
+        );
+    }
+
     #[pg_test]
     unsafe fn test_bq_compressed_storage_empty_table_insert() -> spi::Result<()> {
         crate::access_method::build::tests::test_empty_table_insert_scaffold(
@@ -1052,6 +1282,13 @@ mod tests {
         )
     }
 
+    #[pg_test]
+    unsafe fn test_bq_compressed_storage_unlogged_table() -> spi::Result<()> {
+        crate::access_method::build::tests::test_unlogged_table_scaffold(
+            "num_neighbors=38, storage_layout = memory_optimized",
+        )
+    }
+
     #[pg_test]
     unsafe fn test_bq_compressed_storage_insert_empty_insert() -> spi::Result<()> {
         crate::access_method::build::tests::test_insert_empty_insert_scaffold(
@@ -1075,4 +1312,188 @@ mod tests {
         )?;
         Ok(())
     }
+
+    #[pg_test]
+    unsafe fn test_bq_compressed_storage_inner_product() -> spi::Result<()> {
+        crate::access_method::build::tests::test_distance_operator_scaffold(
+            "storage_layout = memory_optimized",
+            "vector_ip_ops",
+            "<#>",
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_bq_compressed_storage_inner_product_recall() -> spi::Result<()> {
+        crate::access_method::build::tests::test_distance_operator_recall_scaffold(
+            "storage_layout = memory_optimized",
+            "vector_ip_ops",
+            "<#>",
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_bq_compressed_storage_l2() -> spi::Result<()> {
+        crate::access_method::build::tests::test_distance_operator_scaffold(
+            "storage_layout = memory_optimized",
+            "vector_l2_ops",
+            "<->",
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_bq_speedup_storage_l2() -> spi::Result<()> {
+        crate::access_method::build::tests::test_distance_operator_scaffold(
+            "storage_layout = io_optimized",
+            "vector_l2_ops",
+            "<->",
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_bq_compressed_storage_parallel_build() -> spi::Result<()> {
+        crate::access_method::build::tests::test_parallel_build_scaffold(
+            "storage_layout = memory_optimized",
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_bq_compressed_storage_include_columns() -> spi::Result<()> {
+        crate::access_method::build::tests::test_include_columns_scaffold(
+            "storage_layout = memory_optimized",
+            "vector_cosine_ops",
+            "<=>",
+            false,
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_bq_speedup_storage_include_columns() -> spi::Result<()> {
+        crate::access_method::build::tests::test_include_columns_scaffold(
+            "storage_layout = io_optimized",
+            "vector_cosine_ops",
+            "<=>",
+            false,
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_bq_compressed_storage_filtered_search() -> spi::Result<()> {
+        crate::access_method::build::tests::test_filtered_search_scaffold(
+            "storage_layout = memory_optimized",
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_bq_compressed_storage_range_search() -> spi::Result<()> {
+        crate::access_method::build::tests::test_range_search_scaffold(
+            "storage_layout = memory_optimized",
+            "vector_cosine_ops",
+            "<=>",
+            "<<=>>",
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_bq_compressed_storage_halfvec() -> spi::Result<()> {
+        crate::access_method::build::tests::test_halfvec_scaffold(
+            "storage_layout = memory_optimized",
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_bq_compressed_storage_sparsevec() -> spi::Result<()> {
+        crate::access_method::build::tests::test_sparsevec_scaffold(
+            "storage_layout = memory_optimized",
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_bq_compressed_storage_bit() -> spi::Result<()> {
+        crate::access_method::build::tests::test_bit_scaffold("storage_layout = memory_optimized")
+    }
+
+    #[pg_test]
+    unsafe fn test_bq_compressed_storage_bit_high_dimensions() -> spi::Result<()> {
+        crate::access_method::build::tests::test_bit_high_dimensions_scaffold(
+            "storage_layout = memory_optimized",
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_bq_speedup_storage_high_dimensions() -> spi::Result<()> {
+        crate::access_method::build::tests::test_high_dimensions_scaffold(
+            "storage_layout = io_optimized",
+            3072,
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_bq_compressed_storage_high_dimensions() -> spi::Result<()> {
+        crate::access_method::build::tests::test_high_dimensions_scaffold(
+            "storage_layout = memory_optimized, num_bits_per_dimension = 2",
+            3072,
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_bq_compressed_storage_labels() -> spi::Result<()> {
+        crate::access_method::build::tests::test_labels_scaffold(
+            "storage_layout = memory_optimized",
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_bq_speedup_storage_index_creation_multiple_bits() -> spi::Result<()> {
+        crate::access_method::build::tests::test_index_creation_and_accuracy_scaffold(
+            "storage_layout = io_optimized, num_bits_per_dimension = 2",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_bq_speedup_storage_index_updates_four_bits() -> spi::Result<()> {
+        crate::access_method::build::tests::test_index_updates(
+            "storage_layout = io_optimized, num_neighbors=10, num_bits_per_dimension = 4",
+            300,
+        )?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_bq_compressed_storage_index_creation_three_bits() -> spi::Result<()> {
+        crate::access_method::build::tests::test_index_creation_and_accuracy_scaffold(
+            "storage_layout = memory_optimized, num_bits_per_dimension = 3",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_bq_compressed_storage_index_creation_four_bits() -> spi::Result<()> {
+        crate::access_method::build::tests::test_index_creation_and_accuracy_scaffold(
+            "storage_layout = memory_optimized, num_bits_per_dimension = 4",
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_bq_compressed_storage_delete_vacuum_reuse_four_bits() {
+        crate::access_method::vacuum::tests::test_delete_vacuum_reuse_scaffold(
+            "num_neighbors = 10, storage_layout = memory_optimized, num_bits_per_dimension = 4",
+            64,
+        );
+    }
+
+    #[pg_test]
+    unsafe fn test_sbq_learned_boundaries() {
+        let mut training = super::SbqTraining::new();
+        //two dimensions: 0..1000 and 1000..0
+        for i in 0..1000 {
+            training.add_sample(&[i as f32, (1000 - i) as f32]);
+        }
+        let boundaries = training.boundaries(2, 3);
+        assert_eq!(boundaries, vec![250.0, 500.0, 750.0, 251.0, 501.0, 751.0]);
+
+        //no samples: the z-score ranges are used
+        assert!(super::SbqTraining::new().boundaries(2, 3).is_empty());
+    }
 }
@@ -11,11 +11,24 @@ use crate::{
 };
 
 use super::{
-    graph::{Graph, ListSearchResult},
+    graph::{Graph, ListSearchNeighbor, ListSearchResult},
+    labels::{labels_from_scan_keys, LabelSet},
+    meta_page::DistanceType,
+    pg_vector::{PgVectorInternal, VectorType},
+    plain_node::Node,
     plain_storage::{PlainDistanceMeasure, PlainStorage, PlainStorageLsnPrivateData},
+    pq::{PqCodebooks, PqQuantizer, PqSearchDistanceMeasure, PqStorage, PqStorageLsnPrivateData},
+    rabitq::{
+        RabitqCentroid, RabitqQuantizer, RabitqSearchDistanceMeasure, RabitqStorage,
+        RabitqStorageLsnPrivateData,
+    },
     sbq::{SbqMeans, SbqQuantizer, SbqSearchDistanceMeasure, SbqSpeedupStorageLsnPrivateData},
+    sq8::{
+        Sq8Quantizer, Sq8Ranges, Sq8SearchDistanceMeasure, Sq8Storage, Sq8StorageLsnPrivateData,
+    },
     stats::QuantizerStats,
     storage::{Storage, StorageType},
+    storage_common::{has_scalar_columns, read_scalar_columns, scalar_columns_match},
 };
 
 /* Be very careful not to transfer PgRelations in the state, as they can change between calls. That means we shouldn't be
@@ -26,6 +39,30 @@ enum StorageState {
         TSVResponseIterator<SbqSearchDistanceMeasure, SbqSpeedupStorageLsnPrivateData>,
     ),
     Plain(TSVResponseIterator<PlainDistanceMeasure, PlainStorageLsnPrivateData>),
+    Pq(
+        PqQuantizer,
+        TSVResponseIterator<PqSearchDistanceMeasure, PqStorageLsnPrivateData>,
+    ),
+    Sq8(
+        Sq8Quantizer,
+        TSVResponseIterator<Sq8SearchDistanceMeasure, Sq8StorageLsnPrivateData>,
+    ),
+    Rabitq(
+        RabitqQuantizer,
+        TSVResponseIterator<RabitqSearchDistanceMeasure, RabitqStorageLsnPrivateData>,
+    ),
+}
+
+impl StorageState {
+    fn last_distance(&self) -> f32 {
+        match self {
+            StorageState::SbqSpeedup(_, iter) => iter.last_distance,
+            StorageState::Plain(iter) => iter.last_distance,
+            StorageState::Pq(_, iter) => iter.last_distance,
+            StorageState::Sq8(_, iter) => iter.last_distance,
+            StorageState::Rabitq(_, iter) => iter.last_distance,
+        }
+    }
 }
 
 /* no lifetime usage here. */
@@ -34,46 +71,63 @@ struct TSVScanState {
     distance_fn: Option<fn(&[f32], &[f32]) -> f32>,
     meta_page: MetaPage,
     last_buffer: Option<PinnedBufferShare>,
+    has_scalar_columns: bool,
+    /* the index tuple last returned to an index-only scan */
+    last_index_tuple: pg_sys::IndexTuple,
+    /* the radius of a range search or diskann.max_distance, as a distance of the index (see RangeKey) */
+    radius: f32,
+    /* the executor has to recheck the range keys */
+    recheck: bool,
 }
 
 impl TSVScanState {
-    fn new(meta_page: MetaPage) -> Self {
+    fn new(meta_page: MetaPage, has_scalar_columns: bool) -> Self {
         Self {
             storage: std::ptr::null_mut(),
             distance_fn: None,
             meta_page: meta_page,
             last_buffer: None,
+            has_scalar_columns,
+            last_index_tuple: std::ptr::null_mut(),
+            radius: f32::INFINITY,
+            recheck: false,
         }
     }
 
-    fn initialize(
-        &mut self,
-        index: &PgRelation,
-        heap: &PgRelation,
-        query: PgVector,
-        search_list_size: usize,
-    ) {
+    fn initialize(&mut self, index: &PgRelation, heap: &PgRelation, search: ScanQuery) {
         let meta_page = MetaPage::fetch(&index);
-        let storage = meta_page.get_storage_type();
         let distance = meta_page.get_distance_function();
+        let mut stats = QuantizerStats::new();
 
-        let store_type = match storage {
+        let store_type = match meta_page.get_storage_type() {
             StorageType::Plain => {
-                let stats = QuantizerStats::new();
-                let bq =
-                    PlainStorage::load_for_search(index, heap, meta_page.get_distance_function());
-                let it =
-                    TSVResponseIterator::new(&bq, index, query, search_list_size, meta_page, stats);
-                StorageState::Plain(it)
+                let plain = PlainStorage::load_for_search(index, heap, distance);
+                StorageState::Plain(TSVResponseIterator::new(&plain, index, search, stats))
             }
             StorageType::SbqSpeedup | StorageType::SbqCompression => {
-                let mut stats = QuantizerStats::new();
                 let quantizer = unsafe { SbqMeans::load(index, &meta_page, &mut stats) };
                 let bq = SbqSpeedupStorage::load_for_search(index, heap, &quantizer, &meta_page);
-                let it =
-                    TSVResponseIterator::new(&bq, index, query, search_list_size, meta_page, stats);
+                let it = TSVResponseIterator::new(&bq, index, search, stats);
                 StorageState::SbqSpeedup(quantizer, it)
             }
+            StorageType::Pq => {
+                let quantizer = unsafe { PqCodebooks::load(index, &meta_page, &mut stats) };
+                let pq = PqStorage::load_for_search(index, heap, &quantizer, &meta_page);
+                let it = TSVResponseIterator::new(&pq, index, search, stats);
+                StorageState::Pq(quantizer, it)
+            }
+            StorageType::Sq8 => {
+                let quantizer = unsafe { Sq8Ranges::load(index, &meta_page, &mut stats) };
+                let sq8 = Sq8Storage::load_for_search(index, heap, &quantizer, &meta_page);
+                let it = TSVResponseIterator::new(&sq8, index, search, stats);
+                StorageState::Sq8(quantizer, it)
+            }
+            StorageType::Rabitq => {
+                let quantizer = unsafe { RabitqCentroid::load(index, &meta_page, &mut stats) };
+                let rabitq = RabitqStorage::load_for_search(index, heap, &quantizer, &meta_page);
+                let it = TSVResponseIterator::new(&rabitq, index, search, stats);
+                StorageState::Rabitq(quantizer, it)
+            }
         };
 
         self.storage = PgMemoryContexts::CurrentMemoryContext.leak_and_drop_on_delete(store_type);
@@ -81,6 +135,77 @@ impl TSVScanState {
     }
 }
 
+/// What a scan searches for, the same for every storage layout.
+struct ScanQuery {
+    query: PgVector,
+    search_list_size: usize,
+    filter: Option<ScanKeyFilter>,
+}
+
+/* The scan keys on the scalar key columns (e.g. `WHERE tenant_id = 1` on an index on (embedding, tenant_id)).
+They are checked against the scalar columns stored with each node while the graph is traversed, so only the nodes
+that pass them are returned. The keys live in the scan descriptor's keyData, which outlives the scan state. */
+struct ScanKeyFilter {
+    keys: *mut pg_sys::ScanKeyData,
+    nkeys: usize,
+}
+
+impl ScanKeyFilter {
+    unsafe fn matches(&self, index: &PgRelation, index_pointer: IndexPointer) -> bool {
+        let keys = std::slice::from_raw_parts_mut(self.keys, self.nkeys);
+        scalar_columns_match(index, index_pointer, keys)
+    }
+
+    /// The labels the rows matching the filter have one of, if the filter restricts the labels column.
+    unsafe fn get_labels(&self, index: &PgRelation) -> Option<LabelSet> {
+        let keys = std::slice::from_raw_parts(self.keys, self.nkeys);
+        labels_from_scan_keys(index, keys)
+    }
+}
+
+/// Strategy number of the range operators of the vector operator classes (`<<=>>`, `<<->>` and `<<#>>`, see mod.rs).
+const RANGE_STRATEGY_NUMBER: pg_sys::StrategyNumber = 2;
+
+/* A range search (e.g. `WHERE embedding <<=>> sphere($1, 0.2)`) searches the graph from the center of the sphere and
+ends at the first result farther than the radius. The results come in the order of their distance, rescored where the
+storage rescores, so a range search misses about as many rows as a top-k search does. The executor rechecks the
+results, which also takes care of further range keys. */
+struct RangeKey {
+    center: pg_sys::Datum,
+    radius: f64,
+}
+
+impl RangeKey {
+    unsafe fn from_scan_key(key: &pg_sys::ScanKeyData) -> Self {
+        assert!(key.sk_strategy == RANGE_STRATEGY_NUMBER);
+        let sphere =
+            pg_sys::pg_detoast_datum(key.sk_argument.cast_mut_ptr()) as pg_sys::HeapTupleHeader;
+        let mut isnull = false;
+        let center = pg_sys::GetAttributeByNum(sphere, 1, &mut isnull);
+        if isnull {
+            pgrx::error!("the center of the sphere can't be NULL");
+        }
+        let radius = pg_sys::GetAttributeByNum(sphere, 2, &mut isnull);
+        if isnull {
+            pgrx::error!("the radius of the sphere can't be NULL");
+        }
+        Self {
+            center,
+            radius: f64::from_datum(radius, false).unwrap(),
+        }
+    }
+}
+
+/// A distance threshold as a distance of the index, which leaves out the square root of the L2 distance.
+fn index_distance_threshold(threshold: f64, distance_type: DistanceType) -> f32 {
+    match distance_type {
+        //no distance is below a negative threshold
+        DistanceType::L2 if threshold < 0.0 => f32::NEG_INFINITY,
+        DistanceType::L2 => (threshold * threshold) as f32,
+        _ => threshold as f32,
+    }
+}
+
 struct ResortData {
     heap_pointer: HeapPointer,
     index_pointer: IndexPointer,
@@ -122,7 +247,8 @@ impl StreamingStats {
             count: 0,
             mean: 0.0,
             m2: 0.0,
-            max_distance: 0.0,
+            //inner product distances can be negative
+            max_distance: f32::NEG_INFINITY,
         }
     }
 
@@ -145,8 +271,10 @@ impl StreamingStats {
     }
 
     fn update(&mut self, distance: f32, diff: f32) {
-        //base stats only on first resort_size elements
-        self.update_base_stats(diff);
+        //base stats only on first resort_size elements, there is nothing to diff the first distance with
+        if self.max_distance != f32::NEG_INFINITY {
+            self.update_base_stats(diff);
+        }
         self.max_distance = self.max_distance.max(distance);
     }
 }
@@ -162,22 +290,35 @@ struct TSVResponseIterator<QDM, PD> {
     next_calls: i32,
     next_calls_with_resort: i32,
     full_distance_comparisons: i32,
+    filter: Option<ScanKeyFilter>,
+    /// Candidates not rescored yet, by the lower bound of their distance (see next_with_bounded_resort).
+    unscored_buffer: BinaryHeap<ResortData>,
+    /// The estimated distance of the last candidate found and the largest error bound seen so far.
+    last_estimated_distance: Option<f32>,
+    max_error_bound: f32,
+    candidates_exhausted: bool,
+    /// The distance of the last result returned, exact if the result was rescored.
+    last_distance: f32,
 }
 
 impl<QDM, PD> TSVResponseIterator<QDM, PD> {
     fn new<S: Storage<QueryDistanceMeasure = QDM, LSNPrivateData = PD>>(
         storage: &S,
         index: &PgRelation,
-        query: PgVector,
-        search_list_size: usize,
-        //FIXME?
-        _meta_page: MetaPage,
+        search: ScanQuery,
         quantizer_stats: QuantizerStats,
     ) -> Self {
+        let ScanQuery {
+            query,
+            search_list_size,
+            filter,
+        } = search;
         let mut meta_page = MetaPage::fetch(&index);
         let graph = Graph::new(GraphNeighborStore::Disk, &mut meta_page);
 
-        let lsr = graph.greedy_search_streaming_init(query, search_list_size, storage);
+        let labels = filter.as_ref().and_then(|f| unsafe { f.get_labels(index) });
+        let lsr =
+            graph.greedy_search_streaming_init(query, search_list_size, labels.as_ref(), storage);
         let resort_size = super::guc::TSV_RESORT_SIZE.get() as usize;
 
         Self {
@@ -191,6 +332,12 @@ impl<QDM, PD> TSVResponseIterator<QDM, PD> {
             next_calls: 0,
             next_calls_with_resort: 0,
             full_distance_comparisons: 0,
+            filter,
+            unscored_buffer: BinaryHeap::new(),
+            last_estimated_distance: None,
+            max_error_bound: 0.0,
+            candidates_exhausted: false,
+            last_distance: f32::NEG_INFINITY,
         }
     }
 }
@@ -198,24 +345,45 @@ impl<QDM, PD> TSVResponseIterator<QDM, PD> {
 impl<QDM, PD> TSVResponseIterator<QDM, PD> {
     fn next<S: Storage<QueryDistanceMeasure = QDM, LSNPrivateData = PD>>(
         &mut self,
+        index: &PgRelation,
         storage: &S,
     ) -> Option<(HeapPointer, IndexPointer)> {
+        let (heap_pointer, lsn) = self.next_lsn(index, storage)?;
+        self.last_distance = lsn.get_distance();
+        Some((heap_pointer, lsn.index_pointer))
+    }
+
+    fn next_lsn<S: Storage<QueryDistanceMeasure = QDM, LSNPrivateData = PD>>(
+        &mut self,
+        index: &PgRelation,
+        storage: &S,
+    ) -> Option<(HeapPointer, ListSearchNeighbor<PD>)> {
         self.next_calls += 1;
         let graph = Graph::new(GraphNeighborStore::Disk, &mut self.meta_page);
+        let filter = self
+            .filter
+            .as_ref()
+            .map(|f| move |index_pointer| unsafe { f.matches(index, index_pointer) });
 
         /* Iterate until we find a non-deleted tuple */
         loop {
-            graph.greedy_search_iterate(&mut self.lsr, self.search_list_size, None, storage);
+            graph.greedy_search_iterate(
+                &mut self.lsr,
+                self.search_list_size,
+                None,
+                filter.as_ref().map(|f| f as &dyn Fn(IndexPointer) -> bool),
+                storage,
+            );
 
             let item = self.lsr.consume(storage);
 
             match item {
-                Some((heap_pointer, index_pointer)) => {
+                Some((heap_pointer, lsn)) => {
                     if heap_pointer.offset == InvalidOffsetNumber {
                         /* deleted tuple */
                         continue;
                     }
-                    return Some((heap_pointer, index_pointer));
+                    return Some((heap_pointer, lsn));
                 }
                 None => {
                     return None;
@@ -226,12 +394,12 @@ impl<QDM, PD> TSVResponseIterator<QDM, PD> {
 
     fn next_with_resort<S: Storage<QueryDistanceMeasure = QDM, LSNPrivateData = PD>>(
         &mut self,
-        _index: &PgRelation,
+        index: &PgRelation,
         storage: &S,
     ) -> Option<(HeapPointer, IndexPointer)> {
         self.next_calls_with_resort += 1;
         if self.resort_buffer.capacity() == 0 {
-            return self.next(storage);
+            return self.next(index, storage);
         }
 
         while self.resort_buffer.len() < 2
@@ -239,7 +407,7 @@ impl<QDM, PD> TSVResponseIterator<QDM, PD> {
             || (self.streaming_stats.max_distance - self.resort_buffer.peek().unwrap().distance)
                 < self.streaming_stats.variance().sqrt() * (self.resort_size as f32 / 100.0)
         {
-            match self.next(storage) {
+            match self.next(index, storage) {
                 Some((heap_pointer, index_pointer)) => {
                     self.full_distance_comparisons += 1;
                     let distance = storage.get_full_distance_for_resort(
@@ -277,12 +445,92 @@ impl<QDM, PD> TSVResponseIterator<QDM, PD> {
         );*/
 
         match self.resort_buffer.pop() {
-            Some(rd) => Some((rd.heap_pointer, rd.index_pointer)),
+            Some(rd) => {
+                self.last_distance = rd.distance;
+                Some((rd.heap_pointer, rd.index_pointer))
+            }
             None => None,
         }
     }
 }
 
+impl<QDM> TSVResponseIterator<QDM, RabitqStorageLsnPrivateData> {
+    /// Rescoring for quantizers whose estimated distances come with an error bound. A candidate is only rescored
+    /// once the lower bound of its distance is below the best rescored distance, and the best rescored candidate is
+    /// returned once no other candidate can beat it. Candidates come out of the graph ordered by their estimated
+    /// distance, so the candidates not found yet are assumed to be no closer than the last estimated distance minus
+    /// the largest error bound seen.
+    ///
+    /// This is a heuristic, not a guarantee: the bounds only hold with high probability (see
+    /// RABITQ_ERROR_CONFIDENCE), the graph search is approximate itself, and nodes not found yet may have larger
+    /// errors than the ones seen. A few results can be missed or come out of order.
+    fn next_with_bounded_resort<
+        S: Storage<QueryDistanceMeasure = QDM, LSNPrivateData = RabitqStorageLsnPrivateData>,
+    >(
+        &mut self,
+        index: &PgRelation,
+        storage: &S,
+    ) -> Option<(HeapPointer, IndexPointer)> {
+        self.next_calls_with_resort += 1;
+        loop {
+            let best_distance = self.resort_buffer.peek().map(|rd| rd.distance);
+
+            /* rescore the candidates that could beat the best result */
+            if let Some(candidate) = self.unscored_buffer.peek() {
+                if best_distance.map_or(true, |best| candidate.distance < best) {
+                    let candidate = self.unscored_buffer.pop().unwrap();
+                    self.full_distance_comparisons += 1;
+                    let distance = storage.get_full_distance_for_resort(
+                        self.lsr.sdm.as_ref().unwrap(),
+                        candidate.index_pointer,
+                        candidate.heap_pointer,
+                        &self.meta_page,
+                        &mut self.lsr.stats,
+                    );
+                    self.resort_buffer.push(ResortData {
+                        heap_pointer: candidate.heap_pointer,
+                        index_pointer: candidate.index_pointer,
+                        distance,
+                    });
+                    continue;
+                }
+            }
+
+            /* find more candidates while one of them could beat the best result */
+            let next_lower_bound = self
+                .last_estimated_distance
+                .map(|distance| distance - self.max_error_bound);
+            let could_beat_best = match (best_distance, next_lower_bound) {
+                (Some(best), Some(lower_bound)) => lower_bound < best,
+                _ => true,
+            };
+            if !self.candidates_exhausted && could_beat_best {
+                match self.next_lsn(index, storage) {
+                    Some((heap_pointer, lsn)) => {
+                        let error_bound = *lsn.get_private_data();
+                        self.last_estimated_distance = Some(lsn.get_distance());
+                        self.max_error_bound = self.max_error_bound.max(error_bound);
+                        self.unscored_buffer.push(ResortData {
+                            heap_pointer,
+                            index_pointer: lsn.index_pointer,
+                            distance: lsn.get_distance() - error_bound,
+                        });
+                    }
+                    None => {
+                        self.candidates_exhausted = true;
+                    }
+                }
+                continue;
+            }
+
+            return self.resort_buffer.pop().map(|rd| {
+                self.last_distance = rd.distance;
+                (rd.heap_pointer, rd.index_pointer)
+            });
+        }
+    }
+}
+
 /*
 struct TSVScanState<'a, 'b> {
     iterator: *mut TSVResponseIterator<'a, 'b>,
@@ -304,7 +552,7 @@ pub extern "C" fn ambeginscan(
     let indexrel = unsafe { PgRelation::from_pg(index_relation) };
     let meta_page = MetaPage::fetch(&indexrel);
 
-    let state: TSVScanState = TSVScanState::new(meta_page);
+    let state: TSVScanState = TSVScanState::new(meta_page, has_scalar_columns(&indexrel));
     scandesc.opaque =
         PgMemoryContexts::CurrentMemoryContext.leak_and_drop_on_delete(state) as void_mut_ptr;
 
@@ -314,42 +562,95 @@ pub extern "C" fn ambeginscan(
 #[pg_guard]
 pub extern "C" fn amrescan(
     scan: pg_sys::IndexScanDesc,
-    _keys: pg_sys::ScanKey,
+    keys: pg_sys::ScanKey,
     nkeys: ::std::os::raw::c_int,
     orderbys: pg_sys::ScanKey,
     norderbys: ::std::os::raw::c_int,
 ) {
-    if norderbys == 0 {
-        panic!("No order by keys provided");
-    }
     if norderbys > 1 {
         panic!("Too many order by provided");
     }
-    let mut scan: PgBox<pg_sys::IndexScanDescData> = unsafe { PgBox::from_pg(scan) };
+    let scan: PgBox<pg_sys::IndexScanDescData> = unsafe { PgBox::from_pg(scan) };
     let indexrel = unsafe { PgRelation::from_pg(scan.indexRelation) };
     let heaprel = unsafe { PgRelation::from_pg(scan.heapRelation) };
 
-    if nkeys > 0 {
-        scan.xs_recheck = true;
-    }
+    let scan_keys = if nkeys > 0 {
+        unsafe {
+            if keys != scan.keyData {
+                std::ptr::copy(keys, scan.keyData, nkeys as _);
+            }
+            std::slice::from_raw_parts_mut(scan.keyData, nkeys as _)
+        }
+    } else {
+        &mut []
+    };
+    /* the keys on the vector column are range keys, they go after the keys on the scalar key columns */
+    scan_keys.sort_by_key(|key| key.sk_attno == 1);
+    let num_scalar_keys = scan_keys.iter().filter(|key| key.sk_attno != 1).count();
+    let range_key = scan_keys[num_scalar_keys..]
+        .first()
+        .map(|key| unsafe { RangeKey::from_scan_key(key) });
+
+    /* the keys on the scalar key columns are checked exactly during the search, no recheck is needed */
+    let filter = if num_scalar_keys > 0 {
+        Some(ScanKeyFilter {
+            keys: scan.keyData,
+            nkeys: num_scalar_keys,
+        })
+    } else {
+        None
+    };
 
-    let orderby_keys = unsafe {
-        std::slice::from_raw_parts(orderbys as *const pg_sys::ScanKeyData, norderbys as _)
+    let orderby_keys = if norderbys > 0 {
+        unsafe {
+            std::slice::from_raw_parts(orderbys as *const pg_sys::ScanKeyData, norderbys as _)
+        }
+    } else {
+        &[]
     };
 
     let search_list_size = super::guc::TSV_QUERY_SEARCH_LIST_SIZE.get() as usize;
 
     let state = unsafe { (scan.opaque as *mut TSVScanState).as_mut() }.expect("no scandesc state");
 
+    let query_datum = match (orderby_keys.first(), &range_key) {
+        (Some(orderby_key), _) => orderby_key.sk_argument,
+        (None, Some(range_key)) => range_key.center,
+        //amcostestimate keeps the planner from choosing such scans
+        (None, None) => pgrx::error!(
+            "diskann index scans need an ORDER BY on the vector column or a distance threshold"
+        ),
+    };
     let query = unsafe {
         PgVector::from_datum(
-            orderby_keys[0].sk_argument,
+            query_datum,
             &state.meta_page,
             true, /* needed for search */
             true, /* needed for resort */
         )
     };
-    state.initialize(&indexrel, &heaprel, query, search_list_size);
+
+    let distance_type = state.meta_page.get_distance_type();
+    /* the results are only ordered by their distance to the center if the search starts there */
+    let range_radius = range_key.as_ref().and_then(|range_key| {
+        let searches_center = orderby_keys.is_empty() || {
+            let center =
+                unsafe { PgVector::from_datum(range_key.center, &state.meta_page, false, true) };
+            center.to_full_slice() == query.to_full_slice()
+        };
+        searches_center.then(|| index_distance_threshold(range_key.radius, distance_type))
+    });
+    let max_distance = index_distance_threshold(super::guc::TSV_MAX_DISTANCE.get(), distance_type);
+    let radius = range_radius.map_or(max_distance, |radius| radius.min(max_distance));
+
+    let search = ScanQuery {
+        query,
+        search_list_size,
+        filter,
+    };
+    state.initialize(&indexrel, &heaprel, search);
+    state.radius = radius;
+    state.recheck = range_key.is_some();
 }
 
 #[pg_guard]
@@ -364,32 +665,145 @@ pub extern "C" fn amgettuple(
     let indexrel = unsafe { PgRelation::from_pg(scan.indexRelation) };
     let heaprel = unsafe { PgRelation::from_pg(scan.heapRelation) };
 
+    let next = next_result(state, &indexrel, &heaprel);
+    get_tuple(state, next, scan)
+}
+
+#[pg_guard]
+pub extern "C" fn amgetbitmap(scan: pg_sys::IndexScanDesc, tbm: *mut pg_sys::TIDBitmap) -> i64 {
+    let scan: PgBox<pg_sys::IndexScanDescData> = unsafe { PgBox::from_pg(scan) };
+    let state = unsafe { (scan.opaque as *mut TSVScanState).as_mut() }.expect("no scandesc state");
+
+    let indexrel = unsafe { PgRelation::from_pg(scan.indexRelation) };
+    let heaprel = unsafe { PgRelation::from_pg(scan.heapRelation) };
+
+    let mut ntids = 0;
+    while let Some((heap_pointer, _)) = next_result(state, &indexrel, &heaprel) {
+        let mut tid = pg_sys::ItemPointerData::default();
+        heap_pointer.to_item_pointer_data(&mut tid);
+        unsafe { pg_sys::tbm_add_tuples(tbm, &mut tid, 1, state.recheck) };
+        ntids += 1;
+    }
+    ntids
+}
+
+/// The next result of the search, or None once the results are farther than the radius of the scan.
+fn next_result(
+    state: &mut TSVScanState,
+    indexrel: &PgRelation,
+    heaprel: &PgRelation,
+) -> Option<(HeapPointer, IndexPointer)> {
     let mut storage = unsafe { state.storage.as_mut() }.expect("no storage in state");
-    match &mut storage {
+    let next = match &mut storage {
         StorageState::SbqSpeedup(quantizer, iter) => {
-            let bq = SbqSpeedupStorage::load_for_search(
-                &indexrel,
-                &heaprel,
-                quantizer,
-                &state.meta_page,
-            );
-            let next = iter.next_with_resort(&indexrel, &bq);
-            get_tuple(state, next, scan)
+            let bq =
+                SbqSpeedupStorage::load_for_search(indexrel, heaprel, quantizer, &state.meta_page);
+            iter.next_with_resort(indexrel, &bq)
+        }
+        StorageState::Pq(quantizer, iter) => {
+            let pq = PqStorage::load_for_search(indexrel, heaprel, quantizer, &state.meta_page);
+            iter.next_with_resort(indexrel, &pq)
+        }
+        StorageState::Sq8(quantizer, iter) => {
+            let sq8 = Sq8Storage::load_for_search(indexrel, heaprel, quantizer, &state.meta_page);
+            iter.next_with_resort(indexrel, &sq8)
+        }
+        StorageState::Rabitq(quantizer, iter) => {
+            let rabitq =
+                RabitqStorage::load_for_search(indexrel, heaprel, quantizer, &state.meta_page);
+            iter.next_with_bounded_resort(indexrel, &rabitq)
         }
         StorageState::Plain(iter) => {
             let storage =
-                PlainStorage::load_for_search(&indexrel, &heaprel, state.distance_fn.unwrap());
-            let next = if state.meta_page.get_num_dimensions()
-                == state.meta_page.get_num_dimensions_to_index()
+                PlainStorage::load_for_search(indexrel, heaprel, state.distance_fn.unwrap());
+            if state.meta_page.get_num_dimensions() == state.meta_page.get_num_dimensions_to_index()
             {
                 /* no need to resort */
-                iter.next(&storage)
+                iter.next(indexrel, &storage)
             } else {
-                iter.next_with_resort(&indexrel, &storage)
-            };
-            get_tuple(state, next, scan)
+                iter.next_with_resort(indexrel, &storage)
+            }
         }
+    };
+
+    if storage.last_distance() > state.radius {
+        None
+    } else {
+        next
+    }
+}
+
+/// The vector column can only be returned by index-only scans if the nodes contain the vectors as they were inserted.
+fn can_return_vector(meta_page: &MetaPage) -> bool {
+    meta_page.get_storage_type() == StorageType::Plain
+        && meta_page.get_num_dimensions() == meta_page.get_num_dimensions_to_index()
+        //cosine distance normalizes the vectors stored in the nodes
+        && meta_page.get_distance_type() != DistanceType::Cosine
+        //the nodes store f32 vectors
+        && meta_page.get_vector_type() == VectorType::Vector
+}
+
+#[pg_guard]
+pub extern "C" fn amcanreturn(
+    index_relation: pg_sys::Relation,
+    attno: ::std::os::raw::c_int,
+) -> bool {
+    if attno > 1 {
+        /* scalar key columns and INCLUDE columns */
+        return true;
     }
+    let indexrel = unsafe { PgRelation::from_pg(index_relation) };
+    can_return_vector(&MetaPage::fetch(&indexrel))
+}
+
+/* Forms the index tuple returned to index-only scans: the scalar columns stored with the node, plus the vector if
+the index can return it. */
+unsafe fn form_index_tuple(
+    state: &mut TSVScanState,
+    indexrel: &PgRelation,
+    index_pointer: IndexPointer,
+) -> pg_sys::IndexTuple {
+    let tuple_desc = indexrel.rd_att;
+    let natts = (*tuple_desc).natts as usize;
+    let mut values = vec![pg_sys::Datum::from(0); natts];
+    let mut isnull = vec![true; natts];
+
+    let mut scalar_columns: pg_sys::IndexTuple = std::ptr::null_mut();
+    if state.has_scalar_columns {
+        scalar_columns = read_scalar_columns(indexrel, index_pointer);
+        pg_sys::index_deform_tuple(
+            scalar_columns,
+            tuple_desc,
+            values.as_mut_ptr(),
+            isnull.as_mut_ptr(),
+        );
+    }
+
+    let mut vector: *mut PgVectorInternal = std::ptr::null_mut();
+    if can_return_vector(&state.meta_page) {
+        let storage = state.storage.as_mut().expect("no storage in state");
+        let stats = match storage {
+            StorageState::Plain(iter) => &mut iter.lsr.stats,
+            StorageState::SbqSpeedup(_, _) => panic!("SBQ nodes don't contain the vectors"),
+            StorageState::Pq(_, _) => panic!("PQ nodes don't contain the vectors"),
+            StorageState::Sq8(_, _) => panic!("SQ8 nodes don't contain the vectors"),
+            StorageState::Rabitq(_, _) => panic!("RaBitQ nodes don't contain the vectors"),
+        };
+        let rn = Node::read(indexrel, index_pointer, stats);
+        vector = PgVectorInternal::from_slice(rn.get_archived_node().vector.as_slice());
+        values[0] = pg_sys::Datum::from(vector);
+        isnull[0] = false;
+    }
+
+    let tuple = pg_sys::index_form_tuple(tuple_desc, values.as_mut_ptr(), isnull.as_mut_ptr());
+
+    if !scalar_columns.is_null() {
+        pg_sys::pfree(scalar_columns.cast());
+    }
+    if !vector.is_null() {
+        pg_sys::pfree(vector.cast());
+    }
+    tuple
 }
 
 fn get_tuple(
@@ -398,11 +812,24 @@ fn get_tuple(
     mut scan: PgBox<pg_sys::IndexScanDescData>,
 ) -> bool {
     scan.xs_recheckorderby = false;
+    scan.xs_recheck = state.recheck;
+    if !state.last_index_tuple.is_null() {
+        unsafe { pg_sys::pfree(state.last_index_tuple.cast()) };
+        state.last_index_tuple = std::ptr::null_mut();
+    }
     match next {
         Some((heap_pointer, index_pointer)) => {
             let tid_to_set = &mut scan.xs_heaptid;
             heap_pointer.to_item_pointer_data(tid_to_set);
 
+            if scan.xs_want_itup {
+                let indexrel = unsafe { PgRelation::from_pg(scan.indexRelation) };
+                let tuple = unsafe { form_index_tuple(state, &indexrel, index_pointer) };
+                state.last_index_tuple = tuple;
+                scan.xs_itup = tuple;
+                scan.xs_itupdesc = indexrel.rd_att;
+            }
+
             /*
              * An index scan must maintain a pin on the index page holding the
              * item last returned by amgettuple
@@ -434,11 +861,13 @@ pub extern "C" fn amendscan(scan: pg_sys::IndexScanDesc) {
         let scan: PgBox<pg_sys::IndexScanDescData> = unsafe { PgBox::from_pg(scan) };
         let state =
             unsafe { (scan.opaque as *mut TSVScanState).as_mut() }.expect("no scandesc state");
-
         let mut storage = unsafe { state.storage.as_mut() }.expect("no storage in state");
         match &mut storage {
             StorageState::SbqSpeedup(_bq, iter) => end_scan::<SbqSpeedupStorage>(iter),
             StorageState::Plain(iter) => end_scan::<PlainStorage>(iter),
+            StorageState::Pq(_pq, iter) => end_scan::<PqStorage>(iter),
+            StorageState::Sq8(_sq8, iter) => end_scan::<Sq8Storage>(iter),
+            StorageState::Rabitq(_rabitq, iter) => end_scan::<RabitqStorage>(iter),
         }
     }
 }
@@ -0,0 +1,899 @@
+use super::{
+    distance::{distance_l2_sq8, inner_product_sq8, preprocess_cosine},
+    full_vector::get_full_vector_distance,
+    graph::{ListSearchNeighbor, ListSearchResult},
+    graph_neighbor_store::GraphNeighborStore,
+    labels::{read_labels, LabelSet},
+    meta_page::DistanceType,
+    parallel_build::BuildVector,
+    pg_vector::PgVector,
+    stats::{
+        GreedySearchStats, StatsDistanceComparison, StatsHeapNodeRead, StatsNodeModify,
+        StatsNodeRead, StatsNodeWrite, WriteStats,
+    },
+    storage::{ArchivedData, NodeDistanceMeasure, Storage},
+    storage_common::get_attribute_number_from_index,
+};
+use std::{borrow::Cow, cell::RefCell, collections::HashMap, marker::PhantomData, pin::Pin};
+
+use pgrx::{
+    pg_sys::{InvalidBlockNumber, InvalidOffsetNumber},
+    PgRelation,
+};
+use rkyv::{vec::ArchivedVec, Archive, Deserialize, Serialize};
+
+use crate::util::{
+    page::PageType, table_slot::TableSlot, tape::Tape, ArchivedItemPointer, HeapPointer,
+    IndexPointer, ItemPointer, ReadableBuffer,
+};
+
+use super::{meta_page::MetaPage, neighbor_with_distance::NeighborWithDistance};
+use crate::util::WritableBuffer;
+
+const SQ8_MAX_CODE: f32 = u8::MAX as f32;
+
+#[derive(Archive, Deserialize, Serialize, Readable, Writeable)]
+#[archive(check_bytes)]
+#[repr(C)]
+pub struct Sq8Ranges {
+    min: Vec<f32>,
+    max: Vec<f32>,
+}
+
+impl Sq8Ranges {
+    pub unsafe fn load<S: StatsNodeRead>(
+        index: &PgRelation,
+        meta_page: &super::meta_page::MetaPage,
+        stats: &mut S,
+    ) -> Sq8Quantizer {
+        let mut quantizer = Sq8Quantizer::new(meta_page);
+        if meta_page.get_quantizer_metadata_pointer().is_none() {
+            //only the empty index of an unlogged table (see ambuildempty) has no ranges. These are the
+            //ranges an empty build stores.
+            quantizer.start_training(meta_page);
+            quantizer.finish_training();
+            return quantizer;
+        }
+        let quantizer_item_pointer = meta_page.get_quantizer_metadata_pointer().unwrap();
+        let ranges = Sq8Ranges::read(index, quantizer_item_pointer, stats);
+        let archived = ranges.get_archived_node();
+
+        quantizer.load(archived.min.to_vec(), archived.max.to_vec());
+        quantizer
+    }
+
+    pub unsafe fn store<S: StatsNodeWrite>(
+        index: &PgRelation,
+        quantizer: &Sq8Quantizer,
+        stats: &mut S,
+    ) -> ItemPointer {
+        let mut tape = Tape::new(index, PageType::Sq8Ranges);
+        let node = Sq8Ranges {
+            min: quantizer.min.clone(),
+            max: quantizer.max.clone(),
+        };
+        let ptr = node.write(&mut tape, stats);
+        tape.close();
+        ptr
+    }
+}
+
+/// Scalar quantization to 8 bits: every dimension is mapped linearly from the range of values it had during the
+/// build to 0..=255. Values outside of that range (inserted later) are clamped.
+#[derive(Clone)]
+pub struct Sq8Quantizer {
+    distance_type: DistanceType,
+    training: bool,
+    pub min: Vec<f32>,
+    pub max: Vec<f32>,
+    /// The difference between two consecutive codes of every dimension, 0 for constant dimensions.
+    scale: Vec<f32>,
+}
+
+impl Sq8Quantizer {
+    fn new(meta_page: &super::meta_page::MetaPage) -> Sq8Quantizer {
+        Self {
+            distance_type: meta_page.get_distance_type(),
+            training: false,
+            min: vec![],
+            max: vec![],
+            scale: vec![],
+        }
+    }
+
+    fn load(&mut self, min: Vec<f32>, max: Vec<f32>) {
+        assert_eq!(min.len(), max.len());
+        self.min = min;
+        self.max = max;
+        self.update_scale();
+    }
+
+    fn update_scale(&mut self) {
+        self.scale = self
+            .min
+            .iter()
+            .zip(self.max.iter())
+            .map(|(min, max)| (max - min).max(0.0) / SQ8_MAX_CODE)
+            .collect();
+    }
+
+    fn quantize(&self, full_vector: &[f32]) -> Vec<u8> {
+        assert!(!self.training);
+        assert_eq!(full_vector.len(), self.min.len());
+        full_vector
+            .iter()
+            .zip(self.min.iter().zip(self.scale.iter()))
+            .map(|(v, (min, scale))| {
+                if *scale > 0.0 {
+                    ((v - min) / scale).round().clamp(0.0, SQ8_MAX_CODE) as u8
+                } else {
+                    0
+                }
+            })
+            .collect()
+    }
+
+    /// The vector a quantized vector stands for.
+    fn dequantize(&self, sq8_vector: &[u8]) -> Vec<f32> {
+        let mut res: Vec<f32> = sq8_vector
+            .iter()
+            .zip(self.min.iter().zip(self.scale.iter()))
+            .map(|(&c, (min, scale))| min + scale * c as f32)
+            .collect();
+        //the cosine distance is only defined on normalized vectors
+        if self.distance_type == DistanceType::Cosine {
+            preprocess_cosine(&mut res);
+        }
+        res
+    }
+
+    fn start_training(&mut self, meta_page: &super::meta_page::MetaPage) {
+        self.training = true;
+        let num_dimensions = meta_page.get_num_dimensions_to_index() as usize;
+        self.min = vec![f32::MAX; num_dimensions];
+        self.max = vec![f32::MIN; num_dimensions];
+    }
+
+    fn add_sample(&mut self, sample: &[f32]) {
+        assert_eq!(self.min.len(), sample.len());
+        for ((min, max), &v) in self.min.iter_mut().zip(self.max.iter_mut()).zip(sample) {
+            *min = min.min(v);
+            *max = max.max(v);
+        }
+    }
+
+    fn finish_training(&mut self) {
+        self.training = false;
+        //no samples: every dimension is constant
+        for (min, max) in self.min.iter_mut().zip(self.max.iter_mut()) {
+            if min > max {
+                *min = 0.0;
+                *max = 0.0;
+            }
+        }
+        self.update_scale();
+    }
+}
+
+/// A vector prepared for comparisons with 8-bit codes. With the range of every dimension folded into `t`, `w`
+/// and `offset`, a distance needs one pass over the codes (see distance_l2_sq8 and inner_product_sq8).
+pub struct Sq8QueryVector {
+    distance_type: DistanceType,
+    t: Vec<f32>,
+    w: Vec<f32>,
+    offset: f32,
+}
+
+impl Sq8QueryVector {
+    fn new(quantizer: &Sq8Quantizer, vector: &[f32]) -> Self {
+        assert_eq!(vector.len(), quantizer.min.len());
+        let dims = vector
+            .iter()
+            .zip(quantizer.min.iter().zip(quantizer.scale.iter()));
+        match quantizer.distance_type {
+            DistanceType::L2 => {
+                // (v - (min + scale * c))^2 = scale^2 * ((v - min) / scale - c)^2
+                let mut offset = 0.0;
+                let (t, w) = dims
+                    .map(|(v, (min, scale))| {
+                        if *scale > 0.0 {
+                            ((v - min) / scale, scale * scale)
+                        } else {
+                            //every code of a constant dimension stands for min
+                            offset += (v - min) * (v - min);
+                            (0.0, 0.0)
+                        }
+                    })
+                    .unzip();
+                Self {
+                    distance_type: quantizer.distance_type,
+                    t,
+                    w,
+                    offset,
+                }
+            }
+            DistanceType::Cosine | DistanceType::InnerProduct => {
+                // -v * (min + scale * c) = -v * min - (v * scale) * c
+                let mut offset = 0.0;
+                let w = dims
+                    .map(|(v, (min, scale))| {
+                        offset -= v * min;
+                        v * scale
+                    })
+                    .collect();
+                if quantizer.distance_type == DistanceType::Cosine {
+                    //the cosine distance is 1 - the inner product
+                    offset += 1.0;
+                }
+                Self {
+                    distance_type: quantizer.distance_type,
+                    t: vec![],
+                    w,
+                    offset,
+                }
+            }
+            DistanceType::Hamming | DistanceType::Jaccard => {
+                unreachable!("bit vectors can't be indexed with the sq8 storage layout")
+            }
+        }
+    }
+
+    fn distance(&self, sq8_vector: &[u8]) -> f32 {
+        match self.distance_type {
+            DistanceType::L2 => self.offset + distance_l2_sq8(&self.t, &self.w, sq8_vector),
+            _ => self.offset - inner_product_sq8(&self.w, sq8_vector),
+        }
+    }
+}
+
+pub struct Sq8SearchDistanceMeasure {
+    query: PgVector,
+    query_vector: Sq8QueryVector,
+}
+
+impl Sq8SearchDistanceMeasure {
+    pub fn new(quantizer: &Sq8Quantizer, query: PgVector) -> Sq8SearchDistanceMeasure {
+        Sq8SearchDistanceMeasure {
+            query_vector: Sq8QueryVector::new(quantizer, query.to_index_slice()),
+            query,
+        }
+    }
+
+    pub fn calculate_sq8_distance<S: StatsDistanceComparison>(
+        &self,
+        sq8_vector: &[u8],
+        stats: &mut S,
+    ) -> f32 {
+        stats.record_quantized_distance_comparison();
+        self.query_vector.distance(sq8_vector)
+    }
+}
+
+pub struct Sq8NodeDistanceMeasure<'a> {
+    vec: Sq8QueryVector,
+    storage: &'a Sq8Storage<'a>,
+}
+
+impl<'a> Sq8NodeDistanceMeasure<'a> {
+    pub unsafe fn with_index_pointer<T: StatsNodeRead>(
+        storage: &'a Sq8Storage<'a>,
+        index_pointer: IndexPointer,
+        stats: &mut T,
+    ) -> Self {
+        let cache = &mut storage.qv_cache.borrow_mut();
+        let vec = storage
+            .quantizer
+            .dequantize(cache.get(index_pointer, storage, stats));
+        Self {
+            vec: Sq8QueryVector::new(&storage.quantizer, &vec),
+            storage,
+        }
+    }
+}
+
+impl<'a> NodeDistanceMeasure for Sq8NodeDistanceMeasure<'a> {
+    unsafe fn get_distance<T: StatsNodeRead + StatsDistanceComparison>(
+        &self,
+        index_pointer: IndexPointer,
+        stats: &mut T,
+    ) -> f32 {
+        let cache = &mut self.storage.qv_cache.borrow_mut();
+        let vec1 = cache.get(index_pointer, self.storage, stats);
+        stats.record_quantized_distance_comparison();
+        self.vec.distance(vec1)
+    }
+}
+
+struct Sq8VectorCache {
+    sq8_vector_map: HashMap<ItemPointer, Vec<u8>>,
+}
+
+/* like the QuantizedVectorCache of SBQ, this never evicts */
+impl Sq8VectorCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            sq8_vector_map: HashMap::with_capacity(capacity),
+        }
+    }
+
+    fn get<S: StatsNodeRead>(
+        &mut self,
+        index_pointer: IndexPointer,
+        storage: &Sq8Storage,
+        stats: &mut S,
+    ) -> &[u8] {
+        self.sq8_vector_map
+            .entry(index_pointer)
+            .or_insert_with(|| storage.get_sq8_vector_from_index_pointer(index_pointer, stats))
+    }
+}
+
+pub struct Sq8Storage<'a> {
+    pub index: &'a PgRelation,
+    pub distance_fn: fn(&[f32], &[f32]) -> f32,
+    quantizer: Cow<'a, Sq8Quantizer>,
+    heap_rel: &'a PgRelation,
+    heap_attr: pgrx::pg_sys::AttrNumber,
+    qv_cache: RefCell<Sq8VectorCache>,
+}
+
+impl<'a> Sq8Storage<'a> {
+    pub fn new_for_build(
+        index: &'a PgRelation,
+        heap_rel: &'a PgRelation,
+        meta_page: &super::meta_page::MetaPage,
+    ) -> Sq8Storage<'a> {
+        Self {
+            index,
+            distance_fn: meta_page.get_distance_function(),
+            quantizer: Cow::Owned(Sq8Quantizer::new(meta_page)),
+            heap_rel,
+            heap_attr: get_attribute_number_from_index(index),
+            qv_cache: RefCell::new(Sq8VectorCache::new(1000)),
+        }
+    }
+
+    pub fn load_for_insert<S: StatsNodeRead>(
+        heap_rel: &'a PgRelation,
+        index_relation: &'a PgRelation,
+        meta_page: &super::meta_page::MetaPage,
+        stats: &mut S,
+    ) -> Sq8Storage<'a> {
+        Self {
+            index: index_relation,
+            distance_fn: meta_page.get_distance_function(),
+            quantizer: Cow::Owned(unsafe { Sq8Ranges::load(index_relation, meta_page, stats) }),
+            heap_rel,
+            heap_attr: get_attribute_number_from_index(index_relation),
+            qv_cache: RefCell::new(Sq8VectorCache::new(1000)),
+        }
+    }
+
+    pub fn load_for_search(
+        index_relation: &'a PgRelation,
+        heap_relation: &'a PgRelation,
+        quantizer: &'a Sq8Quantizer,
+        meta_page: &super::meta_page::MetaPage,
+    ) -> Sq8Storage<'a> {
+        Self {
+            index: index_relation,
+            distance_fn: meta_page.get_distance_function(),
+            quantizer: Cow::Borrowed(quantizer),
+            heap_rel: heap_relation,
+            heap_attr: get_attribute_number_from_index(index_relation),
+            qv_cache: RefCell::new(Sq8VectorCache::new(1000)),
+        }
+    }
+
+    fn get_sq8_vector_from_index_pointer<S: StatsNodeRead>(
+        &self,
+        index_pointer: IndexPointer,
+        stats: &mut S,
+    ) -> Vec<u8> {
+        let rn = unsafe { Sq8Node::read(self.index, index_pointer, stats) };
+        let node = rn.get_archived_node();
+        node.sq8_vector.as_slice().to_vec()
+    }
+
+    fn write_quantizer_metadata<S: StatsNodeWrite + StatsNodeModify>(&self, stats: &mut S) {
+        let index_pointer = unsafe { Sq8Ranges::store(self.index, &self.quantizer, stats) };
+        super::meta_page::MetaPage::update_quantizer_metadata_pointer(
+            self.index,
+            index_pointer,
+            stats,
+        );
+    }
+
+    fn visit_lsn_internal(
+        &self,
+        lsr: &mut ListSearchResult<
+            <Sq8Storage<'a> as Storage>::QueryDistanceMeasure,
+            <Sq8Storage<'a> as Storage>::LSNPrivateData,
+        >,
+        lsn_index_pointer: IndexPointer,
+        gns: &GraphNeighborStore,
+    ) {
+        match gns {
+            GraphNeighborStore::Disk => {
+                let rn_visiting =
+                    unsafe { Sq8Node::read(self.index, lsn_index_pointer, &mut lsr.stats) };
+                let node_visiting = rn_visiting.get_archived_node();
+                let neighbors = node_visiting.get_index_pointer_to_neighbors();
+
+                for &neighbor_index_pointer in neighbors.iter() {
+                    if !lsr.prepare_insert(neighbor_index_pointer) {
+                        continue;
+                    }
+
+                    let rn_neighbor = unsafe {
+                        Sq8Node::read(self.index, neighbor_index_pointer, &mut lsr.stats)
+                    };
+                    let node_neighbor = rn_neighbor.get_archived_node();
+                    let distance = lsr.sdm.as_ref().unwrap().calculate_sq8_distance(
+                        node_neighbor.sq8_vector.as_slice(),
+                        &mut lsr.stats,
+                    );
+
+                    let lsn = ListSearchNeighbor::new(
+                        neighbor_index_pointer,
+                        distance,
+                        PhantomData::<bool>,
+                    );
+
+                    lsr.insert_neighbor(lsn);
+                }
+            }
+            GraphNeighborStore::Builder(b) => {
+                let neighbors = b.get_neighbors(lsn_index_pointer);
+                for &neighbor_index_pointer in neighbors.iter() {
+                    if !lsr.prepare_insert(neighbor_index_pointer) {
+                        continue;
+                    }
+                    let mut cache = self.qv_cache.borrow_mut();
+                    let sq8_vector = cache.get(neighbor_index_pointer, self, &mut lsr.stats);
+                    let distance = lsr
+                        .sdm
+                        .as_ref()
+                        .unwrap()
+                        .calculate_sq8_distance(sq8_vector, &mut lsr.stats);
+
+                    let lsn = ListSearchNeighbor::new(
+                        neighbor_index_pointer,
+                        distance,
+                        PhantomData::<bool>,
+                    );
+
+                    lsr.insert_neighbor(lsn);
+                }
+            }
+        }
+    }
+
+    unsafe fn get_heap_table_slot_from_heap_pointer<T: StatsHeapNodeRead>(
+        &self,
+        heap_pointer: HeapPointer,
+        stats: &mut T,
+    ) -> TableSlot {
+        TableSlot::new(self.heap_rel, heap_pointer, stats)
+    }
+}
+
+pub type Sq8StorageLsnPrivateData = PhantomData<bool>; //no data stored
+
+impl<'a> Storage for Sq8Storage<'a> {
+    type QueryDistanceMeasure = Sq8SearchDistanceMeasure;
+    type NodeDistanceMeasure<'b> = Sq8NodeDistanceMeasure<'b> where Self: 'b;
+    type ArchivedType = ArchivedSq8Node;
+    type LSNPrivateData = Sq8StorageLsnPrivateData; //no data stored
+
+    fn page_type() -> PageType {
+        PageType::Sq8Node
+    }
+
+    fn create_node<S: StatsNodeWrite>(
+        &self,
+        full_vector: &[f32],
+        heap_pointer: HeapPointer,
+        scalar_columns: &[u8],
+        meta_page: &MetaPage,
+        tape: &mut Tape,
+        stats: &mut S,
+    ) -> ItemPointer {
+        let sq8_vector = self.quantizer.quantize(full_vector);
+
+        let node = Sq8Node::new(
+            heap_pointer,
+            meta_page.get_num_neighbors() as usize,
+            sq8_vector,
+        );
+
+        node.write_with_prefix(scalar_columns, tape, stats)
+    }
+
+    fn start_training(&mut self, meta_page: &super::meta_page::MetaPage) {
+        self.quantizer.to_mut().start_training(meta_page);
+    }
+
+    fn add_sample(&mut self, sample: &[f32]) {
+        self.quantizer.to_mut().add_sample(sample);
+    }
+
+    fn finish_training(&mut self, stats: &mut WriteStats) {
+        self.quantizer.to_mut().finish_training();
+        self.write_quantizer_metadata(stats);
+    }
+
+    fn finalize_node_at_end_of_build<S: StatsNodeRead + StatsNodeModify>(
+        &mut self,
+        meta: &MetaPage,
+        index_pointer: IndexPointer,
+        neighbors: &Vec<NeighborWithDistance>,
+        stats: &mut S,
+    ) {
+        self.set_neighbors_on_disk(meta, index_pointer, neighbors, stats);
+    }
+
+    unsafe fn get_node_distance_measure<'b, S: StatsNodeRead>(
+        &'b self,
+        index_pointer: IndexPointer,
+        stats: &mut S,
+    ) -> Sq8NodeDistanceMeasure<'b> {
+        Sq8NodeDistanceMeasure::with_index_pointer(self, index_pointer, stats)
+    }
+
+    fn get_query_distance_measure(&self, query: PgVector) -> Sq8SearchDistanceMeasure {
+        Sq8SearchDistanceMeasure::new(&self.quantizer, query)
+    }
+
+    fn get_full_distance_for_resort<
+        S: StatsNodeRead + StatsHeapNodeRead + StatsDistanceComparison,
+    >(
+        &self,
+        qdm: &Self::QueryDistanceMeasure,
+        index_pointer: IndexPointer,
+        heap_pointer: HeapPointer,
+        meta_page: &MetaPage,
+        stats: &mut S,
+    ) -> f32 {
+        if meta_page.get_full_vectors_in_index() {
+            return unsafe {
+                get_full_vector_distance(self.index, index_pointer, &qdm.query, meta_page, stats)
+            };
+        }
+
+        let slot = unsafe { self.get_heap_table_slot_from_heap_pointer(heap_pointer, stats) };
+
+        let datum = unsafe { slot.get_attribute(self.heap_attr).unwrap() };
+        let vec = unsafe { PgVector::from_datum(datum, meta_page, false, true) };
+        vec.get_full_distance(&qdm.query, meta_page)
+    }
+
+    fn get_neighbors_with_distances_from_disk<S: StatsNodeRead + StatsDistanceComparison>(
+        &self,
+        neighbors_of: ItemPointer,
+        result: &mut Vec<NeighborWithDistance>,
+        stats: &mut S,
+    ) {
+        let rn = unsafe { Sq8Node::read(self.index, neighbors_of, stats) };
+        let archived = rn.get_archived_node();
+        let q = Sq8QueryVector::new(
+            &self.quantizer,
+            &self.quantizer.dequantize(archived.sq8_vector.as_slice()),
+        );
+
+        for n in archived.iter_neighbors() {
+            let rn1 = unsafe { Sq8Node::read(self.index, n, stats) };
+            stats.record_quantized_distance_comparison();
+            let dist = q.distance(rn1.get_archived_node().sq8_vector.as_slice());
+            result.push(NeighborWithDistance::new(n, dist))
+        }
+    }
+
+    /* get_lsn and visit_lsn are different because the distance
+    comparisons for SQ8 get the vector from different places */
+    fn create_lsn_for_init_id(
+        &self,
+        lsr: &mut ListSearchResult<Self::QueryDistanceMeasure, Self::LSNPrivateData>,
+        index_pointer: ItemPointer,
+        _gns: &GraphNeighborStore,
+    ) -> ListSearchNeighbor<Self::LSNPrivateData> {
+        if !lsr.prepare_insert(index_pointer) {
+            panic!("should not have had an init id already inserted");
+        }
+
+        let rn = unsafe { Sq8Node::read(self.index, index_pointer, &mut lsr.stats) };
+        let node = rn.get_archived_node();
+
+        let distance = lsr
+            .sdm
+            .as_ref()
+            .unwrap()
+            .calculate_sq8_distance(node.sq8_vector.as_slice(), &mut lsr.stats);
+
+        ListSearchNeighbor::new(index_pointer, distance, PhantomData::<bool>)
+    }
+
+    fn visit_lsn(
+        &self,
+        lsr: &mut ListSearchResult<Self::QueryDistanceMeasure, Self::LSNPrivateData>,
+        lsn_idx: usize,
+        gns: &GraphNeighborStore,
+    ) {
+        let lsn_index_pointer = lsr.get_lsn_by_idx(lsn_idx).index_pointer;
+        self.visit_lsn_internal(lsr, lsn_index_pointer, gns);
+    }
+
+    fn return_lsn(
+        &self,
+        lsn: &ListSearchNeighbor<Self::LSNPrivateData>,
+        stats: &mut GreedySearchStats,
+    ) -> HeapPointer {
+        let lsn_index_pointer = lsn.index_pointer;
+        let rn = unsafe { Sq8Node::read(self.index, lsn_index_pointer, stats) };
+        let node = rn.get_archived_node();
+        node.heap_item_pointer.deserialize_item_pointer()
+    }
+
+    fn set_neighbors_on_disk<S: StatsNodeModify + StatsNodeRead>(
+        &self,
+        meta: &MetaPage,
+        index_pointer: IndexPointer,
+        neighbors: &[NeighborWithDistance],
+        stats: &mut S,
+    ) {
+        let node = unsafe { Sq8Node::modify(self.index, index_pointer, stats) };
+        let mut archived = node.get_archived_node();
+        archived.as_mut().set_neighbors(neighbors, meta);
+        node.commit();
+    }
+
+    fn replace_neighbors_on_disk<S: StatsNodeModify + StatsNodeRead>(
+        &self,
+        meta: &MetaPage,
+        index_pointer: IndexPointer,
+        expected: &[IndexPointer],
+        neighbors: &[NeighborWithDistance],
+        stats: &mut S,
+    ) -> bool {
+        let node = unsafe { Sq8Node::modify(self.index, index_pointer, stats) };
+        let mut archived = node.get_archived_node();
+        if archived.get_index_pointer_to_neighbors() != expected {
+            return false;
+        }
+        archived.as_mut().set_neighbors(neighbors, meta);
+        node.commit();
+        true
+    }
+
+    fn get_distance_function(&self) -> fn(&[f32], &[f32]) -> f32 {
+        self.distance_fn
+    }
+
+    fn get_build_vector(&self, full_vector: &[f32]) -> BuildVector {
+        //the same vector the node distance measure compares
+        BuildVector::Full(
+            self.quantizer
+                .dequantize(&self.quantizer.quantize(full_vector)),
+        )
+    }
+
+    fn get_labels(&self, index_pointer: IndexPointer) -> LabelSet {
+        unsafe { read_labels(self.index, index_pointer) }
+    }
+
+    fn is_deleted<S: StatsNodeRead>(&self, index_pointer: IndexPointer, stats: &mut S) -> bool {
+        let rn = unsafe { Sq8Node::read(self.index, index_pointer, stats) };
+        rn.get_archived_node().is_deleted()
+    }
+}
+
+use pgvectorscale_derive::{Readable, Writeable};
+
+#[derive(Archive, Deserialize, Serialize, Readable, Writeable)]
+#[archive(check_bytes)]
+pub struct Sq8Node {
+    pub heap_item_pointer: HeapPointer,
+    pub sq8_vector: Vec<u8>,
+    neighbor_index_pointers: Vec<ItemPointer>,
+}
+
+impl Sq8Node {
+    fn new(heap_pointer: HeapPointer, num_neighbors: usize, sq8_vector: Vec<u8>) -> Self {
+        // always use vectors of num_neighbors in length because we never want the serialized size of a Node to change
+        let neighbor_index_pointers: Vec<_> = (0..num_neighbors)
+            .map(|_| ItemPointer::new(InvalidBlockNumber, InvalidOffsetNumber))
+            .collect();
+
+        Self {
+            heap_item_pointer: heap_pointer,
+            sq8_vector,
+            neighbor_index_pointers,
+        }
+    }
+}
+
+impl ArchivedSq8Node {
+    fn neighbor_index_pointer(self: Pin<&mut Self>) -> Pin<&mut ArchivedVec<ArchivedItemPointer>> {
+        unsafe { self.map_unchecked_mut(|s| &mut s.neighbor_index_pointers) }
+    }
+
+    fn set_neighbors(
+        mut self: Pin<&mut Self>,
+        neighbors: &[NeighborWithDistance],
+        meta_page: &MetaPage,
+    ) {
+        for (i, new_neighbor) in neighbors.iter().enumerate() {
+            let mut a_index_pointer = self.as_mut().neighbor_index_pointer().index_pin(i);
+            let ip = new_neighbor.get_index_pointer_to_neighbor();
+            a_index_pointer.block_number = ip.block_number;
+            a_index_pointer.offset = ip.offset;
+        }
+        //set the marker that the list ended
+        if neighbors.len() < meta_page.get_num_neighbors() as _ {
+            let mut past_last_index_pointers =
+                self.neighbor_index_pointer().index_pin(neighbors.len());
+            past_last_index_pointers.block_number = InvalidBlockNumber;
+            past_last_index_pointers.offset = InvalidOffsetNumber;
+        }
+    }
+
+    pub fn num_neighbors(&self) -> usize {
+        self.neighbor_index_pointers
+            .iter()
+            .position(|f| f.block_number == InvalidBlockNumber)
+            .unwrap_or(self.neighbor_index_pointers.len())
+    }
+
+    pub fn iter_neighbors(&self) -> impl Iterator<Item = ItemPointer> + '_ {
+        self.neighbor_index_pointers
+            .iter()
+            .take(self.num_neighbors())
+            .map(|ip| ip.deserialize_item_pointer())
+    }
+}
+
+impl ArchivedData for ArchivedSq8Node {
+    fn with_data(data: &mut [u8]) -> Pin<&mut ArchivedSq8Node> {
+        ArchivedSq8Node::with_data(data)
+    }
+
+    fn with_readable_data(data: &[u8]) -> &ArchivedSq8Node {
+        unsafe { rkyv::archived_root::<Sq8Node>(data) }
+    }
+
+    fn get_index_pointer_to_neighbors(&self) -> Vec<ItemPointer> {
+        self.iter_neighbors().collect()
+    }
+
+    fn is_deleted(&self) -> bool {
+        self.heap_item_pointer.offset == InvalidOffsetNumber
+    }
+
+    fn delete(self: Pin<&mut Self>) {
+        let mut heap_pointer = unsafe { self.map_unchecked_mut(|s| &mut s.heap_item_pointer) };
+        heap_pointer.offset = InvalidOffsetNumber;
+        heap_pointer.block_number = InvalidBlockNumber;
+    }
+
+    fn get_heap_item_pointer(&self) -> HeapPointer {
+        self.heap_item_pointer.deserialize_item_pointer()
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    #[pg_test]
+    unsafe fn test_sq8_storage_index_creation_default_neighbors() -> spi::Result<()> {
+        crate::access_method::build::tests::test_index_creation_and_accuracy_scaffold(
+            "storage_layout = sq8",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_sq8_storage_index_creation_few_neighbors() -> spi::Result<()> {
+        //a test with few neighbors tests the case that nodes share a page, which has caused deadlocks in the past.
+        crate::access_method::build::tests::test_index_creation_and_accuracy_scaffold(
+            "num_neighbors=10, storage_layout = sq8",
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_sq8_storage_delete_vacuum_plain() {
+        crate::access_method::vacuum::tests::test_delete_vacuum_plain_scaffold(
+            "num_neighbors = 10, storage_layout = sq8",
+        );
+    }
+
+    #[test]
+    fn test_sq8_storage_delete_vacuum_full() {
+        crate::access_method::vacuum::tests::test_delete_vacuum_full_scaffold(
+            "num_neighbors = 38, storage_layout = sq8",
+        );
+    }
+
+    #[test]
+    fn test_sq8_storage_delete_vacuum_repair() {
+        crate::access_method::vacuum::tests::test_delete_vacuum_repair_scaffold(
+            "num_neighbors = 10, storage_layout = sq8",
+        );
+    }
+
+    #[test]
+    fn test_sq8_storage_delete_vacuum_reuse() {
+        crate::access_method::vacuum::tests::test_delete_vacuum_reuse_scaffold(
+            "num_neighbors = 10, storage_layout = sq8",
+            64,
+        );
+    }
+
+    #[pg_test]
+    unsafe fn test_sq8_storage_empty_table_insert() -> spi::Result<()> {
+        crate::access_method::build::tests::test_empty_table_insert_scaffold(
+            "num_neighbors=38, storage_layout = sq8",
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_sq8_storage_unlogged_table() -> spi::Result<()> {
+        crate::access_method::build::tests::test_unlogged_table_scaffold(
+            "num_neighbors=38, storage_layout = sq8",
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_sq8_storage_insert_empty_insert() -> spi::Result<()> {
+        crate::access_method::build::tests::test_insert_empty_insert_scaffold(
+            "num_neighbors=38, storage_layout = sq8",
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_sq8_storage_index_updates() -> spi::Result<()> {
+        crate::access_method::build::tests::test_index_updates(
+            "storage_layout = sq8, num_neighbors=10",
+            300,
+        )?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_sq8_storage_inner_product() -> spi::Result<()> {
+        crate::access_method::build::tests::test_distance_operator_scaffold(
+            "storage_layout = sq8",
+            "vector_ip_ops",
+            "<#>",
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_sq8_storage_l2() -> spi::Result<()> {
+        crate::access_method::build::tests::test_distance_operator_scaffold(
+            "storage_layout = sq8",
+            "vector_l2_ops",
+            "<->",
+        )
+    }
+
+    #[pg_test]
+    unsafe fn test_sq8_storage_parallel_build() -> spi::Result<()> {
+        crate::access_method::build::tests::test_parallel_build_scaffold("storage_layout = sq8")
+    }
+
+    #[pg_test]
+    unsafe fn test_sq8_storage_halfvec() -> spi::Result<()> {
+        crate::access_method::build::tests::test_halfvec_scaffold("storage_layout = sq8")
+    }
+
+    #[pg_test]
+    unsafe fn test_sq8_storage_high_dimensions() -> spi::Result<()> {
+        crate::access_method::build::tests::test_high_dimensions_scaffold(
+            "storage_layout = sq8",
+            3072,
+        )
+    }
+}
@@ -47,6 +47,15 @@ impl PruneNeighborStats {
             num_neighbors_after_prune: 0,
         }
     }
+
+    pub fn combine(&mut self, other: &Self) {
+        self.calls += other.calls;
+        self.distance_comparisons += other.distance_comparisons;
+        self.node_reads += other.node_reads;
+        self.node_modify += other.node_modify;
+        self.num_neighbors_before_prune += other.num_neighbors_before_prune;
+        self.num_neighbors_after_prune += other.num_neighbors_after_prune;
+    }
 }
 
 impl StatsDistanceComparison for PruneNeighborStats {
@@ -5,8 +5,10 @@ use crate::util::{page::PageType, tape::Tape, HeapPointer, IndexPointer, ItemPoi
 use super::{
     graph::{ListSearchNeighbor, ListSearchResult},
     graph_neighbor_store::GraphNeighborStore,
+    labels::LabelSet,
     meta_page::MetaPage,
     neighbor_with_distance::NeighborWithDistance,
+    parallel_build::BuildVector,
     pg_vector::PgVector,
     stats::{
         GreedySearchStats, StatsDistanceComparison, StatsHeapNodeRead, StatsNodeModify,
@@ -25,6 +27,7 @@ pub trait NodeDistanceMeasure {
 
 pub trait ArchivedData {
     fn with_data(data: &mut [u8]) -> Pin<&mut Self>;
+    fn with_readable_data(data: &[u8]) -> &Self;
     fn is_deleted(&self) -> bool;
     fn delete(self: Pin<&mut Self>);
     fn get_heap_item_pointer(&self) -> HeapPointer;
@@ -47,6 +50,7 @@ pub trait Storage {
         &self,
         full_vector: &[f32],
         heap_pointer: HeapPointer,
+        scalar_columns: &[u8],
         meta_page: &MetaPage,
         tape: &mut Tape,
         stats: &mut S,
@@ -72,7 +76,10 @@ pub trait Storage {
 
     fn get_query_distance_measure(&self, query: PgVector) -> Self::QueryDistanceMeasure;
 
-    fn get_full_distance_for_resort<S: StatsHeapNodeRead + StatsDistanceComparison>(
+    /// The exact distance to a node, read from the index if it stores the full vectors and from the heap otherwise.
+    fn get_full_distance_for_resort<
+        S: StatsNodeRead + StatsHeapNodeRead + StatsDistanceComparison,
+    >(
         &self,
         query: &Self::QueryDistanceMeasure,
         index_pointer: IndexPointer,
@@ -121,7 +128,27 @@ pub trait Storage {
         stats: &mut S,
     );
 
+    /// Sets the neighbors of the node if they are still `expected`, checked under the same lock. Returns false if
+    /// someone changed them since they were read.
+    fn replace_neighbors_on_disk<S: StatsNodeModify + StatsNodeRead>(
+        &self,
+        meta: &MetaPage,
+        index_pointer: IndexPointer,
+        expected: &[IndexPointer],
+        neighbors: &[NeighborWithDistance],
+        stats: &mut S,
+    ) -> bool;
+
     fn get_distance_function(&self) -> fn(&[f32], &[f32]) -> f32;
+
+    /// The vector used for node-to-node distance comparisons of a parallel build.
+    fn get_build_vector(&self, full_vector: &[f32]) -> BuildVector;
+
+    /// The labels stored with a node, empty if the index has no labels column.
+    fn get_labels(&self, index_pointer: IndexPointer) -> LabelSet;
+
+    /// Returns true if the node was deleted by VACUUM.
+    fn is_deleted<S: StatsNodeRead>(&self, index_pointer: IndexPointer, stats: &mut S) -> bool;
 }
 
 #[derive(PartialEq, Debug)]
@@ -129,6 +156,9 @@ pub enum StorageType {
     Plain = 0,
     SbqSpeedup = 1,
     SbqCompression = 2,
+    Pq = 3,
+    Sq8 = 4,
+    Rabitq = 5,
 }
 
 pub const DEFAULT_STORAGE_TYPE_STR: &str = "memory_optimized";
@@ -139,6 +169,9 @@ impl StorageType {
             0 => StorageType::Plain,
             1 => StorageType::SbqSpeedup,
             2 => StorageType::SbqCompression,
+            3 => StorageType::Pq,
+            4 => StorageType::Sq8,
+            5 => StorageType::Rabitq,
             _ => panic!("Invalid storage type"),
         }
     }
@@ -148,8 +181,11 @@ impl StorageType {
             "plain" => StorageType::Plain,
             "bq_speedup" | "io_optimized" => StorageType::SbqSpeedup,
             "bq_compression" | "memory_optimized" => StorageType::SbqCompression,
+            "pq" => StorageType::Pq,
+            "sq8" => StorageType::Sq8,
+            "rabitq" => StorageType::Rabitq,
             _ => panic!(
-                "Invalid storage type. Must be one of 'plain', 'bq_speedup', 'bq_compression'"
+                "Invalid storage type. Must be one of 'plain', 'bq_speedup', 'bq_compression', 'pq', 'sq8', 'rabitq'"
             ),
         }
     }
@@ -1,10 +1,125 @@
 use pgrx::{pg_sys, PgRelation};
 
+use crate::util::IndexPointer;
+
+/// The largest size of the scalar (non-vector) columns of one row, stored next to the node on the same page.
+pub const MAX_SCALAR_COLUMNS_SIZE: usize = pg_sys::BLCKSZ as usize / 8;
+
+/// The size of the pointer to the node's full vector stored after the scalar columns, see full_vector.rs. A multiple
+/// of MAXALIGN like the scalar columns.
+pub const FULL_VECTOR_POINTER_SIZE: usize = 8;
+
 pub fn get_attribute_number_from_index(index: &PgRelation) -> pg_sys::AttrNumber {
     unsafe {
         let a = index.rd_index;
+        //the vector is the first column; scalar key columns and INCLUDE columns come after it
         let natts = (*a).indnatts;
-        assert!(natts == 1);
+        assert!(natts >= 1);
         (*a).indkey.values.as_slice(natts as _)[0]
     }
 }
+
+/// Returns true if the index has columns other than the vector: scalar key columns used to filter the search, or
+/// INCLUDE columns.
+pub fn has_scalar_columns(index: &PgRelation) -> bool {
+    unsafe { (*index.rd_index).indnatts > 1 }
+}
+
+/* Scalar columns are stored as an index tuple (with the vector column set to NULL) at the start of the node's item.
+Nodes are rkyv archives, which are read from the end of the item, so a node is read the same way whether or not the
+item starts with scalar columns. The index tuple's size is a multiple of MAXALIGN, which keeps the node aligned. */
+
+/// Returns the bytes to store in front of a node: the scalar columns of the row if the index has any, nothing otherwise.
+pub unsafe fn form_scalar_columns(
+    index: &PgRelation,
+    values: *mut pg_sys::Datum,
+    isnull: *mut bool,
+) -> Vec<u8> {
+    if !has_scalar_columns(index) {
+        return vec![];
+    }
+
+    let natts = (*index.rd_index).indnatts as usize;
+    let mut values = std::slice::from_raw_parts(values, natts).to_vec();
+    let mut isnull = std::slice::from_raw_parts(isnull, natts).to_vec();
+    values[0] = pg_sys::Datum::from(0);
+    isnull[0] = true;
+
+    let tuple = pg_sys::index_form_tuple(index.rd_att, values.as_mut_ptr(), isnull.as_mut_ptr());
+    let size = index_tuple_size(tuple);
+    if size > MAX_SCALAR_COLUMNS_SIZE {
+        pgrx::error!(
+            "scalar columns of index row are too large: size {}, maximum size {}",
+            size,
+            MAX_SCALAR_COLUMNS_SIZE
+        );
+    }
+    let bytes = std::slice::from_raw_parts(tuple as *const u8, size).to_vec();
+    pg_sys::pfree(tuple.cast());
+    bytes
+}
+
+/// Returns the size of the scalar columns at the start of a node's item (`data`), 0 if the index has none.
+pub unsafe fn get_scalar_columns_size(index: &PgRelation, data: &[u8]) -> usize {
+    if !has_scalar_columns(index) {
+        return 0;
+    }
+    index_tuple_size(data.as_ptr() as pg_sys::IndexTuple)
+}
+
+/// Returns a palloc'd copy of the scalar columns stored in front of the node at `index_pointer`.
+/// The index must have scalar columns.
+pub unsafe fn read_scalar_columns(
+    index: &PgRelation,
+    index_pointer: IndexPointer,
+) -> pg_sys::IndexTuple {
+    debug_assert!(has_scalar_columns(index));
+    let rb = index_pointer.read_bytes(index);
+    let data = rb.get_data_slice();
+    let size = index_tuple_size(data.as_ptr() as pg_sys::IndexTuple);
+    assert!(size <= data.len());
+
+    let copy = pg_sys::palloc(size) as *mut u8;
+    std::ptr::copy_nonoverlapping(data.as_ptr(), copy, size);
+    copy as pg_sys::IndexTuple
+}
+
+/// Returns true if the scalar columns stored in front of the node at `index_pointer` satisfy all the scan `keys`.
+/// The keys compare a scalar key column (`sk_attno` > 1) to a constant. NULLs never match.
+pub unsafe fn scalar_columns_match(
+    index: &PgRelation,
+    index_pointer: IndexPointer,
+    keys: &mut [pg_sys::ScanKeyData],
+) -> bool {
+    debug_assert!(has_scalar_columns(index));
+    let rb = index_pointer.read_bytes(index);
+    let tuple = rb.get_data_slice().as_ptr() as pg_sys::IndexTuple;
+
+    let natts = (*index.rd_index).indnatts as usize;
+    let mut values = vec![pg_sys::Datum::from(0); natts];
+    let mut isnull = vec![true; natts];
+    pg_sys::index_deform_tuple(
+        tuple,
+        index.rd_att,
+        values.as_mut_ptr(),
+        isnull.as_mut_ptr(),
+    );
+
+    keys.iter_mut().all(|key| {
+        let att = key.sk_attno as usize - 1;
+        if key.sk_flags & pg_sys::SK_ISNULL as i32 != 0 || isnull[att] {
+            return false;
+        }
+        let res = pg_sys::FunctionCall2Coll(
+            &mut key.sk_func,
+            key.sk_collation,
+            values[att],
+            key.sk_argument,
+        );
+        res.value() != 0
+    })
+}
+
+unsafe fn index_tuple_size(tuple: pg_sys::IndexTuple) -> usize {
+    ((*tuple).t_info as u32 & pg_sys::INDEX_SIZE_MASK) as usize
+}
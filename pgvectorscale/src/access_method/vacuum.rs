@@ -1,20 +1,105 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
 use pgrx::{
     pg_sys::{FirstOffsetNumber, IndexBulkDeleteResult},
     *,
 };
 
 use crate::{
-    access_method::{meta_page::MetaPage, plain_storage::PlainStorage, sbq::SbqSpeedupStorage},
+    access_method::{
+        full_vector::read_full_vector_pointer, meta_page::MetaPage, plain_storage::PlainStorage,
+        pq::PqStorage, rabitq::RabitqStorage, sbq::SbqSpeedupStorage, sq8::Sq8Storage,
+    },
     util::{
-        page::WritablePage,
-        ports::{PageGetItem, PageGetItemId, PageGetMaxOffsetNumber},
-        ItemPointer,
+        page::{is_free_page, PageType, ReadablePage, WritablePage},
+        ports::{
+            BackendIdGetProc, FreeSpaceMapVacuum, PageGetItem, PageGetItemId,
+            PageGetMaxOffsetNumber, RecordPageWithFreeSpace,
+        },
+        IndexPointer, ItemPointer,
     },
 };
 
 use crate::access_method::storage::ArchivedData;
 
-use super::storage::{Storage, StorageType};
+use super::{
+    graph::Graph,
+    graph_neighbor_store::GraphNeighborStore,
+    labels::Label,
+    neighbor_with_distance::NeighborWithDistance,
+    stats::PruneNeighborStats,
+    storage::{NodeDistanceMeasure, Storage, StorageType},
+};
+
+/* Deleted nodes are repaired out of the graph the way FreshDiskANN consolidates deletes: every node pointing to a
+deleted node gets the deleted node's neighbors as candidates for its own neighbors, and is pruned again. Afterwards
+nothing points to the deleted nodes anymore, and they are removed from the index pages.
+
+Removing a node is only safe once no one can follow a pointer to it. Scans and inserts that were already running
+during the repair may still hold such pointers. They hold a lock on the index relation until their transaction ends,
+so VACUUM waits for the transactions holding a lock on the index when the repair is done to finish. Scans and inserts
+that start later don't hold up the removal, and they don't take any lock of their own. Inserts never add edges to
+nodes that are marked deleted, so once every insert that started before the nodes were marked has finished, no new
+edges to them appear. If the transactions are still running after GRAPH_USERS_WAIT_TIMEOUT_MS, the deleted nodes stay
+on the pages, unreachable, and the next VACUUM removes them. */
+
+/// How long VACUUM waits for the scans and inserts that may still reach the deleted nodes before leaving the
+/// removal to the next VACUUM.
+const GRAPH_USERS_WAIT_TIMEOUT_MS: std::os::raw::c_int = 10_000;
+
+/// Waits for the transactions of the scans and inserts running now to finish. Returns false if some are still
+/// running after GRAPH_USERS_WAIT_TIMEOUT_MS.
+fn wait_for_graph_users(index: &PgRelation) -> bool {
+    unsafe {
+        let lock_rel_id = (*index.as_ptr()).rd_lockInfo.lockRelId;
+        let locktag = pg_sys::LOCKTAG {
+            locktag_field1: lock_rel_id.dbId.as_u32(),
+            locktag_field2: lock_rel_id.relId.as_u32(),
+            locktag_field3: 0,
+            locktag_field4: 0,
+            locktag_type: pg_sys::LockTagType_LOCKTAG_RELATION as u8,
+            locktag_lockmethodid: pg_sys::DEFAULT_LOCKMETHOD as u8,
+        };
+        //every lock on the index conflicts with an AccessExclusiveLock
+        let mut num_users = 0;
+        let users = pg_sys::GetLockConflicts(
+            &locktag,
+            pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE,
+            &mut num_users,
+        );
+
+        let start = pg_sys::GetCurrentTimestamp();
+        for &user in std::slice::from_raw_parts(users, num_users as _) {
+            if in_my_lock_group(user) {
+                continue;
+            }
+            //doesn't wait, only checks if the transaction is still running
+            while !pg_sys::VirtualXactLock(user, false) {
+                if pg_sys::TimestampDifferenceExceeds(
+                    start,
+                    pg_sys::GetCurrentTimestamp(),
+                    GRAPH_USERS_WAIT_TIMEOUT_MS,
+                ) {
+                    return false;
+                }
+                pg_sys::vacuum_delay_point();
+                pg_sys::pg_usleep(10_000);
+            }
+        }
+        true
+    }
+}
+
+/// Whether `vxid` is a parallel worker of this backend, e.g. of a parallel VACUUM processing the other indexes. The
+/// workers lock every index of the table, but don't scan or insert.
+unsafe fn in_my_lock_group(vxid: pg_sys::VirtualTransactionId) -> bool {
+    let leader = (*pg_sys::MyProc).lockGroupLeader;
+    if leader.is_null() {
+        return false;
+    }
+    let proc = BackendIdGetProc(vxid.backendId);
+    !proc.is_null() && (*proc).lockGroupLeader == leader
+}
 
 #[pg_guard]
 pub extern "C" fn ambulkdelete(
@@ -30,6 +115,9 @@ pub extern "C" fn ambulkdelete(
     };
 
     let index_relation = unsafe { PgRelation::from_pg((*info).index) };
+    let heap_relation = index_relation
+        .heap_relation()
+        .expect("index has no heap relation");
     let nblocks = unsafe {
         pg_sys::RelationGetNumberOfBlocksInFork(
             index_relation.as_ptr(),
@@ -38,37 +126,92 @@ pub extern "C" fn ambulkdelete(
     };
 
     let meta_page = MetaPage::fetch(&index_relation);
-    let storage = meta_page.get_storage_type();
-    match storage {
+    let (index, heap) = (&index_relation, &heap_relation);
+    match meta_page.get_storage_type() {
         StorageType::SbqSpeedup | StorageType::SbqCompression => {
-            bulk_delete_for_storage::<SbqSpeedupStorage>(
-                &index_relation,
-                nblocks,
-                results,
-                callback,
-                callback_state,
-            );
+            bulk_delete(index, nblocks, results, callback, callback_state, |stats| {
+                SbqSpeedupStorage::load_for_insert(heap, index, &meta_page, stats)
+            })
+        }
+        StorageType::Pq => {
+            bulk_delete(index, nblocks, results, callback, callback_state, |stats| {
+                PqStorage::load_for_insert(heap, index, &meta_page, stats)
+            })
+        }
+        StorageType::Sq8 => {
+            bulk_delete(index, nblocks, results, callback, callback_state, |stats| {
+                Sq8Storage::load_for_insert(heap, index, &meta_page, stats)
+            })
+        }
+        StorageType::Rabitq => {
+            bulk_delete(index, nblocks, results, callback, callback_state, |stats| {
+                RabitqStorage::load_for_insert(heap, index, &meta_page, stats)
+            })
         }
         StorageType::Plain => {
-            bulk_delete_for_storage::<PlainStorage>(
-                &index_relation,
-                nblocks,
-                results,
-                callback,
-                callback_state,
-            );
+            bulk_delete(index, nblocks, results, callback, callback_state, |_| {
+                PlainStorage::load_for_insert(index, heap, meta_page.get_distance_function())
+            })
         }
     }
     results
 }
 
-fn bulk_delete_for_storage<S: Storage>(
+/// Marks the nodes of deleted heap tuples as deleted and, if there are any, removes them from the graph. The storage
+/// is only loaded then.
+fn bulk_delete<S: Storage>(
     index: &PgRelation,
     nblocks: u32,
     results: *mut IndexBulkDeleteResult,
     callback: pg_sys::IndexBulkDeleteCallback,
     callback_state: *mut ::std::os::raw::c_void,
+    load_storage: impl FnOnce(&mut PruneNeighborStats) -> S,
 ) {
+    let deleted = bulk_delete_for_storage::<S>(index, nblocks, results, callback, callback_state);
+    if !deleted.is_empty() {
+        let mut stats = PruneNeighborStats::new();
+        let storage = load_storage(&mut stats);
+        consolidate_deletes(index, &storage, nblocks, &deleted, &mut stats);
+    }
+}
+
+/// Marks the nodes of deleted heap tuples as deleted. Returns all the deleted nodes in the index (including the
+/// ones deleted by earlier VACUUMs but not removed yet) with their neighbors.
+fn bulk_delete_for_storage<S: Storage>(
+    index: &PgRelation,
+    nblocks: u32,
+    results: *mut IndexBulkDeleteResult,
+    callback: pg_sys::IndexBulkDeleteCallback,
+    callback_state: *mut ::std::os::raw::c_void,
+) -> HashMap<IndexPointer, Vec<IndexPointer>> {
+    let mut deleted_nodes = HashMap::new();
+    //returns true if the node was marked deleted
+    let mut visit_node = |index_pointer: ItemPointer, data: &mut [u8]| unsafe {
+        let node = S::ArchivedType::with_data(data);
+
+        if node.is_deleted() {
+            deleted_nodes.insert(index_pointer, node.get_index_pointer_to_neighbors());
+            return false;
+        }
+
+        let heap_pointer: ItemPointer = node.get_heap_item_pointer();
+        let mut ctid: pg_sys::ItemPointerData = pg_sys::ItemPointerData {
+            ..Default::default()
+        };
+        heap_pointer.to_item_pointer_data(&mut ctid);
+
+        let deleted = callback.unwrap()(&mut ctid, callback_state);
+        if deleted {
+            let neighbors = node.get_index_pointer_to_neighbors();
+            node.delete();
+            deleted_nodes.insert(index_pointer, neighbors);
+            (*results).tuples_removed += 1.0;
+        } else {
+            (*results).num_index_tuples += 1.0;
+        }
+        deleted
+    };
+
     for block_number in 0..nblocks {
         let page = unsafe { WritablePage::cleanup(&index, block_number) };
         if page.get_type() != S::page_type() {
@@ -78,32 +221,30 @@ fn bulk_delete_for_storage<S: Storage>(
 
         unsafe { pg_sys::vacuum_delay_point() };
 
+        if page.is_chained() {
+            //the node continues on overflow pages, it's read and written through its copy
+            std::mem::drop(page);
+            let index_pointer = ItemPointer::new(block_number, FirstOffsetNumber);
+            let wb = unsafe { index_pointer.modify_bytes(index) };
+            if visit_node(index_pointer, wb.get_data_slice()) {
+                wb.commit();
+            }
+            continue;
+        }
+
         let max_offset = unsafe { PageGetMaxOffsetNumber(*page) };
         for offset_number in FirstOffsetNumber..(max_offset + 1) as _ {
             unsafe {
                 let item_id = PageGetItemId(*page, offset_number);
+                if (*item_id).lp_flags() == pg_sys::LP_UNUSED {
+                    //removed by an earlier VACUUM
+                    continue;
+                }
                 let item = PageGetItem(*page, item_id) as *mut u8;
                 let len = (*item_id).lp_len();
                 let data = std::slice::from_raw_parts_mut(item, len as _);
-                let node = S::ArchivedType::with_data(data);
-
-                if node.is_deleted() {
-                    continue;
-                }
-
-                let heap_pointer: ItemPointer = node.get_heap_item_pointer();
-                let mut ctid: pg_sys::ItemPointerData = pg_sys::ItemPointerData {
-                    ..Default::default()
-                };
-                heap_pointer.to_item_pointer_data(&mut ctid);
-
-                let deleted = callback.unwrap()(&mut ctid, callback_state);
-                if deleted {
-                    node.delete();
+                if visit_node(ItemPointer::new(block_number, offset_number), data) {
                     modified = true;
-                    (*results).tuples_removed += 1.0;
-                } else {
-                    (*results).num_index_tuples += 1.0;
                 }
             }
         }
@@ -111,6 +252,246 @@ fn bulk_delete_for_storage<S: Storage>(
             page.commit();
         }
     }
+    deleted_nodes
+}
+
+/// Returns the live nodes on a page, with their neighbors.
+fn get_live_nodes<S: Storage>(
+    index: &PgRelation,
+    block_number: pg_sys::BlockNumber,
+) -> Vec<(IndexPointer, Vec<IndexPointer>)> {
+    let page = unsafe { ReadablePage::read(index, block_number) };
+    if page.get_type() != S::page_type() {
+        return vec![];
+    }
+
+    if page.is_chained() {
+        std::mem::drop(page);
+        let index_pointer = ItemPointer::new(block_number, FirstOffsetNumber);
+        let rb = unsafe { index_pointer.read_bytes(index) };
+        let node = S::ArchivedType::with_readable_data(rb.get_data_slice());
+        if node.is_deleted() {
+            return vec![];
+        }
+        return vec![(index_pointer, node.get_index_pointer_to_neighbors())];
+    }
+
+    let mut nodes = vec![];
+    let max_offset = unsafe { PageGetMaxOffsetNumber(*page) };
+    for offset_number in FirstOffsetNumber..(max_offset + 1) as _ {
+        unsafe {
+            let item_id = PageGetItemId(*page, offset_number);
+            if (*item_id).lp_flags() == pg_sys::LP_UNUSED {
+                continue;
+            }
+            let item = PageGetItem(*page, item_id) as *const u8;
+            let len = (*item_id).lp_len();
+            let data = std::slice::from_raw_parts(item, len as _);
+            let node = S::ArchivedType::with_readable_data(data);
+            if !node.is_deleted() {
+                nodes.push((
+                    ItemPointer::new(block_number, offset_number),
+                    node.get_index_pointer_to_neighbors(),
+                ));
+            }
+        }
+    }
+    nodes
+}
+
+/// Repairs the graph around the `deleted` nodes, then removes them if no scan or insert can still reach them.
+fn consolidate_deletes<S: Storage>(
+    index: &PgRelation,
+    storage: &S,
+    nblocks: u32,
+    deleted: &HashMap<IndexPointer, Vec<IndexPointer>>,
+    stats: &mut PruneNeighborStats,
+) {
+    /* Inserts that started before the nodes were marked may still add edges to them. The ones that start later
+    don't add edges to deleted nodes. */
+    let can_remove = wait_for_graph_users(index);
+
+    let mut meta_page = MetaPage::fetch(index);
+    let num_neighbors = meta_page.get_num_neighbors() as usize;
+    let is_deleted = |index_pointer: IndexPointer| deleted.contains_key(&index_pointer);
+
+    //labels whose entry point was deleted get the first live node with the label found below
+    let orphaned_labels = MetaPage::remove_label_entry_points(index, is_deleted, stats);
+    let mut orphaned_labels: HashSet<Label> = orphaned_labels.into_iter().collect();
+    let mut first_live_node = None;
+
+    let graph = Graph::new(GraphNeighborStore::Disk, &mut meta_page);
+    for block_number in 0..nblocks {
+        unsafe { pg_sys::vacuum_delay_point() };
+
+        for (index_pointer, neighbors) in get_live_nodes::<S>(index, block_number) {
+            first_live_node.get_or_insert(index_pointer);
+
+            if !orphaned_labels.is_empty() {
+                let labels = storage.get_labels(index_pointer);
+                let found: Vec<Label> = labels
+                    .iter()
+                    .filter(|l| orphaned_labels.remove(l))
+                    .cloned()
+                    .collect();
+                if !found.is_empty() {
+                    MetaPage::update_label_entry_points(index, &found, index_pointer, stats);
+                }
+            }
+
+            /* Inserts may add edges to the node meanwhile, its neighbors are only replaced if they didn't change.
+            Otherwise the repair starts over from the current neighbors. */
+            let mut neighbors = neighbors;
+            while neighbors.iter().any(|n| is_deleted(*n)) {
+                let new_neighbors = repair_neighbors(
+                    &graph,
+                    storage,
+                    index_pointer,
+                    &neighbors,
+                    deleted,
+                    num_neighbors,
+                    stats,
+                );
+                if storage.replace_neighbors_on_disk(
+                    graph.get_meta_page(),
+                    index_pointer,
+                    &neighbors,
+                    &new_neighbors,
+                    stats,
+                ) {
+                    break;
+                }
+                neighbors = read_neighbors::<S>(index, index_pointer);
+            }
+        }
+    }
+
+    let init_ids = graph.get_meta_page().get_init_ids().unwrap_or_default();
+    if init_ids.iter().any(|id| is_deleted(*id)) {
+        let live_init_ids: Vec<IndexPointer> =
+            init_ids.into_iter().filter(|id| !is_deleted(*id)).collect();
+        let new_init_ids = if live_init_ids.is_empty() {
+            //an empty graph gets a new init id with the next insert
+            first_live_node.into_iter().collect()
+        } else {
+            live_init_ids
+        };
+        MetaPage::update_init_ids(index, new_init_ids, stats);
+    }
+
+    /* Nothing points to the deleted nodes anymore, but scans and inserts running since before the repair may still
+    hold pointers to them. */
+    if can_remove && wait_for_graph_users(index) {
+        remove_deleted_nodes::<S>(index, deleted);
+    }
+}
+
+/// The new neighbors of a node pointing to deleted nodes: its live neighbors and the live neighbors of the deleted
+/// ones, pruned.
+fn repair_neighbors<S: Storage>(
+    graph: &Graph,
+    storage: &S,
+    index_pointer: IndexPointer,
+    neighbors: &[IndexPointer],
+    deleted: &HashMap<IndexPointer, Vec<IndexPointer>>,
+    num_neighbors: usize,
+    stats: &mut PruneNeighborStats,
+) -> Vec<NeighborWithDistance> {
+    let is_deleted = |index_pointer: IndexPointer| deleted.contains_key(&index_pointer);
+
+    //the live neighbors, and the live neighbors of the deleted neighbors
+    let mut candidate_pointers: Vec<IndexPointer> = vec![];
+    for neighbor in neighbors {
+        match deleted.get(neighbor) {
+            Some(neighbors_of_deleted) => {
+                candidate_pointers.extend(neighbors_of_deleted.iter().cloned())
+            }
+            None => candidate_pointers.push(*neighbor),
+        }
+    }
+    let mut seen = HashSet::new();
+    candidate_pointers.retain(|&c| c != index_pointer && !is_deleted(c) && seen.insert(c));
+
+    let candidates: Vec<NeighborWithDistance> = {
+        let dist_state = unsafe { storage.get_node_distance_measure(index_pointer, stats) };
+        candidate_pointers
+            .into_iter()
+            .map(|c| NeighborWithDistance::new(c, unsafe { dist_state.get_distance(c, stats) }))
+            .collect()
+    };
+    if candidates.len() > num_neighbors {
+        graph.prune_neighbors(index_pointer, candidates, storage, stats)
+    } else {
+        candidates
+    }
+}
+
+/// The current neighbors of a node.
+fn read_neighbors<S: Storage>(
+    index: &PgRelation,
+    index_pointer: IndexPointer,
+) -> Vec<IndexPointer> {
+    let rb = unsafe { index_pointer.read_bytes(index) };
+    let node = S::ArchivedType::with_readable_data(rb.get_data_slice());
+    node.get_index_pointer_to_neighbors()
+}
+
+/// Frees the space of the deleted nodes, of their full vectors if the index stores them, and of the continuations of
+/// the chained ones. The line pointers are kept unused, so the offsets of other nodes don't change. The freed space is recorded in the free space map, for inserts
+/// to reuse. The continuations go after all the heads: a crash in between leaves unreachable pieces, but no head
+/// without its pieces.
+fn remove_deleted_nodes<S: Storage>(
+    index: &PgRelation,
+    deleted: &HashMap<IndexPointer, Vec<IndexPointer>>,
+) {
+    let full_vectors_in_index = MetaPage::fetch(index).get_full_vectors_in_index();
+    let mut offsets_by_block: BTreeMap<pg_sys::BlockNumber, Vec<pg_sys::OffsetNumber>> =
+        BTreeMap::new();
+    let mut continuation_offsets_by_block: BTreeMap<_, Vec<_>> = BTreeMap::new();
+    for index_pointer in deleted.keys() {
+        let mut items = vec![*index_pointer];
+        if full_vectors_in_index {
+            items.push(unsafe { read_full_vector_pointer(index, *index_pointer) });
+        }
+        for item in items {
+            offsets_by_block
+                .entry(item.block_number)
+                .or_default()
+                .push(item.offset);
+            for continuation in unsafe { item.get_continuations(index) } {
+                continuation_offsets_by_block
+                    .entry(continuation.block_number)
+                    .or_default()
+                    .push(continuation.offset);
+            }
+        }
+    }
+
+    for (block_number, offsets) in offsets_by_block
+        .into_iter()
+        .chain(continuation_offsets_by_block)
+    {
+        unsafe { pg_sys::vacuum_delay_point() };
+
+        let mut page = unsafe { WritablePage::cleanup(index, block_number) };
+        assert!(
+            page.get_type() == S::page_type()
+                || page.get_type() == PageType::Overflow
+                || page.get_type() == PageType::FullVector
+        );
+        unsafe {
+            for offset_number in offsets {
+                pg_sys::PageIndexTupleDeleteNoCompact(*page, offset_number);
+            }
+            //lets the next items added to the page reuse the unused line pointers
+            (*(*page as pg_sys::PageHeader)).pd_flags |= pg_sys::PD_HAS_FREE_LINES as u16;
+        }
+        //a chained node is alone on its page, the page can take other nodes now
+        page.set_chained(false);
+        let free_space = page.get_free_space();
+        page.commit();
+        unsafe { RecordPageWithFreeSpace(index.as_ptr(), block_number, free_space) };
+    }
 }
 
 #[pg_guard]
@@ -130,6 +511,35 @@ pub extern "C" fn amvacuumcleanup(
             pg_sys::ForkNumber_MAIN_FORKNUM,
         );
 
+        let page_type = match MetaPage::fetch(&index_relation).get_storage_type() {
+            StorageType::SbqSpeedup | StorageType::SbqCompression => SbqSpeedupStorage::page_type(),
+            StorageType::Pq => PqStorage::page_type(),
+            StorageType::Sq8 => Sq8Storage::page_type(),
+            StorageType::Rabitq => RabitqStorage::page_type(),
+            StorageType::Plain => PlainStorage::page_type(),
+        };
+        (*stats).pages_free = 0;
+        for block_number in 0..(*stats).num_pages {
+            let page = ReadablePage::read(&index_relation, block_number);
+            if page.get_type() != page_type
+                && page.get_type() != PageType::Overflow
+                && page.get_type() != PageType::FullVector
+            {
+                continue;
+            }
+            if is_free_page(*page) {
+                (*stats).pages_free += 1;
+            }
+            /* Node, overflow and full vector pages share the free space map. A tape that finds a page of the other type in it
+            records the page as full, this records its free space again. */
+            let free_space = pg_sys::PageGetFreeSpace(*page);
+            std::mem::drop(page);
+            RecordPageWithFreeSpace(index_relation.as_ptr(), block_number, free_space);
+        }
+
+        //propagates the free space recorded to the upper levels of the free space map
+        FreeSpaceMapVacuum(index_relation.as_ptr());
+
         stats
     }
 }
@@ -355,6 +765,327 @@ pub mod tests {
         client.execute("DROP TABLE test_vac_full", &[]).unwrap();
     }
 
+    #[cfg(test)]
+    static VAC_REPAIR_MUTEX: once_cell::sync::Lazy<std::sync::Mutex<()>> =
+        once_cell::sync::Lazy::new(std::sync::Mutex::default);
+
+    #[cfg(test)]
+    pub fn test_delete_vacuum_repair_scaffold(index_options: &str) {
+        //do not run this test in parallel
+        let _lock = VAC_REPAIR_MUTEX.lock().unwrap();
+
+        //bring up the test db by running a fake test on a fake fn
+        pgrx_tests::run_test(
+            "test_delete_mock_fn",
+            None,
+            crate::pg_test::postgresql_conf_options(),
+        )
+        .unwrap();
+
+        let (mut client, _) = pgrx_tests::client().unwrap();
+
+        client
+            .batch_execute(&format!(
+                "CREATE TABLE test_vac_repair(id INT GENERATED ALWAYS AS IDENTITY, embedding vector(64));
+
+        select setseed(0.5);
+        -- generate 1000 vectors
+        INSERT INTO test_vac_repair (embedding)
+        SELECT
+         *
+        FROM (
+            SELECT
+        ('[ ' || array_to_string(array_agg(random()), ',', '0') || ']')::vector AS embedding
+        FROM
+         generate_series(1, 64 * 1000) i
+        GROUP BY
+        i % 1000) g;
+
+        CREATE INDEX idxtest_vac_repair
+              ON test_vac_repair
+           USING diskann(embedding)
+            WITH ({index_options});
+
+        -- the first row is the entry point of the graph
+        DELETE FROM test_vac_repair WHERE id % 2 = 1;
+            "
+            ))
+            .unwrap();
+
+        client.close().unwrap();
+
+        let (mut client, _) = pgrx_tests::client().unwrap();
+        client.execute("VACUUM test_vac_repair", &[]).unwrap();
+
+        let test_vec: Option<Vec<f32>> = client
+            .query_one(
+                "SELECT('{' || array_to_string(array_agg(random()), ',', '0') || '}')::real[] AS embedding
+        FROM generate_series(1, 64)",
+                &[],
+            )
+            .unwrap()
+            .get(0);
+        let test_vec = test_vec
+            .unwrap()
+            .into_iter()
+            .map(|x| Some(x))
+            .collect::<Vec<_>>();
+
+        //the deleted nodes are gone, and every live node is still reachable
+        client.execute("set enable_seqscan = 0;", &[]).unwrap();
+        client
+            .execute("set diskann.query_search_list_size = 600;", &[])
+            .unwrap();
+        let cnt: i64 = client.query_one("WITH cte as (select * from test_vac_repair order by embedding <=> $1::float4[]::vector) SELECT count(*) from cte;", &[&test_vec]).unwrap().get(0);
+        assert_eq!(cnt, 500, "count after vacuum");
+
+        //the repaired graph still finds the nearest neighbors
+        client
+            .execute("set diskann.query_search_list_size = 30;", &[])
+            .unwrap();
+        let index_ids: Vec<i32> = client
+            .query("select id from test_vac_repair order by embedding <=> $1::float4[]::vector LIMIT 10", &[&test_vec])
+            .unwrap()
+            .iter()
+            .map(|r| r.get(0))
+            .collect();
+        client.execute("set enable_seqscan = 1;", &[]).unwrap();
+        client.execute("set enable_indexscan = 0;", &[]).unwrap();
+        let exact_ids: Vec<i32> = client
+            .query("select id from test_vac_repair order by embedding <=> $1::float4[]::vector LIMIT 10", &[&test_vec])
+            .unwrap()
+            .iter()
+            .map(|r| r.get(0))
+            .collect();
+        client.execute("set enable_indexscan = 1;", &[]).unwrap();
+        let found = index_ids.iter().filter(|id| exact_ids.contains(id)).count();
+        assert!(found >= 8, "recall after vacuum: {} of 10", found);
+
+        //inserts after the repair link into the live nodes
+        client
+            .execute(
+                "INSERT INTO test_vac_repair(embedding) SELECT $1::float4[]::vector FROM generate_series(1, 10)",
+                &[&test_vec],
+            )
+            .unwrap();
+        client.execute("set enable_seqscan = 0;", &[]).unwrap();
+        client
+            .execute("set diskann.query_search_list_size = 600;", &[])
+            .unwrap();
+        let cnt: i64 = client.query_one("WITH cte as (select * from test_vac_repair order by embedding <=> $1::float4[]::vector) SELECT count(*) from cte;", &[&test_vec]).unwrap().get(0);
+        assert_eq!(cnt, 510, "count after insert");
+
+        client
+            .execute("DROP INDEX idxtest_vac_repair", &[])
+            .unwrap();
+        client.execute("DROP TABLE test_vac_repair", &[]).unwrap();
+    }
+
+    #[cfg(test)]
+    static VAC_REUSE_MUTEX: once_cell::sync::Lazy<std::sync::Mutex<()>> =
+        once_cell::sync::Lazy::new(std::sync::Mutex::default);
+
+    #[cfg(test)]
+    pub fn test_delete_vacuum_reuse_scaffold(index_options: &str, num_dimensions: usize) {
+        //do not run this test in parallel
+        let _lock = VAC_REUSE_MUTEX.lock().unwrap();
+
+        //bring up the test db by running a fake test on a fake fn
+        pgrx_tests::run_test(
+            "test_delete_mock_fn",
+            None,
+            crate::pg_test::postgresql_conf_options(),
+        )
+        .unwrap();
+
+        let (mut client, _) = pgrx_tests::client().unwrap();
+
+        let insert = format!(
+            "INSERT INTO test_vac_reuse (embedding)
+        SELECT
+         *
+        FROM (
+            SELECT
+        ('[ ' || array_to_string(array_agg(random()), ',', '0') || ']')::vector AS embedding
+        FROM
+         generate_series(1, {num_dimensions} * 500) i
+        GROUP BY
+        i % 500) g;"
+        );
+
+        client
+            .batch_execute(&format!(
+                "CREATE TABLE test_vac_reuse(id INT GENERATED ALWAYS AS IDENTITY, embedding vector({num_dimensions}));
+
+        select setseed(0.5);
+        {insert}
+        {insert}
+
+        CREATE INDEX idxtest_vac_reuse
+              ON test_vac_reuse
+           USING diskann(embedding)
+            WITH ({index_options});
+            "
+            ))
+            .unwrap();
+
+        let size_after_build: i64 = client
+            .query_one("SELECT pg_relation_size('idxtest_vac_reuse')", &[])
+            .unwrap()
+            .get(0);
+
+        //replace half of the rows a few times
+        for _ in 0..3 {
+            client
+                .execute(
+                    "DELETE FROM test_vac_reuse WHERE id IN (SELECT id FROM test_vac_reuse ORDER BY id LIMIT 500)",
+                    &[],
+                )
+                .unwrap();
+            client.execute("VACUUM test_vac_reuse", &[]).unwrap();
+
+            client.batch_execute(&insert).unwrap();
+        }
+
+        //the inserts filled the space freed by VACUUM instead of extending the index
+        let size_after_churn: i64 = client
+            .query_one("SELECT pg_relation_size('idxtest_vac_reuse')", &[])
+            .unwrap()
+            .get(0);
+        assert!(
+            size_after_churn < size_after_build * 3 / 2,
+            "index grew from {} to {} bytes",
+            size_after_build,
+            size_after_churn
+        );
+
+        client.execute("set enable_seqscan = 0;", &[]).unwrap();
+        client
+            .execute("set diskann.query_search_list_size = 1000;", &[])
+            .unwrap();
+        let cnt: i64 = client
+            .query_one(
+                "WITH cte as (select * from test_vac_reuse order by embedding <=> (SELECT embedding FROM test_vac_reuse LIMIT 1)) SELECT count(*) from cte;",
+                &[],
+            )
+            .unwrap()
+            .get(0);
+        assert_eq!(cnt, 1000, "count after churn");
+
+        client.execute("DROP INDEX idxtest_vac_reuse", &[]).unwrap();
+        client.execute("DROP TABLE test_vac_reuse", &[]).unwrap();
+    }
+
+    #[cfg(test)]
+    static VAC_SCAN_MUTEX: once_cell::sync::Lazy<std::sync::Mutex<()>> =
+        once_cell::sync::Lazy::new(std::sync::Mutex::default);
+
+    #[cfg(test)]
+    pub fn test_delete_vacuum_concurrent_scan_scaffold(index_options: &str) {
+        //do not run this test in parallel
+        let _lock = VAC_SCAN_MUTEX.lock().unwrap();
+
+        //bring up the test db by running a fake test on a fake fn
+        pgrx_tests::run_test(
+            "test_delete_mock_fn",
+            None,
+            crate::pg_test::postgresql_conf_options(),
+        )
+        .unwrap();
+
+        let (mut client, _) = pgrx_tests::client().unwrap();
+
+        let insert = "INSERT INTO test_vac_scan (embedding)
+        SELECT
+         *
+        FROM (
+            SELECT
+        ('[ ' || array_to_string(array_agg(random()), ',', '0') || ']')::vector AS embedding
+        FROM
+         generate_series(1, 64 * 500) i
+        GROUP BY
+        i % 500) g;";
+
+        client
+            .batch_execute(&format!(
+                "CREATE TABLE test_vac_scan(id INT GENERATED ALWAYS AS IDENTITY, embedding vector(64));
+
+        select setseed(0.5);
+        {insert}
+        {insert}
+
+        CREATE INDEX idxtest_vac_scan
+              ON test_vac_scan
+           USING diskann(embedding)
+            WITH ({index_options});
+
+        DELETE FROM test_vac_scan WHERE id % 2 = 1;
+            "
+            ))
+            .unwrap();
+
+        //a scan that is open while VACUUM runs, its transaction holds a lock on the index
+        let (mut scan_client, _) = pgrx_tests::client().unwrap();
+        scan_client
+            .batch_execute(
+                "SET enable_seqscan = 0;
+                SET diskann.query_search_list_size = 1000;
+                BEGIN;
+                DECLARE scan CURSOR FOR
+                    SELECT id FROM test_vac_scan ORDER BY embedding <=> (SELECT embedding FROM test_vac_scan WHERE id = 2);",
+            )
+            .unwrap();
+
+        //VACUUM repairs the graph but leaves the deleted nodes to the next VACUUM instead of waiting for the scan
+        client.execute("VACUUM test_vac_scan", &[]).unwrap();
+
+        //the scan still finds every live row
+        let ids: Vec<i32> = scan_client
+            .query("FETCH ALL FROM scan", &[])
+            .unwrap()
+            .iter()
+            .map(|r| r.get(0))
+            .collect();
+        assert_eq!(ids.len(), 500, "count of the open scan");
+        assert!(ids.iter().all(|id| id % 2 == 0), "deleted row returned");
+        scan_client.batch_execute("COMMIT").unwrap();
+        scan_client.close().unwrap();
+
+        //the next VACUUM removes the deleted nodes, and inserts reuse their space
+        client.execute("VACUUM test_vac_scan", &[]).unwrap();
+        let size_before_insert: i64 = client
+            .query_one("SELECT pg_relation_size('idxtest_vac_scan')", &[])
+            .unwrap()
+            .get(0);
+        client.batch_execute(insert).unwrap();
+        let size_after_insert: i64 = client
+            .query_one("SELECT pg_relation_size('idxtest_vac_scan')", &[])
+            .unwrap()
+            .get(0);
+        assert!(
+            size_after_insert <= size_before_insert + 4 * pg_sys::BLCKSZ as i64,
+            "index grew from {} to {} bytes",
+            size_before_insert,
+            size_after_insert
+        );
+
+        client.execute("set enable_seqscan = 0;", &[]).unwrap();
+        client
+            .execute("set diskann.query_search_list_size = 1000;", &[])
+            .unwrap();
+        let cnt: i64 = client
+            .query_one(
+                "WITH cte as (select * from test_vac_scan order by embedding <=> (SELECT embedding FROM test_vac_scan WHERE id = 2)) SELECT count(*) from cte;",
+                &[],
+            )
+            .unwrap()
+            .get(0);
+        assert_eq!(cnt, 1000, "count after insert");
+
+        client.execute("DROP INDEX idxtest_vac_scan", &[]).unwrap();
+        client.execute("DROP TABLE test_vac_scan", &[]).unwrap();
+    }
+
     #[pg_test]
     ///This function is only a mock to bring up the test framewokr in test_delete_vacuum
     fn test_delete_mock_fn() -> spi::Result<()> {
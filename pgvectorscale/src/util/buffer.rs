@@ -8,8 +8,8 @@ use std::ops::Deref;
 use pgrx::*;
 
 use pgrx::pg_sys::{
-    BlockNumber, Buffer, BufferGetBlockNumber, ForkNumber_MAIN_FORKNUM, InvalidBlockNumber,
-    ReadBufferMode_RBM_NORMAL,
+    BlockNumber, Buffer, BufferGetBlockNumber, ForkNumber, ForkNumber_INIT_FORKNUM,
+    ForkNumber_MAIN_FORKNUM, InvalidBlockNumber, ReadBufferMode_RBM_NORMAL,
 };
 
 pub struct LockRelationForExtension<'a> {
@@ -64,18 +64,48 @@ impl<'a> LockedBufferExclusive<'a> {
         //ReadBufferExtended requires the caller to ensure that only one backend extends the relation at one time.
         let _lock = LockRelationForExtension::new(index);
         //should really be using  ExtendBufferedRel but it's not in pgrx so go thru the read path with InvalidBlockNumber
-        unsafe { Self::read_unchecked(index, InvalidBlockNumber) }
+        unsafe { Self::read_unchecked(index, ForkNumber_MAIN_FORKNUM, InvalidBlockNumber) }
+    }
+
+    /// new_in_init_fork returns a buffer for a new block in the init fork of an unlogged relation.
+    pub fn new_in_init_fork(index: &'a PgRelation) -> Self {
+        let _lock = LockRelationForExtension::new(index);
+        unsafe { Self::read_unchecked(index, ForkNumber_INIT_FORKNUM, InvalidBlockNumber) }
     }
 
     /// Safety: Safe because it checks the block number doesn't overflow. ReadBufferExtended will throw an error if the block number is out of range for the relation
     pub fn read(index: &'a PgRelation, block: BlockNumber) -> Self {
-        unsafe { Self::read_unchecked(index, block) }
+        unsafe { Self::read_unchecked(index, ForkNumber_MAIN_FORKNUM, block) }
     }
 
-    /// Safety: unsafe because tje block number is not verifiwed
-    unsafe fn read_unchecked(index: &'a PgRelation, block: BlockNumber) -> Self {
-        let fork_number = ForkNumber_MAIN_FORKNUM;
+    /// Like read, but returns None instead of waiting if another backend holds a lock on the buffer.
+    pub fn try_read(index: &'a PgRelation, block: BlockNumber) -> Option<Self> {
+        unsafe {
+            let buf = pg_sys::ReadBufferExtended(
+                index.as_ptr(),
+                ForkNumber_MAIN_FORKNUM,
+                block,
+                ReadBufferMode_RBM_NORMAL,
+                std::ptr::null_mut(),
+            );
 
+            if !pg_sys::ConditionalLockBuffer(buf) {
+                pg_sys::ReleaseBuffer(buf);
+                return None;
+            }
+            Some(LockedBufferExclusive {
+                _relation: index,
+                buffer: buf,
+            })
+        }
+    }
+
+    /// Safety: unsafe because tje block number is not verifiwed
+    unsafe fn read_unchecked(
+        index: &'a PgRelation,
+        fork_number: ForkNumber,
+        block: BlockNumber,
+    ) -> Self {
         let buf = pg_sys::ReadBufferExtended(
             index.as_ptr(),
             fork_number,
@@ -4,12 +4,14 @@ pub mod ports;
 pub mod table_slot;
 pub mod tape;
 
-use pgrx::PgRelation;
+use pgrx::{pg_sys::BufferGetPage, PgRelation};
 use rkyv::{Archive, Deserialize, Serialize};
 
 use self::{
-    page::{ReadablePage, WritablePage},
+    buffer::LockedBufferExclusive,
+    page::{ReadablePage, WritablePage, MAX_PAGES_PER_RECORD},
     ports::{PageGetItem, PageGetItemId},
+    tape::ChainedItemHeader,
 };
 
 #[derive(Archive, Deserialize, Serialize, Debug, PartialEq, Eq, Hash, Clone, Copy)]
@@ -44,6 +46,8 @@ pub struct ReadableBuffer<'a> {
     _page: ReadablePage<'a>,
     len: usize,
     ptr: *const u8,
+    /* the item put together from its pieces, if it is chained over several pages. ptr points into it. */
+    _chained_data: Option<rkyv::AlignedVec>,
 }
 
 impl<'a> ReadableBuffer<'a> {
@@ -60,6 +64,16 @@ pub struct WritableBuffer<'a> {
     _page: WritablePage<'a>,
     len: usize,
     ptr: *mut u8,
+    chained: Option<ChainedCopy<'a>>,
+}
+
+/// A copy of an item chained over several pages. The changes to the copy are written to the pieces on commit.
+struct ChainedCopy<'a> {
+    data: rkyv::AlignedVec,
+    /* the data of the head on its page */
+    head: (*mut u8, usize),
+    /* the pages of the continuations, locked but only registered in the WAL record of the head if they change */
+    continuations: Vec<(LockedBufferExclusive<'a>, pgrx::pg_sys::OffsetNumber)>,
 }
 
 impl<'a> WritableBuffer<'a> {
@@ -67,8 +81,45 @@ impl<'a> WritableBuffer<'a> {
         unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
     }
 
-    pub fn commit(self) {
-        self._page.commit();
+    /// Writes the changes in one WAL record, the ones to a chained item included. Only the continuations that changed
+    /// are written, a change to more than MAX_PAGES_PER_RECORD pages of an item is an error.
+    pub fn commit(mut self) {
+        let Some(chained) = self.chained else {
+            self._page.commit();
+            return;
+        };
+
+        let (head, head_len) = chained.head;
+        unsafe { std::ptr::copy_nonoverlapping(chained.data.as_ptr(), head, head_len) };
+        let mut start = head_len;
+        let mut linked = vec![];
+        for (buffer, offset) in chained.continuations {
+            unsafe {
+                let (item, len) = ItemPointer::get_item_of_page(BufferGetPage(*buffer), offset);
+                let piece_len = len - ChainedItemHeader::SIZE;
+                let new_piece = &chained.data[start..start + piece_len];
+                let old_piece =
+                    std::slice::from_raw_parts(item.add(ChainedItemHeader::SIZE), piece_len);
+                if old_piece != new_piece {
+                    if linked.len() + 1 == MAX_PAGES_PER_RECORD {
+                        pgrx::error!(
+                            "A change to more than {} pages of an index item can't be written",
+                            MAX_PAGES_PER_RECORD
+                        );
+                    }
+                    let page = self._page.link(buffer);
+                    let (item, _) = ItemPointer::get_item_of_page(*page, offset);
+                    std::ptr::copy_nonoverlapping(
+                        new_piece.as_ptr(),
+                        item.add(ChainedItemHeader::SIZE),
+                        piece_len,
+                    );
+                    linked.push(page);
+                }
+                start += piece_len;
+            }
+        }
+        self._page.commit_linked(linked);
     }
 }
 
@@ -107,20 +158,103 @@ impl ItemPointer {
 
     pub unsafe fn read_bytes(self, index: &PgRelation) -> ReadableBuffer {
         let page = ReadablePage::read(index, self.block_number);
-        page.get_item_unchecked(self.offset)
+        if !page.is_chained() {
+            return page.get_item_unchecked(self.offset);
+        }
+
+        //the head stays locked while the continuations are read, like when they are modified
+        let head = page.get_item_unchecked(self.offset);
+        let header = ChainedItemHeader::from_bytes(head.get_data_slice());
+        let mut data = rkyv::AlignedVec::with_capacity(header.len as _);
+        data.extend_from_slice(&head.get_data_slice()[ChainedItemHeader::SIZE..]);
+        let mut next = header.next;
+        while next.is_valid() {
+            let piece =
+                ReadablePage::read(index, next.block_number).get_item_unchecked(next.offset);
+            next = ChainedItemHeader::from_bytes(piece.get_data_slice()).next;
+            data.extend_from_slice(&piece.get_data_slice()[ChainedItemHeader::SIZE..]);
+        }
+        assert_eq!(data.len(), header.len as usize);
+
+        ReadableBuffer {
+            _page: head._page,
+            len: data.len(),
+            ptr: data.as_ptr(),
+            _chained_data: Some(data),
+        }
+    }
+
+    /// Returns the continuations of an item chained over several pages, none if the item fits on its page.
+    pub unsafe fn get_continuations(self, index: &PgRelation) -> Vec<ItemPointer> {
+        let mut continuations = vec![];
+        let page = ReadablePage::read(index, self.block_number);
+        if !page.is_chained() {
+            return continuations;
+        }
+        let mut piece = page.get_item_unchecked(self.offset);
+        loop {
+            let next = ChainedItemHeader::from_bytes(piece.get_data_slice()).next;
+            if !next.is_valid() {
+                return continuations;
+            }
+            continuations.push(next);
+            piece = ReadablePage::read(index, next.block_number).get_item_unchecked(next.offset);
+        }
     }
 
     pub unsafe fn modify_bytes(self, index: &PgRelation) -> WritableBuffer {
         let page = WritablePage::modify(index, self.block_number);
-        let item_id = PageGetItemId(*page, self.offset);
-        let item = PageGetItem(*page, item_id) as *mut u8;
-        let len = (*item_id).lp_len();
+        let (item, len) = Self::get_item_of_page(*page, self.offset);
+        if !page.is_chained() {
+            return WritableBuffer {
+                _page: page,
+                ptr: item,
+                len,
+                chained: None,
+            };
+        }
+
+        let header = ChainedItemHeader::from_bytes(std::slice::from_raw_parts(item, len));
+        let mut data = rkyv::AlignedVec::with_capacity(header.len as _);
+        let head = (
+            item.add(ChainedItemHeader::SIZE),
+            len - ChainedItemHeader::SIZE,
+        );
+        data.extend_from_slice(std::slice::from_raw_parts(head.0, head.1));
+        let mut continuations = vec![];
+        let mut next = header.next;
+        while next.is_valid() {
+            let buffer = LockedBufferExclusive::read(index, next.block_number);
+            let (item, len) = Self::get_item_of_page(BufferGetPage(*buffer), next.offset);
+            data.extend_from_slice(std::slice::from_raw_parts(
+                item.add(ChainedItemHeader::SIZE),
+                len - ChainedItemHeader::SIZE,
+            ));
+            continuations.push((buffer, next.offset));
+            next = ChainedItemHeader::from_bytes(std::slice::from_raw_parts(item, len)).next;
+        }
+        assert_eq!(data.len(), header.len as usize);
+
         WritableBuffer {
             _page: page,
-            ptr: item,
-            len: len as _,
+            len: data.len(),
+            ptr: data.as_mut_ptr(),
+            chained: Some(ChainedCopy {
+                data,
+                head,
+                continuations,
+            }),
         }
     }
+
+    unsafe fn get_item_of_page(
+        page: pgrx::pg_sys::Page,
+        offset: pgrx::pg_sys::OffsetNumber,
+    ) -> (*mut u8, usize) {
+        let item_id = PageGetItemId(page, offset);
+        let item = PageGetItem(page, item_id) as *mut u8;
+        (item, (*item_id).lp_len() as _)
+    }
 }
 
 pub type IndexPointer = ItemPointer;
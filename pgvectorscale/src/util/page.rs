@@ -3,14 +3,14 @@
 
 use pg_sys::Page;
 use pgrx::{
-    pg_sys::{BlockNumber, BufferGetPage, OffsetNumber, BLCKSZ},
+    pg_sys::{BlockNumber, BufferGetPage, FirstOffsetNumber, OffsetNumber, BLCKSZ},
     *,
 };
 use std::ops::Deref;
 
 use super::{
     buffer::{LockedBufferExclusive, LockedBufferShare},
-    ports::{PageGetItem, PageGetItemId},
+    ports::{PageGetItem, PageGetItemId, PageGetMaxOffsetNumber},
     ReadableBuffer,
 };
 pub struct WritablePage<'a> {
@@ -18,8 +18,17 @@ pub struct WritablePage<'a> {
     page: Page,
     state: *mut pg_sys::GenericXLogState,
     committed: bool,
+    /* init fork pages are always WAL-logged, even though the relation is unlogged */
+    log_new_page: bool,
+    /* false for a page registered in the WAL record of another page, see link() */
+    owns_record: bool,
+    /* the number of pages registered in the WAL record of this page */
+    num_linked: usize,
 }
 
+/// The number of pages one WAL record can change, see WritablePage::link.
+pub const MAX_PAGES_PER_RECORD: usize = pg_sys::MAX_GENERIC_XLOG_PAGES as usize;
+
 pub const TSV_PAGE_ID: u16 = 0xAE24; /* magic number, generated randomly */
 
 /// PageType identifies different types of pages in our index.
@@ -33,6 +42,13 @@ pub enum PageType {
     SbqMeans = 4,
     SbqNode = 5,
     Meta = 6,
+    Overflow = 7,
+    PqNode = 8,
+    Sq8Ranges = 9,
+    Sq8Node = 10,
+    RabitqCentroid = 11,
+    RabitqNode = 12,
+    FullVector = 13,
 }
 
 impl PageType {
@@ -45,17 +61,39 @@ impl PageType {
             4 => PageType::SbqMeans,
             5 => PageType::SbqNode,
             6 => PageType::Meta,
+            7 => PageType::Overflow,
+            8 => PageType::PqNode,
+            9 => PageType::Sq8Ranges,
+            10 => PageType::Sq8Node,
+            11 => PageType::RabitqCentroid,
+            12 => PageType::RabitqNode,
+            13 => PageType::FullVector,
             _ => panic!("Unknown PageType number {}", value),
         }
     }
 }
+/// The only item on the page is the head of an item chained over overflow pages, see Tape::write.
+const TSV_PAGE_CHAINED_ITEM: u8 = 1;
+
+/// The largest item that fits on a page.
+pub fn max_item_size() -> usize {
+    let align = pg_sys::MAXIMUM_ALIGNOF as usize;
+    let special_size = std::mem::size_of::<TsvPageOpaqueData>().next_multiple_of(align);
+    let free_space = BLCKSZ as usize
+        - std::mem::size_of::<pg_sys::PageHeaderData>()
+        - special_size
+        - std::mem::size_of::<pg_sys::ItemIdData>();
+    //items are stored maxaligned
+    free_space / align * align
+}
+
 /// This is the Tsv-specific data that goes on every "diskann-owned" page
 /// It is placed at the end of a page in the "special" area
 
 #[repr(C)]
 struct TsvPageOpaqueData {
     page_type: u8, // stores the PageType enum as an integer (u8 because we doubt we'll have more than 256 types).
-    _reserved: u8, // don't waste bytes, may be able to reuse later. For now: 0
+    flags: u8,     // TSV_PAGE_* flags
     page_id: u16, //  A magic ID for debuging to identify the page as a "diskann-owned". Should be last.
 }
 
@@ -63,7 +101,7 @@ impl TsvPageOpaqueData {
     fn new(page_type: PageType) -> Self {
         Self {
             page_type: page_type as u8,
-            _reserved: 0,
+            flags: 0,
             page_id: TSV_PAGE_ID,
         }
     }
@@ -98,6 +136,22 @@ impl<'a> WritablePage<'a> {
     /// new creates a totally new page on a relation by extending the relation
     pub fn new(index: &'a PgRelation, page_type: PageType) -> Self {
         let buffer = LockedBufferExclusive::new(index);
+        Self::new_with_buffer(index, buffer, page_type)
+    }
+
+    /// new_in_init_fork creates a new page in the init fork of an unlogged relation (see ambuildempty).
+    pub fn new_in_init_fork(index: &'a PgRelation, page_type: PageType) -> Self {
+        let buffer = LockedBufferExclusive::new_in_init_fork(index);
+        let mut new = Self::new_with_buffer(index, buffer, page_type);
+        new.log_new_page = true;
+        new
+    }
+
+    fn new_with_buffer(
+        index: &'a PgRelation,
+        buffer: LockedBufferExclusive<'a>,
+        page_type: PageType,
+    ) -> Self {
         unsafe {
             let state = pg_sys::GenericXLogStart(index.as_ptr());
             //TODO do we need a GENERIC_XLOG_FULL_IMAGE option?
@@ -107,6 +161,9 @@ impl<'a> WritablePage<'a> {
                 page: page,
                 state: state,
                 committed: false,
+                log_new_page: false,
+                owns_record: true,
+                num_linked: 0,
             };
             new.reinit(page_type);
             new
@@ -169,10 +226,67 @@ impl<'a> WritablePage<'a> {
                 page: page,
                 state: state,
                 committed: false,
+                log_new_page: false,
+                owns_record: true,
+                num_linked: 0,
             }
         }
     }
 
+    /// Reinitializes the empty page of `buffer` with the given type, see is_empty_page.
+    pub fn reuse(
+        index: &'a PgRelation,
+        buffer: LockedBufferExclusive<'a>,
+        page_type: PageType,
+    ) -> Self {
+        Self::new_with_buffer(index, buffer, page_type)
+    }
+
+    /// Registers the page of `buffer` in the WAL record of this page, so that the changes to both pages are written
+    /// atomically by commit_linked. A record takes at most MAX_PAGES_PER_RECORD pages.
+    pub fn link(&mut self, buffer: LockedBufferExclusive<'a>) -> WritablePage<'a> {
+        assert!(self.owns_record && self.num_linked + 1 < MAX_PAGES_PER_RECORD);
+        self.num_linked += 1;
+        unsafe {
+            let page = pg_sys::GenericXLogRegisterBuffer(self.state, *buffer, 0);
+            //this check the page
+            _ = TsvPageOpaqueData::read_from_page(&page);
+            WritablePage {
+                buffer,
+                page,
+                state: self.state,
+                committed: false,
+                log_new_page: false,
+                owns_record: false,
+                num_linked: 0,
+            }
+        }
+    }
+
+    /// Like link, for a new or empty page that is (re)initialized with the given type.
+    pub fn link_new(
+        &mut self,
+        buffer: LockedBufferExclusive<'a>,
+        page_type: PageType,
+    ) -> WritablePage<'a> {
+        assert!(self.owns_record && self.num_linked + 1 < MAX_PAGES_PER_RECORD);
+        self.num_linked += 1;
+        unsafe {
+            let page = pg_sys::GenericXLogRegisterBuffer(self.state, *buffer, 0);
+            let mut new = WritablePage {
+                buffer,
+                page,
+                state: self.state,
+                committed: false,
+                log_new_page: false,
+                owns_record: false,
+                num_linked: 0,
+            };
+            new.reinit(page_type);
+            new
+        }
+    }
+
     pub fn get_buffer(&self) -> &LockedBufferExclusive {
         &self.buffer
     }
@@ -195,6 +309,21 @@ impl<'a> WritablePage<'a> {
         }
     }
 
+    pub fn is_chained(&self) -> bool {
+        unsafe { (*TsvPageOpaqueData::with_page(self.page)).flags & TSV_PAGE_CHAINED_ITEM != 0 }
+    }
+
+    pub fn set_chained(&mut self, chained: bool) {
+        unsafe {
+            let opaque_data = TsvPageOpaqueData::with_page(self.page);
+            if chained {
+                (*opaque_data).flags |= TSV_PAGE_CHAINED_ITEM;
+            } else {
+                (*opaque_data).flags &= !TSV_PAGE_CHAINED_ITEM;
+            }
+        }
+    }
+
     pub fn set_types(&self, new: PageType) {
         unsafe {
             let opaque_data =
@@ -206,19 +335,33 @@ impl<'a> WritablePage<'a> {
     }
     /// commit saves all the changes to the page.
     /// Note that this will consume the page and make it unusable after the call.
-    pub fn commit(mut self) {
+    pub fn commit(self) {
+        self.commit_linked(vec![]);
+    }
+
+    /// Saves the changes to the page and to the pages linked to it in one WAL record, see link.
+    pub fn commit_linked(mut self, linked: Vec<WritablePage<'a>>) {
+        assert!(self.owns_record && linked.len() == self.num_linked);
         unsafe {
             pg_sys::MarkBufferDirty(*self.buffer);
+            for page in &linked {
+                assert!(page.state == self.state);
+                pg_sys::MarkBufferDirty(*page.buffer);
+            }
             pg_sys::GenericXLogFinish(self.state);
+            if self.log_new_page {
+                pg_sys::log_newpage_buffer(*self.buffer, true);
+            }
         }
         self.committed = true;
+        //the linked pages stay locked until here, after the record is written
     }
 }
 
 impl<'a> Drop for WritablePage<'a> {
-    // drop aborts the xlog if it has not been committed.
+    // drop aborts the xlog if it has not been committed. A linked page leaves that to the page owning the record.
     fn drop(&mut self) {
-        if !self.committed {
+        if !self.committed && self.owns_record {
             unsafe {
                 pg_sys::GenericXLogAbort(self.state);
             };
@@ -233,6 +376,26 @@ impl<'a> Deref for WritablePage<'a> {
     }
 }
 
+/// Returns true if the page holds no nodes, live or deleted.
+pub unsafe fn is_free_page(page: Page) -> bool {
+    let max_offset = PageGetMaxOffsetNumber(page);
+    (FirstOffsetNumber..(max_offset + 1) as _)
+        .all(|offset_number| (*PageGetItemId(page, offset_number)).lp_flags() == pg_sys::LP_UNUSED)
+}
+
+/// Returns true if the page of `buffer` is of the given type and holds no items, e.g. after VACUUM removed them.
+pub fn is_empty_page(buffer: &LockedBufferExclusive, page_type: PageType) -> bool {
+    unsafe {
+        let page = BufferGetPage(**buffer);
+        //a page the relation was extended with but that was never initialized
+        if (*(page as pg_sys::PageHeader)).pd_upper == 0 {
+            return false;
+        }
+        let opaque_data = TsvPageOpaqueData::read_from_page(&page);
+        PageType::from_u8(opaque_data.page_type) == page_type && is_free_page(page)
+    }
+}
+
 pub struct ReadablePage<'a> {
     buffer: LockedBufferShare<'a>,
     page: Page,
@@ -254,6 +417,11 @@ impl<'a> ReadablePage<'a> {
         PageType::from_u8((*opaque_data).page_type)
     }
 
+    pub fn is_chained(&self) -> bool {
+        let opaque_data = TsvPageOpaqueData::read_from_page(&self.page);
+        opaque_data.flags & TSV_PAGE_CHAINED_ITEM != 0
+    }
+
     pub fn get_buffer(&self) -> &LockedBufferShare {
         &self.buffer
     }
@@ -270,6 +438,7 @@ impl<'a> ReadablePage<'a> {
             _page: self,
             ptr: item,
             len: len as _,
+            _chained_data: None,
         }
     }
 }
@@ -119,3 +119,91 @@ pub unsafe fn slot_getattr(
     }
     return Some(*slot.tts_values.add(index));
 }
+
+// storage/freespace.h is not in the pgrx bindings
+mod freespace {
+    use pgrx::pg_sys;
+
+    extern "C" {
+        pub fn GetPageWithFreeSpace(
+            rel: pg_sys::Relation,
+            spaceNeeded: pg_sys::Size,
+        ) -> pg_sys::BlockNumber;
+        pub fn RecordAndGetPageWithFreeSpace(
+            rel: pg_sys::Relation,
+            oldPage: pg_sys::BlockNumber,
+            oldSpaceAvail: pg_sys::Size,
+            spaceNeeded: pg_sys::Size,
+        ) -> pg_sys::BlockNumber;
+        pub fn RecordPageWithFreeSpace(
+            rel: pg_sys::Relation,
+            heapBlk: pg_sys::BlockNumber,
+            spaceAvail: pg_sys::Size,
+        );
+        pub fn FreeSpaceMapVacuum(rel: pg_sys::Relation);
+        pub fn FreeSpaceMapVacuumRange(
+            rel: pg_sys::Relation,
+            start: pg_sys::BlockNumber,
+            end: pg_sys::BlockNumber,
+        );
+    }
+}
+
+#[allow(non_snake_case)]
+pub unsafe fn GetPageWithFreeSpace(
+    rel: pg_sys::Relation,
+    spaceNeeded: pg_sys::Size,
+) -> pg_sys::BlockNumber {
+    pg_sys::ffi::pg_guard_ffi_boundary(|| freespace::GetPageWithFreeSpace(rel, spaceNeeded))
+}
+
+#[allow(non_snake_case)]
+pub unsafe fn RecordAndGetPageWithFreeSpace(
+    rel: pg_sys::Relation,
+    oldPage: pg_sys::BlockNumber,
+    oldSpaceAvail: pg_sys::Size,
+    spaceNeeded: pg_sys::Size,
+) -> pg_sys::BlockNumber {
+    pg_sys::ffi::pg_guard_ffi_boundary(|| {
+        freespace::RecordAndGetPageWithFreeSpace(rel, oldPage, oldSpaceAvail, spaceNeeded)
+    })
+}
+
+#[allow(non_snake_case)]
+pub unsafe fn RecordPageWithFreeSpace(
+    rel: pg_sys::Relation,
+    heapBlk: pg_sys::BlockNumber,
+    spaceAvail: pg_sys::Size,
+) {
+    pg_sys::ffi::pg_guard_ffi_boundary(|| {
+        freespace::RecordPageWithFreeSpace(rel, heapBlk, spaceAvail)
+    })
+}
+
+#[allow(non_snake_case)]
+pub unsafe fn FreeSpaceMapVacuum(rel: pg_sys::Relation) {
+    pg_sys::ffi::pg_guard_ffi_boundary(|| freespace::FreeSpaceMapVacuum(rel))
+}
+
+#[allow(non_snake_case)]
+pub unsafe fn FreeSpaceMapVacuumRange(
+    rel: pg_sys::Relation,
+    start: pg_sys::BlockNumber,
+    end: pg_sys::BlockNumber,
+) {
+    pg_sys::ffi::pg_guard_ffi_boundary(|| freespace::FreeSpaceMapVacuumRange(rel, start, end))
+}
+
+// storage/sinvaladt.h is not in the pgrx bindings
+mod sinvaladt {
+    use pgrx::pg_sys;
+
+    extern "C" {
+        pub fn BackendIdGetProc(backendID: pg_sys::BackendId) -> *mut pg_sys::PGPROC;
+    }
+}
+
+#[allow(non_snake_case)]
+pub unsafe fn BackendIdGetProc(backendID: pg_sys::BackendId) -> *mut pg_sys::PGPROC {
+    pg_sys::ffi::pg_guard_ffi_boundary(|| sinvaladt::BackendIdGetProc(backendID))
+}
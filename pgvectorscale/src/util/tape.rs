@@ -1,15 +1,59 @@
 //! Tape provides a simple infinite-tape-writing abstraction over postgres pages.
 
-use super::page::{PageType, WritablePage};
+use super::{
+    buffer::LockedBufferExclusive,
+    page::{
+        is_empty_page, max_item_size, PageType, ReadablePage, WritablePage, MAX_PAGES_PER_RECORD,
+    },
+    ports::{
+        FreeSpaceMapVacuumRange, GetPageWithFreeSpace, RecordAndGetPageWithFreeSpace,
+        RecordPageWithFreeSpace,
+    },
+};
 use pgrx::{
-    pg_sys::{BlockNumber, BLCKSZ},
+    pg_sys::{BlockNumber, InvalidBlockNumber, InvalidOffsetNumber, OffsetNumber},
     *,
 };
 
+/// The header of every piece of an item that is larger than a page. Such an item is split into a head, on a page of
+/// the tape's type that is flagged as chained, and continuations on overflow pages. Every piece points to the next
+/// one and is alone on its page, so that changing an item never touches the pages of another one.
+#[derive(Clone, Copy)]
+pub struct ChainedItemHeader {
+    /// bytes of the item in this piece and the ones after it
+    pub len: u32,
+    /// the next piece, invalid for the last one
+    pub next: super::ItemPointer,
+}
+
+impl ChainedItemHeader {
+    pub const SIZE: usize = 4 + 4 + 2;
+
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let len = u32::from_ne_bytes(bytes[0..4].try_into().unwrap());
+        let block_number = BlockNumber::from_ne_bytes(bytes[4..8].try_into().unwrap());
+        let offset = OffsetNumber::from_ne_bytes(bytes[8..10].try_into().unwrap());
+        Self {
+            len,
+            next: super::ItemPointer::new(block_number, offset),
+        }
+    }
+
+    fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[0..4].copy_from_slice(&self.len.to_ne_bytes());
+        bytes[4..8].copy_from_slice(&self.next.block_number.to_ne_bytes());
+        bytes[8..10].copy_from_slice(&self.next.offset.to_ne_bytes());
+        bytes
+    }
+}
+
 pub struct Tape<'a> {
     page_type: PageType,
     index: &'a PgRelation,
     current: BlockNumber,
+    /* whether pages with free space are found through the free space map, see resume() */
+    reuse_free_space: bool,
 }
 
 impl<'a> Tape<'a> {
@@ -21,26 +65,97 @@ impl<'a> Tape<'a> {
             page_type,
             index: index,
             current: block_number,
+            reuse_free_space: false,
         }
     }
 
+    /// A tape that writes to existing pages with enough free space (e.g. the space freed by VACUUM) before
+    /// extending the relation. The free space left on the pages written is recorded on close.
+    pub unsafe fn resume(index: &'a PgRelation, page_type: PageType) -> Self {
+        Self {
+            page_type,
+            index,
+            current: InvalidBlockNumber,
+            reuse_free_space: true,
+        }
+    }
+
+    /// Returns a page of the tape's type with at least `size` bytes free, or None if the free space map knows
+    /// of none.
+    unsafe fn get_page_with_free_space(
+        &self,
+        size: usize,
+        mut block_number: BlockNumber,
+    ) -> Option<WritablePage<'a>> {
+        while block_number != InvalidBlockNumber {
+            let page = WritablePage::modify(self.index, block_number);
+            let free_space = if page.get_type() == self.page_type {
+                page.get_free_space()
+            } else {
+                0
+            };
+            if free_space >= size {
+                return Some(page);
+            }
+            //the free space map is only a hint, correct it and look again
+            std::mem::drop(page);
+            block_number =
+                RecordAndGetPageWithFreeSpace(self.index.as_ptr(), block_number, free_space, size);
+        }
+        None
+    }
+
     pub unsafe fn write(&mut self, data: &[u8]) -> super::ItemPointer {
         let size = data.len();
-        assert!(size < BLCKSZ as usize);
+        if size > max_item_size() {
+            return self.write_chained(data);
+        }
 
-        let mut current_page = WritablePage::modify(self.index, self.current);
+        let mut current_page = if self.current != InvalidBlockNumber {
+            Some(WritablePage::modify(self.index, self.current))
+        } else {
+            None
+        };
 
-        //don't split data over pages. Depending on packing,
-        //we may have to implement that in the future.
-        if current_page.get_free_space() < size {
+        //don't split data that fits on a page over pages.
+        if current_page
+            .as_ref()
+            .map_or(true, |page| page.get_free_space() < size)
+        {
             //TODO update forward pointer;
 
-            current_page = WritablePage::new(self.index, self.page_type);
-            self.current = current_page.get_block_number();
-            if current_page.get_free_space() < size {
+            let mut reused_page = None;
+            if self.reuse_free_space {
+                let block_number = match current_page.take() {
+                    Some(page) => {
+                        let free_space = page.get_free_space();
+                        std::mem::drop(page);
+                        RecordAndGetPageWithFreeSpace(
+                            self.index.as_ptr(),
+                            self.current,
+                            free_space,
+                            size,
+                        )
+                    }
+                    None => GetPageWithFreeSpace(self.index.as_ptr(), size),
+                };
+                reused_page = self.get_page_with_free_space(size, block_number);
+            }
+
+            let page = match reused_page {
+                Some(page) => page,
+                None => {
+                    std::mem::drop(current_page);
+                    WritablePage::new(self.index, self.page_type)
+                }
+            };
+            self.current = page.get_block_number();
+            if page.get_free_space() < size {
                 panic!("Not enough free space on new page");
             }
+            current_page = Some(page);
         }
+        let mut current_page = current_page.unwrap();
         let offset_number = current_page.add_item_unchecked(data);
 
         let item_pointer = super::ItemPointer::with_page(&current_page, offset_number);
@@ -48,7 +163,97 @@ impl<'a> Tape<'a> {
         item_pointer
     }
 
+    /// Returns an empty page of the given type with at least `size` bytes free, or None if the free space map knows
+    /// of none. Other pages of a WAL record may be locked already, so a page locked by another backend is not waited
+    /// for.
+    unsafe fn get_empty_page(
+        &self,
+        page_type: PageType,
+        size: usize,
+    ) -> Option<LockedBufferExclusive<'a>> {
+        if !self.reuse_free_space {
+            return None;
+        }
+        let mut block_number = GetPageWithFreeSpace(self.index.as_ptr(), size);
+        while block_number != InvalidBlockNumber {
+            let buffer = LockedBufferExclusive::try_read(self.index, block_number)?;
+            if is_empty_page(&buffer, page_type) {
+                return Some(buffer);
+            }
+            //the page holds other items, leave it out of the search until VACUUM records its free space again
+            std::mem::drop(buffer);
+            block_number =
+                RecordAndGetPageWithFreeSpace(self.index.as_ptr(), block_number, 0, size);
+        }
+        None
+    }
+
+    /// Writes an item larger than a page as a chain of pieces, see ChainedItemHeader. The pages of up to
+    /// MAX_PAGES_PER_RECORD pieces are written in one WAL record. The records are written from the last pieces, so
+    /// that every piece knows the next one, and the head goes last: the item can't be reached before all of it is
+    /// written.
+    unsafe fn write_chained(&mut self, data: &[u8]) -> super::ItemPointer {
+        let capacity = max_item_size() - ChainedItemHeader::SIZE;
+        let piece = |start: usize, next: super::ItemPointer| {
+            let end = (start + capacity).min(data.len());
+            let header = ChainedItemHeader {
+                len: (data.len() - start) as u32,
+                next,
+            };
+            let mut piece = Vec::with_capacity(ChainedItemHeader::SIZE + end - start);
+            piece.extend_from_slice(&header.to_bytes());
+            piece.extend_from_slice(&data[start..end]);
+            piece
+        };
+
+        let starts: Vec<usize> = (0..data.len()).step_by(capacity).collect();
+        let mut next = super::ItemPointer::new(InvalidBlockNumber, InvalidOffsetNumber);
+        for record in starts.rchunks(MAX_PAGES_PER_RECORD) {
+            let mut first: Option<WritablePage> = None;
+            let mut linked = vec![];
+            for &start in record {
+                let page_type = if start == 0 {
+                    self.page_type
+                } else {
+                    PageType::Overflow
+                };
+                let size = ChainedItemHeader::SIZE + capacity.min(data.len() - start);
+                let buffer = self
+                    .get_empty_page(page_type, size)
+                    .unwrap_or_else(|| LockedBufferExclusive::new(self.index));
+                match first.as_mut() {
+                    Some(first) => linked.push(first.link_new(buffer, page_type)),
+                    None => first = Some(WritablePage::reuse(self.index, buffer, page_type)),
+                }
+            }
+
+            let mut first = first.unwrap();
+            for (i, &start) in record.iter().enumerate().rev() {
+                let page = if i == 0 {
+                    &mut first
+                } else {
+                    &mut linked[i - 1]
+                };
+                page.set_chained(start == 0);
+                let offset_number = page.add_item(&piece(start, next));
+                next = super::ItemPointer::with_page(page, offset_number);
+            }
+            first.commit_linked(linked);
+        }
+        next
+    }
+
     pub fn close(self) {
+        if self.reuse_free_space && self.current != InvalidBlockNumber {
+            unsafe {
+                let page = ReadablePage::read(self.index, self.current);
+                let free_space = pg_sys::PageGetFreeSpace(*page);
+                std::mem::drop(page);
+                RecordPageWithFreeSpace(self.index.as_ptr(), self.current, free_space);
+                //make the free space visible to searches right away, not only after the next VACUUM
+                FreeSpaceMapVacuumRange(self.index.as_ptr(), self.current, self.current + 1);
+            }
+        }
         std::mem::drop(self)
     }
 }